@@ -22,13 +22,23 @@ pub fn dec_benc_it<'a, T: Iterator<Item=&'a u8>>(it: &mut T) -> Result<Benc, &'s
     }
 }
 
+// Decodes one value off the front of the input and says how many bytes it
+// took, for messages that tack raw data on after a bencoded header
+pub fn dec_benc_prefix(s: &[u8]) -> Result<(Benc, usize), &'static str> {
+    let mut it = s.iter().cloned().peekable();
+    let out = dec_benc_helper(&mut it)?;
+    // Whatever's left over (including anything peeked at) wasn't part of it
+    let used = s.len() - it.count();
+    Ok((out, used))
+}
+
 fn dec_benc_helper<T: Iterator<Item=u8>>(it: &mut Peekable<T>) -> Result<Benc, &'static str> {
     let next_char = match it.peek() {
             Some(c) => *c,
             None => return Err("Unable to decode empty string")
         };
 
-    if next_char >= ('1' as u8) && next_char <= ('9' as u8) {
+    if next_char >= ('0' as u8) && next_char <= ('9' as u8) {
         dec_string(it)
     } else if next_char == 'i' as u8 {
         dec_int(it)
@@ -188,24 +198,35 @@ fn dec_int<T: Iterator<Item=u8>>(it: &mut Peekable<T>) -> Result<Benc, &'static
 
 fn dec_string<T: Iterator<Item=u8>>(it: &mut Peekable<T>) -> Result<Benc, &'static str> {
     enum DecState {
-        ExpectNonZeroNum,
+        ExpectNum,
+        ExpectColon,
         ExpectNumOrColon,
         CountingDown
     }
 
-    let mut state = DecState::ExpectNonZeroNum;
+    let mut state = DecState::ExpectNum;
     let mut str_len = String::new();
     let mut bytes_remaining: i32 = 0;
     let mut out = Vec::new();
 
     for c in it {
         match state {
-            DecState::ExpectNonZeroNum => {
-                if c >= '1' as u8 && c <= '9' as u8 {
+            DecState::ExpectNum => {
+                if c == '0' as u8 {
+                    // Empty strings are legit (trackers send "5:peers0:" all the time)
+                    state = DecState::ExpectColon;
+                } else if c >= '1' as u8 && c <= '9' as u8 {
                     str_len.push(c as char);
                     state = DecState::ExpectNumOrColon;
                 } else {
-                    return Err("Needed a non-zero number");
+                    return Err("Needed a number");
+                }
+            },
+            DecState::ExpectColon => {
+                if c == ':' as u8 {
+                    return Ok(Benc::S(out));
+                } else {
+                    return Err("Needed a colon after a zero length");
                 }
             },
             DecState::ExpectNumOrColon => {
@@ -234,6 +255,86 @@ fn dec_string<T: Iterator<Item=u8>>(it: &mut Peekable<T>) -> Result<Benc, &'stat
     Err("Not enough characters in string")
 }
 
+// Benc insists on utf8 dict keys, but some things (scrape responses keyed by
+// info hash, for one) use raw bytes as keys. This keeps them as-is.
+pub enum RawBenc {
+    S(Vec<u8>),
+    I(i64),
+    L(Vec<RawBenc>),
+    D(BTreeMap<Vec<u8>, RawBenc>)
+}
+
+pub fn dec_raw_benc(s: &[u8]) -> Result<RawBenc, &'static str> {
+    let mut it = s.iter().cloned().peekable();
+    let out = dec_raw_helper(&mut it)?;
+    match it.next() {
+        None => Ok(out),
+        Some(_) => Err("Unable to consume whole string!")
+    }
+}
+
+// dec_benc_prefix, for RawBenc
+pub fn dec_raw_benc_prefix(s: &[u8]) -> Result<(RawBenc, usize), &'static str> {
+    let mut it = s.iter().cloned().peekable();
+    let out = dec_raw_helper(&mut it)?;
+    let used = s.len() - it.count();
+    Ok((out, used))
+}
+
+fn dec_raw_helper<T: Iterator<Item=u8>>(it: &mut Peekable<T>) -> Result<RawBenc, &'static str> {
+    let next_char = match it.peek() {
+        Some(c) => *c,
+        None => return Err("Unable to decode empty string")
+    };
+
+    match next_char {
+        b'0'..=b'9' => match dec_string(it)? {
+            Benc::S(s) => Ok(RawBenc::S(s)),
+            _ => unreachable!()
+        },
+        b'i' => match dec_int(it)? {
+            Benc::I(i) => Ok(RawBenc::I(i)),
+            _ => unreachable!()
+        },
+        b'l' => {
+            it.next();
+            let mut out = Vec::new();
+            loop {
+                match it.peek() {
+                    Some(&b'e') => {
+                        it.next();
+                        return Ok(RawBenc::L(out));
+                    },
+                    Some(_) => out.push(dec_raw_helper(it)?),
+                    None => return Err("Did not find terminal, failed to decode list")
+                }
+            }
+        },
+        b'd' => {
+            it.next();
+            let mut out = BTreeMap::new();
+            loop {
+                match it.peek() {
+                    Some(&b'e') => {
+                        it.next();
+                        return Ok(RawBenc::D(out));
+                    },
+                    Some(_) => {
+                        let key = match dec_string(it)? {
+                            Benc::S(s) => s,
+                            _ => unreachable!()
+                        };
+                        let value = dec_raw_helper(it)?;
+                        out.insert(key, value);
+                    },
+                    None => return Err("Ran out of characters, failed to decode dict")
+                }
+            }
+        },
+        _ => Err("Invalid character received!")
+    }
+}
+
 pub fn enc_benc(b: &Benc) -> Vec<u8> {
     match b {
         &Benc::S(ref s) => enc_string(s),
@@ -305,7 +406,7 @@ fn enc_dict(d: &BTreeMap<String, Benc>) -> Vec<u8> {
 #[cfg(test)]
 mod test {
     use std::collections::btree_map::BTreeMap;
-    use super::{Benc, dec_benc, enc_benc, enc_int, enc_dict, enc_list, enc_string};
+    use super::{Benc, RawBenc, dec_benc, dec_benc_prefix, dec_raw_benc, enc_benc, enc_int, enc_dict, enc_list, enc_string};
 
     // Make our lives a bit easier by having a Benc comparator
     fn compare_benc(x: &Benc, y: &Benc) -> bool {
@@ -527,6 +628,18 @@ mod test {
             Err(_) => ()
         };
 
+        let test_str_4b = "0:";
+        match dec_benc(&test_str_4b.as_bytes().to_vec()).unwrap() {
+            Benc::S(s) => assert!(s.is_empty()),
+            _ => unreachable!()
+        };
+
+        let test_str_4c = "01:a";
+        match dec_benc(&test_str_4c.as_bytes().to_vec()) {
+            Ok(_) => unreachable!(),
+            Err(_) => ()
+        };
+
         let test_str_5 = "4294967297:This length doesn't fit in an i32 (2^32 + 1)";
         match dec_benc(&test_str_5.as_bytes().to_vec()) {
             Ok(_) => unreachable!(),
@@ -610,4 +723,45 @@ mod test {
         let benc_dict = Benc::D(test_dict_2);
         assert_eq!(enc_benc(&benc_dict), "d8:filename15:moose_dance.mkv4:hash34:0xdeadbeefabadbabecafefoodfee1dead5:otherli3735928559e9:toothlesse10:part_counti237ee".as_bytes());
     }
+
+    #[test]
+    fn raw_keys() {
+        let raw = vec!('d' as u8, '2' as u8, ':' as u8, 0xfe, 0xff, 'l' as u8, 'i' as u8, '1' as u8, 'e' as u8,
+                '0' as u8, ':' as u8, 'e' as u8, 'e' as u8);
+        match dec_raw_benc(&raw).unwrap() {
+            RawBenc::D(d) => {
+                match d.get(&vec!(0xfe, 0xff)) {
+                    Some(&RawBenc::L(ref l)) => assert_eq!(l.len(), 2),
+                    _ => unreachable!()
+                }
+            },
+            _ => unreachable!()
+        }
+
+        match dec_raw_benc("di1ei2ee".as_bytes()) {
+            Ok(_) => unreachable!(),
+            Err(_) => ()
+        }
+        match dec_raw_benc("d1:ai1e".as_bytes()) {
+            Ok(_) => unreachable!(),
+            Err(_) => ()
+        }
+    }
+
+    #[test]
+    fn prefix() {
+        let (b, used) = dec_benc_prefix("d1:ai1ee\x01\x02".as_bytes()).unwrap();
+        assert_eq!(used, 8);
+        match b {
+            Benc::D(d) => assert_eq!(d.len(), 1),
+            _ => unreachable!()
+        }
+
+        assert_eq!(dec_benc_prefix("4:spamxyz".as_bytes()).unwrap().1, 6);
+        assert_eq!(dec_benc_prefix("i-3e".as_bytes()).unwrap().1, 4);
+        match dec_benc_prefix("d1:ai1e".as_bytes()) {
+            Ok(_) => unreachable!(),
+            Err(_) => ()
+        }
+    }
 }
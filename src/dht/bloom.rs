@@ -0,0 +1,113 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+pub const BLOOM_BYTES: usize = 256;
+const BLOOM_BITS: usize = BLOOM_BYTES * 8;
+
+// The BEP 33 bloom filters nodes send back for scrapes: 2048 bits, with two
+// bits set per peer IP, taken from the SHA-1 of its address. ORing together
+// the filters from a few nodes and counting the bits still clear gives a
+// decent guess at how many distinct IPs went in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BloomFilter(pub [u8; BLOOM_BYTES]);
+
+impl BloomFilter {
+    pub fn new() -> BloomFilter {
+        BloomFilter([0; BLOOM_BYTES])
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<BloomFilter> {
+        if bytes.len() != BLOOM_BYTES {
+            return None;
+        }
+        let mut bits = [0u8; BLOOM_BYTES];
+        bits.copy_from_slice(bytes);
+        Some(BloomFilter(bits))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; BLOOM_BYTES] {
+        &self.0
+    }
+
+    pub fn insert(&mut self, ip: IpAddr) {
+        let mut hasher = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => hasher.input(&ip.octets()),
+            IpAddr::V6(ip) => hasher.input(&ip.octets())
+        }
+        let mut hash = [0u8; 20];
+        hasher.result(&mut hash);
+
+        for pair in hash[..4].chunks(2) {
+            let index = (pair[0] as usize | (pair[1] as usize) << 8) % BLOOM_BITS;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn union(&mut self, other: &BloomFilter) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a |= *b;
+        }
+    }
+
+    // A filter with every bit set would say infinity, so it's treated as
+    // having one bit clear instead
+    pub fn size_estimate(&self) -> f64 {
+        let zeros: u32 = self.0.iter().map(|b| b.count_zeros()).sum();
+        let m = BLOOM_BITS as f64;
+        let c = f64::from(zeros.max(1));
+        (c / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())
+    }
+}
+
+impl Default for BloomFilter {
+    fn default() -> BloomFilter {
+        BloomFilter::new()
+    }
+}
+
+impl fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let set: u32 = self.0.iter().map(|b| b.count_ones()).sum();
+        write!(f, "BloomFilter({} bits set)", set)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::BloomFilter;
+
+    #[test]
+    fn bep_33_vector() {
+        let mut filter = BloomFilter::new();
+        assert_eq!(filter.size_estimate(), 0.0);
+        for i in 0..256 {
+            filter.insert(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i as u8)));
+        }
+        for i in 0..1000 {
+            filter.insert(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)));
+        }
+        assert!((filter.size_estimate() - 1224.9308).abs() < 0.001);
+
+        // Splitting them up and putting them back together changes nothing
+        let mut a = BloomFilter::new();
+        let mut b = BloomFilter::new();
+        for i in 0..256 {
+            let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, i as u8));
+            if i % 2 == 0 { a.insert(ip) } else { b.insert(ip) }
+        }
+        for i in 0..1000 {
+            b.insert(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)));
+        }
+        a.union(&b);
+        assert_eq!(a, filter);
+
+        assert!(BloomFilter::from_slice(&[0; 255]).is_none());
+        assert!(BloomFilter([0xff; 256]).size_estimate().is_finite());
+    }
+}
@@ -0,0 +1,239 @@
+use crypto::digest::Digest;
+use crypto::ed25519;
+use crypto::sha1::Sha1;
+
+use bencode::*;
+use dht::NodeId;
+
+// BEP 44 limits: the bencoded value, and the salt on mutable items
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+
+// Something stored in the DHT (BEP 44). Immutable items live at the SHA-1 of
+// their value. Mutable ones live at the SHA-1 of the public key and salt, so
+// whoever holds the private key can keep replacing the value, bumping seq
+// each time. The value is kept bencoded, since that's what gets hashed and
+// signed, and every way of making an item checks it really is bencoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Item {
+    v: Vec<u8>,
+    // Only mutable items have these
+    signed: Option<Signed>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Signed {
+    k: [u8; 32],
+    salt: Vec<u8>,
+    seq: i64,
+    sig: [u8; 64]
+}
+
+// One whole bencoded value and nothing else. Dict keys can be any bytes,
+// which is why this doesn't go through Benc.
+fn check_value(v: &[u8]) -> Result<(), String> {
+    dec_raw_benc(v).map(|_| ()).map_err(|e| format!("Item value isn't bencoded: {}", e))
+}
+
+impl Item {
+    pub fn immutable(value: &Benc) -> Item {
+        Item { v: enc_benc(value), signed: None }
+    }
+
+    // secret_key is the 64 byte key from crypto::ed25519::keypair, which has
+    // the public key in its second half
+    pub fn mutable(value: &Benc, secret_key: &[u8; 64], salt: &[u8], seq: i64) -> Item {
+        Item::sign(enc_benc(value), secret_key, salt, seq)
+    }
+
+    // For values that are already bencoded, like ones with binary dict keys
+    // that Benc can't hold
+    pub fn immutable_raw(v: Vec<u8>) -> Result<Item, String> {
+        check_value(&v)?;
+        Ok(Item { v, signed: None })
+    }
+
+    pub fn mutable_raw(v: Vec<u8>, secret_key: &[u8; 64], salt: &[u8], seq: i64) -> Result<Item, String> {
+        check_value(&v)?;
+        Ok(Item::sign(v, secret_key, salt, seq))
+    }
+
+    // A mutable item someone else signed, say one that came off the wire.
+    // Whether the signature's any good is up to verify().
+    pub fn from_parts(v: Vec<u8>, k: [u8; 32], salt: Vec<u8>, seq: i64, sig: [u8; 64]) -> Result<Item, String> {
+        check_value(&v)?;
+        Ok(Item { v, signed: Some(Signed { k, salt, seq, sig }) })
+    }
+
+    fn sign(v: Vec<u8>, secret_key: &[u8; 64], salt: &[u8], seq: i64) -> Item {
+        let sig = ed25519::signature(&signed_bytes(salt, seq, &v), secret_key);
+        let mut k = [0u8; 32];
+        k.copy_from_slice(&secret_key[32..]);
+        Item { v, signed: Some(Signed { k, salt: salt.to_vec(), seq, sig }) }
+    }
+
+    pub fn target(&self) -> NodeId {
+        match self.signed {
+            None => immutable_target(&self.v),
+            Some(ref s) => mutable_target(&s.k, &s.salt)
+        }
+    }
+
+    // The bencoded value
+    pub fn v(&self) -> &[u8] {
+        &self.v
+    }
+
+    // Fails for values Benc can't hold
+    pub fn value(&self) -> Result<Benc, String> {
+        dec_benc(&self.v).map_err(|e| format!("Unable to decode item value: {}", e))
+    }
+
+    pub fn is_mutable(&self) -> bool {
+        self.signed.is_some()
+    }
+
+    pub fn k(&self) -> Option<[u8; 32]> {
+        self.signed.as_ref().map(|s| s.k)
+    }
+
+    // Empty for immutable items, and mutable ones without a salt
+    pub fn salt(&self) -> &[u8] {
+        match self.signed {
+            Some(ref s) => &s.salt,
+            None => &[]
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        self.signed.as_ref().map(|s| s.seq)
+    }
+
+    pub fn sig(&self) -> Option<[u8; 64]> {
+        self.signed.as_ref().map(|s| s.sig)
+    }
+
+    // Immutable items can't be wrong about anything once they're at the
+    // right target, so only mutable ones have anything to check
+    pub fn verify(&self) -> bool {
+        match self.signed {
+            None => true,
+            Some(ref s) => ed25519::verify(&signed_bytes(&s.salt, s.seq, &self.v), &s.k, &s.sig)
+        }
+    }
+}
+
+pub fn immutable_target(v: &[u8]) -> NodeId {
+    sha1(&[v])
+}
+
+pub fn mutable_target(k: &[u8; 32], salt: &[u8]) -> NodeId {
+    sha1(&[k, salt])
+}
+
+fn sha1(parts: &[&[u8]]) -> NodeId {
+    let mut hasher = Sha1::new();
+    for p in parts.iter() {
+        hasher.input(p);
+    }
+    let mut hash = [0u8; 20];
+    hasher.result(&mut hash);
+    NodeId(hash)
+}
+
+// What a mutable item's signature covers: the salt (if there is one), seq
+// and v, as they'd look in a bencoded dict minus the surrounding d and e
+fn signed_bytes(salt: &[u8], seq: i64, v: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if !salt.is_empty() {
+        out.extend_from_slice(b"4:salt");
+        out.extend(enc_benc(&Benc::S(salt.to_vec())));
+    }
+    out.extend_from_slice(b"3:seq");
+    out.extend(enc_benc(&Benc::I(seq)));
+    out.extend_from_slice(b"1:v");
+    out.extend_from_slice(v);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use crypto::ed25519;
+
+    use bencode::*;
+    use dht::NodeId;
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn hello() -> Benc {
+        Benc::S(b"Hello World!".to_vec())
+    }
+
+    #[test]
+    fn bep_44_vectors() {
+        let immutable = Item::immutable(&hello());
+        assert_eq!(immutable.v(), b"12:Hello World!");
+        assert_eq!(immutable.target(), NodeId::from_slice(&unhex("e5f96f6f38320f0f33959cb4d3d656452117aadb")).unwrap());
+
+        // The BEP's private key isn't in the seed form ed25519 here wants,
+        // so these only check we sign and verify the same bytes
+        let mut k = [0u8; 32];
+        k.copy_from_slice(&unhex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548"));
+        assert_eq!(signed_bytes(b"", 1, b"12:Hello World!"), b"3:seqi1e1:v12:Hello World!".to_vec());
+        assert_eq!(signed_bytes(b"foobar", 1, b"12:Hello World!"), b"4:salt6:foobar3:seqi1e1:v12:Hello World!".to_vec());
+
+        let mut sig = [0u8; 64];
+        sig.copy_from_slice(&unhex("305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01"));
+        let item = Item::from_parts(b"12:Hello World!".to_vec(), k, Vec::new(), 1, sig).unwrap();
+        assert!(item.verify());
+        assert_eq!(item.target(), NodeId::from_slice(&unhex("4a533d47ec9c7d95b1ad75f576cffc641853b750")).unwrap());
+
+        sig.copy_from_slice(&unhex("6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08"));
+        let salted = Item::from_parts(b"12:Hello World!".to_vec(), k, b"foobar".to_vec(), 1, sig).unwrap();
+        assert!(salted.verify());
+        assert_eq!(salted.target(), NodeId::from_slice(&unhex("411eba73b6f087ca51a3795d9c8c938d365e32c1")).unwrap());
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let (secret, public) = ed25519::keypair(&[7; 32]);
+        let item = Item::mutable(&hello(), &secret, b"feed", 3);
+        assert!(item.verify());
+        assert_eq!(item.seq(), Some(3));
+        assert_eq!(item.target(), mutable_target(&public, b"feed"));
+        match item.value().unwrap() {
+            Benc::S(s) => assert_eq!(s, b"Hello World!".to_vec()),
+            _ => unreachable!()
+        }
+
+        // Changing anything that's signed breaks it
+        let (v, k, sig) = (item.v().to_vec(), item.k().unwrap(), item.sig().unwrap());
+        assert!(!Item::from_parts(v.clone(), k, b"feed".to_vec(), 4, sig).unwrap().verify());
+        assert!(!Item::from_parts(v, k, b"food".to_vec(), 3, sig).unwrap().verify());
+    }
+
+    #[test]
+    fn raw_values() {
+        // Binary dict keys, and keys out of order: Benc can't hold it, but
+        // it's bencoding, and the bytes are kept as they are
+        let v = b"d2:\xff\x01i1e1:bi2e1:ai3ee".to_vec();
+        let item = Item::immutable_raw(v.clone()).unwrap();
+        assert_eq!(item.v(), &v[..]);
+        assert_eq!(item.target(), immutable_target(&v));
+        assert!(item.value().is_err());
+
+        let (secret, public) = ed25519::keypair(&[8; 32]);
+        let item = Item::mutable_raw(v.clone(), &secret, b"", 1).unwrap();
+        assert!(item.verify());
+        assert_eq!(item.k(), Some(public));
+
+        for bad in [&b"i1"[..], b"i1ei2e", b"", b"x"].iter() {
+            assert!(Item::immutable_raw(bad.to_vec()).is_err());
+            assert!(Item::mutable_raw(bad.to_vec(), &secret, b"", 1).is_err());
+            assert!(Item::from_parts(bad.to_vec(), public, Vec::new(), 1, [0; 64]).is_err());
+        }
+    }
+}
@@ -0,0 +1,650 @@
+use std::collections::btree_map::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+
+use bencode::*;
+use dht::*;
+use dht::bloom::BloomFilter;
+use dht::item::Item;
+use tracker::{compact_addr, parse_compact_peers, parse_compact_peers6};
+
+// KRPC error codes from BEP 5
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_SERVER: i64 = 202;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+// And the ones BEP 44 adds for put
+pub const ERROR_VALUE_TOO_BIG: i64 = 205;
+pub const ERROR_INVALID_SIGNATURE: i64 = 206;
+pub const ERROR_SALT_TOO_BIG: i64 = 207;
+pub const ERROR_CAS_MISMATCH: i64 = 301;
+pub const ERROR_SEQ_TOO_LOW: i64 = 302;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    Ping { id: NodeId },
+    FindNode { id: NodeId, target: NodeId },
+    // scrape asks for the BEP 33 bloom filters as well
+    GetPeers { id: NodeId, info_hash: [u8; 20], scrape: bool },
+    // implied_port means "use whatever port this packet came from", for
+    // peers behind NATs that only know their uTP port. seed is BEP 33's
+    // way of saying we've got the whole thing.
+    AnnouncePeer { id: NodeId, info_hash: [u8; 20], port: u16, implied_port: bool, token: Vec<u8>, seed: bool },
+    // BEP 44. seq means "only send the value if you've got something newer".
+    Get { id: NodeId, target: NodeId, seq: Option<i64> },
+    // cas means "only if what you've got now has this seq"
+    Put { id: NodeId, token: Vec<u8>, item: Item, cas: Option<i64> },
+    // BEP 51: some of the info hashes you know about, and nodes near target
+    // so crawlers can keep going
+    SampleInfohashes { id: NodeId, target: NodeId }
+}
+
+impl Query {
+    pub fn id(&self) -> NodeId {
+        match *self {
+            Query::Ping { id } | Query::FindNode { id, .. } | Query::GetPeers { id, .. }
+                | Query::AnnouncePeer { id, .. } | Query::Get { id, .. } | Query::Put { id, .. }
+                | Query::SampleInfohashes { id, .. } => id
+        }
+    }
+
+    pub fn method(&self) -> &'static str {
+        match *self {
+            Query::Ping { .. } => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::Get { .. } => "get",
+            Query::Put { .. } => "put",
+            Query::SampleInfohashes { .. } => "sample_infohashes"
+        }
+    }
+}
+
+// Responses don't say what they're responding to, so this is the union of
+// everything any of them can carry
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub nodes6: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    // BEP 44 get responses. The salt isn't sent back, so these aren't a
+    // whole Item until we put it together with the one we asked for.
+    pub v: Option<Vec<u8>>,
+    pub k: Option<[u8; 32]>,
+    pub seq: Option<i64>,
+    pub sig: Option<[u8; 64]>,
+    // BEP 33 scrape responses: the seeds and the other peers we know of.
+    // Boxed, since they'd make every message half a kilobyte bigger.
+    pub bf_seeds: Option<Box<BloomFilter>>,
+    pub bf_peers: Option<Box<BloomFilter>>,
+    // BEP 51 sample_infohashes responses. interval is how many seconds to
+    // wait before asking again, num how many info hashes the node has.
+    pub interval: Option<i64>,
+    pub num: Option<i64>,
+    pub samples: Vec<[u8; 20]>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error { code: i64, message: String }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    // Transaction ID, picked by whoever sent the query and echoed back
+    pub t: Vec<u8>,
+    // Client version, which hardly anyone looks at
+    pub v: Option<Vec<u8>>,
+    // BEP 42: the address the sender saw us at, so nodes behind NAT can
+    // work out which IP their ID needs to match
+    pub ip: Option<SocketAddr>,
+    pub body: Body
+}
+
+// Something we couldn't make sense of. If it was a query and we got far
+// enough to find the transaction ID, this can go straight back as an error
+// message. Broken responses and errors don't get answered, so they never
+// have one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KrpcError {
+    pub t: Option<Vec<u8>>,
+    pub code: i64,
+    pub message: String
+}
+
+impl fmt::Display for KrpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KRPC error {}: {}", self.code, self.message)
+    }
+}
+
+impl Error for KrpcError {}
+
+impl KrpcError {
+    pub fn to_message(&self) -> Option<Message> {
+        self.t.as_ref().map(|t| Message {
+            t: t.clone(),
+            v: None,
+            ip: None,
+            body: Body::Error { code: self.code, message: self.message.clone() }
+        })
+    }
+}
+
+fn bytes(s: &[u8]) -> Benc {
+    Benc::S(s.to_vec())
+}
+
+// BEP 44 values can be any bencoding at all, binary dict keys included,
+// which Benc can't hold, and they have to get through byte for byte for
+// hashes and signatures to check out. So the 'v' in the 'a' or 'r' dict is
+// cut out of a packet before it's decoded, and spliced into one after it's
+// encoded, with an empty string standing in for it in between.
+fn value_span(packet: &[u8]) -> Option<(usize, usize)> {
+    for (key, start, _) in dict_entries(packet, 0)? {
+        if key == b"a" || key == b"r" {
+            if let Some(&(_, s, e)) = dict_entries(packet, start)?.iter().find(|e| e.0 == b"v") {
+                return Some((s, e));
+            }
+        }
+    }
+    None
+}
+
+// The keys of the dict starting at `at`, each with where its value starts
+// and ends
+fn dict_entries(packet: &[u8], at: usize) -> Option<Vec<(Vec<u8>, usize, usize)>> {
+    if packet.get(at) != Some(&b'd') {
+        return None;
+    }
+    let mut pos = at + 1;
+    let mut out = Vec::new();
+    while *packet.get(pos)? != b'e' {
+        let key = match dec_raw_benc_prefix(&packet[pos..]).ok()? {
+            (RawBenc::S(key), used) => {
+                pos += used;
+                key
+            },
+            _ => { return None; }
+        };
+        let (_, used) = dec_raw_benc_prefix(&packet[pos..]).ok()?;
+        out.push((key, pos, pos + used));
+        pos += used;
+    }
+    Some(out)
+}
+
+fn splice(packet: &[u8], (start, end): (usize, usize), v: &[u8]) -> Vec<u8> {
+    let mut out = packet[..start].to_vec();
+    out.extend_from_slice(v);
+    out.extend_from_slice(&packet[end..]);
+    out
+}
+
+fn fixed<const N: usize>(s: &[u8]) -> Option<[u8; N]> {
+    if s.len() != N {
+        return None;
+    }
+    let mut out = [0u8; N];
+    out.copy_from_slice(s);
+    Some(out)
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut d = BTreeMap::new();
+        d.insert(String::from("t"), bytes(&self.t));
+        if let Some(ref v) = self.v {
+            d.insert(String::from("v"), bytes(v));
+        }
+        if let Some(ref ip) = self.ip {
+            d.insert(String::from("ip"), Benc::S(compact_addr(ip)));
+        }
+
+        match self.body {
+            Body::Query(ref q) => {
+                let mut a = BTreeMap::new();
+                a.insert(String::from("id"), bytes(q.id().as_bytes()));
+                match *q {
+                    Query::Ping { .. } => (),
+                    Query::FindNode { target, .. } | Query::SampleInfohashes { target, .. } => {
+                        a.insert(String::from("target"), bytes(target.as_bytes()));
+                    },
+                    Query::GetPeers { info_hash, scrape, .. } => {
+                        a.insert(String::from("info_hash"), bytes(&info_hash));
+                        if scrape {
+                            a.insert(String::from("scrape"), Benc::I(1));
+                        }
+                    },
+                    Query::AnnouncePeer { info_hash, port, implied_port, ref token, seed, .. } => {
+                        a.insert(String::from("info_hash"), bytes(&info_hash));
+                        a.insert(String::from("port"), Benc::I(port as i64));
+                        a.insert(String::from("token"), bytes(token));
+                        if implied_port {
+                            a.insert(String::from("implied_port"), Benc::I(1));
+                        }
+                        if seed {
+                            a.insert(String::from("seed"), Benc::I(1));
+                        }
+                    },
+                    Query::Get { target, seq, .. } => {
+                        a.insert(String::from("target"), bytes(target.as_bytes()));
+                        if let Some(seq) = seq {
+                            a.insert(String::from("seq"), Benc::I(seq));
+                        }
+                    },
+                    Query::Put { ref token, ref item, cas, .. } => {
+                        a.insert(String::from("token"), bytes(token));
+                        a.insert(String::from("v"), bytes(b""));
+                        if let (Some(k), Some(seq), Some(sig)) = (item.k(), item.seq(), item.sig()) {
+                            a.insert(String::from("k"), bytes(&k));
+                            a.insert(String::from("seq"), Benc::I(seq));
+                            a.insert(String::from("sig"), bytes(&sig));
+                            if !item.salt().is_empty() {
+                                a.insert(String::from("salt"), bytes(item.salt()));
+                            }
+                        }
+                        if let Some(cas) = cas {
+                            a.insert(String::from("cas"), Benc::I(cas));
+                        }
+                    }
+                }
+                d.insert(String::from("y"), bytes(b"q"));
+                d.insert(String::from("q"), bytes(q.method().as_bytes()));
+                d.insert(String::from("a"), Benc::D(a));
+            },
+            Body::Response(ref r) => {
+                let mut rd = BTreeMap::new();
+                rd.insert(String::from("id"), bytes(r.id.as_bytes()));
+                if !r.nodes.is_empty() {
+                    rd.insert(String::from("nodes"), Benc::S(encode_compact_nodes(&r.nodes, false)));
+                }
+                if !r.nodes6.is_empty() {
+                    rd.insert(String::from("nodes6"), Benc::S(encode_compact_nodes(&r.nodes6, true)));
+                }
+                if !r.values.is_empty() {
+                    rd.insert(String::from("values"), Benc::L(r.values.iter().map(|a| Benc::S(compact_addr(a))).collect()));
+                }
+                if let Some(ref token) = r.token {
+                    rd.insert(String::from("token"), bytes(token));
+                }
+                if r.v.is_some() {
+                    rd.insert(String::from("v"), bytes(b""));
+                }
+                if let Some(ref k) = r.k {
+                    rd.insert(String::from("k"), bytes(k));
+                }
+                if let Some(seq) = r.seq {
+                    rd.insert(String::from("seq"), Benc::I(seq));
+                }
+                if let Some(ref sig) = r.sig {
+                    rd.insert(String::from("sig"), bytes(sig));
+                }
+                if let Some(ref bf) = r.bf_seeds {
+                    rd.insert(String::from("BFsd"), bytes(bf.as_bytes()));
+                }
+                if let Some(ref bf) = r.bf_peers {
+                    rd.insert(String::from("BFpe"), bytes(bf.as_bytes()));
+                }
+                if let Some(interval) = r.interval {
+                    rd.insert(String::from("interval"), Benc::I(interval));
+                }
+                if let Some(num) = r.num {
+                    rd.insert(String::from("num"), Benc::I(num));
+                    rd.insert(String::from("samples"), Benc::S(r.samples.concat()));
+                }
+                d.insert(String::from("y"), bytes(b"r"));
+                d.insert(String::from("r"), Benc::D(rd));
+            },
+            Body::Error { code, ref message } => {
+                d.insert(String::from("y"), bytes(b"e"));
+                d.insert(String::from("e"), Benc::L(vec!(Benc::I(code), bytes(message.as_bytes()))));
+            }
+        }
+
+        let packet = enc_benc(&Benc::D(d));
+        let v = match self.body {
+            Body::Query(Query::Put { ref item, .. }) => Some(item.v()),
+            Body::Response(Response { v: Some(ref v), .. }) => Some(&v[..]),
+            _ => None
+        };
+        match (v, value_span(&packet)) {
+            (Some(v), Some(span)) => splice(&packet, span, v),
+            _ => packet
+        }
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<Message, KrpcError> {
+        let (packet, item_v) = match value_span(packet) {
+            Some((start, end)) => (splice(packet, (start, end), b"0:"), Some(packet[start..end].to_vec())),
+            None => (packet.to_vec(), None)
+        };
+        let d = match dec_benc(&packet) {
+            Ok(Benc::D(d)) => d,
+            _ => { return Err(protocol_error(None, "Packet is not a bencoded dictionary")); }
+        };
+
+        let t = match d.get("t") {
+            Some(Benc::S(t)) => t.clone(),
+            _ => { return Err(protocol_error(None, "Missing transaction ID")); }
+        };
+        let v = match d.get("v") {
+            Some(Benc::S(v)) => Some(v.clone()),
+            _ => None
+        };
+        // Anything but a 6 or 18 byte address is ignored rather than failing
+        // the whole message over a hint
+        let ip = match d.get("ip") {
+            Some(Benc::S(ip)) if ip.len() == 6 => parse_compact_peers(ip).ok().map(|p| p[0].addr),
+            Some(Benc::S(ip)) if ip.len() == 18 => parse_compact_peers6(ip).ok().map(|p| p[0].addr),
+            _ => None
+        };
+
+        let body = match d.get("y") {
+            Some(Benc::S(y)) if y == b"q" => Body::Query(decode_query(&t, &d, item_v)?),
+            Some(Benc::S(y)) if y == b"r" => match d.get("r") {
+                Some(Benc::D(r)) => Body::Response(decode_response(r, item_v)?),
+                _ => { return Err(protocol_error(None, "Response is missing 'r'")); }
+            },
+            Some(Benc::S(y)) if y == b"e" => match d.get("e") {
+                Some(Benc::L(e)) if e.len() >= 2 => match (&e[0], &e[1]) {
+                    (Benc::I(code), Benc::S(message)) => Body::Error {
+                        code: *code,
+                        message: String::from_utf8_lossy(message).into_owned()
+                    },
+                    _ => { return Err(protocol_error(None, "Malformed error")); }
+                },
+                _ => { return Err(protocol_error(None, "Error is missing 'e'")); }
+            },
+            _ => { return Err(protocol_error(None, "Unknown message type")); }
+        };
+
+        Ok(Message { t, v, ip, body })
+    }
+}
+
+fn protocol_error(t: Option<&Vec<u8>>, message: &str) -> KrpcError {
+    KrpcError { t: t.cloned(), code: ERROR_PROTOCOL, message: String::from(message) }
+}
+
+fn get_id(t: &Vec<u8>, d: &BTreeMap<String, Benc>, key: &str) -> Result<NodeId, KrpcError> {
+    match d.get(key) {
+        Some(Benc::S(s)) => NodeId::from_slice(s).ok_or_else(|| protocol_error(Some(t), &format!("'{}' must be 20 bytes", key))),
+        _ => Err(protocol_error(Some(t), &format!("Missing '{}'", key)))
+    }
+}
+
+fn decode_query(t: &Vec<u8>, d: &BTreeMap<String, Benc>, item_v: Option<Vec<u8>>) -> Result<Query, KrpcError> {
+    let a = match d.get("a") {
+        Some(Benc::D(a)) => a,
+        _ => { return Err(protocol_error(Some(t), "Query is missing 'a'")); }
+    };
+    let id = get_id(t, a, "id")?;
+
+    match d.get("q") {
+        Some(Benc::S(q)) if q == b"ping" => Ok(Query::Ping { id }),
+        Some(Benc::S(q)) if q == b"find_node" => Ok(Query::FindNode { id, target: get_id(t, a, "target")? }),
+        Some(Benc::S(q)) if q == b"get_peers" => {
+            let scrape = matches!(a.get("scrape"), Some(&Benc::I(1)));
+            Ok(Query::GetPeers { id, info_hash: get_id(t, a, "info_hash")?.0, scrape })
+        },
+        Some(Benc::S(q)) if q == b"announce_peer" => {
+            let info_hash = get_id(t, a, "info_hash")?.0;
+            let implied_port = matches!(a.get("implied_port"), Some(&Benc::I(1)));
+            let port = match a.get("port") {
+                Some(&Benc::I(p)) if p > 0 && p <= 65535 => p as u16,
+                // The port doesn't matter if it's implied
+                _ if implied_port => 0,
+                _ => { return Err(protocol_error(Some(t), "Missing or bad 'port'")); }
+            };
+            let token = match a.get("token") {
+                Some(Benc::S(token)) => token.clone(),
+                _ => { return Err(protocol_error(Some(t), "Missing 'token'")); }
+            };
+            let seed = matches!(a.get("seed"), Some(&Benc::I(1)));
+            Ok(Query::AnnouncePeer { id, info_hash, port, implied_port, token, seed })
+        },
+        Some(Benc::S(q)) if q == b"get" => {
+            let seq = match a.get("seq") {
+                Some(&Benc::I(seq)) => Some(seq),
+                _ => None
+            };
+            Ok(Query::Get { id, target: get_id(t, a, "target")?, seq })
+        },
+        Some(Benc::S(q)) if q == b"put" => decode_put(t, id, a, item_v),
+        Some(Benc::S(q)) if q == b"sample_infohashes" => Ok(Query::SampleInfohashes { id, target: get_id(t, a, "target")? }),
+        Some(Benc::S(_)) => Err(KrpcError { t: Some(t.clone()), code: ERROR_METHOD_UNKNOWN, message: String::from("Method Unknown") }),
+        _ => Err(protocol_error(Some(t), "Query is missing 'q'"))
+    }
+}
+
+// v is the raw value, cut out of the packet before it was decoded
+fn decode_put(t: &Vec<u8>, id: NodeId, a: &BTreeMap<String, Benc>, v: Option<Vec<u8>>) -> Result<Query, KrpcError> {
+    let token = match a.get("token") {
+        Some(Benc::S(token)) => token.clone(),
+        _ => { return Err(protocol_error(Some(t), "Missing 'token'")); }
+    };
+    let v = v.ok_or_else(|| protocol_error(Some(t), "Missing 'v'"))?;
+    let cas = match a.get("cas") {
+        Some(&Benc::I(cas)) => Some(cas),
+        _ => None
+    };
+
+    // No k means it's immutable. Otherwise the rest of the mutable fields
+    // all have to be there, but whether they check out is up to the node.
+    let item = match a.get("k") {
+        None => Item::immutable_raw(v),
+        Some(Benc::S(k)) => {
+            let k = fixed(k).ok_or_else(|| protocol_error(Some(t), "'k' must be 32 bytes"))?;
+            let sig = match a.get("sig") {
+                Some(Benc::S(sig)) => fixed::<64>(sig),
+                _ => None
+            }.ok_or_else(|| protocol_error(Some(t), "Missing or bad 'sig'"))?;
+            let seq = match a.get("seq") {
+                Some(&Benc::I(seq)) => seq,
+                _ => { return Err(protocol_error(Some(t), "Missing 'seq'")); }
+            };
+            let salt = match a.get("salt") {
+                Some(Benc::S(salt)) => salt.clone(),
+                _ => Vec::new()
+            };
+            Item::from_parts(v, k, salt, seq, sig)
+        },
+        Some(_) => { return Err(protocol_error(Some(t), "'k' must be a string")); }
+    }.map_err(|e| protocol_error(Some(t), &e))?;
+
+    Ok(Query::Put { id, token, item, cas })
+}
+
+fn decode_response(r: &BTreeMap<String, Benc>, v: Option<Vec<u8>>) -> Result<Response, KrpcError> {
+    let id = match r.get("id") {
+        Some(Benc::S(s)) => NodeId::from_slice(s),
+        _ => None
+    };
+    let mut out = Response { id: id.ok_or_else(|| protocol_error(None, "Response has a missing or bad 'id'"))?, ..Default::default() };
+
+    if let Some(Benc::S(nodes)) = r.get("nodes") {
+        out.nodes = parse_compact_nodes(nodes).map_err(|e| protocol_error(None, &e))?;
+    }
+    if let Some(Benc::S(nodes)) = r.get("nodes6") {
+        out.nodes6 = parse_compact_nodes6(nodes).map_err(|e| protocol_error(None, &e))?;
+    }
+    if let Some(Benc::L(values)) = r.get("values") {
+        for v in values.iter() {
+            // Anything that isn't a compact address gets skipped
+            let peers = match *v {
+                Benc::S(ref s) if s.len() == 6 => parse_compact_peers(s),
+                Benc::S(ref s) if s.len() == 18 => parse_compact_peers6(s),
+                _ => continue
+            };
+            if let Ok(peers) = peers {
+                out.values.extend(peers.into_iter().map(|p| p.addr));
+            }
+        }
+    }
+    if let Some(Benc::S(token)) = r.get("token") {
+        out.token = Some(token.clone());
+    }
+    // Broken item fields are left out, which looks like not having the item
+    out.v = v;
+    if let Some(Benc::S(k)) = r.get("k") {
+        out.k = fixed(k);
+    }
+    if let Some(&Benc::I(seq)) = r.get("seq") {
+        out.seq = Some(seq);
+    }
+    if let Some(Benc::S(sig)) = r.get("sig") {
+        out.sig = fixed(sig);
+    }
+    if let Some(Benc::S(bf)) = r.get("BFsd") {
+        out.bf_seeds = BloomFilter::from_slice(bf).map(Box::new);
+    }
+    if let Some(Benc::S(bf)) = r.get("BFpe") {
+        out.bf_peers = BloomFilter::from_slice(bf).map(Box::new);
+    }
+    if let Some(&Benc::I(interval)) = r.get("interval") {
+        out.interval = Some(interval);
+    }
+    if let Some(&Benc::I(num)) = r.get("num") {
+        out.num = Some(num);
+    }
+    if let Some(Benc::S(samples)) = r.get("samples") {
+        if !samples.len().is_multiple_of(20) {
+            return Err(protocol_error(None, "'samples' must be a multiple of 20 bytes"));
+        }
+        out.samples = samples.chunks(20).map(|c| NodeId::from_slice(c).unwrap().0).collect();
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use crypto::ed25519;
+
+    use bencode::Benc;
+    use dht::bloom::BloomFilter;
+    use dht::item::Item;
+    use super::*;
+
+    fn round_trip(msg: Message) {
+        assert_eq!(Message::decode(&msg.encode()).unwrap(), msg);
+    }
+
+    #[test]
+    fn bep_5_examples() {
+        let id = NodeId(*b"abcdefghij0123456789");
+        let ping = Message { t: b"aa".to_vec(), v: None, ip: None, body: Body::Query(Query::Ping { id }) };
+        assert_eq!(ping.encode(), b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec());
+
+        let resp = Message::decode(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re").unwrap();
+        match resp.body {
+            Body::Response(r) => assert_eq!(r.id, NodeId(*b"mnopqrstuvwxyz123456")),
+            _ => unreachable!()
+        }
+
+        let err = Message::decode(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(err.body, Body::Error { code: 201, message: String::from("A Generic Error Ocurred") });
+
+        let announce = Message::decode(b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe").unwrap();
+        assert_eq!(announce.body, Body::Query(Query::AnnouncePeer {
+            id,
+            info_hash: *b"mnopqrstuvwxyz123456",
+            port: 6881,
+            implied_port: true,
+            token: b"aoeusnth".to_vec(),
+            seed: false
+        }));
+    }
+
+    #[test]
+    fn round_trips() {
+        let id = NodeId([1; 20]);
+        round_trip(Message { t: vec!(0, 1), v: Some(b"FK01".to_vec()), ip: None, body: Body::Query(Query::FindNode { id, target: NodeId([2; 20]) }) });
+        round_trip(Message { t: vec!(0, 2), v: None, ip: None, body: Body::Query(Query::GetPeers { id, info_hash: [3; 20], scrape: true }) });
+        round_trip(Message { t: vec!(0, 5), v: None, ip: None, body: Body::Query(Query::AnnouncePeer {
+            id, info_hash: [3; 20], port: 1, implied_port: false, token: b"tok".to_vec(), seed: true
+        })});
+        round_trip(Message { t: vec!(0, 3), v: None, ip: Some("203.0.113.9:6881".parse().unwrap()), body: Body::Response(Response {
+            id,
+            nodes: vec!(NodeInfo { id: NodeId([4; 20]), addr: "10.0.0.4:4".parse().unwrap() }),
+            nodes6: vec!(NodeInfo { id: NodeId([6; 20]), addr: "[::6]:6".parse().unwrap() }),
+            values: vec!("10.0.0.5:5".parse().unwrap(), "[::5]:5".parse().unwrap()),
+            token: Some(b"tok".to_vec()),
+            bf_seeds: Some(Box::new(BloomFilter([1; 256]))),
+            bf_peers: Some(Box::new(BloomFilter([2; 256]))),
+            ..Default::default()
+        })});
+        round_trip(Message { t: vec!(0, 6), v: None, ip: None, body: Body::Query(Query::SampleInfohashes { id, target: NodeId([7; 20]) }) });
+        round_trip(Message { t: vec!(0, 7), v: None, ip: None, body: Body::Response(Response {
+            id,
+            interval: Some(21600),
+            num: Some(3),
+            samples: vec!([8; 20], [9; 20]),
+            ..Default::default()
+        })});
+        round_trip(Message { t: vec!(0, 4), v: None, ip: None, body: Body::Error { code: ERROR_SERVER, message: String::from("oops") } });
+    }
+
+    #[test]
+    fn items() {
+        let id = NodeId([1; 20]);
+        let (secret, _) = ed25519::keypair(&[3; 32]);
+        let mutable = Item::mutable(&Benc::I(5), &secret, b"salt", 9);
+        round_trip(Message { t: vec!(1), v: None, ip: None, body: Body::Query(Query::Get { id, target: mutable.target(), seq: Some(8) }) });
+        round_trip(Message { t: vec!(2), v: None, ip: None, body: Body::Query(Query::Put {
+            id, token: b"tok".to_vec(), item: Item::immutable(&Benc::S(b"hi".to_vec())), cas: None
+        })});
+        round_trip(Message { t: vec!(3), v: None, ip: None, body: Body::Query(Query::Put {
+            id, token: b"tok".to_vec(), item: mutable.clone(), cas: Some(8)
+        })});
+
+        round_trip(Message { t: vec!(4), v: None, ip: None, body: Body::Response(Response {
+            id, v: Some(mutable.v().to_vec()), k: mutable.k(), seq: mutable.seq(), sig: mutable.sig(), ..Default::default()
+        })});
+
+        // Values go through byte for byte, even ones Benc can't hold, and
+        // the client version 'v' next to 'a' is left alone
+        let raw = b"d2:\xff\x01i1e1:bi2e1:ai3ee".to_vec();
+        let item = Item::mutable_raw(raw.clone(), &secret, b"", 1).unwrap();
+        let put = Message { t: vec!(5), v: Some(b"FK01".to_vec()), ip: None, body: Body::Query(Query::Put {
+            id, token: b"tok".to_vec(), item: item.clone(), cas: None
+        })};
+        let packet = put.encode();
+        assert!(packet.windows(raw.len()).any(|w| w == &raw[..]));
+        match Message::decode(&packet).unwrap().body {
+            Body::Query(Query::Put { item: decoded, .. }) => {
+                assert_eq!(decoded.v(), &raw[..]);
+                assert!(decoded.verify());
+            },
+            _ => unreachable!()
+        }
+        round_trip(put);
+        round_trip(Message { t: vec!(6), v: None, ip: None, body: Body::Response(Response {
+            id, v: Some(raw), ..Default::default()
+        })});
+
+        // A k without the rest of a mutable item isn't a put
+        let broken = Message::decode(b"d1:ad2:id20:abcdefghij01234567891:k32:abcdefghij0123456789abcdefghij015:token3:tok1:vi1ee1:q3:put1:t2:aa1:y1:qe").unwrap_err();
+        assert_eq!(broken.code, ERROR_PROTOCOL);
+        assert_eq!(broken.t, Some(b"aa".to_vec()));
+    }
+
+    #[test]
+    fn bad_messages() {
+        assert_eq!(Message::decode(b"le").unwrap_err().t, None);
+
+        let unknown = Message::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:pong1:t2:aa1:y1:qe").unwrap_err();
+        assert_eq!(unknown.code, ERROR_METHOD_UNKNOWN);
+        assert_eq!(unknown.to_message().unwrap().t, b"aa".to_vec());
+
+        let short_id = Message::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").unwrap_err();
+        assert_eq!(short_id.code, ERROR_PROTOCOL);
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use rand::{Rng, thread_rng};
+
+use tracker::{compact_addr, parse_compact_peers, parse_compact_peers6};
+
+pub mod bloom;
+pub mod item;
+pub mod krpc;
+pub mod node;
+pub mod routing;
+pub mod state;
+
+// Node IDs live in the same 160 bit space as info hashes, and closeness is
+// the XOR of two IDs read as a big endian number. That makes the derived
+// Ord on the bytes the right ordering for distances, too.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> NodeId {
+        let mut id = [0u8; 20];
+        thread_rng().fill_bytes(&mut id);
+        NodeId(id)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<NodeId> {
+        if bytes.len() != 20 {
+            return None;
+        }
+        let mut id = [0u8; 20];
+        id.copy_from_slice(bytes);
+        Some(NodeId(id))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut out = [0u8; 20];
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = self.0[i] ^ other.0[i];
+        }
+        NodeId(out)
+    }
+
+    // How many leading bits the two IDs share, 160 if they're the same
+    pub fn common_prefix_len(&self, other: &NodeId) -> usize {
+        for (i, b) in self.distance(other).0.iter().enumerate() {
+            if *b != 0 {
+                return i * 8 + b.leading_zeros() as usize;
+            }
+        }
+        160
+    }
+
+    // Counting from the most significant bit
+    pub fn bit(&self, i: usize) -> bool {
+        self.0[i / 8] & (0x80 >> (i % 8)) != 0
+    }
+
+    pub fn flip_bit(&mut self, i: usize) {
+        self.0[i / 8] ^= 0x80 >> (i % 8);
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NodeId(")?;
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, ")")
+    }
+}
+
+// Everything we need to get in touch with a node
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr
+}
+
+// Compact node info is the ID followed by the compact address: 26 bytes a
+// node for IPv4, 38 for IPv6. Nodes of the other family are skipped.
+pub fn encode_compact_nodes(nodes: &[NodeInfo], ipv6: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    for n in nodes.iter().filter(|n| n.addr.is_ipv6() == ipv6) {
+        out.extend_from_slice(n.id.as_bytes());
+        out.extend(compact_addr(&n.addr));
+    }
+    out
+}
+
+pub fn parse_compact_nodes(compact: &[u8]) -> Result<Vec<NodeInfo>, String> {
+    parse_nodes(compact, 6)
+}
+
+pub fn parse_compact_nodes6(compact: &[u8]) -> Result<Vec<NodeInfo>, String> {
+    parse_nodes(compact, 18)
+}
+
+fn parse_nodes(compact: &[u8], addr_len: usize) -> Result<Vec<NodeInfo>, String> {
+    let len = 20 + addr_len;
+    if !compact.len().is_multiple_of(len) {
+        return Err(format!("Compact node list must be a multiple of {} bytes, got {}", len, compact.len()));
+    }
+
+    let mut out = Vec::with_capacity(compact.len() / len);
+    for c in compact.chunks(len) {
+        let peers = if addr_len == 6 {
+            parse_compact_peers(&c[20..])?
+        } else {
+            parse_compact_peers6(&c[20..])?
+        };
+        out.push(NodeInfo { id: NodeId::from_slice(&c[..20]).unwrap(), addr: peers[0].addr });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn distances() {
+        let a = NodeId([0; 20]);
+        let mut b = NodeId([0; 20]);
+        b.flip_bit(9);
+        assert!(b.bit(9));
+        assert_eq!(b.0[1], 0x40);
+        assert_eq!(a.common_prefix_len(&b), 9);
+        assert_eq!(a.common_prefix_len(&a), 160);
+
+        // Closer to a than c is, even though c's bytes look "closer"
+        let mut c = NodeId([0; 20]);
+        c.flip_bit(0);
+        assert!(a.distance(&b) < a.distance(&c));
+    }
+
+    #[test]
+    fn compact_nodes() {
+        let nodes = vec!(
+            NodeInfo { id: NodeId([1; 20]), addr: "10.0.0.1:6881".parse().unwrap() },
+            NodeInfo { id: NodeId([2; 20]), addr: "[2001:db8::1]:6881".parse().unwrap() },
+            NodeInfo { id: NodeId([3; 20]), addr: "10.0.0.3:1".parse().unwrap() }
+        );
+
+        let v4 = encode_compact_nodes(&nodes, false);
+        assert_eq!(v4.len(), 52);
+        assert_eq!(parse_compact_nodes(&v4).unwrap(), vec!(nodes[0], nodes[2]));
+        let v6 = encode_compact_nodes(&nodes, true);
+        assert_eq!(parse_compact_nodes6(&v6).unwrap(), vec!(nodes[1]));
+
+        assert!(parse_compact_nodes(&v4[..30]).is_err());
+    }
+}
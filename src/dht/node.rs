@@ -0,0 +1,1373 @@
+use std::collections::btree_map::BTreeMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use rand::{Rng, thread_rng};
+
+use dht::*;
+use dht::bloom::BloomFilter;
+use dht::item::*;
+use dht::krpc::*;
+use dht::routing::*;
+use dht::state::DhtState;
+use id::{generate_node_id, node_id_matches_ip};
+
+// Queries in flight per lookup
+pub const ALPHA: usize = 3;
+// Token secrets rotate this often, and tokens from the previous secret are
+// still accepted, so a token is good for 5-10 minutes
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+// How long an announced peer sticks around without announcing again
+pub const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+// Per info hash, both for storing and for handing out in one response
+pub const MAX_PEERS_PER_HASH: usize = 100;
+pub const MAX_VALUES: usize = 50;
+// BEP 44 items expire unless they're put again, and we only keep so many
+pub const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+pub const MAX_ITEMS: usize = 1000;
+// BEP 51: how many info hashes we hand out per sample_infohashes, and how
+// long we stick with one sample, which is also how long we ask crawlers to
+// stay away
+pub const MAX_SAMPLES: usize = 20;
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+// Queries in flight per crawl
+pub const CRAWL_IN_FLIGHT: usize = 8;
+// How many nodes' word on our external address we keep (BEP 42)
+pub const MAX_IP_VOTES: usize = 50;
+// Non-DHT packets held for take_other_packets(); past this they're dropped
+pub const MAX_OTHER_PACKETS: usize = 1024;
+
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881"
+];
+
+#[derive(Clone, Debug)]
+pub struct DhtConfig {
+    // None picks one: a BEP 42 one if we know our external IP, otherwise a
+    // random one
+    pub id: Option<NodeId>,
+    pub external_ip: Option<IpAddr>,
+    // host:port strings, resolved when we bootstrap
+    pub bootstrap: Vec<String>,
+    pub query_timeout: Duration,
+    // Where our ID and the good nodes get saved between runs
+    pub state_file: Option<PathBuf>
+}
+
+impl Default for DhtConfig {
+    fn default() -> DhtConfig {
+        DhtConfig {
+            id: None,
+            external_ip: None,
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|s| String::from(*s)).collect(),
+            query_timeout: Duration::from_secs(5),
+            state_file: None
+        }
+    }
+}
+
+pub type LookupId = u64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DhtEvent {
+    // Peers turned up for a get_peers or announce lookup. This can happen
+    // several times per lookup, with only new peers each time.
+    Peers { lookup: LookupId, info_hash: [u8; 20], peers: Vec<SocketAddr> },
+    // How big the swarm looks from the bloom filters of the nodes closest
+    // to the info hash (BEP 33). Comes just before a scrape's LookupDone.
+    Scrape { lookup: LookupId, info_hash: [u8; 20], seeds: usize, peers: usize },
+    // The lookup ran out of closer nodes to ask. For announces, the
+    // announce_peer queries have been sent by now.
+    LookupDone { lookup: LookupId, target: NodeId, closest: Vec<NodeInfo> },
+    // A get found the item, or for mutable items, a newer one than it had
+    // found before
+    Item { lookup: LookupId, item: Item },
+    // A node answered a crawl with some of the info hashes it stores, out of
+    // num in total
+    Samples { lookup: LookupId, from: NodeInfo, num: usize, samples: Vec<[u8; 20]> },
+    // The crawl asked as many nodes as it was allowed to, or ran out
+    CrawlDone { lookup: LookupId, queried: usize }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LookupKind {
+    FindNode,
+    GetPeers,
+    Announce { port: u16, implied_port: bool, seed: bool },
+    Scrape,
+    GetItem,
+    PutItem
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CandidateState {
+    New,
+    Queried,
+    Responded,
+    Failed
+}
+
+struct Candidate {
+    info: NodeInfo,
+    state: CandidateState,
+    token: Option<Vec<u8>>,
+    // Seeds and peers, if it answered a scrape
+    filters: Option<(BloomFilter, BloomFilter)>
+}
+
+struct StoredPeer {
+    addr: SocketAddr,
+    seed: bool,
+    announced: Instant
+}
+
+struct Lookup {
+    target: NodeId,
+    kind: LookupKind,
+    // Keyed by distance to the target, so the front is the closest
+    candidates: BTreeMap<NodeId, Candidate>,
+    in_flight: usize,
+    peers: HashSet<SocketAddr>,
+    item: Option<ItemLookup>
+}
+
+// The extra bits a get or put lookup carries around
+struct ItemLookup {
+    // For mutable gets, the key and salt the item has to be signed with
+    key: Option<([u8; 32], Vec<u8>)>,
+    // The newest one so far, so each is only reported once
+    found: Option<Item>,
+    // What to store once we know who's closest
+    put: Option<(Item, Option<i64>)>
+}
+
+impl ItemLookup {
+    // The item in a get response, if it's the one we're after and newer
+    // than what we had
+    fn newer(&mut self, target: &NodeId, r: &Response) -> Option<Item> {
+        let v = r.v.clone()?;
+        let item = match self.key {
+            None => Item::immutable_raw(v).ok()?,
+            Some((k, ref salt)) => {
+                if r.k != Some(k) {
+                    return None;
+                }
+                Item::from_parts(v, k, salt.clone(), r.seq?, r.sig?).ok()?
+            }
+        };
+        if item.target() != *target || !item.verify() {
+            return None;
+        }
+        if let Some(ref found) = self.found {
+            if found.seq() >= item.seq() {
+                return None;
+            }
+        }
+        self.found = Some(item.clone());
+        Some(item)
+    }
+}
+
+// A BEP 51 walk over the DHT, asking everyone we hear about for samples
+struct Crawl {
+    queue: VecDeque<NodeInfo>,
+    seen: HashSet<SocketAddr>,
+    in_flight: usize,
+    queried: usize,
+    max_nodes: usize
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Purpose {
+    Ping,
+    Lookup(LookupId),
+    Crawl(LookupId),
+    Announce,
+    Put
+}
+
+struct Transaction {
+    addr: SocketAddr,
+    // None for bootstrap nodes we only know the address of
+    id: Option<NodeId>,
+    sent: Instant,
+    purpose: Purpose
+}
+
+// A mainline DHT node on a UDP socket. Drive it by calling poll() every so
+// often; lookups run in the background and report back through events.
+pub struct DhtNode {
+    socket: UdpSocket,
+    config: DhtConfig,
+    table: RoutingTable,
+    transactions: HashMap<Vec<u8>, Transaction>,
+    next_transaction: u16,
+    lookups: HashMap<LookupId, Lookup>,
+    next_lookup: LookupId,
+    secret: [u8; 16],
+    old_secret: [u8; 16],
+    secret_changed: Instant,
+    peers: HashMap<[u8; 20], Vec<StoredPeer>>,
+    items: HashMap<NodeId, (Item, Instant)>,
+    // What we answer sample_infohashes with, and when we picked it
+    sample: Vec<[u8; 20]>,
+    sample_taken: Option<Instant>,
+    crawls: HashMap<LookupId, Crawl>,
+    // Nodes that told a crawl when they'd like to be asked again
+    sample_after: HashMap<SocketAddr, Instant>,
+    events: Vec<DhtEvent>,
+    // From the state file, waiting for bootstrap() to check they're alive
+    saved_nodes: Vec<NodeInfo>,
+    // The address responders say they saw us at, newest last, one per node
+    ip_votes: Vec<(SocketAddr, IpAddr)>,
+    // Anything on the socket that isn't bencoded, like uTP
+    other_packets: Vec<(Vec<u8>, SocketAddr)>
+}
+
+impl DhtNode {
+    pub fn bind<A: ToSocketAddrs>(addr: A, config: DhtConfig) -> io::Result<DhtNode> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+
+        // The state file is only a cache, so if it's unreadable we start fresh
+        let saved = match config.state_file {
+            Some(ref path) => DhtState::load(path).unwrap_or(None),
+            None => None
+        };
+        let (saved_id, saved_nodes) = match saved {
+            Some(state) => (Some(state.id), state.nodes),
+            None => (None, Vec::new())
+        };
+
+        let now = Instant::now();
+        // A saved ID that doesn't go with our address any more is dropped
+        let saved_id = saved_id.filter(|id| config.external_ip.is_none_or(|ip| node_id_matches_ip(id, ip)));
+        let id = config.id.or(saved_id).unwrap_or_else(|| match config.external_ip {
+            Some(ip) => generate_node_id(ip),
+            None => NodeId::random()
+        });
+        let mut rng = thread_rng();
+        let mut secret = [0u8; 16];
+        rng.fill_bytes(&mut secret);
+
+        Ok(DhtNode {
+            socket,
+            config,
+            table: RoutingTable::new(id, now),
+            transactions: HashMap::new(),
+            next_transaction: rng.gen(),
+            lookups: HashMap::new(),
+            next_lookup: 0,
+            secret,
+            old_secret: secret,
+            secret_changed: now,
+            peers: HashMap::new(),
+            items: HashMap::new(),
+            sample: Vec::new(),
+            sample_taken: None,
+            crawls: HashMap::new(),
+            sample_after: HashMap::new(),
+            events: Vec::new(),
+            saved_nodes,
+            ip_votes: Vec::new(),
+            other_packets: Vec::new()
+        })
+    }
+
+    // Writes our ID and the good nodes out to the state file, if there is
+    // one. Call it on the way out. Changing our ID means starting the table
+    // over, so if it doesn't match our external IP we save a new one for
+    // next time instead.
+    pub fn save(&self) -> Result<(), String> {
+        let path = match self.config.state_file {
+            Some(ref path) => path,
+            None => { return Ok(()); }
+        };
+
+        let now = Instant::now();
+        let nodes = self.table.nodes().into_iter().filter(|n| n.is_good(now)).map(|n| n.info).collect();
+        let id = match self.external_ip() {
+            Some(ip) if !node_id_matches_ip(&self.id(), ip) => generate_node_id(ip),
+            _ => self.id()
+        };
+        DhtState { id, nodes }.save(path)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.table.own_id()
+    }
+
+    // The configured external IP, or else what most nodes told us they saw
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        if self.config.external_ip.is_some() {
+            return self.config.external_ip;
+        }
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+        for &(_, ip) in self.ip_votes.iter() {
+            *counts.entry(ip).or_insert(0) += 1;
+        }
+        counts.into_iter().max_by_key(|&(ip, n)| (n, ip)).map(|(ip, _)| ip)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // Another handle on our socket, for whatever takes our other packets to
+    // send its own with
+    pub fn try_clone_socket(&self) -> io::Result<UdpSocket> {
+        self.socket.try_clone()
+    }
+
+    // Packets poll() read that weren't for us. DHT messages are bencoded
+    // dicts, so they all start with a 'd', and uTP packets never do.
+    pub fn take_other_packets(&mut self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.other_packets.drain(..).collect()
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    // Looks ourselves up, starting from the nodes we saved last time, the
+    // configured bootstrap nodes, and whoever's already in the routing table.
+    // Saved nodes only make it into the table if they answer with the ID we
+    // saved for them.
+    pub fn bootstrap(&mut self, now: Instant) -> LookupId {
+        let mut seeds: Vec<(SocketAddr, Option<NodeId>)> = self.saved_nodes.drain(..).map(|n| (n.addr, Some(n.id))).collect();
+        seeds.extend(resolve(&self.config.bootstrap).into_iter().map(|addr| (addr, None)));
+        let id = self.id();
+        self.start_lookup(id, LookupKind::FindNode, &seeds, None, now)
+    }
+
+    // For the "nodes" key in a trackerless torrent, or any other node we've
+    // been told about. It goes in the table if it answers.
+    pub fn add_node(&mut self, addr: SocketAddr, now: Instant) {
+        let id = self.id();
+        self.query(addr, None, Query::Ping { id }, Purpose::Ping, now);
+    }
+
+    pub fn add_torrent_nodes(&mut self, nodes: &[(String, u16)], now: Instant) {
+        let hosts: Vec<String> = nodes.iter().map(|&(ref host, port)| format!("{}:{}", host, port)).collect();
+        for addr in resolve(&hosts) {
+            self.add_node(addr, now);
+        }
+    }
+
+    pub fn find_node(&mut self, target: NodeId, now: Instant) -> LookupId {
+        self.start_lookup(target, LookupKind::FindNode, &[], None, now)
+    }
+
+    pub fn get_peers(&mut self, info_hash: [u8; 20], now: Instant) -> LookupId {
+        self.start_lookup(NodeId(info_hash), LookupKind::GetPeers, &[], None, now)
+    }
+
+    // Finds peers like get_peers, then tells the closest nodes about us. A
+    // port of None asks them to use the port our packets come from.
+    pub fn announce(&mut self, info_hash: [u8; 20], port: Option<u16>, seed: bool, now: Instant) -> LookupId {
+        let kind = LookupKind::Announce { port: port.unwrap_or(0), implied_port: port.is_none(), seed };
+        self.start_lookup(NodeId(info_hash), kind, &[], None, now)
+    }
+
+    // A get_peers that also asks for bloom filters, ending in a
+    // DhtEvent::Scrape with the swarm size
+    pub fn scrape(&mut self, info_hash: [u8; 20], now: Instant) -> LookupId {
+        self.start_lookup(NodeId(info_hash), LookupKind::Scrape, &[], None, now)
+    }
+
+    // BEP 44 gets. Whatever turns up comes back as DhtEvent::Item; mutable
+    // items can turn up more than once as newer ones are found.
+    pub fn get_immutable(&mut self, target: NodeId, now: Instant) -> LookupId {
+        let item = ItemLookup { key: None, found: None, put: None };
+        self.start_lookup(target, LookupKind::GetItem, &[], Some(item), now)
+    }
+
+    pub fn get_mutable(&mut self, k: [u8; 32], salt: &[u8], now: Instant) -> LookupId {
+        let item = ItemLookup { key: Some((k, salt.to_vec())), found: None, put: None };
+        self.start_lookup(mutable_target(&k, salt), LookupKind::GetItem, &[], Some(item), now)
+    }
+
+    // Finds the nodes closest to the item's target and stores it on them.
+    // cas only means anything for mutable items: it's the seq we expect
+    // them to have now.
+    pub fn put(&mut self, item: Item, cas: Option<i64>, now: Instant) -> LookupId {
+        let target = item.target();
+        let item = ItemLookup { key: None, found: None, put: Some((item, cas)) };
+        self.start_lookup(target, LookupKind::PutItem, &[], Some(item), now)
+    }
+
+    // Walks the DHT asking nodes for samples of the info hashes they store
+    // (BEP 51), starting from the routing table and moving on to whoever
+    // they tell us about. Samples come back as DhtEvent::Samples, then a
+    // DhtEvent::CrawlDone once max_nodes have been asked or nobody new is
+    // left. Nodes that asked us to wait are skipped.
+    pub fn crawl(&mut self, max_nodes: usize, now: Instant) -> LookupId {
+        let l = self.next_lookup;
+        self.next_lookup += 1;
+
+        let mut start: Vec<NodeInfo> = self.table.nodes().into_iter().map(|n| n.info).collect();
+        thread_rng().shuffle(&mut start);
+        let crawl = Crawl {
+            seen: start.iter().map(|n| n.addr).collect(),
+            queue: start.into_iter().collect(),
+            in_flight: 0,
+            queried: 0,
+            max_nodes
+        };
+        self.crawls.insert(l, crawl);
+        self.step_crawl(l, now);
+        l
+    }
+
+    pub fn is_running(&self, lookup: LookupId) -> bool {
+        self.lookups.contains_key(&lookup) || self.crawls.contains_key(&lookup)
+    }
+
+    // Reads everything waiting on the socket, times out old queries, keeps
+    // the table fresh, and hands back whatever happened
+    pub fn poll(&mut self, now: Instant) -> Vec<DhtEvent> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((n, from)) if n > 0 && buf[0] != b'd' => {
+                    if self.other_packets.len() < MAX_OTHER_PACKETS {
+                        self.other_packets.push((buf[..n].to_vec(), from));
+                    }
+                },
+                Ok((n, from)) => self.handle_packet(&buf[..n], from, now),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // ICMP unreachables and the like show up here on some
+                // platforms; the timeouts deal with them
+                Err(_) => continue
+            }
+        }
+
+        self.expire(now);
+        self.events.drain(..).collect()
+    }
+
+    pub fn handle_packet(&mut self, packet: &[u8], from: SocketAddr, now: Instant) {
+        let msg = match Message::decode(packet) {
+            Ok(msg) => msg,
+            Err(e) => {
+                if let Some(reply) = e.to_message() {
+                    self.send(from, &reply);
+                }
+                return;
+            }
+        };
+
+        match msg.body {
+            Body::Query(q) => self.handle_query(msg.t, q, from, now),
+            Body::Response(r) => self.handle_response(&msg.t, Ok((r, msg.ip)), from, now),
+            Body::Error { .. } => self.handle_response(&msg.t, Err(()), from, now)
+        }
+    }
+
+    fn handle_query(&mut self, t: Vec<u8>, q: Query, from: SocketAddr, now: Instant) {
+        self.table.heard_from(NodeInfo { id: q.id(), addr: from }, now);
+
+        let mut r = Response { id: self.id(), ..Default::default() };
+        match q {
+            Query::Ping { .. } => (),
+            Query::FindNode { target, .. } => self.fill_nodes(&mut r, &target, from),
+            Query::GetPeers { info_hash, scrape, .. } => {
+                self.fill_nodes(&mut r, &NodeId(info_hash), from);
+                r.token = Some(self.token(from.ip(), &self.secret));
+                if let Some(peers) = self.peers.get(&info_hash) {
+                    r.values = peers.iter()
+                        .filter(|p| p.addr.is_ipv6() == from.is_ipv6())
+                        .take(MAX_VALUES)
+                        .map(|p| p.addr)
+                        .collect();
+                }
+                if scrape {
+                    let mut seeds = BloomFilter::new();
+                    let mut others = BloomFilter::new();
+                    for p in self.peers.get(&info_hash).into_iter().flatten() {
+                        if p.seed { seeds.insert(p.addr.ip()) } else { others.insert(p.addr.ip()) }
+                    }
+                    r.bf_seeds = Some(Box::new(seeds));
+                    r.bf_peers = Some(Box::new(others));
+                }
+            },
+            Query::AnnouncePeer { info_hash, port, implied_port, token, seed, .. } => {
+                if !self.valid_token(from.ip(), &token) {
+                    let e = Message { t, v: None, ip: None, body: Body::Error { code: ERROR_PROTOCOL, message: String::from("Bad token") } };
+                    self.send(from, &e);
+                    return;
+                }
+                let addr = SocketAddr::new(from.ip(), if implied_port { from.port() } else { port });
+                let peers = self.peers.entry(info_hash).or_default();
+                peers.retain(|p| p.addr != addr);
+                if peers.len() >= MAX_PEERS_PER_HASH {
+                    peers.remove(0);
+                }
+                peers.push(StoredPeer { addr, seed, announced: now });
+            },
+            Query::Get { target, seq, .. } => {
+                self.fill_nodes(&mut r, &target, from);
+                r.token = Some(self.token(from.ip(), &self.secret));
+                if let Some((item, _)) = self.items.get(&target) {
+                    match item.seq() {
+                        None => r.v = Some(item.v().to_vec()),
+                        Some(stored) => {
+                            r.k = item.k();
+                            r.seq = Some(stored);
+                            if seq.is_none_or(|seq| stored > seq) {
+                                r.v = Some(item.v().to_vec());
+                                r.sig = item.sig();
+                            }
+                        }
+                    }
+                }
+            },
+            Query::SampleInfohashes { target, .. } => {
+                self.fill_nodes(&mut r, &target, from);
+                let taken = match self.sample_taken {
+                    Some(at) if now < at + SAMPLE_INTERVAL => at,
+                    _ => {
+                        let mut all: Vec<[u8; 20]> = self.peers.keys().cloned().collect();
+                        thread_rng().shuffle(&mut all);
+                        all.truncate(MAX_SAMPLES);
+                        self.sample = all;
+                        self.sample_taken = Some(now);
+                        now
+                    }
+                };
+                r.interval = Some((taken + SAMPLE_INTERVAL - now).as_secs() as i64);
+                r.num = Some(self.peers.len() as i64);
+                r.samples = self.sample.clone();
+            },
+            Query::Put { token, item, cas, .. } => {
+                let result = if self.valid_token(from.ip(), &token) {
+                    self.store(item, cas, now)
+                } else {
+                    Err((ERROR_PROTOCOL, "Bad token"))
+                };
+                if let Err((code, message)) = result {
+                    let e = Message { t, v: None, ip: None, body: Body::Error { code, message: String::from(message) } };
+                    self.send(from, &e);
+                    return;
+                }
+            }
+        }
+
+        self.send(from, &Message { t, v: None, ip: Some(from), body: Body::Response(r) });
+    }
+
+    fn fill_nodes(&self, r: &mut Response, target: &NodeId, from: SocketAddr) {
+        let closest = self.table.closest(target, K * 2);
+        let nodes: Vec<NodeInfo> = closest.into_iter().filter(|n| n.addr.is_ipv6() == from.is_ipv6()).take(K).collect();
+        if from.is_ipv6() {
+            r.nodes6 = nodes;
+        } else {
+            r.nodes = nodes;
+        }
+    }
+
+    // BEP 44's rules for what a put may replace
+    fn store(&mut self, item: Item, cas: Option<i64>, now: Instant) -> Result<(), (i64, &'static str)> {
+        if item.v().len() > MAX_VALUE_SIZE {
+            return Err((ERROR_VALUE_TOO_BIG, "Value too big"));
+        }
+        if item.is_mutable() {
+            if item.salt().len() > MAX_SALT_SIZE {
+                return Err((ERROR_SALT_TOO_BIG, "Salt too big"));
+            }
+            if !item.verify() {
+                return Err((ERROR_INVALID_SIGNATURE, "Invalid signature"));
+            }
+        }
+
+        let target = item.target();
+        if let Some((old, _)) = self.items.get(&target) {
+            if let (Some(old_seq), Some(seq)) = (old.seq(), item.seq()) {
+                if cas.is_some_and(|cas| cas != old_seq) {
+                    return Err((ERROR_CAS_MISMATCH, "CAS mismatch"));
+                }
+                // The same seq again is fine as a refresh, but not with a
+                // different value
+                if seq < old_seq || (seq == old_seq && old.v() != item.v()) {
+                    return Err((ERROR_SEQ_TOO_LOW, "Sequence number too low"));
+                }
+            }
+        } else if self.items.len() >= MAX_ITEMS {
+            // Make room by dropping whatever was put longest ago
+            let oldest = self.items.iter().min_by_key(|&(_, &(_, at))| at).map(|(t, _)| *t);
+            if let Some(oldest) = oldest {
+                self.items.remove(&oldest);
+            }
+        }
+
+        self.items.insert(target, (item, now));
+        Ok(())
+    }
+
+    fn valid_token(&self, ip: IpAddr, token: &[u8]) -> bool {
+        token == &self.token(ip, &self.secret)[..] || token == &self.token(ip, &self.old_secret)[..]
+    }
+
+    fn token(&self, ip: IpAddr, secret: &[u8; 16]) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => hasher.input(&ip.octets()),
+            IpAddr::V6(ip) => hasher.input(&ip.octets())
+        }
+        hasher.input(secret);
+        let mut hash = [0u8; 20];
+        hasher.result(&mut hash);
+        hash[..8].to_vec()
+    }
+
+    fn handle_response(&mut self, t: &[u8], r: Result<(Response, Option<SocketAddr>), ()>, from: SocketAddr, now: Instant) {
+        // Anything that isn't an answer to something we asked, from who we
+        // asked, gets ignored
+        let tx = match self.transactions.get(t) {
+            Some(tx) if tx.addr == from => self.transactions.remove(t).unwrap(),
+            _ => { return; }
+        };
+
+        let (r, ip) = match r {
+            Ok(r) => r,
+            Err(()) => {
+                // They're alive at least, but an error means a lookup gets
+                // nothing useful out of them
+                self.transaction_failed(&tx, now);
+                return;
+            }
+        };
+        // Somebody else is at that address now, as far as we're concerned
+        // the node we wanted is gone
+        if let Some(id) = tx.id {
+            if id != r.id {
+                self.table.failed(&id);
+                self.transaction_failed(&tx, now);
+                return;
+            }
+        }
+
+        if let Some(ip) = ip {
+            self.ip_votes.retain(|&(voter, _)| voter != from);
+            if self.ip_votes.len() >= MAX_IP_VOTES {
+                self.ip_votes.remove(0);
+            }
+            self.ip_votes.push((from, ip.ip()));
+        }
+
+        if let Insert::Cached(questionable) = self.table.heard_from(NodeInfo { id: r.id, addr: from }, now) {
+            for n in questionable.into_iter() {
+                let own = self.id();
+                self.query(n.addr, Some(n.id), Query::Ping { id: own }, Purpose::Ping, now);
+            }
+        }
+
+        match tx.purpose {
+            Purpose::Lookup(l) => self.lookup_response(l, NodeInfo { id: r.id, addr: from }, r, now),
+            Purpose::Crawl(l) => self.crawl_response(l, NodeInfo { id: r.id, addr: from }, r, now),
+            _ => ()
+        }
+    }
+
+    fn transaction_failed(&mut self, tx: &Transaction, now: Instant) {
+        match tx.purpose {
+            Purpose::Lookup(l) => self.lookup_failed(l, tx.id, now),
+            Purpose::Crawl(l) => {
+                if let Some(crawl) = self.crawls.get_mut(&l) {
+                    crawl.in_flight -= 1;
+                    self.step_crawl(l, now);
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.config.query_timeout;
+        let expired: Vec<Vec<u8>> = self.transactions.iter()
+            .filter(|&(_, tx)| now >= tx.sent + timeout)
+            .map(|(t, _)| t.clone())
+            .collect();
+        for t in expired.into_iter() {
+            let tx = self.transactions.remove(&t).unwrap();
+            if let Some(id) = tx.id {
+                self.table.failed(&id);
+            }
+            self.transaction_failed(&tx, now);
+        }
+
+        if now >= self.secret_changed + TOKEN_ROTATION {
+            self.old_secret = self.secret;
+            thread_rng().fill_bytes(&mut self.secret);
+            self.secret_changed = now;
+        }
+
+        for peers in self.peers.values_mut() {
+            peers.retain(|p| now < p.announced + PEER_TTL);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+        self.items.retain(|_, &mut (_, at)| now < at + ITEM_TTL);
+        self.sample_after.retain(|_, &mut at| now < at);
+
+        if !self.table.is_empty() {
+            for target in self.table.refresh_targets(now) {
+                self.find_node(target, now);
+            }
+        }
+    }
+
+    fn query(&mut self, addr: SocketAddr, id: Option<NodeId>, q: Query, purpose: Purpose, now: Instant) {
+        let t = self.next_transaction.to_be_bytes().to_vec();
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        self.send(addr, &Message { t: t.clone(), v: None, ip: None, body: Body::Query(q) });
+        self.transactions.insert(t, Transaction { addr, id, sent: now, purpose });
+    }
+
+    fn send(&self, addr: SocketAddr, msg: &Message) {
+        // UDP is best effort anyway, anything lost here looks like a timeout
+        let _ = self.socket.send_to(&msg.encode(), addr);
+    }
+
+    fn start_lookup(&mut self, target: NodeId, kind: LookupKind, seeds: &[(SocketAddr, Option<NodeId>)],
+                    item: Option<ItemLookup>, now: Instant) -> LookupId {
+        let l = self.next_lookup;
+        self.next_lookup += 1;
+
+        let mut lookup = Lookup {
+            target,
+            kind,
+            candidates: BTreeMap::new(),
+            in_flight: 0,
+            peers: HashSet::new(),
+            item
+        };
+        for n in self.table.closest(&target, K).into_iter() {
+            lookup.candidates.insert(n.id.distance(&target), Candidate { info: n, state: CandidateState::New, token: None, filters: None });
+        }
+        self.lookups.insert(l, lookup);
+
+        // Seeds are asked straight away, and only become candidates once they
+        // answer. We might not know their IDs, and if we do we don't trust them.
+        for &(addr, id) in seeds.iter() {
+            let q = self.lookup_query(target, kind);
+            self.query(addr, id, q, Purpose::Lookup(l), now);
+            self.lookups.get_mut(&l).unwrap().in_flight += 1;
+        }
+
+        self.step_lookup(l, now);
+        l
+    }
+
+    fn lookup_query(&self, target: NodeId, kind: LookupKind) -> Query {
+        let id = self.id();
+        match kind {
+            LookupKind::FindNode => Query::FindNode { id, target },
+            LookupKind::GetPeers | LookupKind::Announce { .. } => Query::GetPeers { id, info_hash: target.0, scrape: false },
+            LookupKind::Scrape => Query::GetPeers { id, info_hash: target.0, scrape: true },
+            LookupKind::GetItem | LookupKind::PutItem => Query::Get { id, target, seq: None }
+        }
+    }
+
+    fn lookup_response(&mut self, l: LookupId, from: NodeInfo, r: Response, now: Instant) {
+        let own = self.id();
+        let (info_hash, new_peers, item) = {
+            let lookup = match self.lookups.get_mut(&l) {
+                Some(lookup) => lookup,
+                None => { return; }
+            };
+            lookup.in_flight -= 1;
+
+            let target = lookup.target;
+            let c = lookup.candidates.entry(from.id.distance(&target))
+                .or_insert(Candidate { info: from, state: CandidateState::New, token: None, filters: None });
+            c.state = CandidateState::Responded;
+            c.token = r.token.clone();
+            if let (Some(seeds), Some(others)) = (r.bf_seeds.as_ref(), r.bf_peers.as_ref()) {
+                c.filters = Some((**seeds, **others));
+            }
+
+            let item = match lookup.item {
+                Some(ref mut item) if lookup.kind == LookupKind::GetItem => item.newer(&target, &r),
+                _ => None
+            };
+
+            for n in r.nodes.iter().chain(r.nodes6.iter()) {
+                if n.id == own {
+                    continue;
+                }
+                lookup.candidates.entry(n.id.distance(&target))
+                    .or_insert(Candidate { info: *n, state: CandidateState::New, token: None, filters: None });
+            }
+
+            let new_peers: Vec<SocketAddr> = r.values.into_iter().filter(|p| lookup.peers.insert(*p)).collect();
+            (target.0, new_peers, item)
+        };
+
+        if !new_peers.is_empty() {
+            self.events.push(DhtEvent::Peers { lookup: l, info_hash, peers: new_peers });
+        }
+        if let Some(item) = item {
+            self.events.push(DhtEvent::Item { lookup: l, item });
+        }
+        self.step_lookup(l, now);
+    }
+
+    fn lookup_failed(&mut self, l: LookupId, id: Option<NodeId>, now: Instant) {
+        {
+            let lookup = match self.lookups.get_mut(&l) {
+                Some(lookup) => lookup,
+                None => { return; }
+            };
+            lookup.in_flight -= 1;
+            if let Some(id) = id {
+                if let Some(c) = lookup.candidates.get_mut(&id.distance(&lookup.target)) {
+                    c.state = CandidateState::Failed;
+                }
+            }
+        }
+        self.step_lookup(l, now);
+    }
+
+    // Asks the closest candidates we haven't asked yet, as long as they're
+    // in the K closest still standing. Once nobody's left to ask and nothing's
+    // in flight, the lookup is over.
+    fn step_lookup(&mut self, l: LookupId, now: Instant) {
+        let (to_query, target, kind) = {
+            let lookup = self.lookups.get_mut(&l).unwrap();
+            let mut to_query = Vec::new();
+            let mut in_flight = lookup.in_flight;
+            for c in lookup.candidates.values_mut().filter(|c| c.state != CandidateState::Failed).take(K) {
+                if in_flight >= ALPHA {
+                    break;
+                }
+                if c.state == CandidateState::New {
+                    c.state = CandidateState::Queried;
+                    in_flight += 1;
+                    to_query.push(c.info);
+                }
+            }
+            lookup.in_flight = in_flight;
+            (to_query, lookup.target, lookup.kind)
+        };
+
+        for n in to_query.into_iter() {
+            let q = self.lookup_query(target, kind);
+            self.query(n.addr, Some(n.id), q, Purpose::Lookup(l), now);
+        }
+
+        if self.lookups[&l].in_flight > 0 {
+            return;
+        }
+
+        let mut lookup = self.lookups.remove(&l).unwrap();
+        let put = lookup.item.take().and_then(|item| item.put);
+        let closest: Vec<Candidate> = lookup.candidates.into_values()
+            .filter(|c| c.state == CandidateState::Responded)
+            .take(K)
+            .collect();
+
+        if let LookupKind::Announce { port, implied_port, seed } = kind {
+            let id = self.id();
+            for c in closest.iter() {
+                if let Some(ref token) = c.token {
+                    let q = Query::AnnouncePeer { id, info_hash: target.0, port, implied_port, token: token.clone(), seed };
+                    self.query(c.info.addr, Some(c.info.id), q, Purpose::Announce, now);
+                }
+            }
+        }
+
+        if let Some((item, cas)) = put {
+            let id = self.id();
+            for c in closest.iter() {
+                if let Some(ref token) = c.token {
+                    let q = Query::Put { id, token: token.clone(), item: item.clone(), cas };
+                    self.query(c.info.addr, Some(c.info.id), q, Purpose::Put, now);
+                }
+            }
+        }
+
+        // Only the closest nodes' filters count, since they're the ones
+        // everybody announces to
+        if kind == LookupKind::Scrape {
+            let mut seeds = BloomFilter::new();
+            let mut others = BloomFilter::new();
+            for (s, o) in closest.iter().filter_map(|c| c.filters.as_ref()) {
+                seeds.union(s);
+                others.union(o);
+            }
+            self.events.push(DhtEvent::Scrape {
+                lookup: l,
+                info_hash: target.0,
+                seeds: seeds.size_estimate().round() as usize,
+                peers: others.size_estimate().round() as usize
+            });
+        }
+
+        self.events.push(DhtEvent::LookupDone { lookup: l, target, closest: closest.into_iter().map(|c| c.info).collect() });
+    }
+
+    fn crawl_response(&mut self, l: LookupId, from: NodeInfo, r: Response, now: Instant) {
+        let own = self.id();
+        {
+            let crawl = match self.crawls.get_mut(&l) {
+                Some(crawl) => crawl,
+                None => { return; }
+            };
+            crawl.in_flight -= 1;
+            for n in r.nodes.iter().chain(r.nodes6.iter()) {
+                if n.id != own && crawl.seen.insert(n.addr) {
+                    crawl.queue.push_back(*n);
+                }
+            }
+        }
+
+        // Anything past BEP 51's six hours is capped there
+        if let Some(interval) = r.interval {
+            let wait = Duration::from_secs(interval.clamp(0, 6 * 60 * 60) as u64);
+            self.sample_after.insert(from.addr, now + wait);
+        }
+        if let Some(num) = r.num {
+            self.events.push(DhtEvent::Samples { lookup: l, from, num: num.max(0) as usize, samples: r.samples });
+        }
+        self.step_crawl(l, now);
+    }
+
+    fn step_crawl(&mut self, l: LookupId, now: Instant) {
+        let to_query = {
+            let crawl = self.crawls.get_mut(&l).unwrap();
+            let mut to_query = Vec::new();
+            while crawl.in_flight < CRAWL_IN_FLIGHT && crawl.queried < crawl.max_nodes {
+                let n = match crawl.queue.pop_front() {
+                    Some(n) => n,
+                    None => break
+                };
+                if self.sample_after.get(&n.addr).is_some_and(|&at| now < at) {
+                    continue;
+                }
+                crawl.in_flight += 1;
+                crawl.queried += 1;
+                to_query.push(n);
+            }
+            to_query
+        };
+
+        // A random target each time spreads us out over the whole DHT
+        let id = self.id();
+        for n in to_query.into_iter() {
+            let q = Query::SampleInfohashes { id, target: NodeId::random() };
+            self.query(n.addr, Some(n.id), q, Purpose::Crawl(l), now);
+        }
+
+        if self.crawls[&l].in_flight == 0 {
+            let crawl = self.crawls.remove(&l).unwrap();
+            self.events.push(DhtEvent::CrawlDone { lookup: l, queried: crawl.queried });
+        }
+    }
+}
+
+// Anything that doesn't resolve is skipped
+fn resolve(hosts: &[String]) -> Vec<SocketAddr> {
+    let mut out = Vec::new();
+    for host in hosts.iter() {
+        if let Ok(addrs) = host.to_socket_addrs() {
+            out.extend(addrs);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use dht::{NodeId, NodeInfo};
+    use crypto::ed25519;
+
+    use bencode::Benc;
+    use dht::state::DhtState;
+    use utp::socket::{UtpConfig, UtpSocket};
+    use super::*;
+
+    fn config(bootstrap: &[SocketAddr]) -> DhtConfig {
+        DhtConfig {
+            id: None,
+            external_ip: None,
+            bootstrap: bootstrap.iter().map(|a| a.to_string()).collect(),
+            query_timeout: Duration::from_millis(500),
+            state_file: None
+        }
+    }
+
+    // Polls everyone until the lookup on node `on` finishes
+    fn run_until_done(nodes: &mut [DhtNode], on: usize, lookup: LookupId) -> Vec<DhtEvent> {
+        let mut events = Vec::new();
+        let start = Instant::now();
+        while nodes[on].is_running(lookup) {
+            assert!(start.elapsed() < Duration::from_secs(10), "Lookup never finished");
+            for (i, node) in nodes.iter_mut().enumerate() {
+                let e = node.poll(Instant::now());
+                if i == on {
+                    events.extend(e);
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        events
+    }
+
+    // Gives fire and forget queries like announces time to land
+    fn settle(nodes: &mut [DhtNode]) {
+        for _ in 0..50 {
+            for node in nodes.iter_mut() {
+                node.poll(Instant::now());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn items_found(events: &[DhtEvent]) -> Vec<Item> {
+        events.iter().filter_map(|e| match *e {
+            DhtEvent::Item { ref item, .. } => Some(item.clone()),
+            _ => None
+        }).collect()
+    }
+
+    fn network(size: usize) -> Vec<DhtNode> {
+        let mut nodes = vec!(DhtNode::bind("127.0.0.1:0", config(&[])).unwrap());
+        let first = nodes[0].local_addr().unwrap();
+        for _ in 1..size {
+            nodes.push(DhtNode::bind("127.0.0.1:0", config(&[first])).unwrap());
+        }
+        for i in 1..size {
+            let l = nodes[i].bootstrap(Instant::now());
+            run_until_done(&mut nodes, i, l);
+        }
+        nodes
+    }
+
+    #[test]
+    fn bootstrap_and_find_node() {
+        let mut nodes = network(30);
+        for node in nodes.iter() {
+            assert!(!node.routing_table().is_empty());
+        }
+        // Responses tell us the address we're talking from
+        assert_eq!(nodes[5].external_ip(), Some("127.0.0.1".parse().unwrap()));
+
+        // Any node can find any other
+        let target = nodes[7].id();
+        let l = nodes[23].find_node(target, Instant::now());
+        let events = run_until_done(&mut nodes, 23, l);
+        match events.last() {
+            Some(DhtEvent::LookupDone { closest, .. }) => assert_eq!(closest[0].id, target),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn announce_and_get_peers() {
+        let mut nodes = network(25);
+        let info_hash = [0x42; 20];
+
+        let l = nodes[3].announce(info_hash, Some(6881), false, Instant::now());
+        run_until_done(&mut nodes, 3, l);
+        settle(&mut nodes);
+
+        let l = nodes[17].get_peers(info_hash, Instant::now());
+        let events = run_until_done(&mut nodes, 17, l);
+        let found: Vec<SocketAddr> = events.iter().flat_map(|e| match *e {
+            DhtEvent::Peers { ref peers, .. } => peers.clone(),
+            _ => Vec::new()
+        }).collect();
+        assert_eq!(found, vec!("127.0.0.1:6881".parse().unwrap()));
+    }
+
+    #[test]
+    fn scrapes_swarm_size() {
+        let mut nodes = network(15);
+        let info_hash = [0x33; 20];
+
+        // The filters count IPs, so each announcer needs its own
+        let first = nodes[0].local_addr().unwrap();
+        for i in 2..8 {
+            nodes.push(DhtNode::bind(format!("127.0.0.{}:0", i).as_str(), config(&[first])).unwrap());
+            let n = nodes.len() - 1;
+            let l = nodes[n].bootstrap(Instant::now());
+            run_until_done(&mut nodes, n, l);
+        }
+        for (n, seed) in (15..21).zip([true, true, false, false, false, false].iter()) {
+            let l = nodes[n].announce(info_hash, Some(6881), *seed, Instant::now());
+            run_until_done(&mut nodes, n, l);
+        }
+        settle(&mut nodes);
+
+        let l = nodes[5].scrape(info_hash, Instant::now());
+        let events = run_until_done(&mut nodes, 5, l);
+        let scrape = events.iter().find(|e| matches!(**e, DhtEvent::Scrape { .. }));
+        assert_eq!(scrape, Some(&DhtEvent::Scrape { lookup: l, info_hash, seeds: 2, peers: 4 }));
+    }
+
+    #[test]
+    fn crawls_samples() {
+        let mut nodes = network(20);
+        let hashes: Vec<[u8; 20]> = (0..5).map(|i| [0x50 + i; 20]).collect();
+        for (i, info_hash) in hashes.iter().enumerate() {
+            let l = nodes[i].announce(*info_hash, Some(6881), false, Instant::now());
+            run_until_done(&mut nodes, i, l);
+        }
+        settle(&mut nodes);
+
+        let l = nodes[12].crawl(100, Instant::now());
+        let events = run_until_done(&mut nodes, 12, l);
+        let mut found = HashSet::new();
+        for e in events.iter() {
+            match *e {
+                DhtEvent::Samples { ref samples, num, .. } => {
+                    assert_eq!(samples.len(), num);
+                    found.extend(samples.iter().cloned());
+                },
+                // Every other node in the network, and nobody twice
+                DhtEvent::CrawlDone { queried, .. } => assert_eq!(queried, 19),
+                _ => ()
+            }
+        }
+        assert_eq!(found, hashes.into_iter().collect());
+
+        // Everybody asked us to give it a rest
+        let l = nodes[12].crawl(100, Instant::now());
+        assert!(!nodes[12].is_running(l));
+        assert_eq!(nodes[12].poll(Instant::now()), vec!(DhtEvent::CrawlDone { lookup: l, queried: 0 }));
+
+        // Somebody else asking for fewer than there are stops there
+        let l = nodes[3].crawl(5, Instant::now());
+        let events = run_until_done(&mut nodes, 3, l);
+        assert!(events.contains(&DhtEvent::CrawlDone { lookup: l, queried: 5 }));
+    }
+
+    #[test]
+    fn tokens_and_bad_packets() {
+        let mut server = DhtNode::bind("127.0.0.1:0", config(&[])).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let mut buf = [0u8; 1500];
+        let mut ask = |server: &mut DhtNode, msg: &[u8]| -> Message {
+            client.send_to(msg, server_addr).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            server.poll(Instant::now());
+            let (n, _) = client.recv_from(&mut buf).unwrap();
+            Message::decode(&buf[..n]).unwrap()
+        };
+
+        let id = NodeId([9; 20]);
+        let announce = |token: Vec<u8>| Message { t: b"an".to_vec(), v: None, ip: None, body: Body::Query(Query::AnnouncePeer {
+            id, info_hash: [1; 20], port: 0, implied_port: true, token, seed: false
+        })}.encode();
+
+        // Made up tokens get turned away
+        match ask(&mut server, &announce(b"nope".to_vec())).body {
+            Body::Error { code, .. } => assert_eq!(code, ERROR_PROTOCOL),
+            _ => unreachable!()
+        }
+
+        let get_peers = Message { t: b"gp".to_vec(), v: None, ip: None, body: Body::Query(Query::GetPeers { id, info_hash: [1; 20], scrape: false }) }.encode();
+        let token = match ask(&mut server, &get_peers).body {
+            Body::Response(r) => r.token.unwrap(),
+            _ => unreachable!()
+        };
+        match ask(&mut server, &announce(token)).body {
+            Body::Response(r) => assert_eq!(r.id, server.id()),
+            _ => unreachable!()
+        }
+        match ask(&mut server, &get_peers).body {
+            Body::Response(r) => assert_eq!(r.values, vec!(client.local_addr().unwrap())),
+            _ => unreachable!()
+        }
+
+        // And queries we don't understand get an error back
+        match ask(&mut server, b"d1:ad2:id20:abcdefghij0123456789e1:q4:pong1:t2:aa1:y1:qe").body {
+            Body::Error { code, .. } => assert_eq!(code, ERROR_METHOD_UNKNOWN),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn puts_and_gets_items() {
+        let mut nodes = network(20);
+
+        let immutable = Item::immutable(&Benc::S(b"Hello World!".to_vec()));
+        let l = nodes[2].put(immutable.clone(), None, Instant::now());
+        run_until_done(&mut nodes, 2, l);
+        settle(&mut nodes);
+        let l = nodes[13].get_immutable(immutable.target(), Instant::now());
+        assert_eq!(items_found(&run_until_done(&mut nodes, 13, l)), vec!(immutable));
+
+        let (secret, public) = ed25519::keypair(&[1; 32]);
+        let first = Item::mutable(&Benc::I(1), &secret, b"feed", 1);
+        let l = nodes[4].put(first, None, Instant::now());
+        run_until_done(&mut nodes, 4, l);
+        settle(&mut nodes);
+        let second = Item::mutable(&Benc::I(2), &secret, b"feed", 2);
+        let l = nodes[4].put(second.clone(), Some(1), Instant::now());
+        run_until_done(&mut nodes, 4, l);
+        settle(&mut nodes);
+
+        let l = nodes[9].get_mutable(public, b"feed", Instant::now());
+        let found = items_found(&run_until_done(&mut nodes, 9, l));
+        assert_eq!(found.last(), Some(&second));
+
+        // Nothing under a different salt
+        let l = nodes[9].get_mutable(public, b"other", Instant::now());
+        assert!(items_found(&run_until_done(&mut nodes, 9, l)).is_empty());
+    }
+
+    #[test]
+    fn item_rules() {
+        let mut node = DhtNode::bind("127.0.0.1:0", config(&[])).unwrap();
+        let now = Instant::now();
+        let (secret, _) = ed25519::keypair(&[2; 32]);
+
+        assert_eq!(node.store(Item::immutable(&Benc::S(vec!(0; MAX_VALUE_SIZE))), None, now).unwrap_err().0, ERROR_VALUE_TOO_BIG);
+        assert_eq!(node.store(Item::mutable(&Benc::I(0), &secret, &[0; 65], 1), None, now).unwrap_err().0, ERROR_SALT_TOO_BIG);
+        let signed = Item::mutable(&Benc::I(0), &secret, b"", 1);
+        let forged = Item::from_parts(b"i1e".to_vec(), signed.k().unwrap(), Vec::new(), 1, signed.sig().unwrap()).unwrap();
+        assert_eq!(node.store(forged, None, now).unwrap_err().0, ERROR_INVALID_SIGNATURE);
+
+        node.store(Item::mutable(&Benc::I(0), &secret, b"", 5), None, now).unwrap();
+        // Refreshing is fine, going back or changing the value without a
+        // new seq isn't
+        node.store(Item::mutable(&Benc::I(0), &secret, b"", 5), None, now).unwrap();
+        assert_eq!(node.store(Item::mutable(&Benc::I(1), &secret, b"", 5), None, now).unwrap_err().0, ERROR_SEQ_TOO_LOW);
+        assert_eq!(node.store(Item::mutable(&Benc::I(1), &secret, b"", 4), None, now).unwrap_err().0, ERROR_SEQ_TOO_LOW);
+        assert_eq!(node.store(Item::mutable(&Benc::I(1), &secret, b"", 6), Some(4), now).unwrap_err().0, ERROR_CAS_MISMATCH);
+        node.store(Item::mutable(&Benc::I(1), &secret, b"", 6), Some(5), now).unwrap();
+
+        // Everything goes eventually
+        assert_eq!(node.items.len(), 1);
+        node.expire(now + ITEM_TTL);
+        assert!(node.items.is_empty());
+    }
+
+    #[test]
+    fn saves_and_checks_state() {
+        let path = env::temp_dir().join(format!("flakes-dht-node-test-{}", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut nodes = network(10);
+        let mut cfg = config(&[nodes[0].local_addr().unwrap()]);
+        cfg.state_file = Some(path.clone());
+        nodes.push(DhtNode::bind("127.0.0.1:0", cfg.clone()).unwrap());
+        let l = nodes[10].bootstrap(Instant::now());
+        run_until_done(&mut nodes, 10, l);
+        let id = nodes[10].id();
+        nodes[10].save().unwrap();
+        let saved = DhtState::load(&path).unwrap().unwrap();
+        assert_eq!(saved.id, id);
+        assert!(saved.nodes.len() >= 5);
+        drop(nodes.pop());
+
+        // Two of the saved nodes go stale: one's gone, one has a new ID
+        let mut state = saved.clone();
+        let gone = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        state.nodes.push(NodeInfo { id: NodeId([0xee; 20]), addr: gone });
+        state.nodes[0].id = NodeId([0xdd; 20]);
+        state.save(&path).unwrap();
+
+        // No bootstrap hosts this time, just what was saved
+        cfg.bootstrap = Vec::new();
+        nodes.push(DhtNode::bind("127.0.0.1:0", cfg).unwrap());
+        assert_eq!(nodes[10].id(), id);
+        assert!(nodes[10].routing_table().is_empty());
+        let l = nodes[10].bootstrap(Instant::now());
+        run_until_done(&mut nodes, 10, l);
+
+        let table = nodes[10].routing_table();
+        assert!(table.len() >= saved.nodes.len() - 2);
+        assert!(table.get(&NodeId([0xee; 20])).is_none());
+        assert!(table.get(&NodeId([0xdd; 20])).is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn secure_ids_from_external_ip() {
+        let path = env::temp_dir().join(format!("flakes-dht-node-secure-test-{}", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ip: IpAddr = "84.124.73.14".parse().unwrap();
+
+        let mut cfg = config(&[]);
+        cfg.external_ip = Some(ip);
+        cfg.state_file = Some(path.clone());
+        let node = DhtNode::bind("127.0.0.1:0", cfg.clone()).unwrap();
+        assert!(node_id_matches_ip(&node.id(), ip));
+        assert_eq!(node.external_ip(), Some(ip));
+
+        // A saved ID from somewhere else gets replaced
+        DhtState { id: NodeId([7; 20]), nodes: Vec::new() }.save(&path).unwrap();
+        let node = DhtNode::bind("127.0.0.1:0", cfg.clone()).unwrap();
+        assert!(node_id_matches_ip(&node.id(), ip));
+        node.save().unwrap();
+        let node2 = DhtNode::bind("127.0.0.1:0", cfg).unwrap();
+        assert_eq!(node2.id(), node.id());
+
+        // And without a configured IP, save() swaps it for one that matches
+        // what we've been told
+        let mut node = DhtNode::bind("127.0.0.1:0", config(&[])).unwrap();
+        node.config.state_file = Some(path.clone());
+        node.ip_votes.push(("10.0.0.1:6881".parse().unwrap(), ip));
+        assert!(!node_id_matches_ip(&node.id(), ip));
+        node.save().unwrap();
+        assert!(node_id_matches_ip(&DhtState::load(&path).unwrap().unwrap().id, ip));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shares_socket_with_utp() {
+        let mut nodes = network(5);
+        let mut ours = UtpSocket::shared(nodes[0].try_clone_socket().unwrap(), UtpConfig::default());
+        let mut theirs = UtpSocket::bind("127.0.0.1:0", UtpConfig::default()).unwrap();
+        let mut stream = theirs.connect(nodes[0].local_addr().unwrap(), Instant::now());
+        stream.write_all(b"hello").unwrap();
+
+        // The DHT carries on as usual while uTP gets its packets
+        let target = nodes[3].id();
+        let l = nodes[0].find_node(target, Instant::now());
+        let mut accepted = None;
+        let mut got = Vec::new();
+        let start = Instant::now();
+        while nodes[0].is_running(l) || got.len() < 5 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            for node in nodes.iter_mut() {
+                node.poll(Instant::now());
+            }
+            for (packet, from) in nodes[0].take_other_packets() {
+                ours.handle_packet(&packet, from, Instant::now());
+            }
+            ours.poll(Instant::now());
+            theirs.poll(Instant::now());
+            if accepted.is_none() {
+                accepted = ours.accept();
+            }
+            if let Some(ref mut s) = accepted {
+                let mut buf = [0u8; 16];
+                if let Ok(n) = s.read(&mut buf) {
+                    got.extend_from_slice(&buf[..n]);
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(got, b"hello".to_vec());
+        assert!(nodes[0].routing_table().nodes().iter().any(|n| n.info.id == target));
+    }
+}
@@ -0,0 +1,392 @@
+use std::time::{Duration, Instant};
+
+use dht::*;
+use id::node_id_matches_ip;
+
+// Nodes per bucket
+pub const K: usize = 8;
+// A node we've heard from in this long is "good"; buckets nobody's touched
+// in this long need refreshing
+pub const GOOD_FOR: Duration = Duration::from_secs(15 * 60);
+// Unanswered queries in a row before a node is bad and can be replaced
+pub const MAX_FAILURES: u32 = 2;
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub info: NodeInfo,
+    pub last_seen: Instant,
+    pub failures: u32,
+    // Whether the ID checks out against the address, per BEP 42. Nodes that
+    // don't are kept, since plenty of clients still pick random IDs, but
+    // they're the first to go when someone better comes along.
+    pub secure: bool
+}
+
+impl Node {
+    fn new(info: NodeInfo, now: Instant) -> Node {
+        Node { info, last_seen: now, failures: 0, secure: node_id_matches_ip(&info.id, info.addr.ip()) }
+    }
+
+    pub fn is_good(&self, now: Instant) -> bool {
+        self.failures == 0 && now < self.last_seen + GOOD_FOR
+    }
+
+    pub fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+struct Bucket {
+    nodes: Vec<Node>,
+    // Nodes we heard from while the bucket was full, newest last, waiting
+    // for someone in the bucket to go bad
+    replacements: Vec<Node>,
+    last_changed: Instant
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Bucket {
+        Bucket { nodes: Vec::new(), replacements: Vec::new(), last_changed: now }
+    }
+}
+
+// What happened to a node we just heard from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Insert {
+    Added,
+    Updated,
+    // The bucket's full of nodes that might still be alive. These are the
+    // ones worth pinging; any that don't answer make room.
+    Cached(Vec<NodeInfo>),
+    // Our own ID, which never goes in the table
+    Ignored
+}
+
+// The BEP 5 routing table. Bucket i holds the nodes that share exactly i
+// leading bits with our ID, except the last one, which holds everything
+// closer than that. Only the last bucket ever splits, so we end up knowing
+// lots of nodes near us and a handful from everywhere else.
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Bucket>
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId, now: Instant) -> RoutingTable {
+        RoutingTable { own_id, buckets: vec!(Bucket::new(now)) }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn num_buckets(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn nodes(&self) -> Vec<&Node> {
+        self.buckets.iter().flat_map(|b| b.nodes.iter()).collect()
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<&Node> {
+        self.buckets[self.bucket_index(id)].nodes.iter().find(|n| n.info.id == *id)
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        self.own_id.common_prefix_len(id).min(self.buckets.len() - 1)
+    }
+
+    // Call whenever a node answers one of our queries or sends us one
+    pub fn heard_from(&mut self, info: NodeInfo, now: Instant) -> Insert {
+        if info.id == self.own_id {
+            return Insert::Ignored;
+        }
+
+        loop {
+            let i = self.bucket_index(&info.id);
+            let last = i == self.buckets.len() - 1;
+            let can_split = last && self.buckets.len() < 160;
+            let bucket = &mut self.buckets[i];
+
+            if let Some(node) = bucket.nodes.iter_mut().find(|n| n.info.id == info.id) {
+                node.info.addr = info.addr;
+                node.secure = node_id_matches_ip(&info.id, info.addr.ip());
+                node.last_seen = now;
+                node.failures = 0;
+                bucket.last_changed = now;
+                return Insert::Updated;
+            }
+
+            if bucket.nodes.len() < K {
+                bucket.nodes.push(Node::new(info, now));
+                bucket.replacements.retain(|n| n.info.id != info.id);
+                bucket.last_changed = now;
+                return Insert::Added;
+            }
+
+            // Full, but it's the bucket we're in, so split it and try again
+            if can_split {
+                self.split(now);
+                continue;
+            }
+
+            let node = Node::new(info, now);
+            if let Some(pos) = bucket.nodes.iter().position(|n| n.is_bad()) {
+                bucket.nodes[pos] = node;
+                bucket.last_changed = now;
+                return Insert::Added;
+            }
+
+            // A secure node bumps the stalest node whose ID doesn't match
+            // its address back to the replacements
+            if node.secure {
+                let insecure = bucket.nodes.iter().enumerate()
+                    .filter(|(_, n)| !n.secure)
+                    .min_by_key(|(_, n)| n.last_seen)
+                    .map(|(pos, _)| pos);
+                if let Some(pos) = insecure {
+                    let old = ::std::mem::replace(&mut bucket.nodes[pos], node);
+                    bucket.replacements.retain(|n| n.info.id != info.id);
+                    if bucket.replacements.len() >= K {
+                        bucket.replacements.remove(0);
+                    }
+                    bucket.replacements.insert(0, old);
+                    bucket.last_changed = now;
+                    return Insert::Added;
+                }
+            }
+
+            bucket.replacements.retain(|n| n.info.id != info.id);
+            if bucket.replacements.len() >= K {
+                // Drop the oldest insecure one if there is one
+                let pos = bucket.replacements.iter().position(|n| !n.secure).unwrap_or(0);
+                bucket.replacements.remove(pos);
+            }
+            bucket.replacements.push(node);
+            return Insert::Cached(bucket.nodes.iter().filter(|n| !n.is_good(now)).map(|n| n.info).collect());
+        }
+    }
+
+    fn split(&mut self, now: Instant) {
+        let depth = self.buckets.len() - 1;
+        let own_id = self.own_id;
+        let mut new = Bucket::new(now);
+        {
+            let old = self.buckets.last_mut().unwrap();
+            let (near, far): (Vec<Node>, Vec<Node>) = old.nodes.drain(..)
+                .partition(|n| own_id.common_prefix_len(&n.info.id) > depth);
+            old.nodes = far;
+            new.nodes = near;
+            let (near, far): (Vec<Node>, Vec<Node>) = old.replacements.drain(..)
+                .partition(|n| own_id.common_prefix_len(&n.info.id) > depth);
+            old.replacements = far;
+            new.replacements = near;
+        }
+        self.buckets.push(new);
+    }
+
+    // A query to this node went unanswered. Bad nodes get swapped for the
+    // newest secure replacement, or the newest of any if there's none.
+    pub fn failed(&mut self, id: &NodeId) {
+        let i = self.bucket_index(id);
+        let bucket = &mut self.buckets[i];
+        let pos = match bucket.nodes.iter().position(|n| n.info.id == *id) {
+            Some(pos) => pos,
+            None => {
+                bucket.replacements.retain(|n| n.info.id != *id);
+                return;
+            }
+        };
+
+        bucket.nodes[pos].failures += 1;
+        if bucket.nodes[pos].is_bad() {
+            let next = bucket.replacements.iter().rposition(|n| n.secure)
+                .or_else(|| bucket.replacements.len().checked_sub(1));
+            if let Some(next) = next {
+                bucket.nodes[pos] = bucket.replacements.remove(next);
+            }
+        }
+    }
+
+    // The closest nodes we know that aren't known to be dead
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.buckets.iter()
+            .flat_map(|b| b.nodes.iter())
+            .filter(|n| !n.is_bad())
+            .map(|n| n.info)
+            .collect();
+        nodes.sort_by_key(|n| n.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    // A random ID to look up for every bucket nothing's happened in for a
+    // while. Looking one up refreshes the bucket.
+    pub fn refresh_targets(&mut self, now: Instant) -> Vec<NodeId> {
+        let mut out = Vec::new();
+        let last = self.buckets.len() - 1;
+        for i in 0..self.buckets.len() {
+            if now < self.buckets[i].last_changed + GOOD_FOR {
+                continue;
+            }
+            self.buckets[i].last_changed = now;
+            out.push(self.random_id_in_bucket(i, i == last));
+        }
+        out
+    }
+
+    fn random_id_in_bucket(&self, i: usize, last: bool) -> NodeId {
+        let mut id = NodeId::random();
+        // Share the first i bits with us, then differ on bit i unless this
+        // is the last bucket, which covers everything closer too
+        for bit in 0..i {
+            if id.bit(bit) != self.own_id.bit(bit) {
+                id.flip_bit(bit);
+            }
+        }
+        if !last && id.bit(i) == self.own_id.bit(i) {
+            id.flip_bit(i);
+        }
+        id
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use id::generate_node_id;
+    use super::*;
+
+    fn node(first: u8, n: u16) -> NodeInfo {
+        let mut id = [0u8; 20];
+        id[0] = first;
+        id[18] = (n >> 8) as u8;
+        id[19] = n as u8;
+        NodeInfo { id: NodeId(id), addr: SocketAddr::new("127.0.0.1".parse().unwrap(), 1000 + n) }
+    }
+
+    #[test]
+    fn splits_near_us() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+
+        // Far away nodes fill up the first bucket after a split, and then get cached
+        for n in 0..20 {
+            table.heard_from(node(0x80, n), now);
+        }
+        assert_eq!(table.len(), K);
+        assert_eq!(table.num_buckets(), 2);
+
+        // Near ones keep splitting, since they're in our bucket, but each
+        // distance still only gets K of them. 1 through 15 all fit, then
+        // 16-31 and 32-40 are a bucket each.
+        for n in 1..41 {
+            table.heard_from(node(0, n), now);
+        }
+        assert_eq!(table.len(), K + 15 + K + K);
+        assert_eq!(table.num_buckets(), 158);
+
+        assert_eq!(table.heard_from(node(0, 3), now), Insert::Updated);
+        assert_eq!(table.heard_from(NodeInfo { id: NodeId([0; 20]), addr: node(0, 0).addr }, now), Insert::Ignored);
+    }
+
+    #[test]
+    fn replaces_bad_nodes() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        for n in 0..K as u16 {
+            table.heard_from(node(0x80, n), now);
+        }
+        // Splits once and the first bucket is full of far nodes
+        table.heard_from(node(0x01, 0), now);
+        assert_eq!(table.num_buckets(), 2);
+
+        // Everyone's good, so the newcomer has to wait
+        match table.heard_from(node(0x80, 100), now) {
+            Insert::Cached(ping) => assert!(ping.is_empty()),
+            _ => unreachable!()
+        }
+
+        // Later on they're questionable and worth pinging
+        let later = now + GOOD_FOR + Duration::from_secs(1);
+        match table.heard_from(node(0x80, 101), later) {
+            Insert::Cached(ping) => assert_eq!(ping.len(), K),
+            _ => unreachable!()
+        }
+
+        // Two strikes and node 0 is swapped for the newest replacement
+        table.failed(&node(0x80, 0).id);
+        assert!(table.get(&node(0x80, 0).id).is_some());
+        table.failed(&node(0x80, 0).id);
+        assert!(table.get(&node(0x80, 0).id).is_none());
+        assert!(table.get(&node(0x80, 101).id).is_some());
+    }
+
+    #[test]
+    fn closest_and_refresh() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        for n in 1..31 {
+            table.heard_from(node(0, n), now);
+        }
+        let target = node(0, 12).id;
+        let closest = table.closest(&target, 3);
+        assert_eq!(closest[0].id, target);
+        assert_eq!(closest.len(), 3);
+        assert!(closest[1].id.distance(&target) < closest[2].id.distance(&target));
+
+        assert!(table.refresh_targets(now).is_empty());
+        let later = now + GOOD_FOR;
+        let targets = table.refresh_targets(later);
+        assert_eq!(targets.len(), table.num_buckets());
+        // Each target lands in the bucket it's meant to refresh
+        for (i, t) in targets.iter().enumerate().take(table.num_buckets() - 1) {
+            assert_eq!(table.own_id().common_prefix_len(t), i);
+        }
+        assert!(table.refresh_targets(later).is_empty());
+    }
+
+    #[test]
+    fn prefers_secure_nodes() {
+        let now = Instant::now();
+        let own = NodeId([0; 20]);
+        let mut table = RoutingTable::new(own, now);
+
+        // Public addresses with random IDs that don't match them
+        let insecure: Vec<NodeInfo> = (0..K as u16).map(|n| {
+            let mut info = node(0x80, n);
+            info.addr = SocketAddr::new(format!("203.0.113.{}", n + 1).parse().unwrap(), 6881);
+            info
+        }).collect();
+        for info in insecure.iter() {
+            table.heard_from(*info, now);
+        }
+        table.heard_from(node(0x01, 0), now);
+        assert!(table.nodes().iter().all(|n| n.secure == (n.info.id.0[0] == 0x01)));
+
+        // Secure IDs are all over the place, so find one for the far bucket
+        let secure = (1..).map(|n| {
+            let ip = format!("198.51.100.{}", n % 250 + 1).parse().unwrap();
+            NodeInfo { id: generate_node_id(ip), addr: SocketAddr::new(ip, 6881) }
+        }).find(|info| own.common_prefix_len(&info.id) == 0).unwrap();
+        assert_eq!(table.heard_from(secure, now), Insert::Added);
+        assert!(table.get(&secure.id).unwrap().secure);
+        assert!(table.get(&insecure[0].id).is_none());
+        assert_eq!(table.len(), K + 1);
+
+        // The bumped node waits as a replacement and comes back when needed
+        table.failed(&secure.id);
+        table.failed(&secure.id);
+        assert!(table.get(&insecure[0].id).is_some());
+    }
+}
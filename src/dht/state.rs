@@ -0,0 +1,94 @@
+use std::collections::btree_map::BTreeMap;
+use std::path::Path;
+
+use bencode::*;
+use dht::*;
+use id::{read_state_file, write_state_file};
+
+// What we keep between runs so we don't have to bootstrap from scratch: our
+// node ID, so the nodes that know us keep knowing us, and the good nodes
+// from the routing table. None of those nodes are trusted until they've
+// answered a ping.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DhtState {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>
+}
+
+impl DhtState {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut d = BTreeMap::new();
+        d.insert(String::from("id"), Benc::S(self.id.as_bytes().to_vec()));
+        d.insert(String::from("nodes"), Benc::S(encode_compact_nodes(&self.nodes, false)));
+        d.insert(String::from("nodes6"), Benc::S(encode_compact_nodes(&self.nodes, true)));
+        enc_benc(&Benc::D(d))
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<DhtState, String> {
+        let d = match dec_benc(&bytes.to_vec()) {
+            Ok(Benc::D(d)) => d,
+            Ok(_) => { return Err(String::from("DHT state file is not a dictionary!")); },
+            Err(e) => { return Err(format!("Unable to decode DHT state file: {}", e)); }
+        };
+
+        let id = match d.get("id") {
+            Some(Benc::S(id)) => match NodeId::from_slice(id) {
+                Some(id) => id,
+                None => { return Err(String::from("DHT state file has an 'id' which isn't 20 bytes!")); }
+            },
+            _ => { return Err(String::from("DHT state file has a missing or invalid 'id'!")); }
+        };
+
+        let mut nodes = Vec::new();
+        if let Some(Benc::S(compact)) = d.get("nodes") {
+            nodes.extend(parse_compact_nodes(compact)?);
+        }
+        if let Some(Benc::S(compact)) = d.get("nodes6") {
+            nodes.extend(parse_compact_nodes6(compact)?);
+        }
+
+        Ok(DhtState { id, nodes })
+    }
+
+    // None if there's no state file yet
+    pub fn load(path: &Path) -> Result<Option<DhtState>, String> {
+        match read_state_file(path)? {
+            Some(bytes) => DhtState::decode(&bytes).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        write_state_file(path, &self.encode())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use dht::*;
+    use super::DhtState;
+
+    #[test]
+    fn round_trip() {
+        let state = DhtState {
+            id: NodeId([5; 20]),
+            nodes: vec!(
+                NodeInfo { id: NodeId([1; 20]), addr: "10.0.0.1:6881".parse().unwrap() },
+                NodeInfo { id: NodeId([2; 20]), addr: "[2001:db8::2]:6881".parse().unwrap() }
+            )
+        };
+
+        let path = env::temp_dir().join(format!("flakes-dht-state-test-{}", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(DhtState::load(&path).unwrap(), None);
+        state.save(&path).unwrap();
+        assert_eq!(DhtState::load(&path).unwrap(), Some(state));
+        fs::remove_file(&path).unwrap();
+
+        assert!(DhtState::decode(b"d2:id3:abce").is_err());
+        assert!(DhtState::decode(b"d2:id20:abcdefghij01234567895:nodes3:abce").is_err());
+    }
+}
@@ -1,12 +1,43 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::collections::btree_map::BTreeMap;
+
 use rand::{Rng, thread_rng};
 
-pub fn generate_id() -> Vec<u8> {
-    let mut id = Vec::with_capacity(20);
+use bencode::*;
+use dht::NodeId;
+
+// Two letter client code we put at the front of our Azureus-style peer IDs
+const CLIENT_CODE: &str = "FK";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(pub [u8; 20]);
 
-    // TODO: Derive the version number from the crate version
-    for c in "-FK0010-".as_bytes() {
-        id.push(*c);
+// What we figured out about the client on the other end from its peer ID
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub name: &'static str,
+    pub version: String
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
     }
+}
+
+pub fn generate_id() -> PeerId {
+    let mut id = [0u8; 20];
+    let prefix = id_prefix();
+    id[..prefix.len()].copy_from_slice(&prefix);
 
     // kscz@2016/04/15 - we need to put a random number after the prefix
     // I have no idea if this needs to be consistent across runs
@@ -14,16 +45,514 @@ pub fn generate_id() -> Vec<u8> {
     // to incorporate the mac addr of the machine or something and
     // use a sha perhaps?
     let mut rng = thread_rng();
-    for _ in 0..(20 - id.len()) {
-        id.push(rng.gen::<u8>());
+    for b in id[prefix.len()..].iter_mut() {
+        *b = rng.gen::<u8>();
+    }
+
+    PeerId(id)
+}
+
+// Builds "-FKxyz0-" from the crate version, so 0.1.0 becomes "-FK0100-"
+pub fn id_prefix() -> [u8; 8] {
+    let mut prefix = [b'-'; 8];
+    prefix[1..3].copy_from_slice(CLIENT_CODE.as_bytes());
+
+    let mut parts = env!("CARGO_PKG_VERSION").split(['.', '-']);
+    for slot in prefix[3..6].iter_mut() {
+        let part = parts.next().and_then(|p| p.parse::<u8>().ok()).unwrap_or(0);
+        *slot = encode_version_char(part);
     }
+    prefix[6] = b'0';
+
+    prefix
+}
 
+// BEP 42 node IDs: the first 21 bits come from a CRC32-C of our external IP,
+// so nobody can pick an ID next to an info hash they want to sit on without
+// also owning an address that hashes there. The last byte is the random
+// number that went into the hash, so anyone can check it.
+pub fn generate_node_id(external_ip: IpAddr) -> NodeId {
+    let mut id = NodeId::random();
+    let crc = node_id_crc(external_ip, id.0[19]);
+    id.0[0] = (crc >> 24) as u8;
+    id.0[1] = (crc >> 16) as u8;
+    id.0[2] = ((crc >> 8) as u8 & 0xf8) | (id.0[2] & 0x07);
     id
 }
 
+// Whether a node's ID is one it could have come up with from the address
+// it's talking to us from. Nodes on local networks get a pass, since their
+// external address isn't the one we see.
+pub fn node_id_matches_ip(id: &NodeId, ip: IpAddr) -> bool {
+    if is_local_ip(ip) {
+        return true;
+    }
+    let crc = node_id_crc(ip, id.0[19]);
+    id.0[0] == (crc >> 24) as u8 && id.0[1] == (crc >> 16) as u8
+        && id.0[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+fn node_id_crc(ip: IpAddr, rand: u8) -> u32 {
+    let r = (rand & 0x07) << 5;
+    match ip {
+        IpAddr::V4(ip) => {
+            let mut bytes = ip.octets();
+            for (b, m) in bytes.iter_mut().zip([0x03, 0x0f, 0x3f, 0xff].iter()) {
+                *b &= m;
+            }
+            bytes[0] |= r;
+            crc32c(&bytes)
+        },
+        IpAddr::V6(ip) => {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&ip.octets()[..8]);
+            for (b, m) in bytes.iter_mut().zip([0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff].iter()) {
+                *b &= m;
+            }
+            bytes[0] |= r;
+            crc32c(&bytes)
+        }
+    }
+}
+
+fn is_local_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // fc00::/7 unique local and fe80::/10 link local
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+// CRC32-C (Castagnoli), the bit at a time way. We only ever hash 8 bytes.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Versions past 9 get letters, which is what most Azureus-style clients do
+fn encode_version_char(v: u8) -> u8 {
+    match v {
+        0..=9 => b'0' + v,
+        10..=35 => b'A' + (v - 10),
+        _ => b'Z'
+    }
+}
+
+fn decode_version_char(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'Z' => Some(c - b'A' + 10),
+        b'a'..=b'z' => Some(c - b'a' + 36),
+        _ => None
+    }
+}
+
+// Shadow-style peer IDs use this alphabet for each version digit
+fn decode_shadow_char(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'Z' => Some(c - b'A' + 10),
+        b'a'..=b'z' => Some(c - b'a' + 36),
+        b'.' => Some(62),
+        _ => None
+    }
+}
+
+enum VersionStyle {
+    // a.b.c, with the fourth char appended only if it isn't zero
+    Dotted,
+    // Transmission does a.bc
+    MajorTwoMinor,
+    // µTorrent does a.b.c and sticks a build letter on the end
+    DottedBuild
+}
+
+fn azureus_client(code: &[u8]) -> Option<(&'static str, VersionStyle)> {
+    let client = match code {
+        b"AG" | b"A~" => ("Ares", VersionStyle::Dotted),
+        b"AR" => ("Arctic", VersionStyle::Dotted),
+        b"AZ" => ("Vuze", VersionStyle::Dotted),
+        b"BB" => ("BitBuddy", VersionStyle::Dotted),
+        b"BC" => ("BitComet", VersionStyle::Dotted),
+        b"BF" => ("Bitflu", VersionStyle::Dotted),
+        b"BI" => ("BiglyBT", VersionStyle::Dotted),
+        b"BT" => ("BitTorrent", VersionStyle::DottedBuild),
+        b"BW" => ("BitWombat", VersionStyle::Dotted),
+        b"CD" => ("Enhanced CTorrent", VersionStyle::Dotted),
+        b"DE" => ("Deluge", VersionStyle::Dotted),
+        b"FG" => ("FlashGet", VersionStyle::Dotted),
+        b"FK" => ("flakes", VersionStyle::Dotted),
+        b"FX" => ("Freebox BitTorrent", VersionStyle::Dotted),
+        b"HL" => ("Halite", VersionStyle::Dotted),
+        b"KG" => ("KGet", VersionStyle::Dotted),
+        b"KT" => ("KTorrent", VersionStyle::Dotted),
+        b"LP" => ("Lphant", VersionStyle::Dotted),
+        b"LT" => ("libtorrent", VersionStyle::Dotted),
+        b"lt" => ("libTorrent", VersionStyle::Dotted),
+        b"MG" => ("MediaGet", VersionStyle::Dotted),
+        b"PI" => ("PicoTorrent", VersionStyle::Dotted),
+        b"qB" => ("qBittorrent", VersionStyle::Dotted),
+        b"RT" => ("Retriever", VersionStyle::Dotted),
+        b"SD" => ("Thunder", VersionStyle::Dotted),
+        b"SZ" => ("Shareaza", VersionStyle::Dotted),
+        b"TL" => ("Tribler", VersionStyle::Dotted),
+        b"TR" => ("Transmission", VersionStyle::MajorTwoMinor),
+        b"UM" => ("µTorrent Mac", VersionStyle::DottedBuild),
+        b"UT" => ("µTorrent", VersionStyle::DottedBuild),
+        b"UW" => ("µTorrent Web", VersionStyle::DottedBuild),
+        b"WW" => ("WebTorrent", VersionStyle::Dotted),
+        b"XL" => ("Xunlei", VersionStyle::Dotted),
+        b"ZT" => ("ZipTorrent", VersionStyle::Dotted),
+        _ => { return None; }
+    };
+    Some(client)
+}
+
+fn shadow_client(code: u8) -> Option<&'static str> {
+    match code {
+        b'A' => Some("ABC"),
+        b'O' => Some("Osprey Permaseed"),
+        b'Q' => Some("BTQueue"),
+        b'R' => Some("Tribler"),
+        b'S' => Some("Shadow's client"),
+        b'T' => Some("BitTornado"),
+        b'U' => Some("UPnP NAT Bit Torrent"),
+        _ => None
+    }
+}
+
+impl PeerId {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    // Copies a peer ID out of a slice, which needs to be exactly 20 bytes
+    pub fn from_slice(s: &[u8]) -> Option<PeerId> {
+        if s.len() != 20 {
+            return None;
+        }
+        let mut id = [0u8; 20];
+        id.copy_from_slice(s);
+        Some(PeerId(id))
+    }
+
+    // Try to work out which client generated this ID. We know about the
+    // Azureus-style "-XX1234-" format, the Shadow-style "S58B-----" format and
+    // mainline's "M4-3-6--" format
+    pub fn client(&self) -> Option<ClientInfo> {
+        self.azureus_client()
+            .or_else(|| self.shadow_client())
+            .or_else(|| self.mainline_client())
+    }
+
+    fn azureus_client(&self) -> Option<ClientInfo> {
+        let id = &self.0;
+        if id[0] != b'-' || id[7] != b'-' {
+            return None;
+        }
+
+        let (name, style) = azureus_client(&id[1..3])?;
+        let mut digits = [0u8; 4];
+        for (d, c) in digits.iter_mut().zip(id[3..7].iter()) {
+            *d = decode_version_char(*c)?;
+        }
+
+        let version = match style {
+            VersionStyle::Dotted => {
+                if digits[3] == 0 {
+                    format!("{}.{}.{}", digits[0], digits[1], digits[2])
+                } else {
+                    format!("{}.{}.{}.{}", digits[0], digits[1], digits[2], digits[3])
+                }
+            },
+            VersionStyle::MajorTwoMinor => {
+                format!("{}.{}{}", digits[0], digits[1], digits[2])
+            },
+            VersionStyle::DottedBuild => {
+                let mut version = format!("{}.{}.{}", digits[0], digits[1], digits[2]);
+                if id[6] != b'0' {
+                    version.push(' ');
+                    version.push(id[6] as char);
+                }
+                version
+            }
+        };
+
+        Some(ClientInfo { name, version })
+    }
+
+    fn shadow_client(&self) -> Option<ClientInfo> {
+        let id = &self.0;
+        let name = shadow_client(id[0])?;
+        if &id[6..9] != b"---" {
+            return None;
+        }
+
+        let mut parts = Vec::new();
+        for c in id[1..6].iter() {
+            if *c == b'-' {
+                break;
+            }
+            parts.push(decode_shadow_char(*c)?.to_string());
+        }
+        if parts.is_empty() {
+            return None;
+        }
+
+        Some(ClientInfo { name, version: parts.join(".") })
+    }
+
+    fn mainline_client(&self) -> Option<ClientInfo> {
+        let id = &self.0;
+        if id[0] != b'M' {
+            return None;
+        }
+
+        // Mainline is M<major>-<minor>-<patch>--, where each part can be one
+        // or two digits
+        let end = id.windows(2).position(|w| w == b"--")?;
+        let parts: Vec<&[u8]> = id[1..end].split(|c| *c == b'-').collect();
+        if parts.len() != 3 {
+            return None;
+        }
+        for part in parts.iter() {
+            if part.is_empty() || part.len() > 2 || !part.iter().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+        }
+
+        let version: Vec<String> = parts.iter().map(|p| String::from_utf8_lossy(p).into_owned()).collect();
+        Some(ClientInfo { name: "BitTorrent (mainline)", version: version.join(".") })
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.client() {
+            Some(client) => write!(f, "{}", client),
+            None => {
+                // Print the printable bits, and escape the rest
+                for b in self.0.iter() {
+                    if b.is_ascii_graphic() {
+                        write!(f, "{}", *b as char)?;
+                    } else {
+                        write!(f, "\\x{:02x}", b)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeerId(")?;
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, ")")
+    }
+}
+
+// How long we hang onto a peer ID before making a new one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentityPolicy {
+    // One ID for everything until we exit
+    PerSession,
+    // A fresh ID for each torrent, so swarms can't correlate us
+    PerTorrent,
+    // One ID, kept in the state file so it survives restarts
+    Persistent
+}
+
+// Our peer ID(s) plus the tracker "key", which trackers use to recognize us
+// even if our IP changes. The key always goes in the state file if we have
+// one, since private trackers track ratio by it.
+pub struct Identity {
+    policy: IdentityPolicy,
+    state_file: Option<PathBuf>,
+    session_id: PeerId,
+    // A persistent ID from the state file that we're not using right now,
+    // kept so saving doesn't lose it for when we switch back
+    saved_id: Option<PeerId>,
+    torrent_ids: HashMap<[u8; 20], PeerId>,
+    key: u32
+}
+
+impl Identity {
+    pub fn new(policy: IdentityPolicy, state_file: Option<PathBuf>) -> Result<Identity, String> {
+        if policy == IdentityPolicy::Persistent && state_file.is_none() {
+            return Err(String::from("A persistent identity needs a state file!"));
+        }
+
+        let saved = match state_file {
+            Some(ref path) => load_identity(path)?,
+            None => None
+        };
+
+        let (saved_id, saved_key) = match saved {
+            Some((id, key)) => (id, Some(key)),
+            None => (None, None)
+        };
+
+        let session_id = match (policy, saved_id) {
+            (IdentityPolicy::Persistent, Some(id)) => id,
+            _ => generate_id()
+        };
+
+        let identity = Identity {
+            policy,
+            state_file,
+            session_id,
+            saved_id,
+            torrent_ids: HashMap::new(),
+            key: saved_key.unwrap_or_else(|| thread_rng().gen::<u32>())
+        };
+
+        // Make sure whatever we just generated sticks around for next time
+        let new_id = policy == IdentityPolicy::Persistent && saved_id.is_none();
+        if identity.state_file.is_some() && (saved_key.is_none() || new_id) {
+            identity.save()?;
+        }
+
+        Ok(identity)
+    }
+
+    pub fn policy(&self) -> IdentityPolicy {
+        self.policy
+    }
+
+    pub fn key(&self) -> u32 {
+        self.key
+    }
+
+    // The peer ID we should hand to trackers and peers for this torrent
+    pub fn peer_id(&mut self, info_hash: &[u8; 20]) -> PeerId {
+        match self.policy {
+            IdentityPolicy::PerSession | IdentityPolicy::Persistent => self.session_id,
+            IdentityPolicy::PerTorrent => {
+                *self.torrent_ids.entry(*info_hash).or_insert_with(generate_id)
+            }
+        }
+    }
+
+    // Writes the key and the persistent peer ID, if we have one, to the state file
+    pub fn save(&self) -> Result<(), String> {
+        let path = match self.state_file {
+            Some(ref path) => path,
+            None => { return Ok(()); }
+        };
+
+        let mut d = BTreeMap::new();
+        d.insert(String::from("key"), Benc::I(self.key as i64));
+        let id = match self.policy {
+            IdentityPolicy::Persistent => Some(self.session_id),
+            _ => self.saved_id
+        };
+        if let Some(id) = id {
+            d.insert(String::from("peer id"), Benc::S(id.as_bytes().to_vec()));
+        }
+
+        write_state_file(path, &enc_benc(&Benc::D(d)))
+    }
+}
+
+// Write to a temporary file and rename it over the old one, so we never leave
+// a half-written state file behind
+pub fn write_state_file(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut f = match File::create(&tmp_path) {
+        Ok(f) => f,
+        Err(e) => { return Err(format!("Unable to create state file {}: {}", tmp_path.display(), e)); }
+    };
+    if let Err(e) = f.write_all(contents).and_then(|_| f.sync_all()) {
+        return Err(format!("Unable to write state file {}: {}", tmp_path.display(), e));
+    }
+
+    match fs::rename(&tmp_path, path) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Unable to move state file into place at {}: {}", path.display(), e))
+    }
+}
+
+// Reads the state file back in, or None if there's nothing there yet
+pub fn read_state_file(path: &Path) -> Result<Option<Vec<u8>>, String> {
+    let mut f = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => { return Ok(None); },
+        Err(e) => { return Err(format!("Unable to open state file {}: {}", path.display(), e)); }
+    };
+
+    let mut buffer = Vec::new();
+    match f.read_to_end(&mut buffer) {
+        Ok(_) => Ok(Some(buffer)),
+        Err(e) => Err(format!("Unable to read state file {}: {}", path.display(), e))
+    }
+}
+
+fn load_identity(path: &Path) -> Result<Option<(Option<PeerId>, u32)>, String> {
+    let buffer = match read_state_file(path)? {
+        Some(b) => b,
+        None => { return Ok(None); }
+    };
+
+    let d = match dec_benc(&buffer) {
+        Ok(Benc::D(d)) => d,
+        Ok(_) => { return Err(String::from("Identity state file is not a dictionary!")); },
+        Err(e) => { return Err(format!("Unable to decode identity state file: {}", e)); }
+    };
+
+    let key = match d.get("key") {
+        Some(&Benc::I(k)) if k >= 0 && k <= u32::MAX as i64 => k as u32,
+        _ => { return Err(String::from("Identity state file has a missing or invalid 'key'!")); }
+    };
+
+    let id = match d.get("peer id") {
+        Some(Benc::S(id)) => match PeerId::from_slice(id) {
+            Some(id) => Some(id),
+            None => { return Err(String::from("Identity state file has a 'peer id' which isn't 20 bytes!")); }
+        },
+        Some(_) => { return Err(String::from("Identity state file has a 'peer id' which isn't a string!")); },
+        None => None
+    };
+
+    Ok(Some((id, key)))
+}
+
 #[cfg(test)]
 mod test {
-    use super::generate_id;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::net::IpAddr;
+
+    use dht::NodeId;
+    use super::{crc32c, generate_id, generate_node_id, id_prefix, node_id_matches_ip, Identity, IdentityPolicy, PeerId};
+
+    fn state_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("flakes-id-test-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn id_from(prefix: &str) -> PeerId {
+        let mut id = [b'x'; 20];
+        id[..prefix.len()].copy_from_slice(prefix.as_bytes());
+        PeerId(id)
+    }
 
     #[test]
     fn test_id() {
@@ -31,13 +560,8 @@ mod test {
         for _ in 0..64 {
             let id = generate_id();
 
-            // Verify length
-            assert_eq!(20, id.len());
-
             // Verify prefix
-            for (expected, received) in "-FK0010-".as_bytes().iter().zip(id.iter()) {
-                assert_eq!(expected, received);
-            }
+            assert_eq!(&id.as_bytes()[..8], &id_prefix());
 
             // Check that we didn't collide
             for prev in prev_ids.iter() {
@@ -46,4 +570,151 @@ mod test {
             prev_ids.push(id);
         }
     }
+
+    #[test]
+    fn prefix_from_crate_version() {
+        let prefix = id_prefix();
+        assert_eq!(&prefix[..3], "-FK".as_bytes());
+        assert_eq!(prefix[7], b'-');
+
+        // We should always recognize ourselves
+        let us = generate_id().client().unwrap();
+        assert_eq!(us.name, "flakes");
+        assert_eq!(us.version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn azureus_style() {
+        assert_eq!(id_from("-qB4620-").client().unwrap().to_string(), "qBittorrent 4.6.2");
+        assert_eq!(id_from("-TR2940-").client().unwrap().to_string(), "Transmission 2.94");
+        assert_eq!(id_from("-UT355W-").client().unwrap().to_string(), "µTorrent 3.5.5 W");
+        assert_eq!(id_from("-lt0D60-").client().unwrap().to_string(), "libTorrent 0.13.6");
+        assert_eq!(id_from("-AZ5750-").client().unwrap().to_string(), "Vuze 5.7.5");
+        assert_eq!(id_from("-DE2113-").client().unwrap().to_string(), "Deluge 2.1.1.3");
+
+        // Unknown client codes and broken framing shouldn't match
+        assert!(id_from("-ZZ1234-").client().is_none());
+        assert!(id_from("-qB4620x").client().is_none());
+        assert!(id_from("-qB4!20-").client().is_none());
+    }
+
+    #[test]
+    fn shadow_style() {
+        assert_eq!(id_from("S58B-----").client().unwrap().to_string(), "Shadow's client 5.8.11");
+        assert_eq!(id_from("T03I-----").client().unwrap().to_string(), "BitTornado 0.3.18");
+        assert_eq!(id_from("A310--001").client(), None);
+    }
+
+    #[test]
+    fn mainline_style() {
+        assert_eq!(id_from("M4-3-6--").client().unwrap().to_string(), "BitTorrent (mainline) 4.3.6");
+        assert_eq!(id_from("M7-10-2--").client().unwrap().to_string(), "BitTorrent (mainline) 7.10.2");
+        assert!(id_from("M4-3--").client().is_none());
+    }
+
+    #[test]
+    fn display_unknown() {
+        let mut raw = [0u8; 20];
+        raw[..4].copy_from_slice("abcd".as_bytes());
+        let s = PeerId(raw).to_string();
+        assert!(s.starts_with("abcd\\x00"));
+    }
+
+    #[test]
+    fn per_session_and_per_torrent() {
+        let mut session = Identity::new(IdentityPolicy::PerSession, None).unwrap();
+        assert_eq!(session.peer_id(&[1; 20]), session.peer_id(&[2; 20]));
+
+        let mut per_torrent = Identity::new(IdentityPolicy::PerTorrent, None).unwrap();
+        let first = per_torrent.peer_id(&[1; 20]);
+        assert!(first != per_torrent.peer_id(&[2; 20]));
+        assert_eq!(first, per_torrent.peer_id(&[1; 20]));
+
+        assert!(Identity::new(IdentityPolicy::Persistent, None).is_err());
+    }
+
+    #[test]
+    fn persistent_identity() {
+        let path = state_path("persistent");
+
+        let mut first = Identity::new(IdentityPolicy::Persistent, Some(path.clone())).unwrap();
+        let mut second = Identity::new(IdentityPolicy::Persistent, Some(path.clone())).unwrap();
+        assert_eq!(first.peer_id(&[0; 20]), second.peer_id(&[0; 20]));
+        assert_eq!(first.key(), second.key());
+
+        // The key sticks around even if we stop persisting the ID
+        let mut third = Identity::new(IdentityPolicy::PerSession, Some(path.clone())).unwrap();
+        assert_eq!(first.key(), third.key());
+        assert!(first.peer_id(&[0; 20]) != third.peer_id(&[0; 20]));
+
+        // And saving without it doesn't throw the persistent ID away
+        third.save().unwrap();
+        let mut fourth = Identity::new(IdentityPolicy::Persistent, Some(path.clone())).unwrap();
+        assert_eq!(first.peer_id(&[0; 20]), fourth.peer_id(&[0; 20]));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn leaves_state_file_alone() {
+        // Nothing new to save, so the file (and the key we don't know about)
+        // shouldn't be touched
+        let path = state_path("untouched");
+        fs::write(&path, "d5:extrai1e3:keyi5ee").unwrap();
+        for policy in [IdentityPolicy::PerSession, IdentityPolicy::PerTorrent].iter() {
+            let identity = Identity::new(*policy, Some(path.clone())).unwrap();
+            assert_eq!(identity.key(), 5);
+            assert_eq!(fs::read(&path).unwrap(), b"d5:extrai1e3:keyi5ee".to_vec());
+        }
+
+        // Going persistent does mean a new ID to keep
+        Identity::new(IdentityPolicy::Persistent, Some(path.clone())).unwrap();
+        assert!(fs::read(&path).unwrap() != b"d5:extrai1e3:keyi5ee".to_vec());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bad_state_file() {
+        let path = state_path("bad");
+        fs::write(&path, "d3:keyi-1ee").unwrap();
+        assert!(Identity::new(IdentityPolicy::PerSession, Some(path.clone())).is_err());
+        fs::write(&path, "d3:keyi5e7:peer id3:abce").unwrap();
+        assert!(Identity::new(IdentityPolicy::Persistent, Some(path.clone())).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn secure_node_ids() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+
+        // The examples from BEP 42, which only pin down the first 21 bits
+        // and the last byte
+        let examples: [(&str, [u8; 3], u8); 5] = [
+            ("124.31.75.21", [0x5f, 0xbf, 0xbf], 0x01),
+            ("21.75.31.124", [0x5a, 0x3c, 0xe9], 0x56),
+            ("65.23.51.170", [0xa5, 0xd4, 0x32], 0x16),
+            ("84.124.73.14", [0x1b, 0x03, 0x21], 0x41),
+            ("43.213.53.83", [0xe5, 0x6f, 0x6c], 0x5a)
+        ];
+        for &(ip, prefix, rand) in examples.iter() {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut id = NodeId([0; 20]);
+            id.0[..3].copy_from_slice(&prefix);
+            id.0[19] = rand;
+            assert!(node_id_matches_ip(&id, ip));
+            id.0[2] ^= 0x08;
+            assert!(!node_id_matches_ip(&id, ip));
+        }
+
+        for ip in ["84.124.73.14", "2001:db8::1"].iter() {
+            let ip: IpAddr = ip.parse().unwrap();
+            let id = generate_node_id(ip);
+            assert!(node_id_matches_ip(&id, ip));
+            assert!(!node_id_matches_ip(&id, "43.213.53.83".parse().unwrap()));
+        }
+
+        // Local addresses can't know their external IP, so anything goes
+        assert!(node_id_matches_ip(&NodeId([0; 20]), "192.168.1.5".parse().unwrap()));
+        assert!(node_id_matches_ip(&NodeId([0; 20]), "::1".parse().unwrap()));
+    }
 }
@@ -0,0 +1,11 @@
+extern crate crypto;
+extern crate rand;
+
+pub mod bencode;
+pub mod torrent;
+pub mod id;
+pub mod tracker;
+pub mod peer;
+pub mod dht;
+pub mod lsd;
+pub mod utp;
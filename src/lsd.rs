@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, thread_rng};
+
+use peer::pool::{PeerPool, PeerSource};
+use torrent::TorrentMetadata;
+
+// Local Service Discovery (BEP 14): HTTP-ish announces multicast to the LAN
+pub const LSD_PORT: u16 = 6771;
+pub const LSD_GROUP4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_GROUP6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+// Each torrent gets announced this often
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Never more than one packet a second from us, however much is due. Up to
+// this many info hashes share a packet.
+pub const MIN_SEND_GAP: Duration = Duration::from_secs(1);
+pub const MAX_HASHES_PER_MESSAGE: usize = 20;
+// Anyone sending more than this in a minute gets ignored for the rest of it
+pub const MAX_RECEIVED_PER_MINUTE: u32 = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    // The port the announcer takes BitTorrent connections on
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    // Lets us recognise our own announces when the group loops them back
+    pub cookie: Option<String>
+}
+
+impl LsdAnnounce {
+    // host goes in the Host header, and is the group it's sent to
+    pub fn encode(&self, host: &str) -> Vec<u8> {
+        let mut out = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n", host, self.port);
+        for h in self.info_hashes.iter() {
+            out.push_str("Infohash: ");
+            for b in h.iter() {
+                out.push_str(&format!("{:02x}", b));
+            }
+            out.push_str("\r\n");
+        }
+        if let Some(ref cookie) = self.cookie {
+            out.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        out.push_str("\r\n\r\n");
+        out.into_bytes()
+    }
+
+    // Header names are case insensitive, and any we don't know are skipped
+    pub fn decode(packet: &[u8]) -> Result<LsdAnnounce, String> {
+        let text = str::from_utf8(packet).map_err(|_| String::from("LSD announce isn't UTF-8"))?;
+        let mut lines = text.split("\r\n");
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return Err(String::from("Not a BT-SEARCH announce"));
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let colon = line.find(':').ok_or_else(|| format!("Bad LSD header line: {}", line))?;
+            let value = line[colon + 1..].trim();
+            match line[..colon].trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse::<u16>().ok().filter(|p| *p != 0),
+                "infohash" => info_hashes.push(parse_info_hash(value).ok_or_else(|| format!("Bad LSD info hash: {}", value))?),
+                "cookie" => cookie = Some(String::from(value)),
+                _ => ()
+            }
+        }
+
+        let port = port.ok_or_else(|| String::from("LSD announce has a missing or bad port"))?;
+        if info_hashes.is_empty() {
+            return Err(String::from("LSD announce has no info hashes"));
+        }
+        Ok(LsdAnnounce { port, info_hashes, cookie })
+    }
+}
+
+fn parse_info_hash(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut out = [0u8; 20];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+// Someone on the LAN with a torrent we've got
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LsdPeer {
+    pub info_hash: [u8; 20],
+    pub addr: SocketAddr
+}
+
+pub struct Lsd {
+    // Each socket and the group it announces to
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    port: u16,
+    cookie: String,
+    // When each torrent is due to be announced next
+    torrents: HashMap<[u8; 20], Instant>,
+    last_sent: Option<Instant>,
+    // Packets per sender IP since the start of its current minute
+    received: HashMap<IpAddr, (Instant, u32)>
+}
+
+impl Lsd {
+    // Joins the IPv4 group, and the IPv6 one too if this machine can. port
+    // is the one we take BitTorrent connections on.
+    pub fn bind(port: u16) -> io::Result<Lsd> {
+        let v4 = bind_or_any(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), LSD_PORT))?;
+        v4.join_multicast_v4(&LSD_GROUP4, &Ipv4Addr::UNSPECIFIED)?;
+        let mut sockets = vec!((v4, SocketAddr::new(IpAddr::V4(LSD_GROUP4), LSD_PORT)));
+
+        // Plenty of LANs have no IPv6, which isn't worth failing over
+        if let Ok(v6) = bind_or_any(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), LSD_PORT)) {
+            if v6.join_multicast_v6(&LSD_GROUP6, 0).is_ok() {
+                sockets.push((v6, SocketAddr::new(IpAddr::V6(LSD_GROUP6), LSD_PORT)));
+            }
+        }
+        Lsd::with_sockets(sockets, port)
+    }
+
+    // For sockets that are already set up, each paired with where its
+    // announces should go
+    pub fn with_sockets(sockets: Vec<(UdpSocket, SocketAddr)>, port: u16) -> io::Result<Lsd> {
+        for (socket, _) in sockets.iter() {
+            socket.set_nonblocking(true)?;
+        }
+        Ok(Lsd {
+            sockets,
+            port,
+            cookie: format!("{:08x}", thread_rng().gen::<u32>()),
+            torrents: HashMap::new(),
+            last_sent: None,
+            received: HashMap::new()
+        })
+    }
+
+    // False for private torrents, which LSD has to stay out of
+    pub fn add_torrent(&mut self, torrent: &TorrentMetadata, now: Instant) -> bool {
+        if !torrent.allows_peer_discovery() {
+            return false;
+        }
+        self.add(torrent.info_hash, now);
+        true
+    }
+
+    // For magnet links, before we know whether the torrent is private.
+    // Remove it if it turns out it is.
+    pub fn add(&mut self, info_hash: [u8; 20], now: Instant) {
+        self.torrents.entry(info_hash).or_insert(now);
+    }
+
+    pub fn remove(&mut self, info_hash: &[u8; 20]) {
+        self.torrents.remove(info_hash);
+    }
+
+    // Sends whatever announces are due and hands back the peers we've heard
+    // about for our torrents
+    pub fn poll(&mut self, now: Instant) -> Vec<LsdPeer> {
+        self.send_due(now);
+
+        let mut out = Vec::new();
+        let mut buf = [0u8; 1500];
+        for i in 0..self.sockets.len() {
+            loop {
+                match self.sockets[i].0.recv_from(&mut buf) {
+                    Ok((n, from)) => out.extend(self.handle_packet(&buf[..n], from, now)),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break
+                }
+            }
+        }
+
+        self.received.retain(|_, &mut (start, _)| now < start + Duration::from_secs(60));
+        out
+    }
+
+    // poll(), with the peers going straight into the pool for their torrent.
+    // Hands back how many of them the pools hadn't seen before.
+    pub fn poll_into(&mut self, pools: &mut HashMap<[u8; 20], PeerPool>, now: Instant) -> usize {
+        let mut added = 0;
+        for p in self.poll(now).into_iter() {
+            if let Some(pool) = pools.get_mut(&p.info_hash) {
+                if pool.add(p.addr, PeerSource::Lsd, now) {
+                    added += 1;
+                }
+            }
+        }
+        added
+    }
+
+    pub fn handle_packet(&mut self, packet: &[u8], from: SocketAddr, now: Instant) -> Vec<LsdPeer> {
+        let announce = match LsdAnnounce::decode(packet) {
+            Ok(a) => a,
+            Err(_) => { return Vec::new(); }
+        };
+        // Our own, looped back. These don't count against our IP, or other
+        // clients on this machine would get cut off too.
+        if announce.cookie.as_ref() == Some(&self.cookie) {
+            return Vec::new();
+        }
+
+        let window = self.received.entry(from.ip()).or_insert((now, 0));
+        if now >= window.0 + Duration::from_secs(60) {
+            *window = (now, 0);
+        }
+        window.1 += 1;
+        if window.1 > MAX_RECEIVED_PER_MINUTE {
+            return Vec::new();
+        }
+
+        let addr = SocketAddr::new(from.ip(), announce.port);
+        announce.info_hashes.into_iter()
+            .filter(|h| self.torrents.contains_key(h))
+            .map(|info_hash| LsdPeer { info_hash, addr })
+            .collect()
+    }
+
+    fn send_due(&mut self, now: Instant) {
+        if self.last_sent.is_some_and(|at| now < at + MIN_SEND_GAP) {
+            return;
+        }
+
+        let mut due: Vec<([u8; 20], Instant)> = self.torrents.iter()
+            .filter(|&(_, &next)| now >= next)
+            .map(|(h, next)| (*h, *next))
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+        // Whoever's waited longest goes first
+        due.sort_by_key(|&(_, next)| next);
+        due.truncate(MAX_HASHES_PER_MESSAGE);
+
+        let announce = LsdAnnounce {
+            port: self.port,
+            info_hashes: due.iter().map(|&(h, _)| h).collect(),
+            cookie: Some(self.cookie.clone())
+        };
+        for &(ref socket, dest) in self.sockets.iter() {
+            // Best effort, like everything else on UDP
+            let _ = socket.send_to(&announce.encode(&dest.to_string()), dest);
+        }
+
+        for (h, _) in due.into_iter() {
+            self.torrents.insert(h, now + ANNOUNCE_INTERVAL);
+        }
+        self.last_sent = Some(now);
+    }
+}
+
+// std can't set SO_REUSEADDR, so if another client on this machine already
+// has the port we fall back to any port. We can still announce, we just
+// won't hear anyone else's.
+fn bind_or_any(addr: SocketAddr) -> io::Result<UdpSocket> {
+    UdpSocket::bind(addr).or_else(|_| UdpSocket::bind(SocketAddr::new(addr.ip(), 0)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use peer::pool::{PeerPool, PeerSource};
+    use torrent::test_torrent;
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let announce = LsdAnnounce { port: 6881, info_hashes: vec!([0xab; 20], [0x01; 20]), cookie: Some(String::from("c00k1e")) };
+        let packet = announce.encode("239.192.152.143:6771");
+        assert!(packet.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: abababab"));
+        assert!(packet.ends_with(b"cookie: c00k1e\r\n\r\n\r\n"));
+        assert_eq!(LsdAnnounce::decode(&packet).unwrap(), announce);
+
+        // Other clients capitalise however they like
+        let theirs = b"BT-SEARCH * HTTP/1.1\r\nhost: [ff15::efc0:988f]:6771\r\nPORT: 51413\r\ninfohash: ABABABABABABABABABABABABABABABABABABABAB\r\n\r\n\r\n";
+        assert_eq!(LsdAnnounce::decode(theirs).unwrap(), LsdAnnounce { port: 51413, info_hashes: vec!([0xab; 20]), cookie: None });
+
+        assert!(LsdAnnounce::decode(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+        assert!(LsdAnnounce::decode(b"BT-SEARCH * HTTP/1.1\r\nInfohash: abab\r\nPort: 1\r\n\r\n").is_err());
+        assert!(LsdAnnounce::decode(b"BT-SEARCH * HTTP/1.1\r\nPort: 0\r\nInfohash: ABABABABABABABABABABABABABABABABABABABAB\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn announces_over_udp() {
+        let now = Instant::now();
+        let a_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let a_addr = a_socket.local_addr().unwrap();
+        let b_addr = b_socket.local_addr().unwrap();
+        // a also hears itself, like a multicast group looping back
+        let a_loop = a_socket.try_clone().unwrap();
+        let mut a = Lsd::with_sockets(vec!((a_socket, b_addr), (a_loop, a_addr)), 6881).unwrap();
+        let mut b = Lsd::with_sockets(vec!((b_socket, a_addr)), 6882).unwrap();
+
+        let public = test_torrent(false);
+        let private = test_torrent(true);
+        assert!(!a.add_torrent(&private, now));
+        assert!(a.add_torrent(&public, now));
+        assert!(b.add_torrent(&public, now));
+
+        // b's peers land in the pool for the torrent they have
+        let mut pools = HashMap::new();
+        pools.insert(public.info_hash, PeerPool::new(50));
+        pools.insert([9; 20], PeerPool::new(50));
+        assert!(a.poll(now).is_empty());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(b.poll_into(&mut pools, now), 1);
+        let pool = &pools[&public.info_hash];
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.get(&"127.0.0.1:6881".parse().unwrap()).unwrap().source, PeerSource::Lsd);
+        assert!(pools[&[9; 20]].is_empty());
+
+        // And b's announce went the other way. a's own came back too, but
+        // the cookie gives it away.
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(a.poll(now), vec!(LsdPeer { info_hash: public.info_hash, addr: "127.0.0.1:6882".parse().unwrap() }));
+
+        // Nothing's due again for a while
+        let later = now + Duration::from_secs(10);
+        a.poll(later);
+        thread::sleep(Duration::from_millis(20));
+        assert!(b.poll(later).is_empty());
+    }
+
+    #[test]
+    fn rate_limits_senders() {
+        let now = Instant::now();
+        let mut lsd = Lsd::with_sockets(Vec::new(), 6881).unwrap();
+        lsd.add([1; 20], now);
+        let packet = LsdAnnounce { port: 1, info_hashes: vec!([1; 20], [2; 20]), cookie: None }.encode("x");
+        let from: SocketAddr = "192.168.1.2:6771".parse().unwrap();
+
+        for _ in 0..MAX_RECEIVED_PER_MINUTE {
+            assert_eq!(lsd.handle_packet(&packet, from, now).len(), 1);
+        }
+        assert!(lsd.handle_packet(&packet, from, now).is_empty());
+        assert_eq!(lsd.handle_packet(&packet, "192.168.1.3:6771".parse().unwrap(), now).len(), 1);
+        assert_eq!(lsd.handle_packet(&packet, from, now + Duration::from_secs(60)).len(), 1);
+    }
+}
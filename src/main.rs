@@ -1,15 +1,11 @@
-extern crate crypto;
-extern crate rand;
-mod bencode;
-mod torrent;
-mod id;
+extern crate flakes;
 
 use std::fs::File;
 use std::io::prelude::*;
 use std::process::exit;
 
-use bencode::*;
-use torrent::*;
+use flakes::bencode::*;
+use flakes::torrent::*;
 
 fn print_benc(b: &Benc, pre: &String) -> () {
     match b {
@@ -129,6 +125,10 @@ fn print_torrent_metadata(tm: &TorrentMetadata) -> () {
         },
         None => ()
     }
+
+    if tm.private {
+        println!("Private torrent");
+    }
 }
 
 fn main() {
@@ -0,0 +1,102 @@
+// Which pieces somebody has, packed high bit first the way the wire wants it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Bitfield {
+        Bitfield { bits: vec![0; len.div_ceil(8)], len }
+    }
+
+    pub fn full(len: usize) -> Bitfield {
+        let mut b = Bitfield::new(len);
+        for i in 0..len {
+            b.set(i);
+        }
+        b
+    }
+
+    // Checks the length and that none of the spare bits on the end are set
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Bitfield, String> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(format!("Bitfield for {} pieces should be {} bytes, got {}", len, len.div_ceil(8), bytes.len()));
+        }
+        if !len.is_multiple_of(8) {
+            let spare = 0xffu8 >> (len % 8);
+            if bytes[bytes.len() - 1] & spare != 0 {
+                return Err(String::from("Bitfield has spare bits set"));
+            }
+        }
+        Ok(Bitfield { bits: bytes.to_vec(), len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bits[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        if index < self.len {
+            self.bits[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn iter_set(&self) -> impl Iterator<Item=usize> + '_ {
+        (0..self.len).filter(move |i| self.has(*i))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Bitfield;
+
+    #[test]
+    fn bits() {
+        let mut b = Bitfield::new(10);
+        assert_eq!(b.as_bytes().len(), 2);
+        b.set(0);
+        b.set(9);
+        b.set(10);
+        assert_eq!(b.as_bytes(), &[0x80, 0x40]);
+        assert_eq!(b.count(), 2);
+        assert_eq!(b.iter_set().collect::<Vec<usize>>(), vec!(0, 9));
+        b.clear(0);
+        assert!(!b.has(0));
+        assert!(Bitfield::full(10).is_complete());
+    }
+
+    #[test]
+    fn from_bytes() {
+        assert!(Bitfield::from_bytes(&[0xff, 0xc0], 10).is_ok());
+        assert!(Bitfield::from_bytes(&[0xff, 0xe0], 10).is_err());
+        assert!(Bitfield::from_bytes(&[0xff], 10).is_err());
+        assert!(Bitfield::from_bytes(&[0xff], 8).unwrap().is_complete());
+    }
+}
@@ -0,0 +1,309 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, thread_rng};
+
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_UNCHOKE_SLOTS: usize = 3;
+// Peers we've had for less than this are three times as likely to get the
+// optimistic unchoke, since they've got nothing to trade yet
+pub const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+const NEW_PEER_WEIGHT: usize = 3;
+
+// What the choker needs to know about each connected peer
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChokePeer {
+    pub addr: SocketAddr,
+    // They're interested in us
+    pub interested: bool,
+    // Bytes a second, them to us and us to them
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub connected_at: Instant,
+    // Whether we're unchoking them right now
+    pub unchoked: bool
+}
+
+// Who gets the regular unchoke slots. Only interested peers get offered,
+// and it hands back at most `slots` of them.
+pub trait ChokeAlgorithm {
+    fn unchoke(&mut self, peers: &[&ChokePeer], seeding: bool, slots: usize) -> Vec<SocketAddr>;
+}
+
+fn fastest(peers: &[&ChokePeer], slots: usize, rate: fn(&ChokePeer) -> f64) -> Vec<SocketAddr> {
+    let mut sorted = peers.to_vec();
+    sorted.sort_by(|a, b| rate(b).partial_cmp(&rate(a)).unwrap_or(Ordering::Equal));
+    sorted.iter().take(slots).map(|p| p.addr).collect()
+}
+
+// The usual: unchoke whoever's been giving us the most. Once we're seeding
+// there's nothing to get back, so it goes to whoever takes it fastest.
+pub struct TitForTat;
+
+impl ChokeAlgorithm for TitForTat {
+    fn unchoke(&mut self, peers: &[&ChokePeer], seeding: bool, slots: usize) -> Vec<SocketAddr> {
+        if seeding {
+            fastest(peers, slots, |p| p.upload_rate)
+        } else {
+            fastest(peers, slots, |p| p.download_rate)
+        }
+    }
+}
+
+// Whoever takes it fastest, seeding or not
+pub struct FastestUpload;
+
+impl ChokeAlgorithm for FastestUpload {
+    fn unchoke(&mut self, peers: &[&ChokePeer], _seeding: bool, slots: usize) -> Vec<SocketAddr> {
+        fastest(peers, slots, |p| p.upload_rate)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChokeDecision {
+    pub unchoke: Vec<SocketAddr>,
+    pub choke: Vec<SocketAddr>
+}
+
+// Decides who we upload to. Every RECHOKE_INTERVAL the algorithm fills the
+// regular slots, and on top of those one optimistic unchoke goes to a
+// random interested peer, moved on every OPTIMISTIC_INTERVAL. That's how
+// new peers get a chance to show what they can do.
+pub struct Choker {
+    algorithm: Box<dyn ChokeAlgorithm>,
+    slots: usize,
+    last_rechoke: Option<Instant>,
+    last_optimistic: Option<Instant>,
+    optimistic: Option<SocketAddr>
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Choker {
+        Choker::with_algorithm(slots, Box::new(TitForTat))
+    }
+
+    pub fn with_algorithm(slots: usize, algorithm: Box<dyn ChokeAlgorithm>) -> Choker {
+        Choker { algorithm, slots, last_rechoke: None, last_optimistic: None, optimistic: None }
+    }
+
+    pub fn set_algorithm(&mut self, algorithm: Box<dyn ChokeAlgorithm>) {
+        self.algorithm = algorithm;
+        self.last_rechoke = None;
+    }
+
+    // Takes effect at the next rechoke
+    pub fn set_slots(&mut self, slots: usize) {
+        self.slots = slots;
+    }
+
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    // Something to do when a rechoke is due, and nothing in between
+    pub fn poll(&mut self, peers: &[ChokePeer], seeding: bool, now: Instant) -> Option<ChokeDecision> {
+        if self.last_rechoke.is_some_and(|t| now.duration_since(t) < RECHOKE_INTERVAL) {
+            return None;
+        }
+        Some(self.rechoke(peers, seeding, now))
+    }
+
+    // Rechokes right away, say when an unchoked peer goes away and its slot
+    // shouldn't sit empty until the next one
+    pub fn rechoke(&mut self, peers: &[ChokePeer], seeding: bool, now: Instant) -> ChokeDecision {
+        self.last_rechoke = Some(now);
+        let interested: Vec<&ChokePeer> = peers.iter().filter(|p| p.interested).collect();
+        let mut unchoke: HashSet<SocketAddr> = self.algorithm.unchoke(&interested, seeding, self.slots).into_iter()
+            .filter(|a| interested.iter().any(|p| p.addr == *a))
+            .take(self.slots)
+            .collect();
+
+        // The optimistic slot moves on when it's due, or when its peer's gone,
+        // lost interest, or earned a regular slot
+        let rotate = self.last_optimistic.is_none_or(|t| now.duration_since(t) >= OPTIMISTIC_INTERVAL);
+        let stale = self.optimistic.is_none_or(|a| unchoke.contains(&a) || !interested.iter().any(|p| p.addr == a));
+        if rotate || stale {
+            let candidates: Vec<&ChokePeer> = interested.iter().cloned().filter(|p| !unchoke.contains(&p.addr)).collect();
+            self.optimistic = pick_optimistic(&candidates, now);
+            self.last_optimistic = Some(now);
+        }
+        unchoke.extend(self.optimistic);
+
+        ChokeDecision {
+            unchoke: peers.iter().filter(|p| !p.unchoked && unchoke.contains(&p.addr)).map(|p| p.addr).collect(),
+            choke: peers.iter().filter(|p| p.unchoked && !unchoke.contains(&p.addr)).map(|p| p.addr).collect()
+        }
+    }
+}
+
+fn pick_optimistic(candidates: &[&ChokePeer], now: Instant) -> Option<SocketAddr> {
+    let weight = |p: &ChokePeer| if now.duration_since(p.connected_at) < NEW_PEER_AGE { NEW_PEER_WEIGHT } else { 1 };
+    let total: usize = candidates.iter().map(|p| weight(p)).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut n = thread_rng().gen_range(0, total);
+    for p in candidates.iter() {
+        if n < weight(p) {
+            return Some(p.addr);
+        }
+        n -= weight(p);
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    // Peer n downloads to us at n KB/s, and we upload to it at 10 - n
+    fn swarm(count: u8, connected_at: Instant) -> Vec<ChokePeer> {
+        (1..=count).map(|n| ChokePeer {
+            addr: addr(n),
+            interested: true,
+            download_rate: f64::from(n) * 1000.0,
+            upload_rate: f64::from(10 - n) * 1000.0,
+            connected_at,
+            unchoked: false
+        }).collect()
+    }
+
+    fn apply(peers: &mut [ChokePeer], decision: &ChokeDecision) {
+        for p in peers.iter_mut() {
+            if decision.unchoke.contains(&p.addr) {
+                p.unchoked = true;
+            }
+            if decision.choke.contains(&p.addr) {
+                p.unchoked = false;
+            }
+        }
+    }
+
+    fn unchoked(peers: &[ChokePeer]) -> Vec<SocketAddr> {
+        peers.iter().filter(|p| p.unchoked).map(|p| p.addr).collect()
+    }
+
+    #[test]
+    fn tit_for_tat() {
+        // Everyone's been around long enough not to count as new
+        let connected = Instant::now();
+        let now = connected + NEW_PEER_AGE;
+        let mut peers = swarm(8, connected);
+        peers[7].interested = false;
+        let mut choker = Choker::new(3);
+
+        // The fastest three that want anything from us, plus one more
+        let d = choker.rechoke(&peers, false, now);
+        apply(&mut peers, &d);
+        let on = unchoked(&peers);
+        assert_eq!(on.len(), 4);
+        for n in 5..8 {
+            assert!(on.contains(&addr(n)));
+        }
+        let optimistic = choker.optimistic().unwrap();
+        assert!(!on.contains(&addr(8)) && [1, 2, 3, 4].iter().any(|&n| optimistic == addr(n)));
+
+        // Seeding goes by how fast they take it instead
+        let d = choker.rechoke(&peers, true, now);
+        apply(&mut peers, &d);
+        let on = unchoked(&peers);
+        assert_eq!(on.len(), 4);
+        for n in 1..4 {
+            assert!(on.contains(&addr(n)));
+        }
+        assert!(d.choke.iter().all(|a| !on.contains(a)));
+    }
+
+    #[test]
+    fn timing_and_optimistic() {
+        let connected = Instant::now();
+        let start = connected + NEW_PEER_AGE;
+        let mut peers = swarm(6, connected);
+        let mut choker = Choker::new(2);
+        let d = choker.poll(&peers, false, start).unwrap();
+        apply(&mut peers, &d);
+        let first = choker.optimistic().unwrap();
+
+        // Nothing until ten seconds are up, and the optimistic unchoke stays
+        // put for thirty
+        assert!(choker.poll(&peers, false, start + Duration::from_secs(9)).is_none());
+        for i in 1..3 {
+            let d = choker.poll(&peers, false, start + RECHOKE_INTERVAL * i).unwrap();
+            assert_eq!(d, ChokeDecision::default());
+            assert_eq!(choker.optimistic(), Some(first));
+        }
+
+        // If it earns a regular slot, someone else gets the optimistic one
+        for p in peers.iter_mut() {
+            if p.addr == first {
+                p.download_rate = 1e9;
+            }
+        }
+        let d = choker.rechoke(&peers, false, start + Duration::from_secs(25));
+        apply(&mut peers, &d);
+        assert!(choker.optimistic().is_some() && choker.optimistic() != Some(first));
+        assert!(unchoked(&peers).contains(&first));
+        assert_eq!(unchoked(&peers).len(), 3);
+
+        // Peer 3 has the regular slot, and new peer 2 should get the
+        // optimistic one three times as often as old peer 1
+        let mut three = swarm(3, connected);
+        three[1].connected_at = connected + Duration::from_secs(55);
+        let mut new_wins = 0;
+        for _ in 0..1000 {
+            let mut choker = Choker::new(1);
+            choker.rechoke(&three, false, start);
+            assert!(choker.optimistic() != Some(addr(3)));
+            if choker.optimistic() == Some(addr(2)) {
+                new_wins += 1;
+            }
+        }
+        assert!(new_wins > 650 && new_wins < 850, "{}", new_wins);
+    }
+
+    // Something that isn't tit-for-tat: unchoke whoever has the lowest address
+    struct Lowest;
+
+    impl ChokeAlgorithm for Lowest {
+        fn unchoke(&mut self, peers: &[&ChokePeer], _seeding: bool, slots: usize) -> Vec<SocketAddr> {
+            let mut addrs: Vec<SocketAddr> = peers.iter().map(|p| p.addr).collect();
+            addrs.sort();
+            addrs.truncate(slots);
+            addrs
+        }
+    }
+
+    #[test]
+    fn pluggable() {
+        let connected = Instant::now();
+        let now = connected + NEW_PEER_AGE;
+        let mut peers = swarm(6, connected);
+        let mut choker = Choker::with_algorithm(2, Box::new(Lowest));
+        let d = choker.rechoke(&peers, false, now);
+        apply(&mut peers, &d);
+        assert!(unchoked(&peers).contains(&addr(1)) && unchoked(&peers).contains(&addr(2)));
+
+        // Fastest upload ignores what they're giving us
+        choker.set_algorithm(Box::new(FastestUpload));
+        let d = choker.poll(&peers, false, now).unwrap();
+        apply(&mut peers, &d);
+        assert!(unchoked(&peers).contains(&addr(1)) && unchoked(&peers).contains(&addr(2)));
+        choker.set_slots(1);
+        peers[0].interested = false;
+        let d = choker.rechoke(&peers, false, now);
+        assert!(d.choke.contains(&addr(1)));
+        apply(&mut peers, &d);
+        assert!(unchoked(&peers).contains(&addr(2)));
+        assert_eq!(unchoked(&peers).len(), 2);
+    }
+}
@@ -0,0 +1,1074 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+
+use peer::bitfield::Bitfield;
+use peer::extension::*;
+use peer::wire::*;
+
+// Nobody should be asking for more than a 16 KiB block at a time
+pub const MAX_REQUEST_LEN: u32 = 16 * 1024;
+// How many requests we'll queue up from a peer before we call it abuse
+pub const MAX_PEER_REQUESTS: usize = 250;
+// Haves we'll hang onto before we know how many pieces there are; a peer
+// sending more than this while we fetch the info dict is up to no good, and
+// the rest get dropped
+const MAX_EARLY_HAVES: usize = 1 << 16;
+// Same for allowed fast pieces, where sets are usually 10 or so
+const MAX_EARLY_ALLOWED_FAST: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32
+}
+
+// Things the session needs to know about, in the order they happened
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Handshake(Handshake),
+    // Whatever we'd asked for is gone, and will need asking for again
+    Choked { dropped: Vec<BlockRequest> },
+    Unchoked,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield,
+    // The peer wants a block from us
+    Request(BlockRequest),
+    Cancel(BlockRequest),
+    // A block we asked for showed up
+    Block { index: u32, begin: u32, data: Vec<u8> },
+    Port(u16),
+    // Fast extension: a piece they'd like us to grab, a request of ours they
+    // won't serve, and a piece we can ask for even while choked
+    Suggest(u32),
+    Rejected(BlockRequest),
+    AllowedFast(u32),
+    // Their extended handshake (BEP 10), which can come more than once
+    ExtendedHandshake(ExtendedHandshake),
+    // An extension message, with the name we registered it under
+    Extended { name: String, payload: Vec<u8> }
+}
+
+#[derive(Debug)]
+pub enum PeerError {
+    Io(io::Error),
+    Wire(WireError),
+    // The other end hung up
+    Closed,
+    InfoHashMismatch,
+    // Bitfield came after some other message, or twice
+    LateBitfield,
+    BadBitfield(String),
+    BadIndex(u32),
+    OversizedRequest(u32),
+    TooManyRequests,
+    // Something we tried to do that the peer won't let us
+    Choked,
+    DontHave(u32),
+    NotConnected,
+    // Fast extension messages without both ends setting the reserved bit
+    FastNotNegotiated,
+    // Extended messages without both ends setting the reserved bit
+    ExtensionsNotNegotiated,
+    BadExtendedHandshake(String),
+    // The peer hasn't told us an ID for this extension
+    ExtensionUnsupported(String)
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PeerError::Io(ref e) => write!(f, "I/O error: {}", e),
+            PeerError::Wire(ref e) => write!(f, "Wire error: {}", e),
+            PeerError::Closed => write!(f, "Connection closed"),
+            PeerError::InfoHashMismatch => write!(f, "Peer handshook for a different torrent"),
+            PeerError::LateBitfield => write!(f, "Peer sent a bitfield after other messages"),
+            PeerError::BadBitfield(ref s) => write!(f, "Bad bitfield: {}", s),
+            PeerError::BadIndex(i) => write!(f, "Piece index {} is out of range", i),
+            PeerError::OversizedRequest(len) => write!(f, "Request for {} bytes is too big", len),
+            PeerError::TooManyRequests => write!(f, "Peer has too many requests queued"),
+            PeerError::Choked => write!(f, "Peer is choking us"),
+            PeerError::DontHave(i) => write!(f, "Peer doesn't have piece {}", i),
+            PeerError::NotConnected => write!(f, "Handshake isn't done yet"),
+            PeerError::FastNotNegotiated => write!(f, "Fast extension wasn't negotiated"),
+            PeerError::ExtensionsNotNegotiated => write!(f, "Extension protocol wasn't negotiated"),
+            PeerError::BadExtendedHandshake(ref s) => write!(f, "Bad extended handshake: {}", s),
+            PeerError::ExtensionUnsupported(ref name) => write!(f, "Peer doesn't support extension {}", name)
+        }
+    }
+}
+
+impl Error for PeerError {}
+
+impl From<io::Error> for PeerError {
+    fn from(e: io::Error) -> PeerError {
+        PeerError::Io(e)
+    }
+}
+
+impl From<WireError> for PeerError {
+    fn from(e: WireError) -> PeerError {
+        PeerError::Wire(e)
+    }
+}
+
+// One connection to one peer. Wrap it around a non-blocking stream, call
+// poll() whenever there might be something to read, and act on the events.
+// It keeps track of who's choking who and which blocks are in flight, and
+// hangs up on peers that break the rules.
+pub struct PeerConnection<S: Read + Write> {
+    stream: S,
+    decoder: Decoder,
+    outgoing: Vec<u8>,
+    info_hash: [u8; 20],
+    // None until we have the info dict, for magnet links
+    num_pieces: Option<usize>,
+    remote: Option<Handshake>,
+    // Has anything but the handshake come in yet? Bitfields have to be first.
+    got_message: bool,
+
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+
+    peer_pieces: Bitfield,
+    // Their bitfield (or have all/none) and haves from before we knew the
+    // piece count, waiting for set_num_pieces() to check them
+    early_bitfield: Option<Message>,
+    early_haves: BTreeSet<u32>,
+    // What we've asked them for, and what they've asked us for
+    our_requests: Vec<BlockRequest>,
+    peer_requests: Vec<BlockRequest>,
+
+    // Pieces they can ask for while we're choking them, and the other way around
+    allowed_fast_out: Vec<u32>,
+    allowed_fast_in: Vec<u32>,
+
+    our_reserved: [u8; 8],
+    extensions: ExtensionRegistry,
+    remote_extensions: Option<ExtendedHandshake>
+}
+
+impl<S: Read + Write> PeerConnection<S> {
+    // Queues our handshake straight away; the peer's shows up as an event
+    pub fn new(stream: S, handshake: Handshake, num_pieces: usize) -> PeerConnection<S> {
+        PeerConnection::with_extensions(stream, handshake, num_pieces, None)
+    }
+
+    // Passing a registry sets the extension protocol bit in our handshake,
+    // and lets the peer send us the extensions in it
+    pub fn with_extensions(stream: S, mut handshake: Handshake, num_pieces: usize,
+            extensions: Option<ExtensionRegistry>) -> PeerConnection<S> {
+        if extensions.is_some() {
+            handshake.set_reserved_bit(EXTENSION_BIT);
+        }
+
+        let mut conn = PeerConnection {
+            stream,
+            decoder: Decoder::new(),
+            outgoing: handshake.encode(),
+            info_hash: handshake.info_hash,
+            num_pieces: Some(num_pieces),
+            remote: None,
+            got_message: false,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            peer_pieces: Bitfield::new(num_pieces),
+            early_bitfield: None,
+            early_haves: BTreeSet::new(),
+            our_requests: Vec::new(),
+            peer_requests: Vec::new(),
+            allowed_fast_out: Vec::new(),
+            allowed_fast_in: Vec::new(),
+            our_reserved: handshake.reserved,
+            extensions: extensions.unwrap_or_default(),
+            remote_extensions: None
+        };
+        // Nothing's gone wrong yet if this would block, it'll go out on the next poll
+        let _ = conn.flush();
+        conn
+    }
+
+    // For magnet links, before we have the info dict: whatever the peer says
+    // it has is kept as is until set_num_pieces(), so ut_metadata can get
+    // going without the bitfield getting the connection killed
+    pub fn without_metadata(stream: S, handshake: Handshake, extensions: ExtensionRegistry) -> PeerConnection<S> {
+        let mut conn = PeerConnection::with_extensions(stream, handshake, 0, Some(extensions));
+        conn.num_pieces = None;
+        conn
+    }
+
+    // Once the info dict's in, checks everything the peer told us about its
+    // pieces so far. An error means they lied, and it's time to hang up.
+    pub fn set_num_pieces(&mut self, num_pieces: usize) -> Result<(), PeerError> {
+        if self.num_pieces.is_some() {
+            return Ok(());
+        }
+        self.num_pieces = Some(num_pieces);
+        self.peer_pieces = match self.early_bitfield.take() {
+            Some(Message::Bitfield(bits)) => Bitfield::from_bytes(&bits, num_pieces).map_err(PeerError::BadBitfield)?,
+            Some(Message::HaveAll) => Bitfield::full(num_pieces),
+            _ => Bitfield::new(num_pieces)
+        };
+        for index in mem::take(&mut self.early_haves) {
+            self.check_index(index)?;
+            self.peer_pieces.set(index as usize);
+        }
+        self.allowed_fast_in.retain(|i| (*i as usize) < num_pieces);
+        Ok(())
+    }
+
+    pub fn remote_handshake(&self) -> Option<&Handshake> {
+        self.remote.as_ref()
+    }
+
+    pub fn peer_pieces(&self) -> &Bitfield {
+        &self.peer_pieces
+    }
+
+    pub fn our_requests(&self) -> &[BlockRequest] {
+        &self.our_requests
+    }
+
+    pub fn peer_requests(&self) -> &[BlockRequest] {
+        &self.peer_requests
+    }
+
+    // Both ends have to set a reserved bit for it to count
+    fn negotiated(&self, bit: usize) -> bool {
+        let ours = self.our_reserved[7 - bit / 8] & (1 << (bit % 8)) != 0;
+        match self.remote {
+            Some(ref h) => ours && h.has_reserved_bit(bit),
+            None => false
+        }
+    }
+
+    pub fn extensions_enabled(&self) -> bool {
+        self.negotiated(EXTENSION_BIT)
+    }
+
+    // Set FAST_BIT in the handshake handed to new() to offer it
+    pub fn fast_enabled(&self) -> bool {
+        self.negotiated(FAST_BIT)
+    }
+
+    fn check_fast(&self) -> Result<(), PeerError> {
+        if self.fast_enabled() {
+            Ok(())
+        } else {
+            Err(PeerError::FastNotNegotiated)
+        }
+    }
+
+    // Pieces the peer says we can request while they're choking us
+    pub fn allowed_fast(&self) -> &[u32] {
+        &self.allowed_fast_in
+    }
+
+    pub fn extensions(&self) -> &ExtensionRegistry {
+        &self.extensions
+    }
+
+    pub fn remote_extensions(&self) -> Option<&ExtendedHandshake> {
+        self.remote_extensions.as_ref()
+    }
+
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    // Reads whatever's available, and hands back what happened
+    pub fn poll(&mut self) -> Result<Vec<Event>, PeerError> {
+        self.flush()?;
+
+        let mut buf = [0u8; 16 * 1024];
+        let mut closed = false;
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                },
+                Ok(n) => self.decoder.feed(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => { return Err(PeerError::Io(e)); }
+            }
+        }
+
+        let events = self.decode()?;
+        // Whatever made it in whole before they hung up still gets handed
+        // back, and the next poll finds nothing more and reports the close.
+        // Half a message left over is never going to be finished.
+        if closed {
+            if events.is_empty() {
+                return Err(PeerError::Closed);
+            }
+            return Ok(events);
+        }
+
+        self.flush()?;
+        Ok(events)
+    }
+
+    fn decode(&mut self) -> Result<Vec<Event>, PeerError> {
+        let mut events = Vec::new();
+        if self.remote.is_none() {
+            match self.decoder.next_handshake()? {
+                Some(handshake) => {
+                    if handshake.info_hash != self.info_hash {
+                        return Err(PeerError::InfoHashMismatch);
+                    }
+                    self.remote = Some(handshake.clone());
+                    events.push(Event::Handshake(handshake));
+                },
+                None => { return Ok(events); }
+            }
+        }
+
+        while let Some(msg) = self.decoder.next_message()? {
+            if let Some(event) = self.handle_message(msg)? {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    fn check_index(&self, index: u32) -> Result<(), PeerError> {
+        match self.num_pieces {
+            Some(n) if (index as usize) < n => Ok(()),
+            _ => Err(PeerError::BadIndex(index))
+        }
+    }
+
+    fn handle_message(&mut self, msg: Message) -> Result<Option<Event>, PeerError> {
+        let first = !self.got_message;
+        // Extended handshakes tend to show up before the bitfield, so they
+        // don't count as the first message
+        match msg {
+            Message::KeepAlive | Message::Extended { .. } => (),
+            _ => self.got_message = true
+        }
+
+        let event = match msg {
+            Message::KeepAlive => None,
+            Message::Choke => {
+                self.peer_choking = true;
+                // With the fast extension they have to reject each one
+                // explicitly, so nothing is implied here
+                let dropped = if self.fast_enabled() {
+                    Vec::new()
+                } else {
+                    self.our_requests.drain(..).collect()
+                };
+                Some(Event::Choked { dropped })
+            },
+            Message::Unchoke => {
+                self.peer_choking = false;
+                Some(Event::Unchoked)
+            },
+            Message::Interested => {
+                self.peer_interested = true;
+                Some(Event::Interested)
+            },
+            Message::NotInterested => {
+                self.peer_interested = false;
+                Some(Event::NotInterested)
+            },
+            Message::Have(index) if self.num_pieces.is_none() => {
+                if self.early_haves.len() < MAX_EARLY_HAVES {
+                    self.early_haves.insert(index);
+                }
+                Some(Event::Have(index))
+            },
+            Message::Have(index) => {
+                self.check_index(index)?;
+                self.peer_pieces.set(index as usize);
+                Some(Event::Have(index))
+            },
+            Message::Bitfield(bits) => {
+                if !first {
+                    return Err(PeerError::LateBitfield);
+                }
+                match self.num_pieces {
+                    Some(n) => self.peer_pieces = Bitfield::from_bytes(&bits, n).map_err(PeerError::BadBitfield)?,
+                    None => self.early_bitfield = Some(Message::Bitfield(bits))
+                }
+                Some(Event::Bitfield)
+            },
+            // Shorthand bitfields, with the same rules about coming first
+            Message::HaveAll | Message::HaveNone => {
+                self.check_fast()?;
+                if !first {
+                    return Err(PeerError::LateBitfield);
+                }
+                match self.num_pieces {
+                    Some(n) if msg == Message::HaveAll => self.peer_pieces = Bitfield::full(n),
+                    Some(n) => self.peer_pieces = Bitfield::new(n),
+                    None => self.early_bitfield = Some(msg)
+                }
+                Some(Event::Bitfield)
+            },
+            Message::Request { index, begin, length } => {
+                self.check_index(index)?;
+                if length > MAX_REQUEST_LEN || length == 0 {
+                    return Err(PeerError::OversizedRequest(length));
+                }
+                let req = BlockRequest { index, begin, length };
+                // Requests while we're choking them just get dropped on the
+                // floor, or rejected if they speak the fast extension. Allowed
+                // fast pieces are the exception.
+                if self.am_choking && !self.allowed_fast_out.contains(&index) {
+                    if self.fast_enabled() {
+                        self.send_reject(req)?;
+                    }
+                    return Ok(None);
+                }
+                if self.peer_requests.len() >= MAX_PEER_REQUESTS {
+                    return Err(PeerError::TooManyRequests);
+                }
+                self.peer_requests.push(req);
+                Some(Event::Request(req))
+            },
+            Message::Cancel { index, begin, length } => {
+                let req = BlockRequest { index, begin, length };
+                let before = self.peer_requests.len();
+                self.peer_requests.retain(|r| *r != req);
+                if self.peer_requests.len() == before {
+                    None
+                } else {
+                    // The fast extension wants every request answered, even
+                    // cancelled ones
+                    if self.fast_enabled() {
+                        self.send_reject(req)?;
+                    }
+                    Some(Event::Cancel(req))
+                }
+            },
+            Message::Piece { index, begin, block } => {
+                let req = BlockRequest { index, begin, length: block.len() as u32 };
+                match self.our_requests.iter().position(|r| *r == req) {
+                    Some(i) => {
+                        self.our_requests.remove(i);
+                        Some(Event::Block { index, begin, data: block })
+                    },
+                    // Probably something we cancelled, which is fine
+                    None => None
+                }
+            },
+            Message::Port(port) => Some(Event::Port(port)),
+            Message::Suggest(index) => {
+                self.check_fast()?;
+                // Nothing we could do with it yet anyway
+                if self.num_pieces.is_none() {
+                    return Ok(None);
+                }
+                self.check_index(index)?;
+                Some(Event::Suggest(index))
+            },
+            Message::Reject { index, begin, length } => {
+                self.check_fast()?;
+                let req = BlockRequest { index, begin, length };
+                match self.our_requests.iter().position(|r| *r == req) {
+                    Some(i) => {
+                        self.our_requests.remove(i);
+                        Some(Event::Rejected(req))
+                    },
+                    // Answering a cancel of ours, most likely
+                    None => None
+                }
+            },
+            Message::AllowedFast(index) => {
+                self.check_fast()?;
+                // Out of range ones get weeded out by set_num_pieces()
+                if self.num_pieces.is_some() {
+                    self.check_index(index)?;
+                } else if self.allowed_fast_in.len() >= MAX_EARLY_ALLOWED_FAST {
+                    return Ok(None);
+                }
+                if !self.allowed_fast_in.contains(&index) {
+                    self.allowed_fast_in.push(index);
+                }
+                Some(Event::AllowedFast(index))
+            },
+            Message::Extended { id, payload } => {
+                if !self.extensions_enabled() {
+                    return Err(PeerError::ExtensionsNotNegotiated);
+                }
+                if id == HANDSHAKE_ID {
+                    let hs = ExtendedHandshake::decode(&payload).map_err(PeerError::BadExtendedHandshake)?;
+                    Some(Event::ExtendedHandshake(self.merge_extended_handshake(hs)))
+                } else {
+                    // Anything we never registered gets ignored
+                    self.extensions.name(id).map(|name| Event::Extended { name: String::from(name), payload })
+                }
+            }
+        };
+
+        Ok(event)
+    }
+
+    // Later handshakes only need to mention what changed, so fold them into
+    // what we already know
+    fn merge_extended_handshake(&mut self, hs: ExtendedHandshake) -> ExtendedHandshake {
+        let merged = match self.remote_extensions.take() {
+            None => hs,
+            Some(mut old) => {
+                for (name, id) in hs.m.into_iter() {
+                    old.m.insert(name, id);
+                }
+                old.v = hs.v.or(old.v);
+                old.p = hs.p.or(old.p);
+                old.yourip = hs.yourip.or(old.yourip);
+                old.reqq = hs.reqq.or(old.reqq);
+                old.metadata_size = hs.metadata_size.or(old.metadata_size);
+                old
+            }
+        };
+        self.remote_extensions = Some(merged.clone());
+        merged
+    }
+
+    // The message ID the peer wants this extension sent on, if it supports it
+    pub fn remote_extension_id(&self, name: &str) -> Option<u8> {
+        match self.remote_extensions {
+            Some(ref hs) => match hs.m.get(name) {
+                Some(&0) | None => None,
+                Some(&id) => Some(id)
+            },
+            None => None
+        }
+    }
+
+    pub fn send_extended_handshake(&mut self, hs: &ExtendedHandshake) -> Result<(), PeerError> {
+        if !self.extensions_enabled() {
+            return Err(PeerError::ExtensionsNotNegotiated);
+        }
+        self.send(&Message::Extended { id: HANDSHAKE_ID, payload: hs.encode() })
+    }
+
+    pub fn send_extended(&mut self, name: &str, payload: Vec<u8>) -> Result<(), PeerError> {
+        if !self.extensions_enabled() {
+            return Err(PeerError::ExtensionsNotNegotiated);
+        }
+        match self.remote_extension_id(name) {
+            Some(id) => self.send(&Message::Extended { id, payload }),
+            None => Err(PeerError::ExtensionUnsupported(String::from(name)))
+        }
+    }
+
+    // Pushes out as much of the outgoing buffer as the stream will take
+    pub fn flush(&mut self) -> Result<(), PeerError> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => { return Err(PeerError::Closed); },
+                Ok(n) => { self.outgoing.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => { return Err(PeerError::Io(e)); }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn send(&mut self, msg: &Message) -> Result<(), PeerError> {
+        msg.encode(&mut self.outgoing);
+        self.flush()
+    }
+
+    pub fn send_bitfield(&mut self, ours: &Bitfield) -> Result<(), PeerError> {
+        self.send(&Message::Bitfield(ours.as_bytes().to_vec()))
+    }
+
+    pub fn choke(&mut self) -> Result<(), PeerError> {
+        if self.am_choking {
+            return Ok(());
+        }
+        self.am_choking = true;
+        self.send(&Message::Choke)?;
+
+        // Choking them throws away everything they've asked for. With the fast
+        // extension we say so for each one, and keep serving allowed fast pieces.
+        if !self.fast_enabled() {
+            self.peer_requests.clear();
+            return Ok(());
+        }
+        let allowed = &self.allowed_fast_out;
+        let (keep, rejected): (Vec<BlockRequest>, Vec<BlockRequest>) =
+            self.peer_requests.drain(..).partition(|r| allowed.contains(&r.index));
+        self.peer_requests = keep;
+        for req in rejected.into_iter() {
+            self.send_reject(req)?;
+        }
+        Ok(())
+    }
+
+    pub fn unchoke(&mut self) -> Result<(), PeerError> {
+        if !self.am_choking {
+            return Ok(());
+        }
+        self.am_choking = false;
+        self.send(&Message::Unchoke)
+    }
+
+    pub fn set_interested(&mut self, interested: bool) -> Result<(), PeerError> {
+        if self.am_interested == interested {
+            return Ok(());
+        }
+        self.am_interested = interested;
+        self.send(if interested { &Message::Interested } else { &Message::NotInterested })
+    }
+
+    pub fn have(&mut self, index: u32) -> Result<(), PeerError> {
+        self.check_index(index)?;
+        self.send(&Message::Have(index))
+    }
+
+    pub fn request(&mut self, req: BlockRequest) -> Result<(), PeerError> {
+        if self.remote.is_none() {
+            return Err(PeerError::NotConnected);
+        }
+        self.check_index(req.index)?;
+        if self.peer_choking && !self.allowed_fast_in.contains(&req.index) {
+            return Err(PeerError::Choked);
+        }
+        if !self.peer_pieces.has(req.index as usize) {
+            return Err(PeerError::DontHave(req.index));
+        }
+        if req.length > MAX_REQUEST_LEN || req.length == 0 {
+            return Err(PeerError::OversizedRequest(req.length));
+        }
+        if self.our_requests.contains(&req) {
+            return Ok(());
+        }
+        self.our_requests.push(req);
+        self.send(&Message::Request { index: req.index, begin: req.begin, length: req.length })
+    }
+
+    pub fn cancel(&mut self, req: BlockRequest) -> Result<(), PeerError> {
+        let before = self.our_requests.len();
+        self.our_requests.retain(|r| *r != req);
+        if self.our_requests.len() == before {
+            return Ok(());
+        }
+        self.send(&Message::Cancel { index: req.index, begin: req.begin, length: req.length })
+    }
+
+    // Instead of a bitfield, for peers with the fast extension
+    pub fn send_have_all(&mut self) -> Result<(), PeerError> {
+        self.check_fast()?;
+        self.send(&Message::HaveAll)
+    }
+
+    pub fn send_have_none(&mut self) -> Result<(), PeerError> {
+        self.check_fast()?;
+        self.send(&Message::HaveNone)
+    }
+
+    pub fn suggest(&mut self, index: u32) -> Result<(), PeerError> {
+        self.check_fast()?;
+        self.check_index(index)?;
+        self.send(&Message::Suggest(index))
+    }
+
+    // Lets them request this piece even while we're choking them; see
+    // peer::fast::allowed_fast_set for which pieces to offer
+    pub fn allow_fast(&mut self, index: u32) -> Result<(), PeerError> {
+        self.check_fast()?;
+        self.check_index(index)?;
+        if self.allowed_fast_out.contains(&index) {
+            return Ok(());
+        }
+        self.allowed_fast_out.push(index);
+        self.send(&Message::AllowedFast(index))
+    }
+
+    // Turns down one of their requests, for when we can't or won't serve it
+    pub fn reject(&mut self, req: BlockRequest) -> Result<(), PeerError> {
+        self.check_fast()?;
+        match self.peer_requests.iter().position(|r| *r == req) {
+            Some(i) => { self.peer_requests.remove(i); },
+            None => { return Ok(()); }
+        }
+        self.send_reject(req)
+    }
+
+    fn send_reject(&mut self, req: BlockRequest) -> Result<(), PeerError> {
+        self.send(&Message::Reject { index: req.index, begin: req.begin, length: req.length })
+    }
+
+    // Answers one of their requests. Anything they cancelled or that we
+    // dropped when we choked them is quietly skipped.
+    pub fn send_block(&mut self, req: BlockRequest, data: Vec<u8>) -> Result<(), PeerError> {
+        match self.peer_requests.iter().position(|r| *r == req) {
+            Some(i) => { self.peer_requests.remove(i); },
+            None => { return Ok(()); }
+        }
+        self.send(&Message::Piece { index: req.index, begin: req.begin, block: data })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use id::PeerId;
+    use peer::bitfield::Bitfield;
+    use peer::extension::ExtensionRegistry;
+    use peer::pipe::{pipe, PipeEnd};
+    use peer::wire::*;
+    use super::{BlockRequest, Event, PeerConnection, PeerError};
+
+    const PIECES: usize = 10;
+
+    fn connected_pair() -> (PeerConnection<PipeEnd>, PeerConnection<PipeEnd>) {
+        let (a, b) = pipe();
+        let mut us = PeerConnection::new(a, Handshake::new([1; 20], PeerId([b'a'; 20])), PIECES);
+        let mut them = PeerConnection::new(b, Handshake::new([1; 20], PeerId([b'b'; 20])), PIECES);
+
+        match us.poll().unwrap()[0] {
+            Event::Handshake(ref h) => assert_eq!(h.peer_id, PeerId([b'b'; 20])),
+            _ => unreachable!()
+        }
+        them.poll().unwrap();
+        (us, them)
+    }
+
+    fn req(index: u32, begin: u32) -> BlockRequest {
+        BlockRequest { index, begin, length: 16384 }
+    }
+
+    #[test]
+    fn request_and_receive() {
+        let (mut us, mut them) = connected_pair();
+
+        them.send_bitfield(&Bitfield::full(PIECES)).unwrap();
+        assert_eq!(us.poll().unwrap(), vec!(Event::Bitfield));
+        assert!(us.peer_pieces().is_complete());
+
+        // Can't ask for anything until we're unchoked
+        match us.request(req(0, 0)) {
+            Err(PeerError::Choked) => (),
+            _ => unreachable!()
+        }
+
+        us.set_interested(true).unwrap();
+        assert_eq!(them.poll().unwrap(), vec!(Event::Interested));
+        assert!(them.peer_interested);
+        them.unchoke().unwrap();
+        assert_eq!(us.poll().unwrap(), vec!(Event::Unchoked));
+
+        us.request(req(3, 0)).unwrap();
+        us.request(req(3, 16384)).unwrap();
+        assert_eq!(us.our_requests().len(), 2);
+        assert_eq!(them.poll().unwrap(), vec!(Event::Request(req(3, 0)), Event::Request(req(3, 16384))));
+        assert_eq!(them.peer_requests().len(), 2);
+
+        them.send_block(req(3, 0), vec!(7; 16384)).unwrap();
+        assert_eq!(us.poll().unwrap(), vec!(Event::Block { index: 3, begin: 0, data: vec!(7; 16384) }));
+        assert_eq!(us.our_requests(), &[req(3, 16384)]);
+
+        // Cancelling takes it off their queue too
+        us.cancel(req(3, 16384)).unwrap();
+        assert_eq!(them.poll().unwrap(), vec!(Event::Cancel(req(3, 16384))));
+        assert!(them.peer_requests().is_empty());
+    }
+
+    #[test]
+    fn choke_drops_requests() {
+        let (mut us, mut them) = connected_pair();
+        them.send_bitfield(&Bitfield::full(PIECES)).unwrap();
+        them.unchoke().unwrap();
+        us.poll().unwrap();
+
+        us.request(req(1, 0)).unwrap();
+        them.poll().unwrap();
+        them.choke().unwrap();
+        assert!(them.peer_requests().is_empty());
+        assert_eq!(us.poll().unwrap(), vec!(Event::Choked { dropped: vec!(req(1, 0)) }));
+        assert!(us.our_requests().is_empty());
+
+        // Requests that show up while they're choked are ignored
+        us.send(&Message::Request { index: 1, begin: 0, length: 16384 }).unwrap();
+        assert_eq!(them.poll().unwrap(), vec!());
+        assert!(them.peer_requests().is_empty());
+    }
+
+    #[test]
+    fn protocol_violations() {
+        // Bitfield after a have
+        let (mut us, mut them) = connected_pair();
+        them.have(1).unwrap();
+        them.send_bitfield(&Bitfield::full(PIECES)).unwrap();
+        match us.poll() {
+            Err(PeerError::LateBitfield) => (),
+            _ => unreachable!()
+        }
+
+        // Oversized requests
+        let (mut us, mut them) = connected_pair();
+        us.unchoke().unwrap();
+        them.send(&Message::Request { index: 0, begin: 0, length: 1 << 17 }).unwrap();
+        match us.poll() {
+            Err(PeerError::OversizedRequest(len)) => assert_eq!(len, 1 << 17),
+            _ => unreachable!()
+        }
+
+        // Out of range have
+        let (mut us, mut them) = connected_pair();
+        them.send(&Message::Have(PIECES as u32)).unwrap();
+        match us.poll() {
+            Err(PeerError::BadIndex(_)) => (),
+            _ => unreachable!()
+        }
+
+        // Bitfield with spare bits set
+        let (mut us, mut them) = connected_pair();
+        them.send(&Message::Bitfield(vec!(0xff, 0xff))).unwrap();
+        match us.poll() {
+            Err(PeerError::BadBitfield(_)) => (),
+            _ => unreachable!()
+        }
+
+        // Asking for something they don't have
+        let (mut us, mut them) = connected_pair();
+        them.unchoke().unwrap();
+        us.poll().unwrap();
+        match us.request(req(2, 0)) {
+            Err(PeerError::DontHave(2)) => (),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn wrong_torrent_and_hangups() {
+        let (a, b) = pipe();
+        let mut us = PeerConnection::new(a, Handshake::new([1; 20], PeerId([b'a'; 20])), PIECES);
+        let _them = PeerConnection::new(b, Handshake::new([2; 20], PeerId([b'b'; 20])), PIECES);
+        match us.poll() {
+            Err(PeerError::InfoHashMismatch) => (),
+            _ => unreachable!()
+        }
+
+        let (mut us, them) = connected_pair();
+        drop(them);
+        match us.poll() {
+            Err(PeerError::Closed) => (),
+            _ => unreachable!()
+        }
+
+        // Hanging up halfway through a message: the whole ones still come
+        // out, then the close, rather than waiting on the rest forever
+        let (a, mut b) = pipe();
+        let mut us = PeerConnection::new(a, Handshake::new([1; 20], PeerId([b'a'; 20])), PIECES);
+        let mut bytes = Handshake::new([1; 20], PeerId([b'b'; 20])).encode();
+        Message::Have(1).encode(&mut bytes);
+        let mut piece = Vec::new();
+        Message::Piece { index: 1, begin: 0, block: vec!(7; 100) }.encode(&mut piece);
+        bytes.extend_from_slice(&piece[..50]);
+        b.write_all(&bytes).unwrap();
+        drop(b);
+        let events = us.poll().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1], Event::Have(1));
+        for _ in 0..2 {
+            match us.poll() {
+                Err(PeerError::Closed) => (),
+                _ => unreachable!()
+            }
+        }
+    }
+
+    #[test]
+    fn extension_messages() {
+        let (a, b) = pipe();
+        let mut our_registry = ExtensionRegistry::new();
+        our_registry.register("ut_metadata");
+        our_registry.register("ut_pex");
+        let mut their_registry = ExtensionRegistry::new();
+        their_registry.register("ut_pex");
+
+        let mut us = PeerConnection::with_extensions(a, Handshake::new([1; 20], PeerId([b'a'; 20])), PIECES,
+                Some(our_registry.clone()));
+        let mut them = PeerConnection::with_extensions(b, Handshake::new([1; 20], PeerId([b'b'; 20])), PIECES,
+                Some(their_registry.clone()));
+        us.poll().unwrap();
+        them.poll().unwrap();
+        assert!(us.extensions_enabled());
+
+        us.send_extended_handshake(&our_registry.handshake()).unwrap();
+        let mut hs = their_registry.handshake();
+        hs.reqq = Some(100);
+        them.send_extended_handshake(&hs).unwrap();
+
+        match us.poll().unwrap()[0] {
+            Event::ExtendedHandshake(ref hs) => assert_eq!(hs.reqq, Some(100)),
+            _ => unreachable!()
+        }
+        them.poll().unwrap();
+
+        // They only know ut_pex, we know both
+        match us.send_extended("ut_metadata", vec!(1)) {
+            Err(PeerError::ExtensionUnsupported(_)) => (),
+            _ => unreachable!()
+        }
+        them.send_extended("ut_metadata", vec!(1)).unwrap();
+        them.send_extended("ut_pex", vec!(1, 2, 3)).unwrap();
+        assert_eq!(us.poll().unwrap(), vec!(
+                Event::Extended { name: String::from("ut_metadata"), payload: vec!(1) },
+                Event::Extended { name: String::from("ut_pex"), payload: vec!(1, 2, 3) }));
+
+        // A later handshake turning ut_metadata off just updates things
+        let mut off = our_registry.handshake();
+        off.m.insert(String::from("ut_metadata"), 0);
+        off.m.remove("ut_pex");
+        us.send_extended_handshake(&off).unwrap();
+        them.poll().unwrap();
+        assert_eq!(them.remote_extension_id("ut_metadata"), None);
+        assert_eq!(them.remote_extension_id("ut_pex"), Some(2));
+    }
+
+    #[test]
+    fn extensions_need_both_bits() {
+        let (a, b) = pipe();
+        let mut us = PeerConnection::new(a, Handshake::new([1; 20], PeerId([b'a'; 20])), PIECES);
+        let mut them = PeerConnection::with_extensions(b, Handshake::new([1; 20], PeerId([b'b'; 20])), PIECES,
+                Some(ExtensionRegistry::new()));
+        us.poll().unwrap();
+        them.poll().unwrap();
+        assert!(!them.extensions_enabled());
+
+        them.send(&Message::Extended { id: 0, payload: b"de".to_vec() }).unwrap();
+        match us.poll() {
+            Err(PeerError::ExtensionsNotNegotiated) => (),
+            _ => unreachable!()
+        }
+    }
+
+    fn fast_pair() -> (PeerConnection<PipeEnd>, PeerConnection<PipeEnd>) {
+        let (a, b) = pipe();
+        let mut ours = Handshake::new([1; 20], PeerId([b'a'; 20]));
+        ours.set_reserved_bit(FAST_BIT);
+        let mut theirs = Handshake::new([1; 20], PeerId([b'b'; 20]));
+        theirs.set_reserved_bit(FAST_BIT);
+        let mut us = PeerConnection::new(a, ours, PIECES);
+        let mut them = PeerConnection::new(b, theirs, PIECES);
+        us.poll().unwrap();
+        them.poll().unwrap();
+        assert!(us.fast_enabled());
+        (us, them)
+    }
+
+    #[test]
+    fn fast_extension() {
+        let (mut us, mut them) = fast_pair();
+        them.send_have_all().unwrap();
+        them.suggest(4).unwrap();
+        them.allow_fast(2).unwrap();
+        assert_eq!(us.poll().unwrap(), vec!(Event::Bitfield, Event::Suggest(4), Event::AllowedFast(2)));
+        assert!(us.peer_pieces().is_complete());
+        assert_eq!(us.allowed_fast(), &[2]);
+
+        // Allowed fast pieces can be asked for while choked, others get rejected
+        us.request(req(2, 0)).unwrap();
+        match us.request(req(3, 0)) {
+            Err(PeerError::Choked) => (),
+            _ => unreachable!()
+        }
+        us.send(&Message::Request { index: 3, begin: 0, length: 16384 }).unwrap();
+        us.our_requests.push(req(3, 0));
+        assert_eq!(them.poll().unwrap(), vec!(Event::Request(req(2, 0))));
+        assert_eq!(us.poll().unwrap(), vec!(Event::Rejected(req(3, 0))));
+        assert_eq!(us.our_requests(), &[req(2, 0)]);
+
+        // Choking no longer implies anything, the rejects say what's dropped
+        them.unchoke().unwrap();
+        us.poll().unwrap();
+        us.request(req(5, 0)).unwrap();
+        them.poll().unwrap();
+        them.choke().unwrap();
+        assert_eq!(us.poll().unwrap(), vec!(Event::Choked { dropped: Vec::new() }, Event::Rejected(req(5, 0))));
+        // The allowed fast one is still coming
+        assert_eq!(them.peer_requests(), &[req(2, 0)]);
+        them.send_block(req(2, 0), vec!(1; 16384)).unwrap();
+        assert_eq!(us.poll().unwrap(), vec!(Event::Block { index: 2, begin: 0, data: vec!(1; 16384) }));
+
+        // Cancels get a reject back, which we don't need to hear about
+        us.request(req(2, 16384)).unwrap();
+        them.poll().unwrap();
+        us.cancel(req(2, 16384)).unwrap();
+        assert_eq!(them.poll().unwrap(), vec!(Event::Cancel(req(2, 16384))));
+        assert_eq!(us.poll().unwrap(), vec!());
+    }
+
+    #[test]
+    fn fast_needs_both_bits() {
+        let (mut us, mut them) = connected_pair();
+        match us.send_have_none() {
+            Err(PeerError::FastNotNegotiated) => (),
+            _ => unreachable!()
+        }
+        them.send(&Message::HaveNone).unwrap();
+        match us.poll() {
+            Err(PeerError::FastNotNegotiated) => (),
+            _ => unreachable!()
+        }
+
+        let (mut us, mut them) = fast_pair();
+        them.send_bitfield(&Bitfield::new(PIECES)).unwrap();
+        them.send(&Message::HaveAll).unwrap();
+        match us.poll() {
+            Err(PeerError::LateBitfield) => (),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn pieces_before_metadata() {
+        // Before the info dict, anything goes and gets held onto
+        let early = || {
+            let (a, b) = pipe();
+            let mut us = PeerConnection::without_metadata(a, Handshake::new([1; 20], PeerId([b'a'; 20])),
+                    ExtensionRegistry::new());
+            let mut them = PeerConnection::new(b, Handshake::new([1; 20], PeerId([b'b'; 20])), PIECES);
+            us.poll().unwrap();
+            them.poll().unwrap();
+            (us, them)
+        };
+
+        let (mut us, mut them) = early();
+        let mut bits = Bitfield::new(PIECES);
+        bits.set(2);
+        them.send_bitfield(&bits).unwrap();
+        them.have(9).unwrap();
+        assert_eq!(us.poll().unwrap(), vec!(Event::Bitfield, Event::Have(9)));
+        assert!(us.request(req(2, 0)).is_err());
+        us.set_num_pieces(PIECES).unwrap();
+        assert_eq!(us.peer_pieces().iter_set().collect::<Vec<usize>>(), vec!(2, 9));
+
+        // But it still has to add up once we know
+        let (mut us, mut them) = early();
+        them.send_bitfield(&Bitfield::full(PIECES)).unwrap();
+        us.poll().unwrap();
+        match us.set_num_pieces(PIECES + 20) {
+            Err(PeerError::BadBitfield(_)) => (),
+            _ => unreachable!()
+        }
+
+        let (mut us, mut them) = early();
+        them.have(9).unwrap();
+        us.poll().unwrap();
+        match us.set_num_pieces(5) {
+            Err(PeerError::BadIndex(9)) => (),
+            _ => unreachable!()
+        }
+    }
+}
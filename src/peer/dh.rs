@@ -0,0 +1,225 @@
+use rand::{Rng, thread_rng};
+
+// Diffie-Hellman over the 768 bit prime message stream encryption uses,
+// with a generator of 2. Keys go over the wire as 96 big endian bytes.
+pub const KEY_LEN: usize = 96;
+const LIMBS: usize = KEY_LEN / 4;
+// 160 bits of private key is what the spec asks for
+const PRIVATE_LEN: usize = 20;
+
+const PRIME: [u8; KEY_LEN] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2, 0x21, 0x68, 0xc2, 0x34,
+    0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1, 0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74,
+    0x02, 0x0b, 0xbe, 0xa6, 0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+    0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d, 0xf2, 0x5f, 0x14, 0x37,
+    0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45, 0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6,
+    0xf4, 0x4c, 0x42, 0xe9, 0xa6, 0x3a, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63
+];
+
+// Little endian 32 bit limbs
+type Num = [u32; LIMBS];
+
+fn from_bytes(bytes: &[u8; KEY_LEN]) -> Num {
+    let mut n = [0u32; LIMBS];
+    for (i, chunk) in bytes.chunks(4).rev().enumerate() {
+        n[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    n
+}
+
+fn to_bytes(n: &Num) -> [u8; KEY_LEN] {
+    let mut bytes = [0u8; KEY_LEN];
+    for (i, chunk) in bytes.chunks_mut(4).rev().enumerate() {
+        chunk.copy_from_slice(&n[i].to_be_bytes());
+    }
+    bytes
+}
+
+fn small(x: u32) -> Num {
+    let mut n = [0u32; LIMBS];
+    n[0] = x;
+    n
+}
+
+fn less_than(a: &Num, b: &Num) -> bool {
+    for i in (0..LIMBS).rev() {
+        if a[i] != b[i] {
+            return a[i] < b[i];
+        }
+    }
+    false
+}
+
+// a - b, throwing away the borrow out the top
+fn sub(a: &Num, b: &Num) -> Num {
+    let mut out = [0u32; LIMBS];
+    let mut borrow = 0u64;
+    for i in 0..LIMBS {
+        let d = (u64::from(a[i]) | 1 << 32) - u64::from(b[i]) - borrow;
+        out[i] = d as u32;
+        borrow = 1 - (d >> 32);
+    }
+    out
+}
+
+// Montgomery arithmetic mod the prime, which keeps the exponentiation down
+// to multiplies and shifts. Numbers in Montgomery form are x * 2^768 mod p.
+struct Montgomery {
+    p: Num,
+    // -1/p mod 2^32
+    p_inv: u32,
+    // 2^1536 mod p, for getting into Montgomery form
+    r2: Num
+}
+
+impl Montgomery {
+    fn new() -> Montgomery {
+        let p = from_bytes(&PRIME);
+        // Newton's method doubles the correct bits each time round
+        let mut inv = 1u32;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(p[0].wrapping_mul(inv)));
+        }
+
+        let mut r2 = small(1);
+        for _ in 0..2 * 32 * LIMBS {
+            let carry = r2[LIMBS - 1] >> 31;
+            for i in (1..LIMBS).rev() {
+                r2[i] = r2[i] << 1 | r2[i - 1] >> 31;
+            }
+            r2[0] <<= 1;
+            if carry == 1 || !less_than(&r2, &p) {
+                r2 = sub(&r2, &p);
+            }
+        }
+        Montgomery { p, p_inv: inv.wrapping_neg(), r2 }
+    }
+
+    // a * b / 2^768 mod p
+    fn mul(&self, a: &Num, b: &Num) -> Num {
+        let mut t = [0u32; LIMBS + 2];
+        for &bi in b.iter() {
+            let mut carry = 0u64;
+            for j in 0..LIMBS {
+                let s = u64::from(t[j]) + u64::from(a[j]) * u64::from(bi) + carry;
+                t[j] = s as u32;
+                carry = s >> 32;
+            }
+            let s = u64::from(t[LIMBS]) + carry;
+            t[LIMBS] = s as u32;
+            t[LIMBS + 1] = (s >> 32) as u32;
+
+            // Add whatever multiple of p clears the bottom limb, then shift
+            // it off
+            let m = u64::from(t[0].wrapping_mul(self.p_inv));
+            let mut carry = (u64::from(t[0]) + m * u64::from(self.p[0])) >> 32;
+            for j in 1..LIMBS {
+                let s = u64::from(t[j]) + m * u64::from(self.p[j]) + carry;
+                t[j - 1] = s as u32;
+                carry = s >> 32;
+            }
+            let s = u64::from(t[LIMBS]) + carry;
+            t[LIMBS - 1] = s as u32;
+            t[LIMBS] = t[LIMBS + 1] + (s >> 32) as u32;
+            t[LIMBS + 1] = 0;
+        }
+
+        let mut out = [0u32; LIMBS];
+        out.copy_from_slice(&t[..LIMBS]);
+        if t[LIMBS] != 0 || !less_than(&out, &self.p) {
+            out = sub(&out, &self.p);
+        }
+        out
+    }
+
+    // base^exp mod p, with exp big endian
+    fn pow(&self, base: &Num, exp: &[u8]) -> Num {
+        let base = self.mul(base, &self.r2);
+        let mut acc = self.mul(&small(1), &self.r2);
+        for byte in exp.iter() {
+            for bit in (0..8).rev() {
+                acc = self.mul(&acc, &acc);
+                if byte >> bit & 1 == 1 {
+                    acc = self.mul(&acc, &base);
+                }
+            }
+        }
+        self.mul(&acc, &small(1))
+    }
+}
+
+pub struct DhKey {
+    private: [u8; PRIVATE_LEN],
+    public: [u8; KEY_LEN]
+}
+
+impl DhKey {
+    pub fn generate() -> DhKey {
+        let mut private = [0u8; PRIVATE_LEN];
+        thread_rng().fill_bytes(&mut private);
+        DhKey::from_private(private)
+    }
+
+    fn from_private(private: [u8; PRIVATE_LEN]) -> DhKey {
+        let public = to_bytes(&Montgomery::new().pow(&small(2), &private));
+        DhKey { private, public }
+    }
+
+    pub fn public(&self) -> &[u8; KEY_LEN] {
+        &self.public
+    }
+
+    // None if their key is one that would give away the secret: 0, 1, p - 1
+    // or anything that isn't below p
+    pub fn shared_secret(&self, theirs: &[u8; KEY_LEN]) -> Option<[u8; KEY_LEN]> {
+        let m = Montgomery::new();
+        let y = from_bytes(theirs);
+        let p_minus_1 = sub(&m.p, &small(1));
+        if less_than(&y, &small(2)) || !less_than(&y, &p_minus_1) {
+            return None;
+        }
+        Some(to_bytes(&m.pow(&y, &self.private)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unhex(s: &str) -> [u8; KEY_LEN] {
+        let mut out = [0u8; KEY_LEN];
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn key_exchange() {
+        let mut x = [0u8; PRIVATE_LEN];
+        let mut y = [0u8; PRIVATE_LEN];
+        for i in 0..PRIVATE_LEN {
+            x[i] = i as u8 + 1;
+            y[i] = i as u8 + 21;
+        }
+        let a = DhKey::from_private(x);
+        let b = DhKey::from_private(y);
+        assert_eq!(a.public()[..], unhex("96e112dab29e8c5272accb9b17b26887ce54a144a4e3b697c7d159b7a817e556b0918db2b4c658e02a87f7e5fb14b18a553e084cbf3dad2d30f16596ccb982d406258c61b30c5c1dae2ddc60bdbd48d79896312aad63238c39e1a633821eb693")[..]);
+
+        let secret = unhex("994aac6c359990cf4f678a1742b587eb1a5248ec7fcc0d0bcfcb12d2461bc1fe25417b70869697d9ca884832f1c5f2a2fd3318c22a5a6ba170d36aac91405457c1e8137b1534a776865ed353f12422ff6afc58435f8bd443f61dd051a37bcdeb");
+        assert_eq!(a.shared_secret(b.public()).unwrap()[..], secret[..]);
+        assert_eq!(b.shared_secret(a.public()).unwrap()[..], secret[..]);
+
+        let (c, d) = (DhKey::generate(), DhKey::generate());
+        assert_eq!(c.shared_secret(d.public()).unwrap()[..], d.shared_secret(c.public()).unwrap()[..]);
+
+        // Keys that would make the secret guessable
+        let mut one = [0u8; KEY_LEN];
+        one[KEY_LEN - 1] = 1;
+        assert!(a.shared_secret(&one).is_none());
+        assert!(a.shared_secret(&PRIME).is_none());
+        let p_minus_1 = to_bytes(&sub(&from_bytes(&PRIME), &small(1)));
+        assert!(a.shared_secret(&p_minus_1).is_none());
+        assert!(a.shared_secret(&[0xff; KEY_LEN]).is_none());
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::btree_map::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use bencode::*;
+
+// Extended message 0 is always the handshake
+pub const HANDSHAKE_ID: u8 = 0;
+
+// What goes back and forth in the BEP 10 extended handshake. Everything but
+// the message map is optional.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    // Extension name to the message ID the sender wants to receive it on.
+    // An ID of 0 means "I've turned this off".
+    pub m: BTreeMap<String, u8>,
+    // Client name and version
+    pub v: Option<String>,
+    // The sender's listen port
+    pub p: Option<u16>,
+    // What the sender sees as our address
+    pub yourip: Option<IpAddr>,
+    // How many outstanding requests the sender is happy to queue
+    pub reqq: Option<u32>,
+    // Size of the info dict, from ut_metadata (BEP 9)
+    pub metadata_size: Option<u32>
+}
+
+impl ExtendedHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut m = BTreeMap::new();
+        for (name, id) in self.m.iter() {
+            m.insert(name.clone(), Benc::I(*id as i64));
+        }
+
+        let mut d = BTreeMap::new();
+        d.insert(String::from("m"), Benc::D(m));
+        if let Some(ref v) = self.v {
+            d.insert(String::from("v"), Benc::S(v.as_bytes().to_vec()));
+        }
+        if let Some(p) = self.p {
+            d.insert(String::from("p"), Benc::I(p as i64));
+        }
+        if let Some(ip) = self.yourip {
+            let bytes = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec()
+            };
+            d.insert(String::from("yourip"), Benc::S(bytes));
+        }
+        if let Some(reqq) = self.reqq {
+            d.insert(String::from("reqq"), Benc::I(reqq as i64));
+        }
+        if let Some(size) = self.metadata_size {
+            d.insert(String::from("metadata_size"), Benc::I(size as i64));
+        }
+
+        enc_benc(&Benc::D(d))
+    }
+
+    // Everything but "m" is advisory, so junk in the other fields is ignored
+    // rather than being fatal
+    pub fn decode(payload: &[u8]) -> Result<ExtendedHandshake, String> {
+        let d = match dec_benc(&payload.to_vec()) {
+            Ok(Benc::D(d)) => d,
+            Ok(_) => { return Err(String::from("Extended handshake is not a dictionary!")); },
+            Err(e) => { return Err(format!("Unable to decode extended handshake: {}", e)); }
+        };
+
+        let mut out = ExtendedHandshake::default();
+
+        match d.get("m") {
+            Some(Benc::D(m)) => {
+                for (name, id) in m.iter() {
+                    match *id {
+                        Benc::I(i) if (0..=255).contains(&i) => { out.m.insert(name.clone(), i as u8); },
+                        _ => { return Err(format!("Extension '{}' has a bad message ID", name)); }
+                    }
+                }
+            },
+            Some(_) => { return Err(String::from("'m' in extended handshake is not a dictionary!")); },
+            None => ()
+        }
+
+        if let Some(Benc::S(v)) = d.get("v") {
+            out.v = Some(String::from_utf8_lossy(v).into_owned());
+        }
+        if let Some(&Benc::I(p)) = d.get("p") {
+            if p > 0 && p <= 65535 {
+                out.p = Some(p as u16);
+            }
+        }
+        if let Some(Benc::S(ip)) = d.get("yourip") {
+            if ip.len() == 4 {
+                out.yourip = Some(IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])));
+            } else if ip.len() == 16 {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(ip);
+                out.yourip = Some(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+        }
+        if let Some(&Benc::I(reqq)) = d.get("reqq") {
+            if reqq > 0 && reqq <= u32::MAX as i64 {
+                out.reqq = Some(reqq as u32);
+            }
+        }
+        if let Some(&Benc::I(size)) = d.get("metadata_size") {
+            if size > 0 && size <= u32::MAX as i64 {
+                out.metadata_size = Some(size as u32);
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+// The extensions we support, each with the message ID we want to be sent it
+// on. IDs get handed out in the order extensions are registered.
+#[derive(Clone, Debug, Default)]
+pub struct ExtensionRegistry {
+    names: Vec<String>
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> ExtensionRegistry {
+        ExtensionRegistry { names: Vec::new() }
+    }
+
+    // Registering the same name twice hands back the same ID
+    pub fn register(&mut self, name: &str) -> u8 {
+        if let Some(id) = self.local_id(name) {
+            return id;
+        }
+        assert!(self.names.len() < 255, "Ran out of extension message IDs");
+        self.names.push(String::from(name));
+        self.names.len() as u8
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.names.iter().position(|n| n == name).map(|i| (i + 1) as u8)
+    }
+
+    pub fn name(&self, local_id: u8) -> Option<&str> {
+        if local_id == HANDSHAKE_ID {
+            return None;
+        }
+        self.names.get(local_id as usize - 1).map(|n| n.as_str())
+    }
+
+    // A handshake advertising everything we've registered; fill in the rest
+    // of the fields before sending it
+    pub fn handshake(&self) -> ExtendedHandshake {
+        let mut hs = ExtendedHandshake {
+            v: Some(format!("flakes {}", env!("CARGO_PKG_VERSION"))),
+            ..Default::default()
+        };
+        for (i, name) in self.names.iter().enumerate() {
+            hs.m.insert(name.clone(), (i + 1) as u8);
+        }
+        hs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ExtendedHandshake, ExtensionRegistry};
+
+    #[test]
+    fn registry() {
+        let mut registry = ExtensionRegistry::new();
+        assert_eq!(registry.register("ut_metadata"), 1);
+        assert_eq!(registry.register("ut_pex"), 2);
+        assert_eq!(registry.register("ut_metadata"), 1);
+        assert_eq!(registry.name(2), Some("ut_pex"));
+        assert_eq!(registry.name(0), None);
+        assert_eq!(registry.name(3), None);
+
+        let hs = registry.handshake();
+        assert_eq!(hs.m.get("ut_pex"), Some(&2));
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let mut hs = ExtendedHandshake::default();
+        hs.m.insert(String::from("ut_metadata"), 3);
+        hs.v = Some(String::from("flakes 0.1.0"));
+        hs.p = Some(6881);
+        hs.yourip = Some("10.1.2.3".parse().unwrap());
+        hs.reqq = Some(250);
+        hs.metadata_size = Some(31235);
+
+        let bytes = hs.encode();
+        assert_eq!(&bytes[..], &b"d1:md11:ut_metadatai3ee13:metadata_sizei31235e1:pi6881e4:reqqi250e1:v12:flakes 0.1.06:yourip4:\x0a\x01\x02\x03e"[..]);
+        assert_eq!(ExtendedHandshake::decode(&bytes).unwrap(), hs);
+    }
+
+    #[test]
+    fn lenient_decoding() {
+        // Junk outside of "m" is ignored
+        let hs = ExtendedHandshake::decode(b"d1:md6:ut_pexi0ee1:pi-5e6:yourip3:abce").unwrap();
+        assert_eq!(hs.m.get("ut_pex"), Some(&0));
+        assert_eq!(hs.p, None);
+        assert_eq!(hs.yourip, None);
+
+        assert!(ExtendedHandshake::decode(b"d1:md6:ut_pexi300eee").is_err());
+        assert!(ExtendedHandshake::decode(b"le").is_err());
+    }
+}
@@ -0,0 +1,72 @@
+use std::net::IpAddr;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+// How many allowed fast pieces we hand each peer
+pub const DEFAULT_ALLOWED_FAST: usize = 10;
+
+// The canonical allowed fast set from BEP 6. Both ends can work it out, so a
+// peer can't get a different set by reconnecting, and peers behind the same
+// /24 all get the same pieces. The BEP only defines it for IPv4, so IPv6
+// peers don't get one.
+pub fn allowed_fast_set(info_hash: &[u8; 20], ip: IpAddr, num_pieces: usize, k: usize) -> Vec<u32> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => { return Vec::new(); }
+        }
+    };
+    let k = k.min(num_pieces);
+
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xffff_ff00).to_be_bytes());
+    x.extend_from_slice(info_hash);
+
+    let mut out = Vec::with_capacity(k);
+    while out.len() < k {
+        let mut hasher = Sha1::new();
+        hasher.input(&x);
+        let mut hash = [0u8; 20];
+        hasher.result(&mut hash);
+        x = hash.to_vec();
+
+        for chunk in hash.chunks(4) {
+            if out.len() >= k {
+                break;
+            }
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let index = (y as u64 % num_pieces as u64) as u32;
+            if !out.contains(&index) {
+                out.push(index);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::allowed_fast_set;
+
+    #[test]
+    fn bep_6_example() {
+        let info_hash = [0xaa; 20];
+        let ip = "80.4.4.200".parse().unwrap();
+        assert_eq!(allowed_fast_set(&info_hash, ip, 1313, 7), vec!(1059, 431, 808, 1217, 287, 376, 1188));
+        assert_eq!(allowed_fast_set(&info_hash, ip, 1313, 9), vec!(1059, 431, 808, 1217, 287, 376, 1188, 353, 508));
+
+        // Same /24, same set
+        assert_eq!(allowed_fast_set(&info_hash, "80.4.4.1".parse().unwrap(), 1313, 7),
+                allowed_fast_set(&info_hash, ip, 1313, 7));
+        assert_eq!(allowed_fast_set(&info_hash, "::ffff:80.4.4.200".parse().unwrap(), 1313, 7),
+                allowed_fast_set(&info_hash, ip, 1313, 7));
+        assert!(allowed_fast_set(&info_hash, "2001:db8::1".parse().unwrap(), 1313, 7).is_empty());
+
+        // Can't hand out more pieces than there are
+        let mut small = allowed_fast_set(&info_hash, ip, 3, 10);
+        small.sort();
+        assert_eq!(small, vec!(0, 1, 2));
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::btree_map::BTreeMap;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use bencode::*;
+use torrent::*;
+
+// What we register ut_metadata (BEP 9) under in the extension registry
+pub const EXTENSION_NAME: &str = "ut_metadata";
+// The info dict goes back and forth in chunks of this size, the last one short
+pub const PIECE_LEN: usize = 16 * 1024;
+// Nobody has a legitimate reason to send us an info dict bigger than this
+pub const MAX_METADATA_SIZE: u32 = 16 * 1024 * 1024;
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data { piece: u32, total_size: u32, data: Vec<u8> },
+    Reject(u32)
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut d = BTreeMap::new();
+        let (msg_type, piece) = match *self {
+            MetadataMessage::Request(piece) => (MSG_REQUEST, piece),
+            MetadataMessage::Data { piece, total_size, .. } => {
+                d.insert(String::from("total_size"), Benc::I(total_size as i64));
+                (MSG_DATA, piece)
+            },
+            MetadataMessage::Reject(piece) => (MSG_REJECT, piece)
+        };
+        d.insert(String::from("msg_type"), Benc::I(msg_type));
+        d.insert(String::from("piece"), Benc::I(piece as i64));
+
+        let mut out = enc_benc(&Benc::D(d));
+        // Data is the only one with anything after the dict
+        if let MetadataMessage::Data { ref data, .. } = *self {
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<MetadataMessage, String> {
+        let (d, used) = match dec_benc_prefix(payload) {
+            Ok((Benc::D(d), used)) => (d, used),
+            Ok(_) => { return Err(String::from("Metadata message is not a dictionary!")); },
+            Err(e) => { return Err(format!("Unable to decode metadata message: {}", e)); }
+        };
+
+        let piece = match d.get("piece") {
+            Some(&Benc::I(p)) if (0..=u32::MAX as i64).contains(&p) => p as u32,
+            _ => { return Err(String::from("Metadata message is missing a valid 'piece'")); }
+        };
+
+        match d.get("msg_type") {
+            Some(&Benc::I(MSG_REQUEST)) => Ok(MetadataMessage::Request(piece)),
+            Some(&Benc::I(MSG_REJECT)) => Ok(MetadataMessage::Reject(piece)),
+            Some(&Benc::I(MSG_DATA)) => {
+                let total_size = match d.get("total_size") {
+                    Some(&Benc::I(s)) if (0..=u32::MAX as i64).contains(&s) => s as u32,
+                    _ => { return Err(String::from("Metadata data message is missing a valid 'total_size'")); }
+                };
+                Ok(MetadataMessage::Data { piece, total_size, data: payload[used..].to_vec() })
+            },
+            Some(&Benc::I(t)) => Err(format!("Unknown metadata message type {}", t)),
+            _ => Err(String::from("Metadata message is missing 'msg_type'"))
+        }
+    }
+}
+
+fn num_pieces(size: u32) -> usize {
+    (size as usize).div_ceil(PIECE_LEN)
+}
+
+// Pulls the info dict together piece by piece, from however many peers are
+// willing to hand it over. Sizes come from the peers' extended handshakes, so
+// none of it is trusted until the whole thing hashes to the info hash.
+pub struct MetadataFetcher {
+    info_hash: [u8; 20],
+    total_size: Option<u32>,
+    pieces: Vec<Option<Vec<u8>>>,
+    // Asked for, but nothing back yet
+    in_flight: Vec<bool>
+}
+
+impl MetadataFetcher {
+    pub fn new(info_hash: [u8; 20]) -> MetadataFetcher {
+        MetadataFetcher {
+            info_hash,
+            total_size: None,
+            pieces: Vec::new(),
+            in_flight: Vec::new()
+        }
+    }
+
+    pub fn total_size(&self) -> Option<u32> {
+        self.total_size
+    }
+
+    // Call with the metadata_size from a peer's extended handshake. The first
+    // size we hear sticks until a hash check fails; peers that disagree with
+    // it get an error and should be left alone.
+    pub fn set_size(&mut self, size: u32) -> Result<(), String> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(format!("Metadata size {} is unreasonable", size));
+        }
+        match self.total_size {
+            Some(s) if s == size => Ok(()),
+            Some(s) => Err(format!("Peer says metadata is {} bytes, but we think it's {}", size, s)),
+            None => {
+                self.total_size = Some(size);
+                self.pieces = vec![None; num_pieces(size)];
+                self.in_flight = vec![false; num_pieces(size)];
+                Ok(())
+            }
+        }
+    }
+
+    // The next piece nobody's been asked for yet
+    pub fn next_request(&mut self) -> Option<u32> {
+        let i = (0..self.pieces.len()).find(|&i| self.pieces[i].is_none() && !self.in_flight[i])?;
+        self.in_flight[i] = true;
+        Some(i as u32)
+    }
+
+    // For rejects, and requests lost to a peer going away
+    pub fn cancel(&mut self, piece: u32) {
+        if let Some(f) = self.in_flight.get_mut(piece as usize) {
+            *f = false;
+        }
+    }
+
+    // Hands back the whole info dict once the last piece shows up and it
+    // checks out. A bad hash throws everything away, size included.
+    pub fn received(&mut self, piece: u32, total_size: u32, data: Vec<u8>) -> Result<Option<Vec<u8>>, String> {
+        if self.total_size.is_none() {
+            self.set_size(total_size)?;
+        }
+        let size = self.total_size.unwrap();
+        if total_size != size {
+            return Err(format!("Got a metadata piece claiming {} total bytes, expected {}", total_size, size));
+        }
+
+        let i = piece as usize;
+        if i >= self.pieces.len() {
+            return Err(format!("Metadata piece {} is out of range", piece));
+        }
+        let expected = if i == self.pieces.len() - 1 {
+            size as usize - i * PIECE_LEN
+        } else {
+            PIECE_LEN
+        };
+        if data.len() != expected {
+            return Err(format!("Metadata piece {} is {} bytes, expected {}", piece, data.len(), expected));
+        }
+
+        self.in_flight[i] = false;
+        self.pieces[i] = Some(data);
+        if self.pieces.iter().any(|p| p.is_none()) {
+            return Ok(None);
+        }
+
+        let mut info = Vec::with_capacity(size as usize);
+        for p in self.pieces.iter() {
+            info.extend_from_slice(p.as_ref().unwrap());
+        }
+
+        let mut hasher = Sha1::new();
+        hasher.input(&info);
+        let mut hash = [0u8; 20];
+        hasher.result(&mut hash);
+        if hash != self.info_hash {
+            self.total_size = None;
+            self.pieces.clear();
+            self.in_flight.clear();
+            return Err(String::from("Metadata doesn't match the info hash, starting over"));
+        }
+
+        Ok(Some(info))
+    }
+}
+
+// Our answer to someone asking for a piece of our info dict
+pub fn serve(info: &[u8], piece: u32) -> MetadataMessage {
+    let start = piece as usize * PIECE_LEN;
+    if start >= info.len() {
+        return MetadataMessage::Reject(piece);
+    }
+    let end = (start + PIECE_LEN).min(info.len());
+    MetadataMessage::Data { piece, total_size: info.len() as u32, data: info[start..end].to_vec() }
+}
+
+// Wraps a fetched info dict up as a torrent, with whatever trackers the
+// magnet link gave us
+pub fn info_to_torrent(info: &[u8], info_hash: &[u8; 20],
+        announce_list: &[Vec<String>]) -> Result<TorrentMetadata, String> {
+    let info = match dec_benc(&info.to_vec()) {
+        Ok(b) => b,
+        Err(e) => { return Err(format!("Unable to decode metadata: {}", e)); }
+    };
+
+    let mut d = BTreeMap::new();
+    d.insert(String::from("info"), info);
+    if !announce_list.is_empty() {
+        let tiers = announce_list.iter().map(|tier| {
+            Benc::L(tier.iter().map(|url| Benc::S(url.as_bytes().to_vec())).collect())
+        }).collect();
+        d.insert(String::from("announce-list"), Benc::L(tiers));
+    }
+
+    let torrent = benc_to_torrent(Benc::D(d))?;
+    // We hash the re-encoded dict, which only matches if the original was
+    // bencoded the canonical way
+    if torrent.info_hash != *info_hash {
+        return Err(String::from("Metadata isn't canonically bencoded"));
+    }
+    Ok(torrent)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::btree_map::BTreeMap;
+
+    use crypto::digest::Digest;
+    use crypto::sha1::Sha1;
+
+    use bencode::*;
+    use id::PeerId;
+    use peer::bitfield::Bitfield;
+    use peer::connection::{Event, PeerConnection};
+    use peer::extension::ExtensionRegistry;
+    use peer::pipe::pipe;
+    use peer::wire::Handshake;
+    use super::*;
+
+    // A single file info dict that's a bit over two pieces long
+    fn info() -> (Vec<u8>, [u8; 20]) {
+        let num_checksums = 2000;
+        let mut d = BTreeMap::new();
+        d.insert(String::from("name"), Benc::S(b"moose_dance.mkv".to_vec()));
+        d.insert(String::from("piece length"), Benc::I(16384));
+        d.insert(String::from("length"), Benc::I(num_checksums * 16384 - 5));
+        d.insert(String::from("pieces"), Benc::S(vec![7; num_checksums as usize * 20]));
+        let info = enc_benc(&Benc::D(d));
+
+        let mut hasher = Sha1::new();
+        hasher.input(&info);
+        let mut hash = [0u8; 20];
+        hasher.result(&mut hash);
+        (info, hash)
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let msgs = vec!(
+            MetadataMessage::Request(3),
+            MetadataMessage::Reject(0),
+            MetadataMessage::Data { piece: 1, total_size: 20000, data: b"d4:spami1ee".to_vec() }
+        );
+        for msg in msgs.into_iter() {
+            assert_eq!(MetadataMessage::decode(&msg.encode()).unwrap(), msg);
+        }
+
+        assert_eq!(MetadataMessage::Request(0).encode(), b"d8:msg_typei0e5:piecei0ee".to_vec());
+        assert!(MetadataMessage::decode(b"d8:msg_typei7e5:piecei0ee").is_err());
+        assert!(MetadataMessage::decode(b"d8:msg_typei1e5:piecei0ee").is_err());
+        assert!(MetadataMessage::decode(b"d8:msg_typei0ee").is_err());
+    }
+
+    #[test]
+    fn bad_pieces_and_hashes() {
+        let (info, hash) = info();
+        let size = info.len() as u32;
+
+        let mut fetcher = MetadataFetcher::new(hash);
+        assert!(fetcher.set_size(0).is_err());
+        fetcher.set_size(size).unwrap();
+        assert!(fetcher.set_size(size + 1).is_err());
+
+        assert_eq!(fetcher.next_request(), Some(0));
+        assert_eq!(fetcher.next_request(), Some(1));
+        fetcher.cancel(0);
+        assert_eq!(fetcher.next_request(), Some(0));
+
+        // Wrong length for a piece that isn't the last
+        assert!(fetcher.received(0, size, vec![0; 100]).is_err());
+        assert!(fetcher.received(9, size, vec![0; 100]).is_err());
+
+        // Garbage of the right shape fails the hash, and we start over
+        let mut last = None;
+        for i in 0..3 {
+            last = Some(match super::serve(&info, i) {
+                MetadataMessage::Data { piece, total_size, mut data } => {
+                    data[0] ^= 1;
+                    fetcher.received(piece, total_size, data)
+                },
+                _ => unreachable!()
+            });
+        }
+        assert!(last.unwrap().is_err());
+        assert_eq!(fetcher.total_size(), None);
+        assert_eq!(fetcher.next_request(), None);
+
+        assert_eq!(super::serve(&info, 3), MetadataMessage::Reject(3));
+    }
+
+    #[test]
+    fn fetch_over_the_wire() {
+        let (info, hash) = info();
+        let mut registry = ExtensionRegistry::new();
+        registry.register(EXTENSION_NAME);
+
+        // They've got the torrent and say which pieces they have, like a
+        // real peer would, before we know how many there are
+        let (a, b) = pipe();
+        let mut us = PeerConnection::without_metadata(a, Handshake::new(hash, PeerId([b'a'; 20])), registry.clone());
+        let mut them = PeerConnection::with_extensions(b, Handshake::new(hash, PeerId([b'b'; 20])), 2000,
+                Some(registry.clone()));
+        us.poll().unwrap();
+        them.poll().unwrap();
+        let mut theirs = Bitfield::new(2000);
+        theirs.set(3);
+        them.send_bitfield(&theirs).unwrap();
+        them.have(1999).unwrap();
+
+        us.send_extended_handshake(&registry.handshake()).unwrap();
+        let mut hs = registry.handshake();
+        hs.metadata_size = Some(info.len() as u32);
+        them.send_extended_handshake(&hs).unwrap();
+        them.poll().unwrap();
+
+        let mut fetcher = MetadataFetcher::new(hash);
+        let mut fetched = None;
+        while fetched.is_none() {
+            for event in us.poll().unwrap().into_iter() {
+                match event {
+                    Event::ExtendedHandshake(hs) => fetcher.set_size(hs.metadata_size.unwrap()).unwrap(),
+                    Event::Extended { payload, .. } => match MetadataMessage::decode(&payload).unwrap() {
+                        MetadataMessage::Data { piece, total_size, data } => {
+                            fetched = fetcher.received(piece, total_size, data).unwrap();
+                        },
+                        _ => unreachable!()
+                    },
+                    _ => ()
+                }
+            }
+            while let Some(piece) = fetcher.next_request() {
+                us.send_extended(EXTENSION_NAME, MetadataMessage::Request(piece).encode()).unwrap();
+            }
+
+            // Serve whatever they asked for
+            for event in them.poll().unwrap().into_iter() {
+                if let Event::Extended { payload, .. } = event {
+                    match MetadataMessage::decode(&payload).unwrap() {
+                        MetadataMessage::Request(piece) => {
+                            them.send_extended(EXTENSION_NAME, super::serve(&info, piece).encode()).unwrap();
+                        },
+                        _ => unreachable!()
+                    }
+                }
+            }
+        }
+
+        let fetched = fetched.unwrap();
+        assert_eq!(fetched, info);
+        let torrent = info_to_torrent(&fetched, &hash, &[vec!(String::from("udp://tracker.invalid:80"))]).unwrap();
+        assert_eq!(torrent.info_hash, hash);
+        assert_eq!(torrent.chunk_checksum.len(), 2000);
+        assert_eq!(torrent.announce_list[0][0], "udp://tracker.invalid:80");
+
+        // Now what they said they had can be checked and used
+        us.set_num_pieces(torrent.chunk_checksum.len()).unwrap();
+        assert_eq!(us.peer_pieces().iter_set().collect::<Vec<usize>>(), vec!(3, 1999));
+    }
+}
@@ -0,0 +1,15 @@
+pub mod bitfield;
+pub mod choker;
+pub mod connection;
+pub mod dh;
+pub mod extension;
+pub mod fast;
+pub mod metadata;
+pub mod mse;
+pub mod pex;
+pub mod picker;
+#[cfg(test)]
+mod pipe;
+pub mod pool;
+pub mod stream;
+pub mod wire;
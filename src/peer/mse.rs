@@ -0,0 +1,762 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crypto::digest::Digest;
+use crypto::rc4::Rc4;
+use crypto::sha1::Sha1;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use rand::{Rng, thread_rng};
+
+use peer::dh::{DhKey, KEY_LEN};
+use peer::wire::PROTOCOL;
+
+// crypto_provide and crypto_select bits
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+// The most padding either end can put after its key, or anywhere else
+const MAX_PAD: usize = 512;
+// Verification constant, which tells the other end it found the right key
+const VC: [u8; 8] = [0; 8];
+// Bytes encrypted ahead of what the stream's taken before writes block
+const MAX_PENDING: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    // RC4 or nothing; plaintext peers get turned away
+    Forced,
+    // Offer both and pick RC4 whenever the other end offers it, but still
+    // talk to plaintext peers. Those show up as MseError::NotSupported when
+    // we connect to them, and it's up to the caller to reconnect and try
+    // again with encryption disabled.
+    Preferred,
+    // Plain BitTorrent handshakes only
+    Disabled
+}
+
+impl EncryptionPolicy {
+    pub fn crypto_provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+            EncryptionPolicy::Preferred => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Disabled => 0
+        }
+    }
+
+    fn select(self, provided: u32) -> Option<u32> {
+        let both = self.crypto_provide() & provided;
+        if both & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if both & CRYPTO_PLAINTEXT != 0 {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MseError {
+    Io(io::Error),
+    // The other end hung up
+    Closed,
+    // We connected and the other end hung up on our key (or sent a plain
+    // handshake back), which is what a peer that doesn't do MSE does
+    NotSupported,
+    // A plain BitTorrent handshake, with encryption forced
+    PlaintextRefused,
+    // An encrypted handshake, with encryption disabled
+    EncryptionRefused,
+    // Their public key would have given away the shared secret
+    BadKey,
+    // What we were scanning for wasn't anywhere in the padding
+    NoSync,
+    UnknownInfoHash,
+    BadVc,
+    BadPadding(usize),
+    // Nothing both ends support, with what they offered or picked
+    NoCommonCrypto(u32)
+}
+
+impl fmt::Display for MseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MseError::Io(ref e) => write!(f, "I/O error: {}", e),
+            MseError::Closed => write!(f, "Connection closed"),
+            MseError::NotSupported => write!(f, "Peer doesn't support encryption"),
+            MseError::PlaintextRefused => write!(f, "Peer didn't encrypt, and encryption is forced"),
+            MseError::EncryptionRefused => write!(f, "Peer wants encryption, and it's disabled"),
+            MseError::BadKey => write!(f, "Peer sent a bad public key"),
+            MseError::NoSync => write!(f, "Couldn't find the end of the peer's padding"),
+            MseError::UnknownInfoHash => write!(f, "Peer wants a torrent we don't have"),
+            MseError::BadVc => write!(f, "Peer's verification constant is wrong"),
+            MseError::BadPadding(len) => write!(f, "Padding of {} bytes is too long", len),
+            MseError::NoCommonCrypto(c) => write!(f, "No crypto method in common with {:#x}", c)
+        }
+    }
+}
+
+impl Error for MseError {}
+
+impl From<io::Error> for MseError {
+    fn from(e: io::Error) -> MseError {
+        MseError::Io(e)
+    }
+}
+
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for p in parts.iter() {
+        hasher.input(p);
+    }
+    let mut hash = [0u8; 20];
+    hasher.result(&mut hash);
+    hash
+}
+
+// keyA encrypts what the side that connected sends, keyB the other way.
+// The first 1024 bytes of keystream are thrown away, since RC4's early
+// output is known to be biased.
+fn rc4(name: &[u8], secret: &[u8; KEY_LEN], skey: &[u8; 20]) -> Rc4 {
+    let mut cipher = Rc4::new(&sha1(&[name, secret, skey]));
+    let mut discard = [0u8; 1024];
+    cipher.process(&[0u8; 1024], &mut discard);
+    cipher
+}
+
+fn apply(cipher: &mut Rc4, data: &mut [u8]) {
+    let input = data.to_vec();
+    cipher.process(&input, data);
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = thread_rng();
+    let mut pad = vec![0u8; rng.gen_range(0, MAX_PAD + 1)];
+    rng.fill_bytes(&mut pad);
+    pad
+}
+
+fn is_hangup(e: &MseError) -> bool {
+    match *e {
+        MseError::Closed => true,
+        MseError::Io(ref e) => matches!(e.kind(),
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe),
+        _ => false
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    // Incoming: is it a plain handshake or a public key?
+    Sniff,
+    WaitKey,
+    // Connecting: their encrypted VC marks the end of their padding
+    SyncVc,
+    ReadSelect,
+    ReadPadD(usize),
+    // Incoming: HASH('req1', S) marks the end of their padding
+    SyncReq1,
+    ReadSkey,
+    ReadProvide,
+    ReadPadC(usize),
+    ReadIa(usize),
+    Done
+}
+
+// Message stream encryption, the obfuscated handshake: a Diffie-Hellman
+// exchange, then RC4 keyed from the shared secret and the info hash. The
+// info hash never goes over the wire, so the side being connected to works
+// out which torrent it's for by trying every one it has. Wrap it around a
+// fresh non-blocking connection, poll() until it's done, then take the
+// stream out and hand it to PeerConnection like any other.
+pub struct MseHandshake<S: Read + Write> {
+    stream: S,
+    outgoing: bool,
+    policy: EncryptionPolicy,
+    provide: u32,
+    select: u32,
+    // Ours if we connected, whichever one matched if they did
+    info_hash: Option<[u8; 20]>,
+    known: Vec<[u8; 20]>,
+    key: DhKey,
+    secret: [u8; KEY_LEN],
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    // Read but not used yet, still encrypted
+    inbuf: Vec<u8>,
+    // Decrypted and meant for whoever reads the stream next
+    plain: Vec<u8>,
+    outbuf: Vec<u8>,
+    step: Step
+}
+
+impl<S: Read + Write> MseHandshake<S> {
+    // With encryption disabled this finishes straight away, as a plain
+    // connection
+    pub fn outgoing(stream: S, info_hash: [u8; 20], policy: EncryptionPolicy) -> MseHandshake<S> {
+        let mut hs = MseHandshake::new(stream, true, policy, Some(info_hash), Vec::new());
+        if policy == EncryptionPolicy::Disabled {
+            hs.step = Step::Done;
+        } else {
+            hs.send_key();
+            hs.step = Step::WaitKey;
+        }
+        hs
+    }
+
+    // Plain handshakes are let through untouched, unless encryption is forced
+    pub fn incoming(stream: S, info_hashes: Vec<[u8; 20]>, policy: EncryptionPolicy) -> MseHandshake<S> {
+        MseHandshake::new(stream, false, policy, None, info_hashes)
+    }
+
+    fn new(stream: S, outgoing: bool, policy: EncryptionPolicy, info_hash: Option<[u8; 20]>, known: Vec<[u8; 20]>) -> MseHandshake<S> {
+        MseHandshake {
+            stream,
+            outgoing,
+            policy,
+            provide: policy.crypto_provide(),
+            select: 0,
+            info_hash,
+            known,
+            key: DhKey::generate(),
+            secret: [0; KEY_LEN],
+            encrypt: None,
+            decrypt: None,
+            inbuf: Vec::new(),
+            plain: Vec::new(),
+            outbuf: Vec::new(),
+            step: Step::Sniff
+        }
+    }
+
+    // Changes what we offer when connecting, say to plaintext only for an
+    // obfuscated handshake without paying for RC4 on everything after it
+    pub fn set_crypto_provide(&mut self, provide: u32) {
+        self.provide = provide & (CRYPTO_RC4 | CRYPTO_PLAINTEXT);
+    }
+
+    // True once it's done and the stream's ready for into_stream()
+    pub fn poll(&mut self) -> Result<bool, MseError> {
+        let result = self.poll_steps();
+        if self.outgoing && self.step == Step::WaitKey {
+            if let Err(ref e) = result {
+                if is_hangup(e) {
+                    return Err(MseError::NotSupported);
+                }
+            }
+        }
+        result
+    }
+
+    fn poll_steps(&mut self) -> Result<bool, MseError> {
+        let mut buf = [0u8; 4096];
+        loop {
+            self.flush()?;
+            if self.step == Step::Done {
+                return Ok(true);
+            }
+            if self.advance()? {
+                continue;
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) => { return Err(MseError::Closed); },
+                Ok(n) => self.inbuf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { return Ok(false); },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => { return Err(MseError::Io(e)); }
+            }
+        }
+    }
+
+    pub fn into_stream(self) -> MseStream<S> {
+        let rc4 = self.select == CRYPTO_RC4;
+        let mut plain = self.plain;
+        let mut rest = self.inbuf;
+        let mut decrypt = if rc4 { self.decrypt } else { None };
+        if let Some(ref mut cipher) = decrypt {
+            apply(cipher, &mut rest);
+        }
+        plain.extend(rest);
+        MseStream {
+            stream: self.stream,
+            encrypt: if rc4 { self.encrypt } else { None },
+            decrypt,
+            buffered: plain,
+            pending: self.outbuf,
+            info_hash: self.info_hash
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), MseError> {
+        while !self.outbuf.is_empty() {
+            match self.stream.write(&self.outbuf) {
+                Ok(0) => { return Err(MseError::Closed); },
+                Ok(n) => { self.outbuf.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => { return Err(MseError::Io(e)); }
+            }
+        }
+        Ok(())
+    }
+
+    fn send_key(&mut self) {
+        self.outbuf.extend_from_slice(self.key.public());
+        self.outbuf.extend(random_pad());
+    }
+
+    fn send_encrypted(&mut self, data: &[u8]) {
+        let mut data = data.to_vec();
+        apply(self.encrypt.as_mut().unwrap(), &mut data);
+        self.outbuf.extend(data);
+    }
+
+    // Takes n bytes off the front of what we've read, decrypting them
+    fn take(&mut self, n: usize) -> Option<Vec<u8>> {
+        if self.inbuf.len() < n {
+            return None;
+        }
+        let mut out: Vec<u8> = self.inbuf.drain(..n).collect();
+        if let Some(ref mut cipher) = self.decrypt {
+            apply(cipher, &mut out);
+        }
+        Some(out)
+    }
+
+    // Moves things along as far as what we've read allows. False if it
+    // needs more.
+    fn advance(&mut self) -> Result<bool, MseError> {
+        match self.step {
+            Step::Sniff => {
+                if self.inbuf.len() < 1 + PROTOCOL.len() {
+                    return Ok(false);
+                }
+                if self.inbuf[0] as usize == PROTOCOL.len() && &self.inbuf[1..1 + PROTOCOL.len()] == PROTOCOL {
+                    if self.policy == EncryptionPolicy::Forced {
+                        return Err(MseError::PlaintextRefused);
+                    }
+                    self.step = Step::Done;
+                } else if self.policy == EncryptionPolicy::Disabled {
+                    return Err(MseError::EncryptionRefused);
+                } else {
+                    self.send_key();
+                    self.step = Step::WaitKey;
+                }
+            },
+            Step::WaitKey => {
+                if self.outgoing && self.inbuf.len() > PROTOCOL.len() && self.inbuf[0] as usize == PROTOCOL.len()
+                        && &self.inbuf[1..1 + PROTOCOL.len()] == PROTOCOL {
+                    return Err(MseError::NotSupported);
+                }
+                let theirs = match self.take(KEY_LEN) {
+                    Some(key) => key,
+                    None => { return Ok(false); }
+                };
+                let mut key = [0u8; KEY_LEN];
+                key.copy_from_slice(&theirs);
+                self.secret = self.key.shared_secret(&key).ok_or(MseError::BadKey)?;
+
+                if self.outgoing {
+                    let skey = self.info_hash.unwrap();
+                    let req3 = sha1(&[b"req3", &self.secret]);
+                    let mut req2 = sha1(&[b"req2", &skey]);
+                    for (a, b) in req2.iter_mut().zip(req3.iter()) {
+                        *a ^= *b;
+                    }
+                    self.outbuf.extend_from_slice(&sha1(&[b"req1", &self.secret]));
+                    self.outbuf.extend_from_slice(&req2);
+
+                    self.encrypt = Some(rc4(b"keyA", &self.secret, &skey));
+                    self.decrypt = Some(rc4(b"keyB", &self.secret, &skey));
+                    // VC, crypto_provide, and no PadC or initial payload;
+                    // padding's only worth having where it's not encrypted
+                    let mut msg = VC.to_vec();
+                    msg.extend_from_slice(&self.provide.to_be_bytes());
+                    msg.extend_from_slice(&[0, 0, 0, 0]);
+                    self.send_encrypted(&msg);
+                    self.step = Step::SyncVc;
+                } else {
+                    self.step = Step::SyncReq1;
+                }
+            },
+            Step::SyncVc => {
+                // What VC looks like encrypted, without using up our keystream
+                let mut marker = VC;
+                apply(&mut self.decrypt.unwrap(), &mut marker);
+                match find(&self.inbuf, &marker) {
+                    Some(i) => {
+                        self.inbuf.drain(..i);
+                        self.take(VC.len());
+                        self.step = Step::ReadSelect;
+                    },
+                    None if self.inbuf.len() >= MAX_PAD + VC.len() => { return Err(MseError::NoSync); },
+                    None => { return Ok(false); }
+                }
+            },
+            Step::ReadSelect => {
+                let msg = match self.take(6) {
+                    Some(msg) => msg,
+                    None => { return Ok(false); }
+                };
+                let select = u32::from_be_bytes([msg[0], msg[1], msg[2], msg[3]]);
+                if select.count_ones() != 1 || select & self.provide == 0 {
+                    return Err(MseError::NoCommonCrypto(select));
+                }
+                self.select = select;
+                self.step = Step::ReadPadD(self.pad_len(msg[4], msg[5])?);
+            },
+            Step::ReadPadD(len) => {
+                if self.take(len).is_none() {
+                    return Ok(false);
+                }
+                self.step = Step::Done;
+            },
+            Step::SyncReq1 => {
+                let req1 = sha1(&[b"req1", &self.secret]);
+                match find(&self.inbuf, &req1) {
+                    Some(i) => {
+                        self.inbuf.drain(..i + req1.len());
+                        self.step = Step::ReadSkey;
+                    },
+                    None if self.inbuf.len() >= MAX_PAD + req1.len() => { return Err(MseError::NoSync); },
+                    None => { return Ok(false); }
+                }
+            },
+            Step::ReadSkey => {
+                let hash = match self.take(20) {
+                    Some(hash) => hash,
+                    None => { return Ok(false); }
+                };
+                let req3 = sha1(&[b"req3", &self.secret]);
+                let skey = *self.known.iter().find(|h| {
+                    sha1(&[b"req2", &h[..]]).iter().zip(req3.iter()).map(|(a, b)| a ^ b).eq(hash.iter().cloned())
+                }).ok_or(MseError::UnknownInfoHash)?;
+                self.info_hash = Some(skey);
+                self.decrypt = Some(rc4(b"keyA", &self.secret, &skey));
+                self.encrypt = Some(rc4(b"keyB", &self.secret, &skey));
+                self.step = Step::ReadProvide;
+            },
+            Step::ReadProvide => {
+                let msg = match self.take(VC.len() + 6) {
+                    Some(msg) => msg,
+                    None => { return Ok(false); }
+                };
+                if msg[..8] != VC {
+                    return Err(MseError::BadVc);
+                }
+                self.provide = u32::from_be_bytes([msg[8], msg[9], msg[10], msg[11]]);
+                self.step = Step::ReadPadC(self.pad_len(msg[12], msg[13])?);
+            },
+            Step::ReadPadC(len) => {
+                let msg = match self.take(len + 2) {
+                    Some(msg) => msg,
+                    None => { return Ok(false); }
+                };
+                self.step = Step::ReadIa(u16::from_be_bytes([msg[len], msg[len + 1]]) as usize);
+            },
+            Step::ReadIa(len) => {
+                // Their initial payload, usually the BitTorrent handshake
+                self.plain = match self.take(len) {
+                    Some(ia) => ia,
+                    None => { return Ok(false); }
+                };
+                self.select = self.policy.select(self.provide).ok_or(MseError::NoCommonCrypto(self.provide))?;
+                let mut msg = VC.to_vec();
+                msg.extend_from_slice(&self.select.to_be_bytes());
+                msg.extend_from_slice(&[0, 0]);
+                self.send_encrypted(&msg);
+                self.step = Step::Done;
+            },
+            Step::Done => { return Ok(false); }
+        }
+        Ok(true)
+    }
+
+    fn pad_len(&self, hi: u8, lo: u8) -> Result<usize, MseError> {
+        let len = u16::from_be_bytes([hi, lo]) as usize;
+        if len > MAX_PAD {
+            return Err(MseError::BadPadding(len));
+        }
+        Ok(len)
+    }
+}
+
+// A connection after the handshake, encrypted or not depending on what was
+// picked. Like the stream inside, it's non-blocking, but whatever it's
+// encrypted gets written eventually, so writes can take more than the
+// stream would and hold onto the rest.
+pub struct MseStream<S: Read + Write> {
+    stream: S,
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    // Read during the handshake, already decrypted
+    buffered: Vec<u8>,
+    // Encrypted, waiting for the stream to take it
+    pending: Vec<u8>,
+    info_hash: Option<[u8; 20]>
+}
+
+impl<S: Read + Write> MseStream<S> {
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    // None for plain connections from other people, where it's in the
+    // BitTorrent handshake instead
+    pub fn info_hash(&self) -> Option<[u8; 20]> {
+        self.info_hash
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => { return Err(io::Error::new(io::ErrorKind::WriteZero, "stream closed")); },
+                Ok(n) => { self.pending.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => { return Err(e); }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Read for MseStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.write_pending()?;
+        if !self.buffered.is_empty() {
+            let n = buf.len().min(self.buffered.len());
+            buf[..n].copy_from_slice(&self.buffered[..n]);
+            self.buffered.drain(..n);
+            return Ok(n);
+        }
+        let n = self.stream.read(buf)?;
+        if let Some(ref mut cipher) = self.decrypt {
+            apply(cipher, &mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for MseStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_pending()?;
+        if self.pending.is_empty() && self.encrypt.is_none() {
+            return self.stream.write(buf);
+        }
+        let n = buf.len().min(MAX_PENDING.saturating_sub(self.pending.len()));
+        if n == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "too much waiting to be written"));
+        }
+        let mut data = buf[..n].to_vec();
+        if let Some(ref mut cipher) = self.encrypt {
+            apply(cipher, &mut data);
+        }
+        self.pending.extend(data);
+        self.write_pending()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use id::PeerId;
+    use peer::connection::{Event, PeerConnection};
+    use peer::pipe::{pipe, PipeEnd};
+    use peer::wire::Handshake;
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    // Polls both until they're done or one fails
+    fn run(mut a: MseHandshake<PipeEnd>, mut b: MseHandshake<PipeEnd>) -> Result<(MseStream<PipeEnd>, MseStream<PipeEnd>), MseError> {
+        let (mut a_done, mut b_done) = (false, false);
+        for _ in 0..100 {
+            a_done = a_done || a.poll()?;
+            b_done = b_done || b.poll()?;
+            if a_done && b_done {
+                return Ok((a.into_stream(), b.into_stream()));
+            }
+        }
+        panic!("Handshake never finished");
+    }
+
+    fn read_all(s: &mut MseStream<PipeEnd>) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 1000];
+        while let Ok(n) = s.read(&mut buf) {
+            out.extend_from_slice(&buf[..n]);
+        }
+        out
+    }
+
+    #[test]
+    fn encrypted_handshake() {
+        let (a, b) = pipe();
+        let us = MseHandshake::outgoing(a, INFO_HASH, EncryptionPolicy::Preferred);
+        let them = MseHandshake::incoming(b, vec!([1; 20], INFO_HASH), EncryptionPolicy::Preferred);
+        let (us, them) = run(us, them).unwrap();
+        assert!(us.is_encrypted() && them.is_encrypted());
+        assert_eq!(them.info_hash(), Some(INFO_HASH));
+
+        // The BitTorrent handshake goes over it like any other stream
+        let mut us = PeerConnection::new(us, Handshake::new(INFO_HASH, PeerId([b'a'; 20])), 10);
+        let mut them = PeerConnection::new(them, Handshake::new(INFO_HASH, PeerId([b'b'; 20])), 10);
+        let mut got = None;
+        for _ in 0..10 {
+            them.poll().unwrap();
+            for e in us.poll().unwrap() {
+                if let Event::Handshake(h) = e { got = Some(h.peer_id); }
+            }
+        }
+        assert_eq!(got, Some(PeerId([b'b'; 20])));
+    }
+
+    #[test]
+    fn plaintext_select() {
+        let (a, b) = pipe();
+        let mut us = MseHandshake::outgoing(a, INFO_HASH, EncryptionPolicy::Preferred);
+        us.set_crypto_provide(CRYPTO_PLAINTEXT);
+        let them = MseHandshake::incoming(b, vec!(INFO_HASH), EncryptionPolicy::Preferred);
+        let (mut us, mut them) = run(us, them).unwrap();
+        assert!(!us.is_encrypted() && !them.is_encrypted());
+
+        us.write_all(b"hello").unwrap();
+        them.write_all(b"there").unwrap();
+        assert_eq!(read_all(&mut them), b"hello".to_vec());
+        assert_eq!(read_all(&mut us), b"there".to_vec());
+
+        // Forced only takes RC4
+        let (a, b) = pipe();
+        let mut us = MseHandshake::outgoing(a, INFO_HASH, EncryptionPolicy::Preferred);
+        us.set_crypto_provide(CRYPTO_PLAINTEXT);
+        let them = MseHandshake::incoming(b, vec!(INFO_HASH), EncryptionPolicy::Forced);
+        match run(us, them) {
+            Err(MseError::NoCommonCrypto(CRYPTO_PLAINTEXT)) => (),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn encrypted_data() {
+        let (a, b) = pipe();
+        let us = MseHandshake::outgoing(a, INFO_HASH, EncryptionPolicy::Forced);
+        let them = MseHandshake::incoming(b, vec!(INFO_HASH), EncryptionPolicy::Preferred);
+        let (mut us, mut them) = run(us, them).unwrap();
+
+        // Lots at once, so some of it has to wait for the pipe to be read
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 256) as u8).collect();
+        let mut sent = 0;
+        let mut got = Vec::new();
+        while got.len() < data.len() {
+            if sent < data.len() {
+                if let Ok(n) = us.write(&data[sent..]) {
+                    sent += n;
+                }
+            }
+            got.extend(read_all(&mut them));
+        }
+        assert!(got == data);
+    }
+
+    #[test]
+    fn policies_and_failures() {
+        // Plain handshakes go through untouched, unless encryption is forced
+        let hs = Handshake::new(INFO_HASH, PeerId([b'a'; 20])).encode();
+        let (mut a, b) = pipe();
+        a.write_all(&hs).unwrap();
+        let mut them = MseHandshake::incoming(b, vec!(INFO_HASH), EncryptionPolicy::Preferred);
+        assert!(them.poll().unwrap());
+        let mut them = them.into_stream();
+        assert!(!them.is_encrypted());
+        assert_eq!(read_all(&mut them), hs);
+
+        let (mut a, b) = pipe();
+        a.write_all(&hs).unwrap();
+        match MseHandshake::incoming(b, vec!(INFO_HASH), EncryptionPolicy::Forced).poll() {
+            Err(MseError::PlaintextRefused) => (),
+            _ => unreachable!()
+        }
+
+        // Disabled doesn't bother with any of it
+        let (a, b) = pipe();
+        let mut us = MseHandshake::outgoing(a, INFO_HASH, EncryptionPolicy::Disabled);
+        assert!(us.poll().unwrap());
+        let mut them = MseHandshake::incoming(b, vec!(INFO_HASH), EncryptionPolicy::Preferred);
+        assert!(!them.poll().unwrap());
+
+        let (a, b) = pipe();
+        let us = MseHandshake::outgoing(a, INFO_HASH, EncryptionPolicy::Preferred);
+        let them = MseHandshake::incoming(b, vec!(INFO_HASH), EncryptionPolicy::Disabled);
+        match run(us, them) {
+            Err(MseError::EncryptionRefused) => (),
+            _ => unreachable!()
+        }
+
+        // Plaintext-only peers hang up on our key, or answer it with their
+        // own handshake, and a plain connection gets through after that
+        let (a, mut b) = pipe();
+        let mut us = MseHandshake::outgoing(a, INFO_HASH, EncryptionPolicy::Preferred);
+        assert!(!us.poll().unwrap());
+        let mut key = [0u8; KEY_LEN];
+        b.read_exact(&mut key).unwrap();
+        drop(b);
+        match us.poll() {
+            Err(MseError::NotSupported) => (),
+            _ => unreachable!()
+        }
+
+        let (a, mut b) = pipe();
+        let mut us = MseHandshake::outgoing(a, INFO_HASH, EncryptionPolicy::Forced);
+        assert!(!us.poll().unwrap());
+        b.write_all(&hs).unwrap();
+        match us.poll() {
+            Err(MseError::NotSupported) => (),
+            _ => unreachable!()
+        }
+
+        let (a, mut b) = pipe();
+        let mut us = MseHandshake::outgoing(a, INFO_HASH, EncryptionPolicy::Disabled);
+        assert!(us.poll().unwrap());
+        let mut us = us.into_stream();
+        assert!(!us.is_encrypted());
+        us.write_all(&hs).unwrap();
+        b.write_all(&hs).unwrap();
+        assert_eq!(read_all(&mut us), hs);
+
+        // A torrent they don't have
+        let (a, b) = pipe();
+        let us = MseHandshake::outgoing(a, INFO_HASH, EncryptionPolicy::Preferred);
+        let them = MseHandshake::incoming(b, vec!([1; 20]), EncryptionPolicy::Preferred);
+        match run(us, them) {
+            Err(MseError::UnknownInfoHash) => (),
+            _ => unreachable!()
+        }
+
+        // Junk that never gets to the marker
+        let (mut a, b) = pipe();
+        let mut them = MseHandshake::incoming(b, vec!(INFO_HASH), EncryptionPolicy::Preferred);
+        a.write_all(&[0x55; KEY_LEN]).unwrap();
+        a.write_all(&[0xaa; 600]).unwrap();
+        match them.poll() {
+            Err(MseError::NoSync) => (),
+            _ => unreachable!()
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::btree_map::BTreeMap;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bencode::*;
+use peer::extension::ExtensionRegistry;
+use torrent::TorrentMetadata;
+use tracker::{compact_addr, parse_compact_peers, parse_compact_peers6};
+
+// What we register ut_pex (BEP 11) under in the extension registry
+pub const EXTENSION_NAME: &str = "ut_pex";
+// Everyone sticks to one message a minute per peer
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+// Cap on added (and separately dropped) peers in one message
+pub const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+// Bits in the added.f flags byte
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+// How ut_pex should get into a registry, so it's never offered in a private
+// torrent's extended handshake. Gives back the ID if it was registered.
+pub fn register(registry: &mut ExtensionRegistry, torrent: &TorrentMetadata) -> Option<u8> {
+    if !torrent.allows_peer_discovery() {
+        return None;
+    }
+    Some(registry.register(EXTENSION_NAME))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PexPeer {
+    pub addr: SocketAddr,
+    pub flags: u8
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<PexPeer>,
+    pub dropped: Vec<SocketAddr>
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut added = Vec::new();
+        let mut added_f = Vec::new();
+        let mut added6 = Vec::new();
+        let mut added6_f = Vec::new();
+        for p in self.added.iter() {
+            if p.addr.is_ipv4() {
+                added.extend(compact_addr(&p.addr));
+                added_f.push(p.flags);
+            } else {
+                added6.extend(compact_addr(&p.addr));
+                added6_f.push(p.flags);
+            }
+        }
+
+        let mut dropped = Vec::new();
+        let mut dropped6 = Vec::new();
+        for addr in self.dropped.iter() {
+            if addr.is_ipv4() {
+                dropped.extend(compact_addr(addr));
+            } else {
+                dropped6.extend(compact_addr(addr));
+            }
+        }
+
+        let mut d = BTreeMap::new();
+        d.insert(String::from("added"), Benc::S(added));
+        d.insert(String::from("added.f"), Benc::S(added_f));
+        d.insert(String::from("dropped"), Benc::S(dropped));
+        // Plenty of clients never send the IPv6 keys, so leave them out when empty
+        if !added6.is_empty() {
+            d.insert(String::from("added6"), Benc::S(added6));
+            d.insert(String::from("added6.f"), Benc::S(added6_f));
+        }
+        if !dropped6.is_empty() {
+            d.insert(String::from("dropped6"), Benc::S(dropped6));
+        }
+        enc_benc(&Benc::D(d))
+    }
+
+    // Missing keys just mean nothing changed. Missing or short flags are
+    // treated as no flags at all.
+    pub fn decode(payload: &[u8]) -> Result<PexMessage, String> {
+        let d = match dec_benc(&payload.to_vec()) {
+            Ok(Benc::D(d)) => d,
+            Ok(_) => { return Err(String::from("PEX message is not a dictionary!")); },
+            Err(e) => { return Err(format!("Unable to decode PEX message: {}", e)); }
+        };
+
+        let bytes = |key: &str| -> Result<Vec<u8>, String> {
+            match d.get(key) {
+                Some(Benc::S(s)) => Ok(s.clone()),
+                Some(_) => Err(format!("'{}' in PEX message is not a string!", key)),
+                None => Ok(Vec::new())
+            }
+        };
+
+        let mut out = PexMessage::default();
+        let added = parse_compact_peers(&bytes("added")?)?;
+        let added_f = bytes("added.f")?;
+        let added6 = parse_compact_peers6(&bytes("added6")?)?;
+        let added6_f = bytes("added6.f")?;
+        for (i, p) in added.iter().enumerate() {
+            out.added.push(PexPeer { addr: p.addr, flags: added_f.get(i).cloned().unwrap_or(0) });
+        }
+        for (i, p) in added6.iter().enumerate() {
+            out.added.push(PexPeer { addr: p.addr, flags: added6_f.get(i).cloned().unwrap_or(0) });
+        }
+
+        for p in parse_compact_peers(&bytes("dropped")?)?.into_iter()
+                .chain(parse_compact_peers6(&bytes("dropped6")?)?) {
+            out.dropped.push(p.addr);
+        }
+
+        Ok(out)
+    }
+}
+
+// PEX bookkeeping for one connection: what we've told the peer about so far,
+// and when we last sent or heard anything. For private torrents it stays
+// off, never sending anything and turning down everything that comes in.
+pub struct PexState {
+    enabled: bool,
+    // Peers the other end currently thinks we're connected to
+    advertised: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>
+}
+
+impl PexState {
+    pub fn new(torrent: &TorrentMetadata) -> PexState {
+        PexState {
+            enabled: torrent.allows_peer_discovery(),
+            advertised: HashSet::new(),
+            last_sent: None,
+            last_received: None
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Diffs our current peer list against what we last sent, and hands back a
+    // message if there's anything new and it's been long enough. The first
+    // message goes out straight away. Anything past the per-message cap waits
+    // for the next round.
+    pub fn update(&mut self, connected: &[PexPeer], now: Instant) -> Option<PexMessage> {
+        if !self.enabled {
+            return None;
+        }
+        if let Some(t) = self.last_sent {
+            if now < t + PEX_INTERVAL {
+                return None;
+            }
+        }
+
+        let current: HashSet<SocketAddr> = connected.iter().map(|p| p.addr).collect();
+        let added: Vec<PexPeer> = connected.iter()
+            .filter(|p| !self.advertised.contains(&p.addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .cloned()
+            .collect();
+        let dropped: Vec<SocketAddr> = self.advertised.iter()
+            .filter(|a| !current.contains(a))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .cloned()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        for p in added.iter() {
+            self.advertised.insert(p.addr);
+        }
+        for a in dropped.iter() {
+            self.advertised.remove(a);
+        }
+        self.last_sent = Some(now);
+        Some(PexMessage { added, dropped })
+    }
+
+    // False if the peer is sending faster than once a minute, or PEX is off
+    // for this torrent, in which case the message should be ignored
+    pub fn accept(&mut self, now: Instant) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if let Some(t) = self.last_received {
+            if now < t + PEX_INTERVAL {
+                return false;
+            }
+        }
+        self.last_received = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use torrent::test_torrent;
+    use super::*;
+
+    fn peer(s: &str, flags: u8) -> PexPeer {
+        PexPeer { addr: s.parse().unwrap(), flags }
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let msg = PexMessage {
+            added: vec!(peer("10.0.0.1:6881", FLAG_SEED | FLAG_UTP), peer("[2001:db8::1]:51413", FLAG_ENCRYPTION)),
+            dropped: vec!("10.0.0.2:80".parse().unwrap(), "[::1]:1".parse().unwrap())
+        };
+        let bytes = msg.encode();
+        assert_eq!(PexMessage::decode(&bytes).unwrap(), msg);
+
+        let v4_only = PexMessage { added: vec!(peer("1.2.3.4:258", FLAG_REACHABLE)), dropped: Vec::new() };
+        assert_eq!(&v4_only.encode()[..], &b"d5:added6:\x01\x02\x03\x04\x01\x027:added.f1:\x107:dropped0:e"[..]);
+    }
+
+    #[test]
+    fn lenient_decoding() {
+        // No flags at all, and no dropped key
+        let msg = PexMessage::decode(b"d5:added6:\x01\x02\x03\x04\x01\x02e").unwrap();
+        assert_eq!(msg.added, vec!(peer("1.2.3.4:258", 0)));
+        assert!(msg.dropped.is_empty());
+
+        assert!(PexMessage::decode(b"d5:added5:\x01\x02\x03\x04\x01e").is_err());
+        assert!(PexMessage::decode(b"d5:addedi1ee").is_err());
+    }
+
+    #[test]
+    fn diffs_and_rate_limits() {
+        let mut state = PexState::new(&test_torrent(false));
+        let now = Instant::now();
+        let a = peer("10.0.0.1:1", 0);
+        let b = peer("10.0.0.2:2", FLAG_SEED);
+        let c = peer("10.0.0.3:3", 0);
+
+        let first = state.update(&[a, b], now).unwrap();
+        assert_eq!(first.added.len(), 2);
+
+        // Too soon, even though something changed
+        assert_eq!(state.update(&[b, c], now + Duration::from_secs(30)), None);
+
+        let second = state.update(&[b, c], now + Duration::from_secs(60)).unwrap();
+        assert_eq!(second.added, vec!(c));
+        assert_eq!(second.dropped, vec!(a.addr));
+
+        // Nothing changed, nothing to say
+        assert_eq!(state.update(&[b, c], now + Duration::from_secs(200)), None);
+
+        assert!(state.accept(now));
+        assert!(!state.accept(now + Duration::from_secs(59)));
+        assert!(state.accept(now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn caps_message_size() {
+        let mut state = PexState::new(&test_torrent(false));
+        let now = Instant::now();
+        let peers: Vec<PexPeer> = (0..80).map(|i| PexPeer {
+            addr: SocketAddr::new("10.0.0.1".parse().unwrap(), 1000 + i),
+            flags: 0
+        }).collect();
+
+        assert_eq!(state.update(&peers, now).unwrap().added.len(), MAX_PEERS_PER_MESSAGE);
+        assert_eq!(state.update(&peers, now + PEX_INTERVAL).unwrap().added.len(), 30);
+    }
+
+    #[test]
+    fn off_for_private_torrents() {
+        let now = Instant::now();
+        let peers = [peer("10.0.0.1:1", 0)];
+
+        let mut state = PexState::new(&test_torrent(true));
+        assert!(!state.is_enabled());
+        assert_eq!(state.update(&peers, now), None);
+        assert_eq!(state.update(&peers, now + PEX_INTERVAL), None);
+        assert!(!state.accept(now));
+
+        let mut state = PexState::new(&test_torrent(false));
+        assert!(state.is_enabled());
+        assert!(state.update(&peers, now).is_some());
+        assert!(state.accept(now));
+    }
+
+    #[test]
+    fn not_advertised_for_private_torrents() {
+        let mut registry = ExtensionRegistry::new();
+        registry.register("ut_metadata");
+        assert_eq!(register(&mut registry, &test_torrent(true)), None);
+        assert!(!registry.handshake().m.contains_key(EXTENSION_NAME));
+
+        assert_eq!(register(&mut registry, &test_torrent(false)), Some(2));
+        assert_eq!(registry.handshake().m.get(EXTENSION_NAME), Some(&2));
+    }
+}
@@ -0,0 +1,551 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use rand::{Rng, thread_rng};
+
+use peer::bitfield::Bitfield;
+use peer::connection::BlockRequest;
+use torrent::{TorrentFile, TorrentMetadata};
+
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+// Until we have this many pieces we pick at random instead of rarest first.
+// The rarest pieces are the slowest to get, and early on having anything
+// at all to trade matters more.
+pub const RANDOM_FIRST_PIECES: usize = 4;
+// In endgame, how many peers beyond the first a block can be asked for
+pub const DEFAULT_ENDGAME_DUPLICATES: usize = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    // Don't download at all
+    Skip,
+    Low,
+    Normal,
+    High
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockState {
+    Missing,
+    Requested,
+    Received
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub state: BlockState,
+    // Who it's been asked for and hasn't come from yet
+    pub requested_from: Vec<SocketAddr>
+}
+
+// What to do after a block comes in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockDone {
+    // Every block's in, so the piece is ready to check
+    pub piece_complete: bool,
+    // The other peers it was asked for in endgame, who should get a cancel
+    pub cancel: Vec<SocketAddr>
+}
+
+// Decides which blocks to ask peers for. It keeps count of how many peers
+// have each piece, from their bitfields and haves, and goes for the rarest
+// pieces we want first, breaking ties at random so everybody isn't chasing
+// the same ones. Pieces that are already started get finished before new
+// ones are, so there are never more partial pieces around than needed.
+//
+// Once every block we still need has been asked for, it goes into endgame:
+// the last few blocks get asked for again from other peers, so one slow
+// peer can't hold up the end of the download. How many extra copies of a
+// block can be out at once is capped by endgame_duplicates.
+pub struct PiecePicker {
+    piece_len: u32,
+    total_len: u64,
+    availability: Vec<u32>,
+    have: Bitfield,
+    priorities: Vec<Priority>,
+    // Blocks for pieces we've started on
+    partial: HashMap<u32, Vec<Block>>,
+    endgame_duplicates: usize
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize, piece_len: u32, total_len: u64) -> PiecePicker {
+        PiecePicker {
+            piece_len,
+            total_len,
+            availability: vec![0; num_pieces],
+            have: Bitfield::new(num_pieces),
+            priorities: vec![Priority::Normal; num_pieces],
+            partial: HashMap::new(),
+            endgame_duplicates: DEFAULT_ENDGAME_DUPLICATES
+        }
+    }
+
+    pub fn from_metadata(meta: &TorrentMetadata) -> PiecePicker {
+        let total = meta.files.iter().map(|f| f.length as u64).sum();
+        PiecePicker::new(meta.chunk_checksum.len(), meta.chunk_size as u32, total)
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.availability.len()
+    }
+
+    // The last piece is whatever's left over
+    pub fn piece_size(&self, index: u32) -> u32 {
+        if index as usize + 1 == self.num_pieces() {
+            (self.total_len - u64::from(self.piece_len) * (self.num_pieces() as u64 - 1)) as u32
+        } else {
+            self.piece_len
+        }
+    }
+
+    pub fn block_count(&self, index: u32) -> usize {
+        self.piece_size(index).div_ceil(BLOCK_SIZE) as usize
+    }
+
+    fn block(&self, index: u32, block: usize) -> BlockRequest {
+        let begin = block as u32 * BLOCK_SIZE;
+        BlockRequest { index, begin, length: BLOCK_SIZE.min(self.piece_size(index) - begin) }
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    // Everything we want is in, skipped pieces aside
+    pub fn is_complete(&self) -> bool {
+        (0..self.num_pieces()).all(|i| self.have.has(i) || self.priorities[i] == Priority::Skip)
+    }
+
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability[index as usize]
+    }
+
+    pub fn peer_bitfield(&mut self, pieces: &Bitfield) {
+        for i in pieces.iter_set() {
+            self.availability[i] += 1;
+        }
+    }
+
+    pub fn peer_have(&mut self, index: u32) {
+        if let Some(a) = self.availability.get_mut(index as usize) {
+            *a += 1;
+        }
+    }
+
+    // Takes a peer's pieces back off when it goes away
+    pub fn peer_gone(&mut self, pieces: &Bitfield) {
+        for i in pieces.iter_set() {
+            self.availability[i] = self.availability[i].saturating_sub(1);
+        }
+    }
+
+    pub fn priority(&self, index: u32) -> Priority {
+        self.priorities[index as usize]
+    }
+
+    pub fn set_piece_priority(&mut self, index: u32, priority: Priority) {
+        self.priorities[index as usize] = priority;
+    }
+
+    // One priority per file, in the torrent's order. Pieces that straddle
+    // files take the highest priority of any file they touch, since skipping
+    // them would leave a hole in a file we want.
+    pub fn set_file_priorities(&mut self, files: &[TorrentFile], priorities: &[Priority]) {
+        let mut piece_priorities = vec![Priority::Skip; self.num_pieces()];
+        let mut offset = 0u64;
+        for (file, &priority) in files.iter().zip(priorities.iter()) {
+            let len = file.length as u64;
+            if len > 0 {
+                let first = (offset / u64::from(self.piece_len)) as usize;
+                let last = ((offset + len - 1) / u64::from(self.piece_len)) as usize;
+                for p in piece_priorities[first..=last.min(self.num_pieces() - 1)].iter_mut() {
+                    *p = (*p).max(priority);
+                }
+            }
+            offset += len;
+        }
+        self.priorities = piece_priorities;
+    }
+
+    pub fn blocks(&self, index: u32) -> Option<&[Block]> {
+        self.partial.get(&index).map(|b| &b[..])
+    }
+
+    // 0 turns endgame off
+    pub fn set_endgame_duplicates(&mut self, duplicates: usize) {
+        self.endgame_duplicates = duplicates;
+    }
+
+    // Nothing we want is left that hasn't been asked for
+    pub fn in_endgame(&self) -> bool {
+        (0..self.num_pieces()).all(|i| {
+            self.have.has(i) || self.priorities[i] == Priority::Skip || self.partial.get(&(i as u32))
+                .is_some_and(|blocks| blocks.iter().all(|b| b.state != BlockState::Missing))
+        })
+    }
+
+    // Up to max blocks for this peer to send us, marked as requested from
+    // it. Blocks nobody's been asked for come first; in endgame, blocks
+    // other peers are still sending fill in the rest.
+    pub fn pick(&mut self, addr: SocketAddr, peer: &Bitfield, max: usize) -> Vec<BlockRequest> {
+        let mut picked = self.pick_missing(addr, peer, max);
+        if picked.len() < max && self.endgame_duplicates > 0 && self.in_endgame() {
+            let more = max - picked.len();
+            picked.extend(self.pick_duplicates(addr, peer, more));
+        }
+        picked
+    }
+
+    fn wanted(&self, peer: &Bitfield, i: usize) -> bool {
+        peer.has(i) && !self.have.has(i) && self.priorities[i] != Priority::Skip
+    }
+
+    fn pick_missing(&mut self, addr: SocketAddr, peer: &Bitfield, max: usize) -> Vec<BlockRequest> {
+        let mut picked = Vec::new();
+        let wanted = |i: usize| self.wanted(peer, i);
+
+        // Pieces we've started come first
+        let mut started: Vec<u32> = self.partial.keys().cloned().filter(|&i| wanted(i as usize)).collect();
+        started.sort_by_key(|&i| (Reverse(self.priorities[i as usize]), self.availability[i as usize], i));
+
+        let mut fresh: Vec<u32> = (0..self.num_pieces() as u32).filter(|&i| wanted(i as usize) && !self.partial.contains_key(&i)).collect();
+        thread_rng().shuffle(&mut fresh);
+        if self.have.count() < RANDOM_FIRST_PIECES {
+            fresh.sort_by_key(|&i| Reverse(self.priorities[i as usize]));
+        } else {
+            fresh.sort_by_key(|&i| (Reverse(self.priorities[i as usize]), self.availability[i as usize]));
+        }
+
+        for index in started.into_iter().chain(fresh) {
+            if picked.len() >= max {
+                break;
+            }
+            let count = self.block_count(index);
+            let blocks = self.partial.entry(index).or_insert_with(|| vec![Block { state: BlockState::Missing, requested_from: Vec::new() }; count]);
+            let mut new = Vec::new();
+            for (b, block) in blocks.iter_mut().enumerate() {
+                if picked.len() + new.len() >= max {
+                    break;
+                }
+                if block.state == BlockState::Missing {
+                    block.state = BlockState::Requested;
+                    block.requested_from.push(addr);
+                    new.push(b);
+                }
+            }
+            picked.extend(new.into_iter().map(|b| self.block(index, b)));
+        }
+        picked
+    }
+
+    // Blocks that are out with the fewest other peers go first, and the
+    // rarer the piece the better, since those are the likeliest to be stuck
+    // with one slow peer
+    fn pick_duplicates(&mut self, addr: SocketAddr, peer: &Bitfield, max: usize) -> Vec<BlockRequest> {
+        let limit = 1 + self.endgame_duplicates;
+        let mut candidates = Vec::new();
+        for (&index, blocks) in self.partial.iter() {
+            if !self.wanted(peer, index as usize) {
+                continue;
+            }
+            for (b, block) in blocks.iter().enumerate() {
+                if block.state == BlockState::Requested && block.requested_from.len() < limit && !block.requested_from.contains(&addr) {
+                    candidates.push((block.requested_from.len(), self.availability[index as usize], index, b));
+                }
+            }
+        }
+        candidates.sort();
+        candidates.truncate(max);
+
+        let mut picked = Vec::new();
+        for (_, _, index, b) in candidates.into_iter() {
+            self.partial.get_mut(&index).unwrap()[b].requested_from.push(addr);
+            picked.push(self.block(index, b));
+        }
+        picked
+    }
+
+    // A block came in from `from`. Anyone else it was asked for should be
+    // told not to bother.
+    pub fn block_received(&mut self, req: &BlockRequest, from: SocketAddr) -> BlockDone {
+        let b = (req.begin / BLOCK_SIZE) as usize;
+        match self.partial.get_mut(&req.index) {
+            Some(blocks) if b < blocks.len() => {
+                let block = &mut blocks[b];
+                // A late copy, and whoever got here first already finished
+                // the piece off if that was the last block
+                if block.state == BlockState::Received {
+                    return BlockDone { piece_complete: false, cancel: Vec::new() };
+                }
+                block.state = BlockState::Received;
+                let cancel = block.requested_from.drain(..).filter(|a| *a != from).collect();
+                BlockDone { piece_complete: blocks.iter().all(|b| b.state == BlockState::Received), cancel }
+            },
+            _ => BlockDone { piece_complete: false, cancel: Vec::new() }
+        }
+    }
+
+    // A request that won't be coming after all: choked, rejected, or the
+    // peer went away. Once nobody's sending it, someone else can have it.
+    pub fn request_dropped(&mut self, req: &BlockRequest, from: SocketAddr) {
+        let b = (req.begin / BLOCK_SIZE) as usize;
+        if let Some(block) = self.partial.get_mut(&req.index).and_then(|blocks| blocks.get_mut(b)) {
+            block.requested_from.retain(|a| *a != from);
+            if block.state == BlockState::Requested && block.requested_from.is_empty() {
+                block.state = BlockState::Missing;
+            }
+        }
+    }
+
+    // Forgets everything we'd asked a peer that's gone for, so its blocks
+    // aren't left waiting on it or counting against the endgame limit. Its
+    // pieces come off the availability with peer_gone().
+    pub fn peer_disconnected(&mut self, addr: SocketAddr) {
+        for blocks in self.partial.values_mut() {
+            for block in blocks.iter_mut() {
+                block.requested_from.retain(|a| *a != addr);
+                if block.state == BlockState::Requested && block.requested_from.is_empty() {
+                    block.state = BlockState::Missing;
+                }
+            }
+        }
+    }
+
+    pub fn piece_passed(&mut self, index: u32) {
+        self.partial.remove(&index);
+        self.have.set(index as usize);
+    }
+
+    // The hash didn't match, so it all has to come again
+    pub fn piece_failed(&mut self, index: u32) {
+        self.partial.remove(&index);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use peer::bitfield::Bitfield;
+    use torrent::TorrentFile;
+    use super::*;
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    fn bits(len: usize, set: &[usize]) -> Bitfield {
+        let mut b = Bitfield::new(len);
+        for &i in set.iter() {
+            b.set(i);
+        }
+        b
+    }
+
+    // Ten one-block pieces, with the first RANDOM_FIRST_PIECES already in
+    fn started_picker() -> PiecePicker {
+        let mut p = PiecePicker::new(10, BLOCK_SIZE, 10 * u64::from(BLOCK_SIZE));
+        for i in 0..RANDOM_FIRST_PIECES as u32 {
+            p.piece_passed(i);
+        }
+        p
+    }
+
+    #[test]
+    fn rarest_first() {
+        let mut p = started_picker();
+        p.peer_bitfield(&Bitfield::full(10));
+        p.peer_bitfield(&bits(10, &[5, 6, 7, 8, 9]));
+        p.peer_bitfield(&bits(10, &[6, 7, 8, 9]));
+        p.peer_have(8);
+        p.peer_have(9);
+        assert_eq!(p.availability(4), 1);
+        assert_eq!(p.availability(9), 4);
+
+        let picked: Vec<u32> = p.pick(peer(1), &Bitfield::full(10), 10).iter().map(|r| r.index).collect();
+        assert_eq!(&picked[..2], &[4, 5]);
+        assert!(picked[2] == 6 || picked[2] == 7);
+        assert!(picked[4] == 8 || picked[4] == 9);
+        assert_eq!(picked.len(), 6);
+
+        // Everything's been asked for now
+        assert!(p.pick(peer(1), &Bitfield::full(10), 10).is_empty());
+        p.peer_gone(&Bitfield::full(10));
+        assert_eq!(p.availability(4), 0);
+    }
+
+    #[test]
+    fn random_first() {
+        // With nothing yet, the rarest piece isn't always first
+        let mut firsts = Vec::new();
+        for _ in 0..50 {
+            let mut p = PiecePicker::new(10, BLOCK_SIZE, 10 * u64::from(BLOCK_SIZE));
+            p.peer_bitfield(&Bitfield::full(10));
+            p.peer_bitfield(&bits(10, &[1, 2, 3, 4, 5, 6, 7, 8, 9]));
+            firsts.push(p.pick(peer(1), &Bitfield::full(10), 1)[0].index);
+        }
+        assert!(firsts.iter().any(|&i| i != 0));
+
+        // But once we have a few, it always is
+        for _ in 0..10 {
+            let mut p = started_picker();
+            p.peer_bitfield(&Bitfield::full(10));
+            p.peer_bitfield(&bits(10, &[4, 5, 6, 7, 8]));
+            assert_eq!(p.pick(peer(1), &Bitfield::full(10), 1)[0].index, 9);
+        }
+    }
+
+    #[test]
+    fn blocks() {
+        // Three pieces of 40 KiB, and a last one of 1000 bytes
+        let piece = 40 * 1024;
+        let mut p = PiecePicker::new(4, piece, 3 * u64::from(piece) + 1000);
+        assert_eq!(p.block_count(0), 3);
+        assert_eq!(p.block_count(3), 1);
+        assert_eq!(p.piece_size(3), 1000);
+
+        let has = bits(4, &[0, 3]);
+        let mut picked = p.pick(peer(1), &has, 2);
+        picked.extend(p.pick(peer(1), &has, 10));
+        picked.sort();
+        assert_eq!(picked, vec!(
+            BlockRequest { index: 0, begin: 0, length: BLOCK_SIZE },
+            BlockRequest { index: 0, begin: BLOCK_SIZE, length: BLOCK_SIZE },
+            BlockRequest { index: 0, begin: 2 * BLOCK_SIZE, length: 8 * 1024 },
+            BlockRequest { index: 3, begin: 0, length: 1000 }
+        ));
+
+        // A dropped block goes to the next peer before any new piece does
+        p.request_dropped(&picked[1], peer(1));
+        assert_eq!(p.blocks(0).unwrap()[1], Block { state: BlockState::Missing, requested_from: Vec::new() });
+        assert_eq!(p.pick(peer(2), &Bitfield::full(4), 1), vec!(picked[1]));
+        assert_eq!(p.blocks(0).unwrap()[1].requested_from, vec!(peer(2)));
+
+        assert!(!p.block_received(&picked[0], peer(1)).piece_complete);
+        assert!(!p.block_received(&picked[1], peer(2)).piece_complete);
+        assert_eq!(p.block_received(&picked[2], peer(1)), BlockDone { piece_complete: true, cancel: Vec::new() });
+
+        // A bad hash means starting over
+        p.piece_failed(0);
+        assert!(p.blocks(0).is_none());
+        assert_eq!(p.pick(peer(1), &has, 10).len(), 3);
+        p.piece_passed(0);
+        p.piece_passed(3);
+        assert!(p.pick(peer(1), &has, 10).is_empty());
+        assert!(p.have().has(0) && !p.is_complete());
+    }
+
+    #[test]
+    fn file_priorities() {
+        // Pieces of 100 bytes: the first file is pieces 0-2, the second
+        // 2-6, the last 6-9
+        let files = vec!(
+            TorrentFile { path: vec!(String::from("a")), length: 250 },
+            TorrentFile { path: vec!(String::from("b")), length: 400 },
+            TorrentFile { path: vec!(String::from("empty")), length: 0 },
+            TorrentFile { path: vec!(String::from("c")), length: 350 }
+        );
+        let mut p = PiecePicker::new(10, 100, 1000);
+        p.set_file_priorities(&files, &[Priority::Low, Priority::Skip, Priority::Normal, Priority::High]);
+        let expect = [Priority::Low, Priority::Low, Priority::Low, Priority::Skip, Priority::Skip, Priority::Skip,
+                      Priority::High, Priority::High, Priority::High, Priority::High];
+        for (i, e) in expect.iter().enumerate() {
+            assert_eq!(p.priority(i as u32), *e);
+        }
+
+        let picked: Vec<u32> = p.pick(peer(1), &Bitfield::full(10), 10).iter().map(|r| r.index).collect();
+        assert_eq!(picked.len(), 7);
+        assert!(picked[..4].iter().all(|&i| i >= 6));
+        assert!(picked[4..].iter().all(|&i| i <= 2));
+
+        for i in [0, 1, 2, 6, 7, 8, 9].iter() {
+            p.piece_passed(*i);
+        }
+        assert!(p.is_complete());
+    }
+
+    #[test]
+    fn endgame() {
+        // Two pieces of two blocks each
+        let mut p = PiecePicker::new(2, 2 * BLOCK_SIZE, 4 * u64::from(BLOCK_SIZE));
+        let all = Bitfield::full(2);
+        let first = p.pick(peer(1), &all, 3);
+        assert_eq!(first.len(), 3);
+        assert!(!p.in_endgame());
+
+        // The last block goes to peer 2, and then everything's out, so peer 3
+        // gets copies of what the other two are still sending, the ones
+        // only one peer's been asked for going first
+        let last = p.pick(peer(2), &all, 1);
+        assert!(p.in_endgame());
+        let dups = p.pick(peer(3), &all, 10);
+        assert_eq!(dups.len(), 4);
+        assert!(dups.contains(&last[0]));
+
+        // One extra copy each is the limit
+        assert!(p.pick(peer(1), &all, 10).is_empty());
+        assert!(p.pick(peer(4), &all, 10).is_empty());
+        p.set_endgame_duplicates(2);
+        // And nobody gets a block twice
+        assert_eq!(p.pick(peer(1), &all, 10), vec!(last[0]));
+        assert_eq!(p.pick(peer(4), &all, 10).len(), 3);
+
+        // The first copy in gets the others cancelled
+        let done = p.block_received(&last[0], peer(3));
+        let mut cancel = done.cancel;
+        cancel.sort();
+        assert_eq!(cancel, vec!(peer(1), peer(2)));
+        assert!(p.pick(peer(5), &all, 10).iter().all(|r| *r != last[0]));
+
+        // Copies that turn up late don't change anything
+        let late = p.block_received(&last[0], peer(2));
+        assert_eq!(late, BlockDone { piece_complete: false, cancel: Vec::new() });
+
+        // A block only goes back to missing once every copy's been dropped
+        p.request_dropped(&first[0], peer(1));
+        assert_eq!(p.blocks(first[0].index).unwrap()[0].state, BlockState::Requested);
+        for a in 1..6 {
+            p.request_dropped(&first[0], peer(a));
+        }
+        assert_eq!(p.blocks(first[0].index).unwrap()[0].state, BlockState::Missing);
+        assert!(!p.in_endgame());
+
+        // With endgame off nobody doubles up
+        p.set_endgame_duplicates(0);
+        assert_eq!(p.pick(peer(6), &all, 10), vec!(first[0]));
+        assert!(p.pick(peer(7), &all, 10).is_empty());
+
+        // A late copy of a piece's last block doesn't finish it a second time
+        let mut p = PiecePicker::new(1, BLOCK_SIZE, u64::from(BLOCK_SIZE));
+        let all = Bitfield::full(1);
+        let only = p.pick(peer(1), &all, 1);
+        assert_eq!(p.pick(peer(2), &all, 1), only);
+        assert_eq!(p.block_received(&only[0], peer(2)), BlockDone { piece_complete: true, cancel: vec!(peer(1)) });
+        assert_eq!(p.block_received(&only[0], peer(1)), BlockDone { piece_complete: false, cancel: Vec::new() });
+    }
+
+    #[test]
+    fn disconnects() {
+        // One piece of two blocks, out to peer 1 and copied to peer 2
+        let mut p = PiecePicker::new(1, 2 * BLOCK_SIZE, 2 * u64::from(BLOCK_SIZE));
+        let all = Bitfield::full(1);
+        let first = p.pick(peer(1), &all, 2);
+        assert_eq!(p.pick(peer(2), &all, 2).len(), 2);
+        assert!(p.pick(peer(3), &all, 2).is_empty());
+
+        // Peer 1 going frees up its copies, but peer 2's still sending
+        p.peer_disconnected(peer(1));
+        for block in p.blocks(0).unwrap().iter() {
+            assert_eq!(*block, Block { state: BlockState::Requested, requested_from: vec!(peer(2)) });
+        }
+        assert_eq!(p.pick(peer(3), &all, 2).len(), 2);
+
+        // Once nobody is, they're up for grabs again
+        p.peer_disconnected(peer(2));
+        p.peer_disconnected(peer(3));
+        assert!(p.blocks(0).unwrap().iter().all(|b| b.state == BlockState::Missing));
+        assert_eq!(p.pick(peer(4), &all, 2), first);
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+struct Half {
+    buf: VecDeque<u8>,
+    closed: bool
+}
+
+// One end of an in-memory, non-blocking duplex pipe. Reads with nothing to
+// read give WouldBlock like a non-blocking socket would, and once the other
+// end is dropped they give 0 like a closed one. Handy for running two peers
+// against each other without any sockets involved.
+pub struct PipeEnd {
+    rx: Arc<Mutex<Half>>,
+    tx: Arc<Mutex<Half>>
+}
+
+pub fn pipe() -> (PipeEnd, PipeEnd) {
+    let a = Arc::new(Mutex::new(Half { buf: VecDeque::new(), closed: false }));
+    let b = Arc::new(Mutex::new(Half { buf: VecDeque::new(), closed: false }));
+    (PipeEnd { rx: a.clone(), tx: b.clone() }, PipeEnd { rx: b, tx: a })
+}
+
+impl Read for PipeEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut rx = self.rx.lock().unwrap();
+        if rx.buf.is_empty() {
+            return if rx.closed { Ok(0) } else { Err(io::Error::new(io::ErrorKind::WouldBlock, "pipe is empty")) };
+        }
+        let n = buf.len().min(rx.buf.len());
+        for (dst, src) in buf.iter_mut().zip(rx.buf.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for PipeEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut tx = self.tx.lock().unwrap();
+        if tx.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "other end of the pipe is gone"));
+        }
+        tx.buf.extend(buf.iter());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        self.tx.lock().unwrap().closed = true;
+        self.rx.lock().unwrap().closed = true;
+    }
+}
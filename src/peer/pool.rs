@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+// Where we first heard about a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    Incoming
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolPeer {
+    pub addr: SocketAddr,
+    pub source: PeerSource,
+    pub added: Instant
+}
+
+// Every peer we know of for one torrent, whether or not we're connected.
+// Trackers, the DHT, PEX and LSD all feed into it, and whatever makes
+// connections draws from it. Once it's full, new peers are dropped until
+// someone's removed.
+pub struct PeerPool {
+    peers: HashMap<SocketAddr, PoolPeer>,
+    max_peers: usize
+}
+
+impl PeerPool {
+    pub fn new(max_peers: usize) -> PeerPool {
+        PeerPool { peers: HashMap::new(), max_peers }
+    }
+
+    // True if the peer is new to us. Peers we already know keep their
+    // original source.
+    pub fn add(&mut self, addr: SocketAddr, source: PeerSource, now: Instant) -> bool {
+        if self.peers.contains_key(&addr) || self.peers.len() >= self.max_peers {
+            return false;
+        }
+        self.peers.insert(addr, PoolPeer { addr, source, added: now });
+        true
+    }
+
+    // How many were new
+    pub fn extend<I: IntoIterator<Item=SocketAddr>>(&mut self, addrs: I, source: PeerSource, now: Instant) -> usize {
+        addrs.into_iter().filter(|a| self.add(*a, source, now)).count()
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> Option<PoolPeer> {
+        self.peers.remove(addr)
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PoolPeer> {
+        self.peers.get(addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn peers(&self) -> Vec<&PoolPeer> {
+        self.peers.values().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use super::{PeerPool, PeerSource};
+
+    #[test]
+    fn add_and_remove() {
+        let now = Instant::now();
+        let mut pool = PeerPool::new(2);
+        let a = "10.0.0.1:6881".parse().unwrap();
+        let b = "[2001:db8::1]:6881".parse().unwrap();
+
+        assert!(pool.add(a, PeerSource::Tracker, now));
+        assert!(!pool.add(a, PeerSource::Lsd, now));
+        assert_eq!(pool.get(&a).unwrap().source, PeerSource::Tracker);
+
+        assert_eq!(pool.extend(vec!(a, b, "10.0.0.3:1".parse().unwrap()), PeerSource::Dht, now), 1);
+        assert_eq!(pool.len(), 2);
+
+        assert!(pool.remove(&a).is_some());
+        assert!(pool.add("10.0.0.3:1".parse().unwrap(), PeerSource::Pex, now));
+    }
+}
@@ -0,0 +1,124 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use utp::socket::UtpStream;
+
+// Whatever a peer connection runs over. PeerConnection wants a
+// non-blocking stream, so TCP ones are switched over when they're wrapped.
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream)
+}
+
+impl PeerStream {
+    pub fn tcp(stream: TcpStream) -> io::Result<PeerStream> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(PeerStream::Tcp(stream))
+    }
+
+    pub fn utp(stream: UtpStream) -> PeerStream {
+        PeerStream::Utp(stream)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            PeerStream::Tcp(ref s) => s.peer_addr(),
+            PeerStream::Utp(ref s) => Ok(s.peer_addr())
+        }
+    }
+
+    pub fn is_utp(&self) -> bool {
+        matches!(*self, PeerStream::Utp(_))
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            PeerStream::Tcp(ref mut s) => s.read(buf),
+            PeerStream::Utp(ref mut s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            PeerStream::Tcp(ref mut s) => s.write(buf),
+            PeerStream::Utp(ref mut s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            PeerStream::Tcp(ref mut s) => s.flush(),
+            PeerStream::Utp(ref mut s) => s.flush()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use id::PeerId;
+    use peer::connection::{Event, PeerConnection};
+    use peer::wire::Handshake;
+    use utp::socket::{UtpConfig, UtpSocket};
+    use super::PeerStream;
+
+    const PIECES: usize = 10;
+
+    // Runs the handshake both ways, polling `drive` in between for whatever
+    // needs it
+    fn handshake<F: FnMut()>(a: PeerStream, b: PeerStream, mut drive: F) {
+        let mut us = PeerConnection::new(a, Handshake::new([1; 20], PeerId([b'a'; 20])), PIECES);
+        let mut them = PeerConnection::new(b, Handshake::new([1; 20], PeerId([b'b'; 20])), PIECES);
+        let (mut ours, mut theirs) = (None, None);
+        let start = Instant::now();
+        while ours.is_none() || theirs.is_none() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            drive();
+            for e in us.poll().unwrap() {
+                if let Event::Handshake(h) = e { ours = Some(h.peer_id); }
+            }
+            for e in them.poll().unwrap() {
+                if let Event::Handshake(h) = e { theirs = Some(h.peer_id); }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(ours, Some(PeerId([b'b'; 20])));
+        assert_eq!(theirs, Some(PeerId([b'a'; 20])));
+    }
+
+    #[test]
+    fn tcp_and_utp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+        let (a, b) = (PeerStream::tcp(a).unwrap(), PeerStream::tcp(b).unwrap());
+        assert!(!a.is_utp());
+        assert_eq!(a.peer_addr().unwrap(), listener.local_addr().unwrap());
+        handshake(a, b, || ());
+
+        let mut x = UtpSocket::bind("127.0.0.1:0", UtpConfig::default()).unwrap();
+        let mut y = UtpSocket::bind("127.0.0.1:0", UtpConfig::default()).unwrap();
+        let a = PeerStream::utp(x.connect(y.local_addr().unwrap(), Instant::now()));
+        let mut b = None;
+        while b.is_none() {
+            x.poll(Instant::now());
+            y.poll(Instant::now());
+            b = y.accept();
+        }
+        let b = PeerStream::utp(b.unwrap());
+        assert!(b.is_utp());
+        assert_eq!(b.peer_addr().unwrap(), x.local_addr().unwrap());
+        handshake(a, b, || {
+            x.poll(Instant::now());
+            y.poll(Instant::now());
+        });
+    }
+}
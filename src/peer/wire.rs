@@ -0,0 +1,453 @@
+use std::error::Error;
+use std::fmt;
+
+use id::PeerId;
+
+pub const PROTOCOL: &[u8] = b"BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 49 + 19;
+
+// Big enough for a 16 KiB block, or the bitfield of a torrent with 8M pieces
+pub const DEFAULT_MAX_MESSAGE_LEN: u32 = 1 << 20;
+
+const ID_CHOKE: u8 = 0;
+const ID_UNCHOKE: u8 = 1;
+const ID_INTERESTED: u8 = 2;
+const ID_NOT_INTERESTED: u8 = 3;
+const ID_HAVE: u8 = 4;
+const ID_BITFIELD: u8 = 5;
+const ID_REQUEST: u8 = 6;
+const ID_PIECE: u8 = 7;
+const ID_CANCEL: u8 = 8;
+const ID_PORT: u8 = 9;
+const ID_SUGGEST: u8 = 13;
+const ID_HAVE_ALL: u8 = 14;
+const ID_HAVE_NONE: u8 = 15;
+const ID_REJECT: u8 = 16;
+const ID_ALLOWED_FAST: u8 = 17;
+const ID_EXTENDED: u8 = 20;
+
+// Reserved bit saying we speak the fast extension (BEP 6)
+pub const FAST_BIT: usize = 2;
+// Reserved bit saying we speak the extension protocol (BEP 10)
+pub const EXTENSION_BIT: usize = 20;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: PeerId
+}
+
+impl Handshake {
+    pub fn new(info_hash: [u8; 20], peer_id: PeerId) -> Handshake {
+        Handshake { reserved: [0; 8], info_hash, peer_id }
+    }
+
+    // Reserved bits count from the right the way the BEPs talk about them,
+    // so bit 0 (DHT) is the low bit of the last byte and bit 20 (extension
+    // protocol) is 0x10 in the sixth byte
+    pub fn has_reserved_bit(&self, bit: usize) -> bool {
+        self.reserved[7 - bit / 8] & (1 << (bit % 8)) != 0
+    }
+
+    pub fn set_reserved_bit(&mut self, bit: usize) {
+        self.reserved[7 - bit / 8] |= 1 << (bit % 8);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HANDSHAKE_LEN);
+        out.push(PROTOCOL.len() as u8);
+        out.extend_from_slice(PROTOCOL);
+        out.extend_from_slice(&self.reserved);
+        out.extend_from_slice(&self.info_hash);
+        out.extend_from_slice(self.peer_id.as_bytes());
+        out
+    }
+
+    // Ok(None) means we need more bytes, otherwise we hand back how many we used
+    pub fn decode(buf: &[u8]) -> Result<Option<(Handshake, usize)>, WireError> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        if buf[0] as usize != PROTOCOL.len() {
+            return Err(WireError::BadProtocol);
+        }
+        // Bail on the protocol string as soon as we can, rather than waiting for all 68 bytes
+        let have = buf.len().min(1 + PROTOCOL.len());
+        if buf[1..have] != PROTOCOL[..have - 1] {
+            return Err(WireError::BadProtocol);
+        }
+        if buf.len() < HANDSHAKE_LEN {
+            return Ok(None);
+        }
+
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&buf[20..28]);
+        let mut info_hash = [0u8; 20];
+        info_hash.copy_from_slice(&buf[28..48]);
+        let peer_id = PeerId::from_slice(&buf[48..68]).unwrap();
+
+        Ok(Some((Handshake { reserved, info_hash, peer_id }, HANDSHAKE_LEN)))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, block: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    // The peer's DHT port
+    Port(u16),
+    // The fast extension (BEP 6)
+    Suggest(u32),
+    HaveAll,
+    HaveNone,
+    Reject { index: u32, begin: u32, length: u32 },
+    AllowedFast(u32),
+    // BEP 10; id 0 is the extended handshake, the rest are whatever the
+    // receiving end assigned in its handshake
+    Extended { id: u8, payload: Vec<u8> }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WireError {
+    // The handshake didn't start with "\x13BitTorrent protocol"
+    BadProtocol,
+    // The length prefix was bigger than we're willing to buffer
+    MessageTooLong(u32),
+    // The message was the wrong size for its ID
+    BadLength { id: u8, len: u32 },
+    UnknownMessage(u8)
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WireError::BadProtocol => write!(f, "Peer didn't send a BitTorrent handshake"),
+            WireError::MessageTooLong(len) => write!(f, "Message of {} bytes is too long", len),
+            WireError::BadLength { id, len } => write!(f, "Message {} can't be {} bytes long", id, len),
+            WireError::UnknownMessage(id) => write!(f, "Unknown message ID {}", id)
+        }
+    }
+}
+
+impl Error for WireError {}
+
+fn read_u32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | (b[3] as u32)
+}
+
+fn put_header(out: &mut Vec<u8>, len: u32, id: u8) {
+    out.extend_from_slice(&len.to_be_bytes());
+    out.push(id);
+}
+
+impl Message {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Message::KeepAlive => out.extend_from_slice(&[0, 0, 0, 0]),
+            Message::Choke => put_header(out, 1, ID_CHOKE),
+            Message::Unchoke => put_header(out, 1, ID_UNCHOKE),
+            Message::Interested => put_header(out, 1, ID_INTERESTED),
+            Message::NotInterested => put_header(out, 1, ID_NOT_INTERESTED),
+            Message::HaveAll => put_header(out, 1, ID_HAVE_ALL),
+            Message::HaveNone => put_header(out, 1, ID_HAVE_NONE),
+            Message::Have(index) | Message::Suggest(index) | Message::AllowedFast(index) => {
+                let id = match *self {
+                    Message::Have(_) => ID_HAVE,
+                    Message::Suggest(_) => ID_SUGGEST,
+                    _ => ID_ALLOWED_FAST
+                };
+                put_header(out, 5, id);
+                out.extend_from_slice(&index.to_be_bytes());
+            },
+            Message::Bitfield(ref bits) => {
+                put_header(out, 1 + bits.len() as u32, ID_BITFIELD);
+                out.extend_from_slice(bits);
+            },
+            Message::Request { index, begin, length } | Message::Cancel { index, begin, length }
+                    | Message::Reject { index, begin, length } => {
+                let id = match *self {
+                    Message::Request { .. } => ID_REQUEST,
+                    Message::Cancel { .. } => ID_CANCEL,
+                    _ => ID_REJECT
+                };
+                put_header(out, 13, id);
+                out.extend_from_slice(&index.to_be_bytes());
+                out.extend_from_slice(&begin.to_be_bytes());
+                out.extend_from_slice(&length.to_be_bytes());
+            },
+            Message::Piece { index, begin, ref block } => {
+                put_header(out, 9 + block.len() as u32, ID_PIECE);
+                out.extend_from_slice(&index.to_be_bytes());
+                out.extend_from_slice(&begin.to_be_bytes());
+                out.extend_from_slice(block);
+            },
+            Message::Port(port) => {
+                put_header(out, 3, ID_PORT);
+                out.extend_from_slice(&port.to_be_bytes());
+            },
+            Message::Extended { id, ref payload } => {
+                put_header(out, 2 + payload.len() as u32, ID_EXTENDED);
+                out.push(id);
+                out.extend_from_slice(payload);
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+
+    // Ok(None) means we need more bytes, otherwise we hand back how many we used
+    pub fn decode(buf: &[u8], max_len: u32) -> Result<Option<(Message, usize)>, WireError> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = read_u32(&buf[0..4]);
+        if len == 0 {
+            return Ok(Some((Message::KeepAlive, 4)));
+        }
+        if len > max_len {
+            return Err(WireError::MessageTooLong(len));
+        }
+        if buf.len() < 4 + len as usize {
+            return Ok(None);
+        }
+
+        let id = buf[4];
+        let payload = &buf[5..4 + len as usize];
+        let expect = |want: usize| {
+            if payload.len() == want {
+                Ok(())
+            } else {
+                Err(WireError::BadLength { id, len })
+            }
+        };
+
+        let msg = match id {
+            ID_CHOKE => { expect(0)?; Message::Choke },
+            ID_UNCHOKE => { expect(0)?; Message::Unchoke },
+            ID_INTERESTED => { expect(0)?; Message::Interested },
+            ID_NOT_INTERESTED => { expect(0)?; Message::NotInterested },
+            ID_HAVE => {
+                expect(4)?;
+                Message::Have(read_u32(payload))
+            },
+            ID_BITFIELD => Message::Bitfield(payload.to_vec()),
+            ID_REQUEST => {
+                expect(12)?;
+                Message::Request { index: read_u32(&payload[0..4]), begin: read_u32(&payload[4..8]), length: read_u32(&payload[8..12]) }
+            },
+            ID_PIECE => {
+                if payload.len() < 8 {
+                    return Err(WireError::BadLength { id, len });
+                }
+                Message::Piece { index: read_u32(&payload[0..4]), begin: read_u32(&payload[4..8]), block: payload[8..].to_vec() }
+            },
+            ID_CANCEL => {
+                expect(12)?;
+                Message::Cancel { index: read_u32(&payload[0..4]), begin: read_u32(&payload[4..8]), length: read_u32(&payload[8..12]) }
+            },
+            ID_PORT => {
+                expect(2)?;
+                Message::Port(((payload[0] as u16) << 8) | (payload[1] as u16))
+            },
+            ID_SUGGEST => {
+                expect(4)?;
+                Message::Suggest(read_u32(payload))
+            },
+            ID_HAVE_ALL => { expect(0)?; Message::HaveAll },
+            ID_HAVE_NONE => { expect(0)?; Message::HaveNone },
+            ID_REJECT => {
+                expect(12)?;
+                Message::Reject { index: read_u32(&payload[0..4]), begin: read_u32(&payload[4..8]), length: read_u32(&payload[8..12]) }
+            },
+            ID_ALLOWED_FAST => {
+                expect(4)?;
+                Message::AllowedFast(read_u32(payload))
+            },
+            ID_EXTENDED => {
+                if payload.is_empty() {
+                    return Err(WireError::BadLength { id, len });
+                }
+                Message::Extended { id: payload[0], payload: payload[1..].to_vec() }
+            },
+            _ => { return Err(WireError::UnknownMessage(id)); }
+        };
+
+        Ok(Some((msg, 4 + len as usize)))
+    }
+}
+
+// Buffers up whatever comes off the socket and hands back whole messages.
+// The first thing out of it has to be the handshake.
+pub struct Decoder {
+    buf: Vec<u8>,
+    max_len: u32,
+    got_handshake: bool
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder { buf: Vec::new(), max_len: DEFAULT_MAX_MESSAGE_LEN, got_handshake: false }
+    }
+
+    pub fn set_max_message_len(&mut self, max_len: u32) {
+        self.max_len = max_len;
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    // How many bytes are sitting around waiting for the rest of a message
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn next_handshake(&mut self) -> Result<Option<Handshake>, WireError> {
+        match Handshake::decode(&self.buf)? {
+            Some((handshake, used)) => {
+                self.buf.drain(..used);
+                self.got_handshake = true;
+                Ok(Some(handshake))
+            },
+            None => Ok(None)
+        }
+    }
+
+    pub fn next_message(&mut self) -> Result<Option<Message>, WireError> {
+        if !self.got_handshake {
+            return Ok(None);
+        }
+        match Message::decode(&self.buf, self.max_len)? {
+            Some((msg, used)) => {
+                self.buf.drain(..used);
+                Ok(Some(msg))
+            },
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use id::PeerId;
+    use super::{Decoder, Handshake, Message, WireError, DEFAULT_MAX_MESSAGE_LEN};
+
+    fn handshake() -> Handshake {
+        let mut h = Handshake::new([7; 20], PeerId(*b"-FK0100-abcdefghijkl"));
+        h.set_reserved_bit(20);
+        h
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let h = handshake();
+        let bytes = h.encode();
+        assert_eq!(bytes.len(), 68);
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(bytes[25], 0x10);
+        assert!(h.has_reserved_bit(20));
+        assert!(!h.has_reserved_bit(0));
+
+        assert_eq!(Handshake::decode(&bytes[..67]).unwrap(), None);
+        assert_eq!(Handshake::decode(&bytes).unwrap(), Some((h, 68)));
+
+        assert_eq!(Handshake::decode(b"\x13BitTorrent protocoX"), Err(WireError::BadProtocol));
+        assert_eq!(Handshake::decode(b"\x13Bad"), Err(WireError::BadProtocol));
+        assert_eq!(Handshake::decode(b"GET / HTTP/1.1"), Err(WireError::BadProtocol));
+    }
+
+    #[test]
+    fn message_round_trips() {
+        let messages = vec!(
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(0xdeadbeef),
+            Message::Bitfield(vec!(0xff, 0x80)),
+            Message::Request { index: 1, begin: 16384, length: 16384 },
+            Message::Piece { index: 1, begin: 0, block: vec!(1, 2, 3, 4) },
+            Message::Cancel { index: 1, begin: 16384, length: 16384 },
+            Message::Port(6881),
+            Message::Suggest(4),
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::Reject { index: 1, begin: 0, length: 16384 },
+            Message::AllowedFast(9),
+            Message::Extended { id: 0, payload: b"de".to_vec() }
+        );
+
+        for msg in messages.iter() {
+            let bytes = msg.to_bytes();
+            assert_eq!(Message::decode(&bytes, DEFAULT_MAX_MESSAGE_LEN).unwrap(), Some((msg.clone(), bytes.len())));
+            // Any shorter and we should be asking for more
+            assert_eq!(Message::decode(&bytes[..bytes.len() - 1], DEFAULT_MAX_MESSAGE_LEN).unwrap(), None);
+        }
+
+        assert_eq!(Message::Have(1).to_bytes(), vec!(0, 0, 0, 5, 4, 0, 0, 0, 1));
+        assert_eq!(Message::AllowedFast(1).to_bytes(), vec!(0, 0, 0, 5, 17, 0, 0, 0, 1));
+        assert_eq!(Message::HaveNone.to_bytes(), vec!(0, 0, 0, 1, 15));
+    }
+
+    #[test]
+    fn bad_lengths() {
+        let max = DEFAULT_MAX_MESSAGE_LEN;
+        assert_eq!(Message::decode(&[0, 0, 0, 2, 0, 0], max), Err(WireError::BadLength { id: 0, len: 2 }));
+        assert_eq!(Message::decode(&[0, 0, 0, 4, 4, 0, 0, 0], max), Err(WireError::BadLength { id: 4, len: 4 }));
+        assert_eq!(Message::decode(&[0, 0, 0, 5, 7, 0, 0, 0, 0], max), Err(WireError::BadLength { id: 7, len: 5 }));
+        assert_eq!(Message::decode(&[0, 0, 0, 2, 9, 0], max), Err(WireError::BadLength { id: 9, len: 2 }));
+        assert_eq!(Message::decode(&[0, 0, 0, 2, 14, 0], max), Err(WireError::BadLength { id: 14, len: 2 }));
+        assert_eq!(Message::decode(&[0, 0, 0, 1, 20], max), Err(WireError::BadLength { id: 20, len: 1 }));
+        assert_eq!(Message::decode(&[0, 0, 0, 1, 99], max), Err(WireError::UnknownMessage(99)));
+        assert_eq!(Message::decode(&[0, 0x10, 0, 1], max), Err(WireError::MessageTooLong(0x100001)));
+    }
+
+    #[test]
+    fn incremental_decoding() {
+        let mut stream = handshake().encode();
+        Message::Unchoke.encode(&mut stream);
+        Message::Piece { index: 3, begin: 0, block: vec!(9; 100) }.encode(&mut stream);
+        Message::KeepAlive.encode(&mut stream);
+
+        // Dribble it in a few bytes at a time
+        let mut decoder = Decoder::new();
+        let mut handshakes = Vec::new();
+        let mut messages = Vec::new();
+        for chunk in stream.chunks(7) {
+            decoder.feed(chunk);
+            if handshakes.is_empty() {
+                if let Some(h) = decoder.next_handshake().unwrap() {
+                    handshakes.push(h);
+                }
+            }
+            while let Some(msg) = decoder.next_message().unwrap() {
+                messages.push(msg);
+            }
+        }
+
+        assert_eq!(handshakes, vec!(handshake()));
+        assert_eq!(messages, vec!(Message::Unchoke, Message::Piece { index: 3, begin: 0, block: vec!(9; 100) },
+                Message::KeepAlive));
+        assert_eq!(decoder.buffered(), 0);
+    }
+}
@@ -17,7 +17,19 @@ pub struct TorrentMetadata {
     pub chunk_checksum: Vec<[u8; 20]>,
     pub files: Vec<TorrentFile>,
     pub info_hash: [u8; 20],
-    pub creation_date: Option<i64>
+    pub creation_date: Option<i64>,
+    // Private torrents (BEP 27) only get peers from their trackers
+    pub private: bool,
+    // DHT nodes to bootstrap from, for trackerless torrents (BEP 5)
+    pub nodes: Vec<(String, u16)>
+}
+
+impl TorrentMetadata {
+    // Private torrents must only get peers from their trackers, so no PEX,
+    // LSD or DHT for them
+    pub fn allows_peer_discovery(&self) -> bool {
+        !self.private
+    }
 }
 
 pub fn benc_to_torrent(input: Benc) -> Result<TorrentMetadata, String> {
@@ -33,17 +45,18 @@ pub fn benc_to_torrent(input: Benc) -> Result<TorrentMetadata, String> {
     let name = try!(extract_name(info));
     let chunk_size = try!(extract_chunk_size(info));
     let chunk_checksum = try!(extract_checksums(info));
-    let announce = try!(extract_announce(d));
 
     // Fields which might exist in the info dict
     let files = try!(extract_files(info));
     let single_file_length = try!(extract_single_file_length(info));
-    // let private = ... "private" // TODO: implement me!
+    let private = try!(extract_private(info));
     // let md5sum = ... "md5sum" // TODO: implement me!
 
     // Fields which might exist in the torrent dict
+    let announce = try!(extract_announce(d));
     let announce_list = try!(extract_announce_list(d));
     let creation_date = try!(extract_creation_date(d));
+    let nodes = try!(extract_nodes(d));
     // let comment = ... "comment" // TODO: implement me!
     // let created_by = ... "created by" // TODO: implement me!
     // let encoding = ... "encoding" // TODO: implement me!
@@ -72,8 +85,13 @@ pub fn benc_to_torrent(input: Benc) -> Result<TorrentMetadata, String> {
         return Err(format!("Got {} checksums, but only wanted {}", chunk_checksum.len(), (total_size / chunk_size) + 1));
     }
 
-    // Resolve announce ambiguity
-    let announce_list = announce_list.unwrap_or(vec![vec![announce]]);
+    // Resolve announce ambiguity. Trackerless torrents (DHT only, or built
+    // from a bare magnet link) end up with no trackers at all.
+    let announce_list = match (announce_list, announce) {
+        (Some(al), _) => al,
+        (None, Some(announce)) => vec![vec![announce]],
+        (None, None) => Vec::new()
+    };
 
     // Generate the info hash
     let mut sha1_hasher = Sha1::new();
@@ -89,10 +107,62 @@ pub fn benc_to_torrent(input: Benc) -> Result<TorrentMetadata, String> {
         chunk_checksum: chunk_checksum,
         files: files,
         info_hash: sha1_sum,
-        creation_date: creation_date
+        creation_date: creation_date,
+        private: private,
+        nodes: nodes
     })
 }
 
+fn extract_nodes(d: &BTreeMap<String, Benc>) -> Result<Vec<(String, u16)>, String> {
+    let nodes_benc = match d.get("nodes") {
+        Some(n) => n,
+        None => { return Ok(Vec::new()); }
+    };
+
+    let nodes = match nodes_benc {
+        &Benc::L(ref n) => n,
+        _ => { return Err(String::from("Value for key 'nodes' is not a list!")); }
+    };
+
+    let mut out = Vec::with_capacity(nodes.len());
+    for node in nodes.iter() {
+        // Each one is a [host, port] pair
+        let pair = match node {
+            &Benc::L(ref pair) if pair.len() == 2 => pair,
+            _ => { return Err(String::from("Node was not a [host, port] list!")); }
+        };
+
+        let host = match &pair[0] {
+            &Benc::S(ref bs) => {
+                match String::from_utf8(bs.clone()) {
+                    Ok(s) => s,
+                    Err(e) => { return Err(format!("Unable to parse node host as UTF8 string! Got err: {}", e)); }
+                }
+            },
+            _ => { return Err(String::from("Node host was not a string!")); }
+        };
+
+        match &pair[1] {
+            &Benc::I(port) if port > 0 && port <= 65535 => out.push((host, port as u16)),
+            _ => { return Err(String::from("Node port was not a valid port number!")); }
+        }
+    }
+
+    Ok(out)
+}
+
+fn extract_private(info: &BTreeMap<String, Benc>) -> Result<bool, String> {
+    let private_benc = match info.get("private") {
+        Some(p) => p,
+        None => { return Ok(false); }
+    };
+
+    match private_benc {
+        &Benc::I(p) => Ok(p == 1),
+        _ => Err(String::from("Value for key 'private' is not an integer!"))
+    }
+}
+
 fn extract_creation_date(d: &BTreeMap<String, Benc>) -> Result<Option<i64>, String> {
     let creation_date_benc = match d.get("creation date") {
         Some(cd) => cd,
@@ -246,16 +316,16 @@ fn extract_path(path_benc: &Vec<Benc>) -> Result<Vec<String>, String> {
     Ok(path)
 }
 
-fn extract_announce(d: &BTreeMap<String, Benc>) -> Result<String, String> {
+fn extract_announce(d: &BTreeMap<String, Benc>) -> Result<Option<String>, String> {
     let announce_benc = match d.get("announce") {
         Some(announce) => announce,
-        None => { return Err(String::from("No field named 'announce' in torrent file!")); }
+        None => { return Ok(None); }
     };
 
     match announce_benc {
         &Benc::S(ref bs) => {
             match String::from_utf8(bs.clone()) {
-                Ok(s) => Ok(s),
+                Ok(s) => Ok(Some(s)),
                 Err(e) => Err(format!("Unable to decode 'announce' as a UTF8 string! Got error: {}", e))
             }
         },
@@ -338,3 +408,18 @@ fn extract_info(d: &BTreeMap<String, Benc>) -> Result<&BTreeMap<String, Benc>, S
     }
 }
 
+// A one file torrent for tests that only care about the flags on it
+#[cfg(test)]
+pub fn test_torrent(private: bool) -> TorrentMetadata {
+    let mut info = BTreeMap::new();
+    info.insert(String::from("name"), Benc::S(b"a".to_vec()));
+    info.insert(String::from("piece length"), Benc::I(16384));
+    info.insert(String::from("length"), Benc::I(10));
+    info.insert(String::from("pieces"), Benc::S(vec![0; 20]));
+    if private {
+        info.insert(String::from("private"), Benc::I(1));
+    }
+    let mut d = BTreeMap::new();
+    d.insert(String::from("info"), Benc::D(info));
+    benc_to_torrent(Benc::D(d)).unwrap()
+}
@@ -0,0 +1,356 @@
+use std::time::{Duration, Instant};
+
+use rand::{Rng, thread_rng};
+
+use tracker::*;
+
+// After a failure we wait 60s, then 120s, ... up to an hour
+const BACKOFF_BASE_SECS: u64 = 60;
+const BACKOFF_MAX_SECS: u64 = 3600;
+// How long one tracker gets before we move on to the next, rather than sitting
+// through a dead UDP tracker's whole retransmit schedule
+const ATTEMPT_TIMEOUT_SECS: u64 = 30;
+
+pub type TrackerFactory = Box<dyn FnMut(&str) -> Result<Box<dyn Tracker>, TrackerError>>;
+
+struct TrackerState {
+    url: String,
+    // Made on first use, so we don't go resolving hosts we never talk to
+    client: Option<Box<dyn Tracker>>,
+    tracker_id: Option<Vec<u8>>,
+    // The tracker's min interval, which we wait out without moving on
+    min_interval_until: Option<Instant>,
+    // After failures we skip this one until then, and lower tiers get a go
+    backoff_until: Option<Instant>,
+    failures: u32,
+    // We can't talk to it at all (say it's https), so there's no point retrying
+    unsupported: bool
+}
+
+impl TrackerState {
+    fn backoff(&self) -> Duration {
+        let shift = self.failures.saturating_sub(1).min(16);
+        Duration::from_secs((BACKOFF_BASE_SECS << shift).min(BACKOFF_MAX_SECS))
+    }
+}
+
+// Walks the announce-list tiers the way BEP 12 wants: every tier is shuffled
+// once up front, trackers in a tier are tried in order, whoever answers gets
+// moved to the front of its tier, and we only fall through to the next tier
+// once everyone in the current one has failed.
+pub struct Announcer {
+    tiers: Vec<Vec<TrackerState>>,
+    factory: TrackerFactory,
+    attempt_timeout: Duration,
+    // When we should announce next, based on whoever last answered
+    next_announce: Option<Instant>
+}
+
+impl Announcer {
+    pub fn new(announce_list: &[Vec<String>]) -> Announcer {
+        Announcer::with_factory(announce_list, Box::new(connect))
+    }
+
+    pub fn with_factory(announce_list: &[Vec<String>], factory: TrackerFactory) -> Announcer {
+        let mut rng = thread_rng();
+        let tiers = announce_list.iter().map(|tier| {
+            let mut tier: Vec<TrackerState> = tier.iter().map(|url| TrackerState {
+                url: url.clone(),
+                client: None,
+                tracker_id: None,
+                min_interval_until: None,
+                backoff_until: None,
+                failures: 0,
+                unsupported: false
+            }).collect();
+            rng.shuffle(&mut tier);
+            tier
+        }).collect();
+
+        Announcer {
+            tiers,
+            factory,
+            attempt_timeout: Duration::from_secs(ATTEMPT_TIMEOUT_SECS),
+            next_announce: None
+        }
+    }
+
+    pub fn set_attempt_timeout(&mut self, timeout: Duration) {
+        self.attempt_timeout = timeout;
+    }
+
+    // The urls in the order we'll try them
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.tiers.iter().map(|t| t.iter().map(|s| s.url.clone()).collect()).collect()
+    }
+
+    pub fn next_announce(&self) -> Option<Instant> {
+        self.next_announce
+    }
+
+    // Gives back TrackerError::NotDue without sending anything if the
+    // tracker we'd use asked us to wait longer
+    pub fn announce(&mut self, req: &AnnounceRequest, now: Instant) -> Result<AnnounceResponse, TrackerError> {
+        let mut last_err = None;
+        // Events have to go out regardless, anything else waits its turn
+        let regular = req.event == AnnounceEvent::None;
+
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                let resp = {
+                    let state = &mut tier[i];
+                    if state.unsupported {
+                        continue;
+                    }
+
+                    // Only a tracker that's failing lets the rest have a go.
+                    // One that's fine but wants us to wait gets waited on.
+                    if regular && state.backoff_until.is_some_and(|t| t > now) {
+                        continue;
+                    }
+                    if let Some(t) = state.min_interval_until {
+                        if regular && t > now {
+                            return Err(TrackerError::NotDue(t));
+                        }
+                    }
+
+                    match try_announce(state, &mut self.factory, self.attempt_timeout, req) {
+                        Ok(resp) => {
+                            state.failures = 0;
+                            state.backoff_until = None;
+                            state.min_interval_until = resp.min_interval.map(|m| now + Duration::from_secs(m as u64));
+                            if resp.tracker_id.is_some() {
+                                state.tracker_id = resp.tracker_id.clone();
+                            }
+                            resp
+                        },
+                        Err(e @ TrackerError::Unsupported(_)) => {
+                            state.unsupported = true;
+                            last_err = Some(e);
+                            continue;
+                        },
+                        Err(e) => {
+                            state.failures += 1;
+                            state.backoff_until = Some(now + state.backoff());
+                            last_err = Some(e);
+                            continue;
+                        }
+                    }
+                };
+
+                // Whoever answered goes to the front of the line
+                let state = tier.remove(i);
+                tier.insert(0, state);
+
+                self.next_announce = Some(now + Duration::from_secs(resp.interval as u64));
+                return Ok(resp);
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| TrackerError::Protocol(String::from("No tracker we can announce to right now, try again later"))))
+    }
+}
+
+fn try_announce(state: &mut TrackerState, factory: &mut TrackerFactory, timeout: Duration,
+        req: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+    if state.client.is_none() {
+        let mut client = factory(&state.url)?;
+        client.set_timeout(timeout);
+        state.client = Some(client);
+    }
+
+    let mut req = req.clone();
+    if state.tracker_id.is_some() {
+        req.tracker_id = state.tracker_id.clone();
+    }
+
+    match state.client {
+        Some(ref mut client) => client.announce(&req),
+        None => unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::net::UdpSocket;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use id::PeerId;
+    use tracker::*;
+    use tracker::udp::UdpTracker;
+    use super::Announcer;
+
+    // Which tracker got asked, and what tracker id it was handed
+    type Log = Rc<RefCell<Vec<(String, Option<Vec<u8>>)>>>;
+
+    struct MockTracker {
+        url: String,
+        up: bool,
+        log: Log
+    }
+
+    impl Tracker for MockTracker {
+        fn announce(&mut self, req: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+            self.log.borrow_mut().push((self.url.clone(), req.tracker_id.clone()));
+            if !self.up {
+                return Err(TrackerError::Failure(String::from("down")));
+            }
+            Ok(AnnounceResponse {
+                interval: 1800,
+                min_interval: Some(10),
+                warning_message: None,
+                tracker_id: Some(self.url.as_bytes().to_vec()),
+                complete: None,
+                incomplete: None,
+                peers: Vec::new()
+            })
+        }
+    }
+
+    fn announcer(tiers: &[&[&str]], down: &'static [&'static str]) -> (Announcer, Log) {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let factory_log = log.clone();
+        let tiers: Vec<Vec<String>> = tiers.iter().map(|t| t.iter().map(|s| String::from(*s)).collect()).collect();
+        let announcer = Announcer::with_factory(&tiers, Box::new(move |url: &str| {
+            if url.starts_with("https://") {
+                factory_log.borrow_mut().push((String::from(url), None));
+                return Err(TrackerError::Unsupported(String::from("no tls")));
+            }
+            Ok(Box::new(MockTracker {
+                url: String::from(url),
+                up: !down.contains(&url),
+                log: factory_log.clone()
+            }) as Box<dyn Tracker>)
+        }));
+        (announcer, log)
+    }
+
+    fn request(event: AnnounceEvent) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [0; 20],
+            peer_id: PeerId([0; 20]),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event,
+            numwant: None,
+            key: None,
+            ipv4: None,
+            ipv6: None,
+            tracker_id: None
+        }
+    }
+
+    #[test]
+    fn shuffles_within_tiers() {
+        let (announcer, _) = announcer(&[&["http://a", "http://b", "http://c"], &["udp://d"]], &[]);
+        let tiers = announcer.tiers();
+        assert_eq!(tiers.len(), 2);
+        let mut first = tiers[0].clone();
+        first.sort();
+        assert_eq!(first, vec!("http://a", "http://b", "http://c"));
+        assert_eq!(tiers[1], vec!("udp://d"));
+    }
+
+    #[test]
+    fn falls_through_and_promotes() {
+        let (mut announcer, log) = announcer(
+                &[&["https://a", "http://b"], &["udp://c", "http://d"]],
+                &["http://b", "http://d"]);
+        let now = Instant::now();
+
+        let resp = announcer.announce(&request(AnnounceEvent::Started), now).unwrap();
+        assert_eq!(resp.interval, 1800);
+        assert_eq!(announcer.next_announce(), Some(now + Duration::from_secs(1800)));
+
+        // Everyone in the first tier failed, and c should be at the front of tier 2
+        assert_eq!(log.borrow().last().unwrap().0, "udp://c");
+        assert_eq!(log.borrow().iter().filter(|(url, _)| url == "https://a").count(), 1);
+        assert_eq!(announcer.tiers()[1][0], "udp://c");
+
+        // Next time around, the first tier is backing off so we go straight to c
+        log.borrow_mut().clear();
+        let later = now + Duration::from_secs(30);
+        announcer.announce(&request(AnnounceEvent::None), later).unwrap();
+        assert_eq!(log.borrow().len(), 1);
+        assert_eq!(log.borrow()[0].0, "udp://c");
+
+        // Once the backoff is over we try them again
+        log.borrow_mut().clear();
+        let much_later = now + Duration::from_secs(7200);
+        announcer.announce(&request(AnnounceEvent::None), much_later).unwrap();
+        assert_eq!(log.borrow().len(), 2);
+        assert_eq!(log.borrow()[0].0, "http://b");
+        // ...except the https one, which we'll never be able to talk to
+        assert!(!log.borrow().iter().any(|(url, _)| url == "https://a"));
+    }
+
+    #[test]
+    fn dead_udp_tracker_falls_through() {
+        // Nobody's ever going to read from this
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dead_addr = dead.local_addr().unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let factory_log = log.clone();
+        let tiers = vec!(vec!(String::from("udp://dead")), vec!(String::from("http://up")));
+        let mut announcer = Announcer::with_factory(&tiers, Box::new(move |url: &str| {
+            if url == "udp://dead" {
+                return Ok(Box::new(UdpTracker::with_addr(dead_addr)?) as Box<dyn Tracker>);
+            }
+            Ok(Box::new(MockTracker { url: String::from(url), up: true, log: factory_log.clone() }) as Box<dyn Tracker>)
+        }));
+        announcer.set_attempt_timeout(Duration::from_millis(100));
+
+        let start = Instant::now();
+        announcer.announce(&request(AnnounceEvent::Started), start).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(log.borrow()[0].0, "http://up");
+    }
+
+    #[test]
+    fn respects_min_interval() {
+        let (mut announcer, log) = announcer(&[&["http://a"]], &[]);
+        let now = Instant::now();
+        announcer.announce(&request(AnnounceEvent::Started), now).unwrap();
+
+        // Too soon for a regular announce
+        match announcer.announce(&request(AnnounceEvent::None), now + Duration::from_secs(5)) {
+            Err(TrackerError::NotDue(t)) => assert_eq!(t, now + Duration::from_secs(10)),
+            _ => unreachable!()
+        }
+        assert_eq!(log.borrow().len(), 1);
+        // But events go out anyway
+        announcer.announce(&request(AnnounceEvent::Completed), now + Duration::from_secs(5)).unwrap();
+        announcer.announce(&request(AnnounceEvent::None), now + Duration::from_secs(20)).unwrap();
+        assert_eq!(log.borrow().len(), 3);
+    }
+
+    #[test]
+    fn min_interval_doesnt_fall_through() {
+        let (mut announcer, log) = announcer(&[&["http://a"], &["http://b"]], &[]);
+        let now = Instant::now();
+        announcer.announce(&request(AnnounceEvent::Started), now).unwrap();
+
+        // Tier 0 is fine, just not ready for us yet, so tier 1 is left alone
+        match announcer.announce(&request(AnnounceEvent::None), now + Duration::from_secs(5)) {
+            Err(TrackerError::NotDue(_)) => (),
+            _ => unreachable!()
+        }
+        announcer.announce(&request(AnnounceEvent::None), now + Duration::from_secs(20)).unwrap();
+        assert_eq!(log.borrow().len(), 2);
+        assert!(log.borrow().iter().all(|(url, _)| url == "http://a"));
+    }
+
+    #[test]
+    fn remembers_tracker_id() {
+        let (mut announcer, log) = announcer(&[&["http://x"]], &[]);
+        let now = Instant::now();
+        announcer.announce(&request(AnnounceEvent::Started), now).unwrap();
+        announcer.announce(&request(AnnounceEvent::Stopped), now).unwrap();
+        assert_eq!(log.borrow()[0].1, None);
+        assert_eq!(log.borrow()[1].1, Some(b"http://x".to_vec()));
+    }
+}
@@ -0,0 +1,625 @@
+use std::collections::btree_map::BTreeMap;
+use std::io::prelude::*;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use bencode::*;
+use id::PeerId;
+use tracker::*;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 15;
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    // Everything after the host, including any query string
+    pub path: String
+}
+
+impl HttpUrl {
+    pub fn parse(url: &str) -> Result<HttpUrl, TrackerError> {
+        let rest = if let Some(rest) = url.strip_prefix("http://") {
+            rest
+        } else if url.starts_with("https://") {
+            return Err(TrackerError::Unsupported(format!("No TLS support yet, can't talk to {}", url)));
+        } else {
+            return Err(TrackerError::Unsupported(format!("Not an http url: {}", url)));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/")
+        };
+
+        let (host, port) = split_host_port(authority, 80)?;
+        Ok(HttpUrl { host, port, path: String::from(path) })
+    }
+
+    // Tacks some extra query params on, minding whether there's already a '?'
+    pub fn with_query(&self, query: &str) -> HttpUrl {
+        let sep = if self.path.contains('?') { '&' } else { '?' };
+        HttpUrl { host: self.host.clone(), port: self.port, path: format!("{}{}{}", self.path, sep, query) }
+    }
+}
+
+// Handles "host", "host:port" and "[v6addr]:port"
+pub fn split_host_port(authority: &str, default_port: u16) -> Result<(String, u16), TrackerError> {
+    let bad_port = || TrackerError::Unsupported(format!("Couldn't parse port in {}", authority));
+
+    if let Some(rest) = authority.strip_prefix('[') {
+        let end = match rest.find(']') {
+            Some(end) => end,
+            None => { return Err(TrackerError::Unsupported(format!("Unterminated IPv6 host in {}", authority))); }
+        };
+        let host = String::from(&rest[..end]);
+        let port = match rest[end + 1..].strip_prefix(':') {
+            Some(p) => p.parse::<u16>().map_err(|_| bad_port())?,
+            None => default_port
+        };
+        return Ok((host, port));
+    }
+
+    match authority.rfind(':') {
+        Some(i) => {
+            let port = authority[i + 1..].parse::<u16>().map_err(|_| bad_port())?;
+            Ok((String::from(&authority[..i]), port))
+        },
+        None => Ok((String::from(authority), default_port))
+    }
+}
+
+// Everything but the unreserved characters gets %XX'd
+pub fn url_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 3);
+    for b in bytes.iter() {
+        match *b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(*b as char),
+            _ => out.push_str(&format!("%{:02X}", b))
+        }
+    }
+    out
+}
+
+pub struct HttpTracker {
+    url: HttpUrl,
+    timeout: Duration
+}
+
+impl HttpTracker {
+    pub fn new(url: &str) -> Result<HttpTracker, TrackerError> {
+        Ok(HttpTracker {
+            url: HttpUrl::parse(url)?,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS)
+        })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn url(&self) -> &HttpUrl {
+        &self.url
+    }
+
+    pub fn announce(&mut self, req: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+        let url = self.url.with_query(&announce_query(req));
+        let body = http_get(&url, self.timeout)?;
+        parse_announce_response(&body)
+    }
+
+    // The scrape url is only there if the announce url's last path segment
+    // starts with "announce", which then gets swapped for "scrape"
+    pub fn scrape_url(&self) -> Option<HttpUrl> {
+        let (path, query) = match self.url.path.find('?') {
+            Some(i) => (&self.url.path[..i], &self.url.path[i..]),
+            None => (self.url.path.as_str(), "")
+        };
+        let slash = path.rfind('/')?;
+        let tail = path[slash + 1..].strip_prefix("announce")?;
+
+        Some(HttpUrl {
+            host: self.url.host.clone(),
+            port: self.url.port,
+            path: format!("{}scrape{}{}", &path[..slash + 1], tail, query)
+        })
+    }
+
+    pub fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<ScrapeResponse, TrackerError> {
+        let url = match self.scrape_url() {
+            Some(url) => url,
+            None => { return Err(TrackerError::Unsupported(format!("Can't derive a scrape url from {}", self.url.path))); }
+        };
+
+        let query: Vec<String> = info_hashes.iter().map(|h| format!("info_hash={}", url_encode(h))).collect();
+        let url = if query.is_empty() { url } else { url.with_query(&query.join("&")) };
+        let body = http_get(&url, self.timeout)?;
+        parse_scrape_response(&body)
+    }
+}
+
+pub fn announce_query(req: &AnnounceRequest) -> String {
+    let mut query = format!("info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+            url_encode(&req.info_hash), url_encode(req.peer_id.as_bytes()), req.port,
+            req.uploaded, req.downloaded, req.left);
+
+    if let Some(event) = req.event.as_str() {
+        query.push_str("&event=");
+        query.push_str(event);
+    }
+    if let Some(numwant) = req.numwant {
+        query.push_str(&format!("&numwant={}", numwant));
+    }
+    if let Some(key) = req.key {
+        query.push_str(&format!("&key={:08x}", key));
+    }
+    if let Some(ip) = req.ipv4 {
+        query.push_str(&format!("&ipv4={}", ip));
+    }
+    if let Some(ip) = req.ipv6 {
+        query.push_str("&ipv6=");
+        query.push_str(&url_encode(ip.to_string().as_bytes()));
+    }
+    if let Some(ref tracker_id) = req.tracker_id {
+        query.push_str("&trackerid=");
+        query.push_str(&url_encode(tracker_id));
+    }
+
+    query
+}
+
+// A deliberately tiny HTTP/1.0 client, which is all the trackers need
+pub fn http_get(url: &HttpUrl, timeout: Duration) -> Result<Vec<u8>, TrackerError> {
+    let mut url = url.clone();
+
+    for _ in 0..MAX_REDIRECTS {
+        let response = http_get_once(&url, timeout)?;
+        let response = parse_http_response(&response)?;
+
+        match response.status {
+            200 => return Ok(response.body),
+            301 | 302 | 303 | 307 | 308 => {
+                let location = match response.headers.iter().find(|&(k, _)| k == "location") {
+                    Some((_, v)) => v.clone(),
+                    None => { return Err(TrackerError::Protocol(format!("Got a {} redirect without a location", response.status))); }
+                };
+                url = if location.starts_with('/') {
+                    HttpUrl { host: url.host.clone(), port: url.port, path: location }
+                } else {
+                    HttpUrl::parse(&location)?
+                };
+            },
+            status => { return Err(TrackerError::Protocol(format!("Tracker replied with HTTP status {}", status))); }
+        }
+    }
+
+    Err(TrackerError::Protocol(String::from("Too many redirects!")))
+}
+
+fn http_get_once(url: &HttpUrl, timeout: Duration) -> Result<Vec<u8>, TrackerError> {
+    let addrs: Vec<SocketAddr> = (url.host.as_str(), url.port).to_socket_addrs()?.collect();
+    let mut last_err = None;
+    let mut stream = None;
+    for addr in addrs.iter() {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            },
+            Err(e) => last_err = Some(e)
+        }
+    }
+    let mut stream = match (stream, last_err) {
+        (Some(s), _) => s,
+        (None, Some(e)) => { return Err(TrackerError::Io(e)); },
+        (None, None) => { return Err(TrackerError::Protocol(format!("No addresses for host {}", url.host))); }
+    };
+
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let host = if url.host.contains(':') { format!("[{}]", url.host) } else { url.host.clone() };
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}:{}\r\nUser-Agent: flakes/{}\r\nConnection: close\r\n\r\n",
+            url.path, host, url.port, env!("CARGO_PKG_VERSION"));
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+struct HttpResponse {
+    status: u16,
+    // Names are lowercased so we don't have to care how the server spells them
+    headers: Vec<(String, String)>,
+    body: Vec<u8>
+}
+
+fn parse_http_response(response: &[u8]) -> Result<HttpResponse, TrackerError> {
+    let header_end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => i,
+        None => { return Err(TrackerError::Protocol(String::from("HTTP response had no end of headers"))); }
+    };
+
+    let head = String::from_utf8_lossy(&response[..header_end]);
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next().unwrap_or("");
+    let mut parts = status_line.split(' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => {
+            code.parse::<u16>().map_err(|_| TrackerError::Protocol(format!("Bad HTTP status line: {}", status_line)))?
+        },
+        _ => { return Err(TrackerError::Protocol(format!("Bad HTTP status line: {}", status_line))); }
+    };
+
+    let mut headers = Vec::new();
+    for line in lines {
+        if let Some(i) = line.find(':') {
+            headers.push((line[..i].trim().to_lowercase(), String::from(line[i + 1..].trim())));
+        }
+    }
+
+    let body = &response[header_end + 4..];
+    let chunked = headers.iter().any(|(k, v)| k == "transfer-encoding" && v.eq_ignore_ascii_case("chunked"));
+    let body = if chunked { dechunk(body)? } else { body.to_vec() };
+
+    Ok(HttpResponse { status, headers, body })
+}
+
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, TrackerError> {
+    let bad = || TrackerError::Protocol(String::from("Malformed chunked HTTP body"));
+    let mut out = Vec::new();
+
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n").ok_or_else(bad)?;
+        let size_str = String::from_utf8_lossy(&body[..line_end]);
+        let size_str = size_str.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_str, 16).map_err(|_| bad())?;
+        body = &body[line_end + 2..];
+
+        if size == 0 {
+            return Ok(out);
+        }
+        if body.len() < size + 2 {
+            return Err(bad());
+        }
+        out.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+pub fn parse_announce_response(body: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+    let d = match dec_benc(&body.to_vec()) {
+        Ok(Benc::D(d)) => d,
+        Ok(_) => { return Err(TrackerError::Protocol(String::from("Tracker response is not a dictionary!"))); },
+        Err(e) => { return Err(TrackerError::Protocol(format!("Unable to decode tracker response: {}", e))); }
+    };
+
+    if let Some(reason) = extract_opt_string(&d, "failure reason")? {
+        return Err(TrackerError::Failure(reason));
+    }
+
+    let interval = match extract_opt_u32(&d, "interval")? {
+        Some(i) => i,
+        None => { return Err(TrackerError::Protocol(String::from("Tracker response has no 'interval'!"))); }
+    };
+
+    let mut peers = match d.get("peers") {
+        Some(Benc::S(compact)) => parse_compact_peers(compact).map_err(TrackerError::Protocol)?,
+        Some(Benc::L(l)) => extract_dict_peers(l)?,
+        Some(_) => { return Err(TrackerError::Protocol(String::from("'peers' is neither a string nor a list!"))); },
+        None => Vec::new()
+    };
+
+    // BEP 7 puts the IPv6 peers off on their own
+    match d.get("peers6") {
+        Some(Benc::S(compact)) => peers.extend(parse_compact_peers6(compact).map_err(TrackerError::Protocol)?),
+        Some(Benc::L(l)) => peers.extend(extract_dict_peers(l)?),
+        Some(_) => { return Err(TrackerError::Protocol(String::from("'peers6' is neither a string nor a list!"))); },
+        None => ()
+    }
+
+    let tracker_id = match d.get("tracker id") {
+        Some(Benc::S(s)) => Some(s.clone()),
+        Some(_) => { return Err(TrackerError::Protocol(String::from("'tracker id' is not a string!"))); },
+        None => None
+    };
+
+    Ok(AnnounceResponse {
+        interval,
+        min_interval: extract_opt_u32(&d, "min interval")?,
+        warning_message: extract_opt_string(&d, "warning message")?,
+        tracker_id,
+        complete: extract_opt_u32(&d, "complete")?,
+        incomplete: extract_opt_u32(&d, "incomplete")?,
+        peers
+    })
+}
+
+pub fn parse_scrape_response(body: &[u8]) -> Result<ScrapeResponse, TrackerError> {
+    // The files dict is keyed by raw info hash, so no utf8 keys here
+    let d = match dec_raw_benc(body) {
+        Ok(RawBenc::D(d)) => d,
+        Ok(_) => { return Err(TrackerError::Protocol(String::from("Scrape response is not a dictionary!"))); },
+        Err(e) => { return Err(TrackerError::Protocol(format!("Unable to decode scrape response: {}", e))); }
+    };
+
+    if let Some(RawBenc::S(reason)) = d.get(&b"failure reason"[..]) {
+        return Err(TrackerError::Failure(String::from_utf8_lossy(reason).into_owned()));
+    }
+
+    let files = match d.get(&b"files"[..]) {
+        Some(RawBenc::D(files)) => files,
+        _ => { return Err(TrackerError::Protocol(String::from("Scrape response has a missing or bad 'files'!"))); }
+    };
+
+    let mut out = ScrapeResponse::new();
+    for (hash, stats) in files.iter() {
+        if hash.len() != 20 {
+            return Err(TrackerError::Protocol(format!("Scrape info hash should be 20 bytes, got {}", hash.len())));
+        }
+        let stats = match *stats {
+            RawBenc::D(ref stats) => stats,
+            _ => { return Err(TrackerError::Protocol(String::from("Scrape stats are not a dictionary!"))); }
+        };
+        let count = |key: &str| match stats.get(key.as_bytes()) {
+            Some(&RawBenc::I(i)) if i >= 0 && i <= u32::MAX as i64 => Ok(i as u32),
+            Some(_) => Err(TrackerError::Protocol(format!("Scrape value for '{}' is not a valid integer!", key))),
+            None => Ok(0)
+        };
+
+        let mut info_hash = [0u8; 20];
+        info_hash.copy_from_slice(hash);
+        out.insert(info_hash, ScrapeStats {
+            complete: count("complete")?,
+            downloaded: count("downloaded")?,
+            incomplete: count("incomplete")?
+        });
+    }
+
+    Ok(out)
+}
+
+fn extract_dict_peers(l: &[Benc]) -> Result<Vec<TrackerPeer>, TrackerError> {
+    let mut out = Vec::with_capacity(l.len());
+
+    for peer in l.iter() {
+        let peer = match *peer {
+            Benc::D(ref p) => p,
+            _ => { return Err(TrackerError::Protocol(String::from("Got a peer which isn't a dictionary!"))); }
+        };
+
+        let ip = match extract_opt_string(peer, "ip")? {
+            Some(ip) => ip,
+            None => { return Err(TrackerError::Protocol(String::from("Peer is missing its 'ip'!"))); }
+        };
+        let port = match peer.get("port") {
+            Some(&Benc::I(p)) if p > 0 && p <= 65535 => p as u16,
+            _ => { return Err(TrackerError::Protocol(String::from("Peer has a missing or bad 'port'!"))); }
+        };
+        let peer_id = match peer.get("peer id") {
+            Some(Benc::S(id)) => PeerId::from_slice(id),
+            _ => None
+        };
+
+        // Some trackers hand out hostnames here; we don't go resolving those
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => out.push(TrackerPeer { peer_id, addr: SocketAddr::new(ip, port) }),
+            Err(_) => continue
+        }
+    }
+
+    Ok(out)
+}
+
+pub fn extract_opt_string(d: &BTreeMap<String, Benc>, key: &str) -> Result<Option<String>, TrackerError> {
+    match d.get(key) {
+        Some(Benc::S(s)) => Ok(Some(String::from_utf8_lossy(s).into_owned())),
+        Some(_) => Err(TrackerError::Protocol(format!("Value for key '{}' is not a string!", key))),
+        None => Ok(None)
+    }
+}
+
+pub fn extract_opt_u32(d: &BTreeMap<String, Benc>, key: &str) -> Result<Option<u32>, TrackerError> {
+    match d.get(key) {
+        Some(&Benc::I(i)) if i >= 0 && i <= u32::MAX as i64 => Ok(Some(i as u32)),
+        Some(_) => Err(TrackerError::Protocol(format!("Value for key '{}' is not a valid integer!", key))),
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::btree_map::BTreeMap;
+    use std::io::prelude::*;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use bencode::*;
+    use id::PeerId;
+    use tracker::*;
+    use super::{HttpTracker, HttpUrl, announce_query, parse_announce_response, parse_scrape_response, url_encode};
+
+    // Answers a single request with `body`, and hands back the request line
+    fn stand_in_tracker(body: Vec<u8>) -> (String, ::std::sync::mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            tx.send(String::from(request.lines().next().unwrap())).unwrap();
+
+            let header = format!("HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(&body).unwrap();
+        });
+
+        (format!("http://127.0.0.1:{}/announce", port), rx)
+    }
+
+    fn test_request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [0xab; 20],
+            peer_id: PeerId(*b"-FK0100-abcdefghijkl"),
+            port: 6881,
+            uploaded: 10,
+            downloaded: 20,
+            left: 30,
+            event: AnnounceEvent::Started,
+            numwant: None,
+            key: Some(0xdeadbeef),
+            ipv4: None,
+            ipv6: None,
+            tracker_id: None
+        }
+    }
+
+    #[test]
+    fn parse_urls() {
+        let url = HttpUrl::parse("http://tracker.example.com:8080/announce?passkey=abc").unwrap();
+        assert_eq!(url.host, "tracker.example.com");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.with_query("a=b").path, "/announce?passkey=abc&a=b");
+
+        let url = HttpUrl::parse("http://[::1]/announce").unwrap();
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.port, 80);
+        assert_eq!(url.with_query("a=b").path, "/announce?a=b");
+
+        assert!(HttpUrl::parse("https://tracker.example.com/announce").is_err());
+        assert!(HttpUrl::parse("udp://tracker.example.com:80").is_err());
+        assert!(HttpUrl::parse("http://tracker.example.com:huh/announce").is_err());
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(url_encode(&[0x12, 0x34, b'a', b'~', b' ', 0xff]), "%124a~%20%FF");
+    }
+
+    #[test]
+    fn announce_to_stand_in() {
+        let mut d = BTreeMap::new();
+        d.insert(String::from("interval"), Benc::I(1800));
+        d.insert(String::from("min interval"), Benc::I(900));
+        d.insert(String::from("tracker id"), Benc::S(b"xyz".to_vec()));
+        d.insert(String::from("complete"), Benc::I(5));
+        d.insert(String::from("peers"), Benc::S(vec!(127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2)));
+        let (url, rx) = stand_in_tracker(enc_benc(&Benc::D(d)));
+
+        let mut tracker = HttpTracker::new(&url).unwrap();
+        let resp = tracker.announce(&test_request()).unwrap();
+
+        let request_line = rx.recv().unwrap();
+        assert!(request_line.starts_with("GET /announce?info_hash=%AB%AB"));
+        assert!(request_line.contains("&peer_id=-FK0100-abcdefghijkl&port=6881&uploaded=10&downloaded=20&left=30&compact=1"));
+        assert!(request_line.contains("&event=started"));
+        assert!(request_line.contains("&key=deadbeef"));
+
+        assert_eq!(resp.interval, 1800);
+        assert_eq!(resp.min_interval, Some(900));
+        assert_eq!(resp.tracker_id, Some(b"xyz".to_vec()));
+        assert_eq!(resp.complete, Some(5));
+        assert_eq!(resp.incomplete, None);
+        let addrs: Vec<SocketAddr> = resp.peers.iter().map(|p| p.addr).collect();
+        assert_eq!(addrs, vec!("127.0.0.1:6881".parse().unwrap(), "10.0.0.2:6882".parse().unwrap()));
+    }
+
+    #[test]
+    fn failure_from_stand_in() {
+        let (url, _rx) = stand_in_tracker(b"d14:failure reason9:not todaye".to_vec());
+        let mut tracker = HttpTracker::new(&url).unwrap();
+        match tracker.announce(&test_request()) {
+            Err(TrackerError::Failure(reason)) => assert_eq!(reason, "not today"),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn dict_peers() {
+        let body = "d8:intervali60e15:warning message4:hmm!5:peersl\
+                d2:ip9:127.0.0.17:peer id20:-qB4620-abcdefghijkl4:porti6881ee\
+                d2:ip15:tracker.invalid4:porti1ee\
+                d2:ip3:::14:porti51413eeee";
+        let resp = parse_announce_response(body.as_bytes()).unwrap();
+        assert_eq!(resp.warning_message, Some(String::from("hmm!")));
+        assert_eq!(resp.peers.len(), 2);
+        assert_eq!(resp.peers[0].peer_id.unwrap().to_string(), "qBittorrent 4.6.2");
+        assert_eq!(resp.peers[1].addr, "[::1]:51413".parse().unwrap());
+
+        // Bad compact lengths and missing intervals are errors
+        assert!(parse_announce_response(b"d8:intervali60e5:peers5:abcdee").is_err());
+        assert!(parse_announce_response(b"d5:peers0:e").is_err());
+        assert!(parse_announce_response(b"d8:intervali60e5:peersld2:ip9:127.0.0.1eee").is_err());
+    }
+
+    #[test]
+    fn ipv6_peers() {
+        let mut body = b"d8:intervali60e5:peers6:".to_vec();
+        body.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        body.extend_from_slice(b"6:peers636:");
+        let mut v6 = [0u8; 18];
+        v6[0] = 0x20;
+        v6[1] = 0x01;
+        v6[15] = 1;
+        v6[17] = 80;
+        body.extend_from_slice(&v6);
+        v6[15] = 2;
+        body.extend_from_slice(&v6);
+        body.push(b'e');
+
+        let resp = parse_announce_response(&body).unwrap();
+        let addrs: Vec<SocketAddr> = resp.peers.iter().map(|p| p.addr).collect();
+        assert_eq!(addrs, vec!("10.0.0.1:6881".parse().unwrap(), "[2001::1]:80".parse().unwrap(),
+                "[2001::2]:80".parse().unwrap()));
+
+        assert!(parse_announce_response(b"d8:intervali60e6:peers65:abcdee").is_err());
+    }
+
+    #[test]
+    fn ip_params() {
+        let mut req = test_request();
+        req.ipv4 = Some("192.0.2.1".parse().unwrap());
+        req.ipv6 = Some("2001:db8::1".parse().unwrap());
+        let query = announce_query(&req);
+        assert!(query.contains("&ipv4=192.0.2.1"));
+        assert!(query.contains("&ipv6=2001%3Adb8%3A%3A1"));
+    }
+
+    #[test]
+    fn scrape_urls() {
+        let scrape = |url: &str| HttpTracker::new(url).unwrap().scrape_url().map(|u| u.path);
+        assert_eq!(scrape("http://example.com/announce"), Some(String::from("/scrape")));
+        assert_eq!(scrape("http://example.com/x/announce.php?passkey=abc"), Some(String::from("/x/scrape.php?passkey=abc")));
+        assert_eq!(scrape("http://example.com/announce?x2%0644"), Some(String::from("/scrape?x2%0644")));
+        assert_eq!(scrape("http://example.com/a"), None);
+        assert_eq!(scrape("http://example.com/announce/x"), None);
+        assert_eq!(scrape("http://example.com/x%064announce"), None);
+    }
+
+    #[test]
+    fn scrape_stand_in() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[0xff; 20]);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let (url, rx) = stand_in_tracker(body);
+
+        let mut tracker = HttpTracker::new(&url).unwrap();
+        let stats = tracker.scrape(&[[0xff; 20]]).unwrap();
+        assert_eq!(rx.recv().unwrap(), format!("GET /scrape?info_hash={} HTTP/1.0", url_encode(&[0xff; 20])));
+        assert_eq!(stats[&[0xff; 20]], ScrapeStats { complete: 5, downloaded: 50, incomplete: 10 });
+
+        assert!(parse_scrape_response(b"d14:failure reason4:nopee").is_err());
+        assert!(parse_scrape_response(b"d5:filesd3:abcdeee").is_err());
+    }
+}
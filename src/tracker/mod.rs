@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use id::PeerId;
+use tracker::http::HttpTracker;
+use tracker::udp::UdpTracker;
+
+pub mod announcer;
+pub mod http;
+pub mod udp;
+
+// The optional "event" we send along with an announce
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None,
+    Started,
+    Stopped,
+    Completed
+}
+
+impl AnnounceEvent {
+    // What the HTTP trackers want to see, None means leave the param off
+    pub fn as_str(&self) -> Option<&'static str> {
+        match *self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+            AnnounceEvent::Completed => Some("completed")
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: PeerId,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub numwant: Option<u32>,
+    pub key: Option<u32>,
+    // Our other addresses (BEP 7), so a tracker we reach over one family
+    // can still hand us out to peers on the other
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    // Whatever the tracker told us to send back in its last response
+    pub tracker_id: Option<Vec<u8>>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackerPeer {
+    pub peer_id: Option<PeerId>,
+    pub addr: SocketAddr
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    pub interval: u32,
+    pub min_interval: Option<u32>,
+    pub warning_message: Option<String>,
+    pub tracker_id: Option<Vec<u8>>,
+    // Seeders and leechers, if the tracker bothered to tell us
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
+    pub peers: Vec<TrackerPeer>
+}
+
+// What a scrape tells us about a single torrent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScrapeStats {
+    // Seeders
+    pub complete: u32,
+    // How many times it's been fully downloaded
+    pub downloaded: u32,
+    // Leechers
+    pub incomplete: u32
+}
+
+pub type ScrapeResponse = HashMap<[u8; 20], ScrapeStats>;
+
+#[derive(Debug)]
+pub enum TrackerError {
+    // Couldn't talk to the tracker at all
+    Io(io::Error),
+    // The tracker sent us something we couldn't make sense of
+    Protocol(String),
+    // The tracker understood us just fine, and said no
+    Failure(String),
+    // We don't know how to talk to this kind of tracker
+    Unsupported(String),
+    // The tracker asked us to wait (min interval) and it hasn't been long
+    // enough, so nothing was sent. Try again at this time.
+    NotDue(Instant)
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrackerError::Io(ref e) => write!(f, "I/O error talking to tracker: {}", e),
+            TrackerError::Protocol(ref s) => write!(f, "Bad response from tracker: {}", s),
+            TrackerError::Failure(ref s) => write!(f, "Tracker returned failure: {}", s),
+            TrackerError::Unsupported(ref s) => write!(f, "Unsupported tracker: {}", s),
+            TrackerError::NotDue(_) => write!(f, "Too soon to announce to the tracker again")
+        }
+    }
+}
+
+impl Error for TrackerError {}
+
+impl From<io::Error> for TrackerError {
+    fn from(e: io::Error) -> TrackerError {
+        TrackerError::Io(e)
+    }
+}
+
+// Anything we can announce to, regardless of how we talk to it
+pub trait Tracker {
+    fn announce(&mut self, req: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError>;
+
+    // Not every tracker can scrape, so that's the default
+    fn scrape(&mut self, _info_hashes: &[[u8; 20]]) -> Result<ScrapeResponse, TrackerError> {
+        Err(TrackerError::Unsupported(String::from("This tracker doesn't support scraping")))
+    }
+
+    // Caps how long one announce or scrape can take, retries and all
+    fn set_timeout(&mut self, _timeout: Duration) {}
+}
+
+impl Tracker for HttpTracker {
+    fn announce(&mut self, req: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+        HttpTracker::announce(self, req)
+    }
+
+    fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<ScrapeResponse, TrackerError> {
+        HttpTracker::scrape(self, info_hashes)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        HttpTracker::set_timeout(self, timeout)
+    }
+}
+
+impl Tracker for UdpTracker {
+    fn announce(&mut self, req: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+        UdpTracker::announce(self, req)
+    }
+
+    fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<ScrapeResponse, TrackerError> {
+        UdpTracker::scrape(self, info_hashes)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        UdpTracker::set_timeout(self, timeout)
+    }
+}
+
+// Picks the right kind of tracker for the url's scheme
+pub fn connect(url: &str) -> Result<Box<dyn Tracker>, TrackerError> {
+    if url.starts_with("udp://") {
+        Ok(Box::new(UdpTracker::new(url)?))
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Box::new(HttpTracker::new(url)?))
+    } else {
+        Err(TrackerError::Unsupported(format!("Don't know how to talk to {}", url)))
+    }
+}
+
+// The compact format is 4 bytes of IP and 2 bytes of port, in network order
+pub fn parse_compact_peers(compact: &[u8]) -> Result<Vec<TrackerPeer>, String> {
+    if !compact.len().is_multiple_of(6) {
+        return Err(format!("Compact peer list must be a multiple of 6 bytes, got {}", compact.len()));
+    }
+
+    Ok(compact.chunks(6).map(|c| {
+        let ip = Ipv4Addr::new(c[0], c[1], c[2], c[3]);
+        let port = ((c[4] as u16) << 8) | (c[5] as u16);
+        TrackerPeer { peer_id: None, addr: SocketAddr::new(IpAddr::V4(ip), port) }
+    }).collect())
+}
+
+// The other direction: 4 or 16 bytes of IP, then the port, big endian
+pub fn compact_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut out = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec()
+    };
+    out.push((addr.port() >> 8) as u8);
+    out.push(addr.port() as u8);
+    out
+}
+
+// Same deal for IPv6, but 16 bytes of IP and 2 bytes of port
+pub fn parse_compact_peers6(compact: &[u8]) -> Result<Vec<TrackerPeer>, String> {
+    if !compact.len().is_multiple_of(18) {
+        return Err(format!("Compact IPv6 peer list must be a multiple of 18 bytes, got {}", compact.len()));
+    }
+
+    Ok(compact.chunks(18).map(|c| {
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&c[..16]);
+        let port = ((c[16] as u16) << 8) | (c[17] as u16);
+        TrackerPeer { peer_id: None, addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port) }
+    }).collect())
+}
@@ -0,0 +1,531 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use rand::{Rng, thread_rng};
+
+use tracker::*;
+use tracker::http::split_host_port;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// BEP 15 says to wait 15 * 2^n seconds, and give up after n = 8
+const BASE_TIMEOUT_SECS: u64 = 15;
+const MAX_RETRIES: u32 = 8;
+// Connection IDs are good for a minute after we get them
+const CONNECTION_TTL_SECS: u64 = 60;
+
+// A UDP packet can only fit this many info hashes in a scrape
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+pub struct UdpTracker {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+    max_retries: u32,
+    // If set, a whole request gives up after this long, even partway through
+    // the retransmit schedule
+    timeout: Option<Duration>,
+    connection_ttl: Duration
+}
+
+impl UdpTracker {
+    pub fn new(url: &str) -> Result<UdpTracker, TrackerError> {
+        let rest = match url.strip_prefix("udp://") {
+            Some(rest) => rest,
+            None => { return Err(TrackerError::Unsupported(format!("Not a udp url: {}", url))); }
+        };
+        // Anything after the host is ignored, though some trackers stick "/announce" there
+        let authority = match rest.find('/') {
+            Some(i) => &rest[..i],
+            None => rest
+        };
+        let (host, port) = split_host_port(authority, 80)?;
+
+        let addr = match (host.as_str(), port).to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => { return Err(TrackerError::Protocol(format!("No addresses for host {}", host))); }
+        };
+
+        UdpTracker::with_addr(addr)
+    }
+
+    pub fn with_addr(addr: SocketAddr) -> Result<UdpTracker, TrackerError> {
+        let bind_addr = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0"
+        };
+
+        Ok(UdpTracker {
+            socket: UdpSocket::bind(bind_addr)?,
+            addr,
+            connection: None,
+            base_timeout: Duration::from_secs(BASE_TIMEOUT_SECS),
+            max_retries: MAX_RETRIES,
+            timeout: None,
+            connection_ttl: Duration::from_secs(CONNECTION_TTL_SECS)
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Mostly so tests don't have to sit around for 15 seconds at a time
+    pub fn set_retransmit(&mut self, base_timeout: Duration, max_retries: u32) {
+        self.base_timeout = base_timeout;
+        self.max_retries = max_retries;
+    }
+
+    // BEP 15's schedule takes hours to run out, which is a long time to sit
+    // on a tracker that's gone
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn announce(&mut self, req: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+        let resp = self.request(|connection_id, tid| encode_announce(connection_id, tid, req))?;
+        decode_announce(&resp, self.addr.is_ipv6())
+    }
+
+    // Asks about as many torrents as will fit in a packet at a time
+    pub fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<ScrapeResponse, TrackerError> {
+        let mut out = ScrapeResponse::new();
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let stats = self.scrape_chunk(chunk)?;
+            for (hash, stats) in chunk.iter().zip(stats) {
+                out.insert(*hash, stats);
+            }
+        }
+        Ok(out)
+    }
+
+    // Stats come back in the same order as the hashes we asked about
+    fn scrape_chunk(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let resp = self.request(|connection_id, tid| encode_scrape(connection_id, tid, info_hashes))?;
+        let stats = decode_scrape(&resp)?;
+        if stats.len() != info_hashes.len() {
+            return Err(TrackerError::Protocol(format!("Asked to scrape {} hashes, got {} back",
+                    info_hashes.len(), stats.len())));
+        }
+        Ok(stats)
+    }
+
+    // Gets a reply to whatever encode() builds from a connection ID and
+    // transaction ID, connecting first if our connection ID is stale. The
+    // connect and the request share one retransmit count, so a dead tracker
+    // costs one run of BEP 15's 15 * 2^n timeouts and no more.
+    fn request<F: Fn(u64, u32) -> Vec<u8>>(&mut self, encode: F) -> Result<Vec<u8>, TrackerError> {
+        let give_up = self.timeout.map(|t| Instant::now() + t);
+        let mut attempt = 0;
+        while attempt <= self.max_retries && give_up.is_none_or(|t| Instant::now() < t) {
+            let connection_id = match self.connection {
+                Some((id, when)) if when.elapsed() < self.connection_ttl => id,
+                _ => {
+                    self.connection = None;
+                    let tid = thread_rng().gen::<u32>();
+                    match self.transact(&encode_connect(tid), tid, attempt, give_up)? {
+                        // Straight on to the request, at the same n
+                        Some(resp) => {
+                            let id = decode_connect(&resp)?;
+                            self.connection = Some((id, Instant::now()));
+                            id
+                        },
+                        None => {
+                            attempt += 1;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let tid = thread_rng().gen::<u32>();
+            match self.transact(&encode(connection_id, tid), tid, attempt, give_up)? {
+                Some(resp) => { return Ok(resp); },
+                None => attempt += 1
+            }
+        }
+
+        Err(timed_out())
+    }
+
+    // Sends the packet once, and waits out the timeout for attempt n (or
+    // until give_up, if that's sooner) for a reply carrying our transaction
+    // ID. Ok(None) means we timed out.
+    fn transact(&mut self, packet: &[u8], tid: u32, attempt: u32,
+            give_up: Option<Instant>) -> Result<Option<Vec<u8>>, TrackerError> {
+        self.socket.send_to(packet, self.addr)?;
+
+        let mut deadline = Instant::now() + self.base_timeout * 2u32.pow(attempt);
+        if let Some(t) = give_up {
+            deadline = deadline.min(t);
+        }
+        let mut buf = [0u8; 2048];
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    return Ok(None);
+                },
+                Err(e) => { return Err(TrackerError::Io(e)); }
+            };
+
+            // Anything not from the tracker or for some other transaction is junk
+            if from != self.addr || len < 8 || read_u32(&buf[4..8]) != tid {
+                continue;
+            }
+
+            return Ok(Some(buf[..len].to_vec()));
+        }
+    }
+}
+
+fn timed_out() -> TrackerError {
+    TrackerError::Io(io::Error::new(io::ErrorKind::TimedOut, "UDP tracker never answered"))
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | (b[3] as u32)
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    ((read_u32(&b[0..4]) as u64) << 32) | (read_u32(&b[4..8]) as u64)
+}
+
+fn event_id(event: AnnounceEvent) -> u32 {
+    match event {
+        AnnounceEvent::None => 0,
+        AnnounceEvent::Completed => 1,
+        AnnounceEvent::Started => 2,
+        AnnounceEvent::Stopped => 3
+    }
+}
+
+pub fn encode_connect(tid: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16);
+    out.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    out.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    out.extend_from_slice(&tid.to_be_bytes());
+    out
+}
+
+pub fn encode_announce(connection_id: u64, tid: u32, req: &AnnounceRequest) -> Vec<u8> {
+    let mut out = Vec::with_capacity(98);
+    out.extend_from_slice(&connection_id.to_be_bytes());
+    out.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    out.extend_from_slice(&tid.to_be_bytes());
+    out.extend_from_slice(&req.info_hash);
+    out.extend_from_slice(req.peer_id.as_bytes());
+    out.extend_from_slice(&req.downloaded.to_be_bytes());
+    out.extend_from_slice(&req.left.to_be_bytes());
+    out.extend_from_slice(&req.uploaded.to_be_bytes());
+    out.extend_from_slice(&event_id(req.event).to_be_bytes());
+    // IP address, 0 means "use the one you see"
+    out.extend_from_slice(&0u32.to_be_bytes());
+    out.extend_from_slice(&req.key.unwrap_or(0).to_be_bytes());
+    let numwant = match req.numwant {
+        Some(n) => n.min(i32::MAX as u32) as i32,
+        None => -1
+    };
+    out.extend_from_slice(&numwant.to_be_bytes());
+    out.extend_from_slice(&req.port.to_be_bytes());
+    out
+}
+
+pub fn encode_scrape(connection_id: u64, tid: u32, info_hashes: &[[u8; 20]]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16 + 20 * info_hashes.len());
+    out.extend_from_slice(&connection_id.to_be_bytes());
+    out.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+    out.extend_from_slice(&tid.to_be_bytes());
+    for hash in info_hashes.iter() {
+        out.extend_from_slice(hash);
+    }
+    out
+}
+
+// Checks the action, turning error packets into a Failure
+fn check_action(resp: &[u8], expected: u32) -> Result<(), TrackerError> {
+    if resp.len() < 8 {
+        return Err(TrackerError::Protocol(format!("UDP tracker response too short: {} bytes", resp.len())));
+    }
+
+    match read_u32(&resp[0..4]) {
+        ACTION_ERROR => Err(TrackerError::Failure(String::from_utf8_lossy(&resp[8..]).into_owned())),
+        action if action == expected => Ok(()),
+        action => Err(TrackerError::Protocol(format!("Expected action {} from UDP tracker, got {}", expected, action)))
+    }
+}
+
+pub fn decode_connect(resp: &[u8]) -> Result<u64, TrackerError> {
+    check_action(resp, ACTION_CONNECT)?;
+    if resp.len() < 16 {
+        return Err(TrackerError::Protocol(format!("Connect response should be 16 bytes, got {}", resp.len())));
+    }
+    Ok(read_u64(&resp[8..16]))
+}
+
+// Trackers we talk to over IPv6 send back 18 byte IPv6 peers instead
+pub fn decode_announce(resp: &[u8], ipv6: bool) -> Result<AnnounceResponse, TrackerError> {
+    check_action(resp, ACTION_ANNOUNCE)?;
+    if resp.len() < 20 {
+        return Err(TrackerError::Protocol(format!("Announce response should be at least 20 bytes, got {}", resp.len())));
+    }
+
+    Ok(AnnounceResponse {
+        interval: read_u32(&resp[8..12]),
+        min_interval: None,
+        warning_message: None,
+        tracker_id: None,
+        incomplete: Some(read_u32(&resp[12..16])),
+        complete: Some(read_u32(&resp[16..20])),
+        peers: if ipv6 {
+            parse_compact_peers6(&resp[20..]).map_err(TrackerError::Protocol)?
+        } else {
+            parse_compact_peers(&resp[20..]).map_err(TrackerError::Protocol)?
+        }
+    })
+}
+
+pub fn decode_scrape(resp: &[u8]) -> Result<Vec<ScrapeStats>, TrackerError> {
+    check_action(resp, ACTION_SCRAPE)?;
+    let body = &resp[8..];
+    if !body.len().is_multiple_of(12) {
+        return Err(TrackerError::Protocol(format!("Scrape response body should be a multiple of 12 bytes, got {}", body.len())));
+    }
+
+    Ok(body.chunks(12).map(|c| ScrapeStats {
+        complete: read_u32(&c[0..4]),
+        downloaded: read_u32(&c[4..8]),
+        incomplete: read_u32(&c[8..12])
+    }).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use id::PeerId;
+    use tracker::*;
+    use super::{decode_announce, read_u32, read_u64, UdpTracker, PROTOCOL_ID};
+
+    #[derive(Default)]
+    struct StandInStats {
+        connects: u32,
+        announces: u32,
+        dropped: u32
+    }
+
+    // A pretend tracker which drops the first `drop_first` packets it gets
+    fn stand_in_tracker(drop_first: u32) -> (SocketAddr, Arc<Mutex<StandInStats>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let stats = Arc::new(Mutex::new(StandInStats::default()));
+        let thread_stats = stats.clone();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            let connection_id = 0x1122334455667788u64;
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).unwrap();
+                let packet = &buf[..len];
+                let mut stats = thread_stats.lock().unwrap();
+                if stats.dropped < drop_first {
+                    stats.dropped += 1;
+                    continue;
+                }
+
+                let action = read_u32(&packet[8..12]);
+                let tid = &packet[12..16];
+                let mut out = Vec::new();
+
+                if action == 0 {
+                    assert_eq!(read_u64(&packet[0..8]), PROTOCOL_ID);
+                    stats.connects += 1;
+                    // Throw a reply with the wrong transaction ID in first
+                    let mut junk = vec!(0, 0, 0, 0, 0, 0, 0, 0);
+                    junk.extend_from_slice(&connection_id.to_be_bytes());
+                    socket.send_to(&junk, from).unwrap();
+
+                    out.extend_from_slice(&0u32.to_be_bytes());
+                    out.extend_from_slice(tid);
+                    out.extend_from_slice(&connection_id.to_be_bytes());
+                } else {
+                    assert_eq!(read_u64(&packet[0..8]), connection_id);
+                    if action == 1 {
+                        stats.announces += 1;
+                        assert_eq!(len, 98);
+                        if packet[16] == 0xee {
+                            out.extend_from_slice(&3u32.to_be_bytes());
+                            out.extend_from_slice(tid);
+                            out.extend_from_slice(b"go away");
+                        } else {
+                            out.extend_from_slice(&1u32.to_be_bytes());
+                            out.extend_from_slice(tid);
+                            out.extend_from_slice(&1800u32.to_be_bytes());
+                            out.extend_from_slice(&3u32.to_be_bytes());
+                            out.extend_from_slice(&7u32.to_be_bytes());
+                            out.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+                        }
+                    } else if action == 2 {
+                        out.extend_from_slice(&2u32.to_be_bytes());
+                        out.extend_from_slice(tid);
+                        for (i, _) in packet[16..].chunks(20).enumerate() {
+                            out.extend_from_slice(&(i as u32).to_be_bytes());
+                            out.extend_from_slice(&100u32.to_be_bytes());
+                            out.extend_from_slice(&(i as u32 * 2).to_be_bytes());
+                        }
+                    }
+                }
+                socket.send_to(&out, from).unwrap();
+            }
+        });
+
+        (addr, stats)
+    }
+
+    fn test_request(info_hash: [u8; 20]) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash,
+            peer_id: PeerId(*b"-FK0100-abcdefghijkl"),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: AnnounceEvent::Started,
+            numwant: Some(50),
+            key: Some(1234),
+            ipv4: None,
+            ipv6: None,
+            tracker_id: None
+        }
+    }
+
+    #[test]
+    fn announce_and_reuse_connection() {
+        let (addr, stats) = stand_in_tracker(0);
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_retransmit(Duration::from_millis(200), 2);
+
+        let resp = tracker.announce(&test_request([1; 20])).unwrap();
+        assert_eq!(resp.interval, 1800);
+        assert_eq!(resp.incomplete, Some(3));
+        assert_eq!(resp.complete, Some(7));
+        assert_eq!(resp.peers[0].addr, "127.0.0.1:6881".parse().unwrap());
+
+        // Second announce should use the cached connection ID
+        tracker.announce(&test_request([1; 20])).unwrap();
+        assert_eq!(stats.lock().unwrap().connects, 1);
+
+        // ...until it expires
+        tracker.connection_ttl = Duration::from_millis(0);
+        tracker.announce(&test_request([1; 20])).unwrap();
+        assert_eq!(stats.lock().unwrap().connects, 2);
+        assert_eq!(stats.lock().unwrap().announces, 3);
+    }
+
+    #[test]
+    fn retransmits() {
+        let (addr, stats) = stand_in_tracker(2);
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_retransmit(Duration::from_millis(20), 3);
+
+        tracker.announce(&test_request([1; 20])).unwrap();
+        assert_eq!(stats.lock().unwrap().dropped, 2);
+        assert_eq!(stats.lock().unwrap().connects, 1);
+    }
+
+    #[test]
+    fn gives_up() {
+        let (addr, stats) = stand_in_tracker(100);
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_retransmit(Duration::from_millis(5), 2);
+
+        match tracker.announce(&test_request([1; 20])) {
+            Err(TrackerError::Io(_)) => (),
+            _ => unreachable!()
+        }
+        // One try per n, not a whole run of connects for each announce try
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(stats.lock().unwrap().dropped, 3);
+
+        // Connecting eats into the same count: two lost connects leave one
+        // try for the connect and the announce both
+        let (addr, stats) = stand_in_tracker(2);
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_retransmit(Duration::from_millis(20), 2);
+        tracker.announce(&test_request([1; 20])).unwrap();
+        assert_eq!(stats.lock().unwrap().announces, 1);
+
+        // An overall timeout cuts the schedule short
+        let (addr, _) = stand_in_tracker(100);
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_timeout(Duration::from_millis(100));
+        let start = Instant::now();
+        assert!(tracker.announce(&test_request([1; 20])).is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn error_action() {
+        let (addr, _) = stand_in_tracker(0);
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_retransmit(Duration::from_millis(200), 2);
+
+        match tracker.announce(&test_request([0xee; 20])) {
+            Err(TrackerError::Failure(msg)) => assert_eq!(msg, "go away"),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn scrape() {
+        let (addr, _) = stand_in_tracker(0);
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_retransmit(Duration::from_millis(200), 2);
+
+        let stats = tracker.scrape(&[[1; 20], [2; 20], [3; 20]]).unwrap();
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats[&[3; 20]], ScrapeStats { complete: 2, downloaded: 100, incomplete: 4 });
+
+        // More than fit in one packet get split up
+        let mut hashes = Vec::new();
+        for i in 0..100u8 {
+            hashes.push([i; 20]);
+        }
+        let stats = tracker.scrape(&hashes).unwrap();
+        assert_eq!(stats.len(), 100);
+        assert_eq!(stats[&[80; 20]], ScrapeStats { complete: 6, downloaded: 100, incomplete: 12 });
+    }
+
+    #[test]
+    fn ipv6_announce_response() {
+        let mut resp = vec!(0, 0, 0, 1, 0, 0, 0, 9, 0, 0, 0, 60, 0, 0, 0, 1, 0, 0, 0, 2);
+        resp.extend_from_slice(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1]);
+
+        let parsed = decode_announce(&resp, true).unwrap();
+        assert_eq!(parsed.peers.len(), 1);
+        assert_eq!(parsed.peers[0].addr, "[fe80::1]:6881".parse().unwrap());
+    }
+
+    #[test]
+    fn parse_url() {
+        assert!(UdpTracker::new("udp://127.0.0.1:1337/announce").is_ok());
+        assert!(UdpTracker::new("http://127.0.0.1:1337/announce").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use utp::PAYLOAD_SIZE;
+
+pub const DEFAULT_TARGET_DELAY: Duration = Duration::from_millis(100);
+
+// The most the window grows by in one RTT, when there's no queueing at all
+const MAX_WINDOW_GROWTH: f64 = 3000.0;
+pub const MIN_WINDOW: usize = PAYLOAD_SIZE;
+const MAX_WINDOW: usize = 1 << 20;
+const INITIAL_WINDOW: usize = 2 * PAYLOAD_SIZE;
+
+// Base delay is the lowest one-way delay seen, with one minimum kept per
+// minute for the last couple of minutes so it can follow a route change
+const BASE_DELAY_HISTORY: usize = 2;
+const BASE_DELAY_PERIOD: Duration = Duration::from_secs(60);
+
+// LEDBAT congestion control (BEP 29, RFC 6817). Anything above the lowest
+// delay we've seen is taken to be queueing, and the window is grown or
+// shrunk to keep that queueing at the target. Since TCP backs off on loss
+// rather than on delay, this backs off first and leaves it the bandwidth.
+pub struct Ledbat {
+    target: Duration,
+    window: f64,
+    // Each is (when the minute started, lowest delay in it)
+    base_delays: VecDeque<(Instant, u32)>
+}
+
+impl Ledbat {
+    pub fn new(target: Duration) -> Ledbat {
+        Ledbat { target, window: INITIAL_WINDOW as f64, base_delays: VecDeque::new() }
+    }
+
+    // Bytes we're allowed in flight
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    pub fn target(&self) -> Duration {
+        self.target
+    }
+
+    // delay is the timestamp_diff the other end sent with the ack. It
+    // includes whatever the offset is between our clocks, which is why only
+    // the difference from the base delay is used.
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32, now: Instant) {
+        let base = self.update_base_delay(delay, now);
+        let queueing = f64::from((delay.wrapping_sub(base) as i32).max(0));
+        let target = self.target.as_micros() as f64;
+        let off_target = (target - queueing) / target;
+        let window_factor = (bytes_acked as f64 / self.window).min(1.0);
+        self.window += MAX_WINDOW_GROWTH * off_target * window_factor;
+        self.clamp();
+    }
+
+    pub fn on_loss(&mut self) {
+        self.window /= 2.0;
+        self.clamp();
+    }
+
+    pub fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW as f64;
+    }
+
+    fn update_base_delay(&mut self, delay: u32, now: Instant) -> u32 {
+        let new_period = self.base_delays.back().is_none_or(|&(start, _)| now.duration_since(start) >= BASE_DELAY_PERIOD);
+        if new_period {
+            self.base_delays.push_back((now, delay));
+            if self.base_delays.len() > BASE_DELAY_HISTORY {
+                self.base_delays.pop_front();
+            }
+        } else if let Some((_, lowest)) = self.base_delays.back_mut() {
+            // These wrap, so lower means behind by less than half the range
+            if (delay.wrapping_sub(*lowest) as i32) < 0 {
+                *lowest = delay;
+            }
+        }
+        self.base_delays.iter().map(|&(_, d)| d).fold(delay, |a, d| if (d.wrapping_sub(a) as i32) < 0 { d } else { a })
+    }
+
+    fn clamp(&mut self) {
+        self.window = self.window.clamp(MIN_WINDOW as f64, MAX_WINDOW as f64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn follows_the_target() {
+        let now = Instant::now();
+        let mut l = Ledbat::new(Duration::from_millis(100));
+        let start = l.window();
+
+        // No queueing, so the window grows, even if our clocks are way off
+        for _ in 0..10 {
+            l.on_ack(1000, u32::MAX - 5000, now);
+        }
+        let grown = l.window();
+        assert!(grown > start);
+
+        // 200ms over the base delay is over target, so it shrinks again
+        let over = (u32::MAX - 5000).wrapping_add(200_000);
+        for _ in 0..10 {
+            l.on_ack(1000, over, now);
+        }
+        assert!(l.window() < grown);
+
+        // Right on target holds steady
+        let steady = l.window();
+        l.on_ack(1000, (u32::MAX - 5000).wrapping_add(100_000), now);
+        assert_eq!(l.window(), steady);
+
+        // A couple of minutes later the old base delay has aged out, and the
+        // new one is whatever we see now
+        let later = now + BASE_DELAY_PERIOD * 3;
+        l.on_ack(1000, over, later);
+        let before = l.window();
+        l.on_ack(1000, over, later + BASE_DELAY_PERIOD);
+        assert!(l.window() > before);
+    }
+
+    #[test]
+    fn loss_and_timeouts() {
+        let mut l = Ledbat::new(DEFAULT_TARGET_DELAY);
+        for _ in 0..100 {
+            l.on_ack(10_000, 0, Instant::now());
+        }
+        let w = l.window();
+        l.on_loss();
+        assert!((l.window() as i64 - (w / 2) as i64).abs() <= 1);
+        l.on_timeout();
+        assert_eq!(l.window(), MIN_WINDOW);
+        l.on_loss();
+        assert_eq!(l.window(), MIN_WINDOW);
+    }
+}
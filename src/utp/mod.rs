@@ -0,0 +1,6 @@
+// Leaves room for the IP, UDP and uTP headers in a 1500 byte packet
+pub const PAYLOAD_SIZE: usize = 1400;
+
+pub mod ledbat;
+pub mod packet;
+pub mod socket;
@@ -0,0 +1,206 @@
+use std::fmt;
+
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 20;
+
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SACK: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketType {
+    Data,
+    Fin,
+    // A bare ack
+    State,
+    Reset,
+    Syn
+}
+
+impl PacketType {
+    fn from_u8(t: u8) -> Option<PacketType> {
+        match t {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            PacketType::Data => 0,
+            PacketType::Fin => 1,
+            PacketType::State => 2,
+            PacketType::Reset => 3,
+            PacketType::Syn => 4
+        }
+    }
+}
+
+// A uTP packet (BEP 29). Timestamps are microseconds on the sender's clock,
+// and timestamp_diff is how long ago, on the sender's clock, the last packet
+// we sent them was stamped. Only the differences mean anything, which is all
+// LEDBAT needs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub ptype: PacketType,
+    pub conn_id: u16,
+    pub timestamp: u32,
+    pub timestamp_diff: u32,
+    // Bytes the sender has room to receive
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    // Selective ack: bit i (least significant first in each byte) means
+    // ack_nr + 2 + i got here
+    pub sack: Option<Vec<u8>>,
+    pub payload: Vec<u8>
+}
+
+impl Packet {
+    pub fn new(ptype: PacketType, conn_id: u16) -> Packet {
+        Packet {
+            ptype,
+            conn_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr: 0,
+            ack_nr: 0,
+            sack: None,
+            payload: Vec::new()
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.payload.len() + 10);
+        out.push(self.ptype.as_u8() << 4 | VERSION);
+        out.push(if self.sack.is_some() { EXTENSION_SACK } else { EXTENSION_NONE });
+        out.extend_from_slice(&self.conn_id.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.timestamp_diff.to_be_bytes());
+        out.extend_from_slice(&self.wnd_size.to_be_bytes());
+        out.extend_from_slice(&self.seq_nr.to_be_bytes());
+        out.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(ref sack) = self.sack {
+            out.push(EXTENSION_NONE);
+            out.push(sack.len() as u8);
+            out.extend_from_slice(sack);
+        }
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    // Extensions we don't know are skipped
+    pub fn decode(packet: &[u8]) -> Result<Packet, String> {
+        if packet.len() < HEADER_LEN {
+            return Err(format!("uTP packet needs at least {} bytes, got {}", HEADER_LEN, packet.len()));
+        }
+        if packet[0] & 0x0f != VERSION {
+            return Err(format!("Unknown uTP version {}", packet[0] & 0x0f));
+        }
+        let ptype = PacketType::from_u8(packet[0] >> 4).ok_or_else(|| format!("Unknown uTP packet type {}", packet[0] >> 4))?;
+
+        let u16_at = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes([packet[i], packet[i + 1], packet[i + 2], packet[i + 3]]);
+        let mut p = Packet {
+            ptype,
+            conn_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack: None,
+            payload: Vec::new()
+        };
+
+        let mut next = packet[1];
+        let mut pos = HEADER_LEN;
+        while next != EXTENSION_NONE {
+            if pos + 2 > packet.len() {
+                return Err(String::from("uTP extension header runs off the end"));
+            }
+            let (ext, len) = (next, packet[pos + 1] as usize);
+            next = packet[pos];
+            pos += 2;
+            if pos + len > packet.len() {
+                return Err(String::from("uTP extension runs off the end"));
+            }
+            if ext == EXTENSION_SACK {
+                p.sack = Some(packet[pos..pos + len].to_vec());
+            }
+            pos += len;
+        }
+        p.payload = packet[pos..].to_vec();
+        Ok(p)
+    }
+}
+
+impl fmt::Debug for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Packet({:?} conn {} seq {} ack {}, {} bytes)", self.ptype, self.conn_id, self.seq_nr, self.ack_nr, self.payload.len())
+    }
+}
+
+pub fn sack_has(sack: &[u8], i: usize) -> bool {
+    i < sack.len() * 8 && sack[i / 8] & (1 << (i % 8)) != 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut p = Packet::new(PacketType::Data, 0x1234);
+        p.timestamp = 0xdead_beef;
+        p.timestamp_diff = 7;
+        p.wnd_size = 1 << 20;
+        p.seq_nr = 65535;
+        p.ack_nr = 3;
+        p.payload = b"hello".to_vec();
+
+        let bytes = p.encode();
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(&bytes[2..4], &[0x12, 0x34]);
+        assert_eq!(bytes.len(), HEADER_LEN + 5);
+        assert_eq!(Packet::decode(&bytes).unwrap(), p);
+
+        p.ptype = PacketType::State;
+        p.sack = Some(vec!(0b0000_0101, 0, 0, 0x80));
+        p.payload = Vec::new();
+        let bytes = p.encode();
+        assert_eq!(bytes[0], 0x21);
+        assert_eq!(bytes[1], 1);
+        let back = Packet::decode(&bytes).unwrap();
+        assert_eq!(back, p);
+        let sack = back.sack.unwrap();
+        assert!(sack_has(&sack, 0) && !sack_has(&sack, 1) && sack_has(&sack, 2) && sack_has(&sack, 31));
+        assert!(!sack_has(&sack, 32));
+    }
+
+    #[test]
+    fn bad_packets() {
+        assert!(Packet::decode(&[0x01; 19]).is_err());
+        // Version 2, and type 6, which is what bencoded DHT packets look like
+        assert!(Packet::decode(&[0x02; 20]).is_err());
+        assert!(Packet::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").is_err());
+
+        // An extension that says it's longer than the packet
+        let mut bytes = Packet::new(PacketType::State, 1).encode();
+        bytes[1] = 1;
+        bytes.extend_from_slice(&[0, 8, 1]);
+        assert!(Packet::decode(&bytes).is_err());
+
+        // Unknown extensions get skipped
+        let mut bytes = Packet::new(PacketType::State, 1).encode();
+        bytes[1] = 9;
+        bytes.extend_from_slice(&[0, 2, 0xaa, 0xbb, b'x']);
+        let p = Packet::decode(&bytes).unwrap();
+        assert_eq!(p.sack, None);
+        assert_eq!(p.payload, b"x".to_vec());
+    }
+}
@@ -0,0 +1,845 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::{Rng, thread_rng};
+
+use utp::PAYLOAD_SIZE;
+use utp::ledbat::{Ledbat, DEFAULT_TARGET_DELAY};
+use utp::packet::{Packet, PacketType, sack_has};
+
+// Per connection, each way
+const RECV_BUFFER: usize = 1 << 20;
+const SEND_BUFFER: usize = 1 << 20;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+// Timeouts in a row before we give up on the other end
+const MAX_RETRANSMITS: u32 = 5;
+// How many packets have to be acked past a missing one, or how many
+// duplicate acks we need, before we decide it was lost
+const LOSS_THRESHOLD: u32 = 3;
+// Our selective acks cover this many packets past the first missing one
+const SACK_BITS: usize = 32;
+// Packets further ahead than this get dropped instead of buffered
+const REORDER_LIMIT: u16 = 1024;
+// Defaults for UtpConfig's connection limits
+const DEFAULT_MAX_BACKLOG: usize = 128;
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct UtpConfig {
+    // How much queueing delay LEDBAT lets us add to the path. Lower leaves
+    // more room for everyone else on the link, at some cost to our own speed.
+    pub target_delay: Duration,
+    // Connections made to us that haven't been accept()ed yet, and
+    // connections of any kind. SYNs past either limit get reset, so a flood
+    // of them on a shared port can't eat all our memory.
+    pub max_backlog: usize,
+    pub max_connections: usize
+}
+
+impl Default for UtpConfig {
+    fn default() -> UtpConfig {
+        UtpConfig {
+            target_delay: DEFAULT_TARGET_DELAY,
+            max_backlog: DEFAULT_MAX_BACKLOG,
+            max_connections: DEFAULT_MAX_CONNECTIONS
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Closed,
+    Reset,
+    TimedOut
+}
+
+// A packet that used up a sequence number and is waiting to be acked
+struct Sent {
+    seq: u16,
+    ptype: PacketType,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    resend: bool,
+    // Only resent early once; after that it's up to the timeout
+    fast_resent: bool
+}
+
+struct Conn {
+    addr: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: State,
+    // Next one we send
+    seq_nr: u16,
+    // Last one we got everything up to
+    ack_nr: u16,
+    incoming: VecDeque<u8>,
+    reorder: HashMap<u16, Vec<u8>>,
+    reorder_bytes: usize,
+    // The sequence number of their FIN
+    eof: Option<u16>,
+    outgoing: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    bytes_in_flight: usize,
+    ledbat: Ledbat,
+    peer_window: usize,
+    // Smoothed RTT and its variance, in microseconds
+    rtt: Option<(i64, i64)>,
+    rto: Duration,
+    timeouts: u32,
+    last_ack: u16,
+    dup_acks: u32,
+    // What goes in timestamp_diff: how long ago their last packet was stamped
+    reply_micros: u32,
+    need_ack: bool,
+    // No more writes; a FIN goes out once everything written has
+    closing: bool,
+    fin_seq: Option<u16>,
+    // The stream's gone, so nobody's reading either
+    dropped: bool
+}
+
+impl Conn {
+    fn new(addr: SocketAddr, recv_id: u16, send_id: u16, state: State, seq_nr: u16, ack_nr: u16, config: &UtpConfig) -> Conn {
+        Conn {
+            addr,
+            recv_id,
+            send_id,
+            state,
+            seq_nr,
+            ack_nr,
+            incoming: VecDeque::new(),
+            reorder: HashMap::new(),
+            reorder_bytes: 0,
+            eof: None,
+            outgoing: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            ledbat: Ledbat::new(config.target_delay),
+            peer_window: RECV_BUFFER,
+            rtt: None,
+            rto: INITIAL_RTO,
+            timeouts: 0,
+            last_ack: 0,
+            dup_acks: 0,
+            reply_micros: 0,
+            need_ack: false,
+            closing: false,
+            fin_seq: None,
+            dropped: false
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self.state, State::Closed | State::Reset | State::TimedOut)
+    }
+
+    fn at_eof(&self) -> bool {
+        self.eof == Some(self.ack_nr)
+    }
+
+    fn header(&self, ptype: PacketType, seq_nr: u16, micros: u32) -> Packet {
+        let mut p = Packet::new(ptype, if ptype == PacketType::Syn { self.recv_id } else { self.send_id });
+        p.timestamp = micros;
+        p.timestamp_diff = self.reply_micros;
+        p.wnd_size = RECV_BUFFER.saturating_sub(self.incoming.len() + self.reorder_bytes) as u32;
+        p.seq_nr = seq_nr;
+        p.ack_nr = self.ack_nr;
+        if !self.reorder.is_empty() {
+            let mut bits = vec![0u8; SACK_BITS / 8];
+            for i in 0..SACK_BITS {
+                if self.reorder.contains_key(&self.ack_nr.wrapping_add(2 + i as u16)) {
+                    bits[i / 8] |= 1 << (i % 8);
+                }
+            }
+            p.sack = Some(bits);
+        }
+        p
+    }
+
+    fn send_new(&mut self, ptype: PacketType, payload: Vec<u8>, now: Instant, micros: u32, out: &mut Vec<Packet>) {
+        let seq = self.seq_nr;
+        self.seq_nr = seq.wrapping_add(1);
+        let mut p = self.header(ptype, seq, micros);
+        p.payload = payload.clone();
+        out.push(p);
+
+        self.bytes_in_flight += payload.len();
+        self.in_flight.push_back(Sent { seq, ptype, payload, sent_at: now, transmissions: 1, resend: false, fast_resent: false });
+        self.need_ack = false;
+    }
+
+    fn on_packet(&mut self, p: Packet, now: Instant, micros: u32) {
+        self.reply_micros = micros.wrapping_sub(p.timestamp);
+        self.peer_window = p.wnd_size as usize;
+        if p.ptype == PacketType::Reset {
+            self.state = State::Reset;
+            return;
+        }
+        if self.state == State::SynSent {
+            if p.ptype != PacketType::State {
+                return;
+            }
+            // Their first data packet comes right after the one that
+            // answered our SYN
+            self.state = State::Connected;
+            self.ack_nr = p.seq_nr.wrapping_sub(1);
+        }
+        if self.is_finished() {
+            return;
+        }
+
+        self.handle_ack(&p, now);
+        match p.ptype {
+            PacketType::Data | PacketType::Fin => self.receive(p.ptype, p.seq_nr, p.payload),
+            // They missed our answer
+            PacketType::Syn => self.need_ack = true,
+            _ => ()
+        }
+    }
+
+    fn receive(&mut self, ptype: PacketType, seq: u16, payload: Vec<u8>) {
+        self.need_ack = true;
+        let ahead = seq.wrapping_sub(self.ack_nr);
+        if ahead == 0 || ahead > REORDER_LIMIT || self.at_eof() {
+            return;
+        }
+        // No room means no ack, so they'll send it again later
+        if self.incoming.len() + self.reorder_bytes + payload.len() > RECV_BUFFER {
+            return;
+        }
+        if ptype == PacketType::Fin {
+            self.eof = Some(seq);
+        }
+
+        if ahead == 1 {
+            self.incoming.extend(payload);
+            self.ack_nr = seq;
+            while let Some(next) = self.reorder.remove(&self.ack_nr.wrapping_add(1)) {
+                self.reorder_bytes -= next.len();
+                self.incoming.extend(next);
+                self.ack_nr = self.ack_nr.wrapping_add(1);
+            }
+        } else if !self.reorder.contains_key(&seq) {
+            self.reorder_bytes += payload.len();
+            self.reorder.insert(seq, payload);
+        }
+    }
+
+    fn handle_ack(&mut self, p: &Packet, now: Instant) {
+        let mut acked_bytes = 0;
+        let mut progressed = false;
+
+        // Sequence numbers wrap, so ack_nr covers a packet if it's no more
+        // than half the range past it
+        while let Some(front) = self.in_flight.front() {
+            if p.ack_nr.wrapping_sub(front.seq) >= 0x8000 {
+                break;
+            }
+            let sent = self.in_flight.pop_front().unwrap();
+            if sent.transmissions == 1 {
+                self.update_rtt(now.duration_since(sent.sent_at));
+            }
+            acked_bytes += sent.payload.len();
+            progressed = true;
+        }
+
+        if let Some(ref sack) = p.sack {
+            // Bit i is ack_nr + 2 + i, so relative to ack_nr + 1 it's i + 1
+            let rel = |seq: u16| seq.wrapping_sub(p.ack_nr).wrapping_sub(1) as usize;
+            let mut i = 0;
+            while i < self.in_flight.len() {
+                let r = rel(self.in_flight[i].seq);
+                if r > 0 && sack_has(sack, r - 1) {
+                    let sent = self.in_flight.remove(i).unwrap();
+                    acked_bytes += sent.payload.len();
+                    progressed = true;
+                } else {
+                    i += 1;
+                }
+            }
+
+            let mut lost = false;
+            for sent in self.in_flight.iter_mut() {
+                let r = rel(sent.seq);
+                let past = (r..sack.len() * 8).filter(|&j| sack_has(sack, j)).count() as u32;
+                if past >= LOSS_THRESHOLD && !sent.fast_resent {
+                    sent.resend = true;
+                    sent.fast_resent = true;
+                    lost = true;
+                }
+            }
+            if lost {
+                self.ledbat.on_loss();
+            }
+        }
+
+        if progressed {
+            self.bytes_in_flight -= acked_bytes;
+            self.timeouts = 0;
+            self.dup_acks = 0;
+            if acked_bytes > 0 {
+                self.ledbat.on_ack(acked_bytes, p.timestamp_diff, now);
+            }
+        } else if p.ptype == PacketType::State && p.ack_nr == self.last_ack && !self.in_flight.is_empty() {
+            self.dup_acks += 1;
+            if self.dup_acks == LOSS_THRESHOLD && !self.in_flight[0].fast_resent {
+                self.in_flight[0].resend = true;
+                self.in_flight[0].fast_resent = true;
+                self.ledbat.on_loss();
+            }
+        }
+        self.last_ack = p.ack_nr;
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let sample = sample.as_micros() as i64;
+        let (rtt, var) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, var)) => (rtt + (sample - rtt) / 8, var + ((rtt - sample).abs() - var) / 4)
+        };
+        self.rtt = Some((rtt, var));
+        self.rto = Duration::from_micros((rtt + 4 * var) as u64).max(MIN_RTO);
+    }
+
+    fn tick(&mut self, now: Instant, micros: u32, out: &mut Vec<Packet>) {
+        if self.is_finished() {
+            return;
+        }
+        let timed_out = self.in_flight.front().is_some_and(|s| now.duration_since(s.sent_at) >= self.rto);
+        if timed_out {
+            self.timeouts += 1;
+            if self.timeouts > MAX_RETRANSMITS {
+                self.state = State::TimedOut;
+                return;
+            }
+            self.ledbat.on_timeout();
+            self.rto = (self.rto * 2).min(MAX_RTO);
+            self.in_flight[0].resend = true;
+        }
+        self.flush(now, micros, out);
+    }
+
+    // Sends whatever's due: resends, new data if the window has room, our
+    // FIN, and an ack if we owe one and nothing else carried it
+    fn flush(&mut self, now: Instant, micros: u32, out: &mut Vec<Packet>) {
+        for i in 0..self.in_flight.len() {
+            if !self.in_flight[i].resend {
+                continue;
+            }
+            let mut p = self.header(self.in_flight[i].ptype, self.in_flight[i].seq, micros);
+            p.payload = self.in_flight[i].payload.clone();
+            out.push(p);
+            let sent = &mut self.in_flight[i];
+            sent.resend = false;
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            self.need_ack = false;
+        }
+
+        if self.state == State::Connected {
+            // With nothing in flight one packet always goes, so a closed
+            // window still gets probed
+            let window = self.ledbat.window().min(self.peer_window);
+            while !self.outgoing.is_empty() {
+                let n = self.outgoing.len().min(PAYLOAD_SIZE);
+                if self.bytes_in_flight > 0 && self.bytes_in_flight + n > window {
+                    break;
+                }
+                let payload = self.outgoing.drain(..n).collect();
+                self.send_new(PacketType::Data, payload, now, micros, out);
+            }
+
+            if self.closing && self.outgoing.is_empty() && self.fin_seq.is_none() {
+                self.fin_seq = Some(self.seq_nr);
+                self.send_new(PacketType::Fin, Vec::new(), now, micros, out);
+            }
+            if self.fin_seq.is_some() && self.in_flight.is_empty() && (self.at_eof() || self.dropped) {
+                self.state = State::Closed;
+            }
+        }
+
+        if self.need_ack {
+            out.push(self.header(PacketType::State, self.seq_nr, micros));
+            self.need_ack = false;
+        }
+    }
+}
+
+// uTP (BEP 29): TCP-like streams over UDP, with LEDBAT congestion control
+// so bulk transfers get out of the way of everything else on the link.
+// Drive it by calling poll() every so often. It can have a socket to
+// itself, or share one with something else that reads it and passes uTP
+// packets along to handle_packet(), like the DHT does.
+pub struct UtpSocket {
+    socket: UdpSocket,
+    // False when somebody else reads the socket
+    reads: bool,
+    config: UtpConfig,
+    // Our packet timestamps count microseconds from here
+    epoch: Instant,
+    // Keyed on the address and the connection ID they send to us with
+    conns: HashMap<(SocketAddr, u16), Arc<Mutex<Conn>>>,
+    accepted: VecDeque<UtpStream>
+}
+
+impl UtpSocket {
+    pub fn bind<A: ToSocketAddrs>(addr: A, config: UtpConfig) -> io::Result<UtpSocket> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(UtpSocket::new(socket, config, true))
+    }
+
+    // For a socket someone else is reading, usually a clone of the DHT's.
+    // Only handle_packet() feeds this one.
+    pub fn shared(socket: UdpSocket, config: UtpConfig) -> UtpSocket {
+        UtpSocket::new(socket, config, false)
+    }
+
+    fn new(socket: UdpSocket, config: UtpConfig, reads: bool) -> UtpSocket {
+        UtpSocket {
+            socket,
+            reads,
+            config,
+            epoch: Instant::now(),
+            conns: HashMap::new(),
+            accepted: VecDeque::new()
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // The stream's usable straight away; writes wait in its buffer until
+    // the other end answers
+    pub fn connect(&mut self, addr: SocketAddr, now: Instant) -> UtpStream {
+        let mut rng = thread_rng();
+        let mut recv_id: u16 = rng.gen();
+        while self.conns.contains_key(&(addr, recv_id)) {
+            recv_id = rng.gen();
+        }
+        let mut conn = Conn::new(addr, recv_id, recv_id.wrapping_add(1), State::SynSent, 1, 0, &self.config);
+        let mut out = Vec::new();
+        conn.send_new(PacketType::Syn, Vec::new(), now, self.micros(now), &mut out);
+        self.send(addr, &out);
+
+        let conn = Arc::new(Mutex::new(conn));
+        self.conns.insert((addr, recv_id), conn.clone());
+        UtpStream { conn, addr }
+    }
+
+    // Connections other people made to us, oldest first
+    pub fn accept(&mut self) -> Option<UtpStream> {
+        self.accepted.pop_front()
+    }
+
+    // Reads the socket if it's ours, resends anything that's timed out,
+    // sends whatever the windows allow, and forgets finished connections
+    pub fn poll(&mut self, now: Instant) {
+        if self.reads {
+            let mut buf = [0u8; 4096];
+            loop {
+                match self.socket.recv_from(&mut buf) {
+                    Ok((n, from)) => self.handle_packet(&buf[..n], from, now),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    // ICMP unreachables; the timeouts deal with them
+                    Err(_) => continue
+                }
+            }
+        }
+
+        let micros = self.micros(now);
+        for conn in self.conns.values() {
+            let mut out = Vec::new();
+            let addr = {
+                let mut c = conn.lock().unwrap();
+                c.tick(now, micros, &mut out);
+                c.addr
+            };
+            self.send(addr, &out);
+        }
+        self.conns.retain(|_, c| !c.lock().unwrap().is_finished());
+    }
+
+    pub fn handle_packet(&mut self, packet: &[u8], from: SocketAddr, now: Instant) {
+        let p = match Packet::decode(packet) {
+            Ok(p) => p,
+            Err(_) => { return; }
+        };
+        let micros = self.micros(now);
+        let mut out = Vec::new();
+
+        let key = match p.ptype {
+            // SYNs carry the ID the sender receives on, and we send to it
+            // Ones we've no room for fall through to the reset below
+            PacketType::Syn => {
+                let key = (from, p.conn_id.wrapping_add(1));
+                if self.conns.contains_key(&key) {
+                    Some(key)
+                } else if self.accepted.len() < self.config.max_backlog && self.conns.len() < self.config.max_connections {
+                    self.accept_syn(key, &p, now, micros);
+                    Some(key)
+                } else {
+                    None
+                }
+            },
+            // Resets come with either ID, depending on who sent them
+            PacketType::Reset => [p.conn_id, p.conn_id.wrapping_sub(1), p.conn_id.wrapping_add(1)].iter()
+                .map(|&id| (from, id))
+                .find(|key| self.conns.get(key).is_some_and(|c| {
+                    let c = c.lock().unwrap();
+                    c.send_id == p.conn_id || c.recv_id == p.conn_id
+                })),
+            _ => Some((from, p.conn_id))
+        };
+
+        match key.and_then(|key| self.conns.get(&key)) {
+            Some(conn) => {
+                let mut c = conn.lock().unwrap();
+                c.on_packet(p, now, micros);
+                c.flush(now, micros, &mut out);
+            },
+            None if p.ptype != PacketType::Reset => {
+                let mut reset = Packet::new(PacketType::Reset, p.conn_id);
+                reset.timestamp = micros;
+                reset.seq_nr = thread_rng().gen();
+                reset.ack_nr = p.seq_nr;
+                out.push(reset);
+            },
+            None => ()
+        }
+        self.send(from, &out);
+    }
+
+    // Our answer to a SYN carries the sequence number our first data packet
+    // will use, which is where they start acking from
+    fn accept_syn(&mut self, key: (SocketAddr, u16), syn: &Packet, now: Instant, micros: u32) {
+        let mut conn = Conn::new(key.0, key.1, syn.conn_id, State::Connected, thread_rng().gen(), syn.seq_nr, &self.config);
+        let mut out = Vec::new();
+        conn.on_packet(syn.clone(), now, micros);
+        conn.flush(now, micros, &mut out);
+        self.send(key.0, &out);
+
+        let conn = Arc::new(Mutex::new(conn));
+        self.conns.insert(key, conn.clone());
+        self.accepted.push_back(UtpStream { conn, addr: key.0 });
+    }
+
+    fn micros(&self, now: Instant) -> u32 {
+        now.saturating_duration_since(self.epoch).as_micros() as u32
+    }
+
+    fn send(&self, addr: SocketAddr, packets: &[Packet]) {
+        for p in packets.iter() {
+            let _ = self.socket.send_to(&p.encode(), addr);
+        }
+    }
+}
+
+// One uTP connection. Like a non-blocking TcpStream, reads and writes give
+// WouldBlock when they can't do anything yet, and reads give 0 once the
+// other end's closed and everything it sent has been read. Nothing goes
+// over the wire until the socket's polled.
+pub struct UtpStream {
+    conn: Arc<Mutex<Conn>>,
+    addr: SocketAddr
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.conn.lock().unwrap().state == State::Connected
+    }
+
+    // Stops writes. Whatever's already written still goes, followed by a
+    // FIN, and reads carry on until the other end closes too.
+    pub fn close(&mut self) {
+        self.conn.lock().unwrap().closing = true;
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut c = self.conn.lock().unwrap();
+        if !c.incoming.is_empty() {
+            let before = c.incoming.len();
+            let n = buf.len().min(before);
+            for (dst, src) in buf.iter_mut().zip(c.incoming.drain(..n)) {
+                *dst = src;
+            }
+            // Tell them there's room again if they might have stopped
+            if before >= RECV_BUFFER / 2 && c.incoming.len() < RECV_BUFFER / 2 {
+                c.need_ack = true;
+            }
+            return Ok(n);
+        }
+        if c.at_eof() {
+            return Ok(0);
+        }
+        match c.state {
+            State::Reset => Err(io::Error::new(io::ErrorKind::ConnectionReset, "uTP connection reset")),
+            State::TimedOut => Err(io::Error::new(io::ErrorKind::TimedOut, "uTP connection timed out")),
+            _ => Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing to read yet"))
+        }
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut c = self.conn.lock().unwrap();
+        match c.state {
+            State::Reset => { return Err(io::Error::new(io::ErrorKind::ConnectionReset, "uTP connection reset")); },
+            State::TimedOut => { return Err(io::Error::new(io::ErrorKind::TimedOut, "uTP connection timed out")); },
+            _ if c.closing => { return Err(io::Error::new(io::ErrorKind::BrokenPipe, "uTP connection closed")); },
+            _ => ()
+        }
+        let n = buf.len().min(SEND_BUFFER - c.outgoing.len());
+        if n == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "send buffer is full"));
+        }
+        c.outgoing.extend(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut c = self.conn.lock().unwrap();
+        c.closing = true;
+        c.dropped = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::{Duration, Instant};
+
+    use utp::packet::{Packet, PacketType};
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    // Writes what it can of `send`, and reads everything there is into `got`
+    fn pump(stream: &mut UtpStream, send: &mut Vec<u8>, got: &mut Vec<u8>) -> bool {
+        if !send.is_empty() {
+            if let Ok(n) = stream.write(send) {
+                send.drain(..n);
+            }
+        }
+        let mut buf = [0u8; 8192];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => { return true; },
+                Ok(n) => got.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { return false; },
+                Err(e) => panic!("{}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn transfers_both_ways() {
+        let mut a = UtpSocket::bind("127.0.0.1:0", UtpConfig::default()).unwrap();
+        let mut b = UtpSocket::bind("127.0.0.1:0", UtpConfig::default()).unwrap();
+        let mut ours = a.connect(b.local_addr().unwrap(), Instant::now());
+        assert!(!ours.is_connected());
+        // Written before it's even connected
+        assert_eq!(ours.write(b"hi").unwrap(), 2);
+
+        let (a_data, b_data) = (data(300_000), data(200_000));
+        let (mut a_send, mut b_send) = (a_data.clone(), b_data.clone());
+        let (mut a_got, mut b_got) = (Vec::new(), Vec::new());
+        let mut theirs = None;
+
+        let start = Instant::now();
+        while a_got.len() < b_data.len() || b_got.len() < a_data.len() + 2 {
+            assert!(start.elapsed() < Duration::from_secs(20));
+            a.poll(Instant::now());
+            b.poll(Instant::now());
+            if theirs.is_none() {
+                theirs = b.accept();
+            }
+            pump(&mut ours, &mut a_send, &mut a_got);
+            if let Some(ref mut t) = theirs {
+                pump(t, &mut b_send, &mut b_got);
+            }
+        }
+        assert!(ours.is_connected());
+        assert_eq!(&b_got[..2], b"hi");
+        assert_eq!(&b_got[2..], &a_data[..]);
+        assert_eq!(a_got, b_data);
+
+        // Closing one side ends the other's reads, then both go away
+        let mut theirs = theirs.unwrap();
+        ours.close();
+        assert!(ours.write(b"more").is_err());
+        let mut closed = false;
+        while !closed {
+            assert!(start.elapsed() < Duration::from_secs(20));
+            a.poll(Instant::now());
+            b.poll(Instant::now());
+            closed = pump(&mut theirs, &mut Vec::new(), &mut Vec::new());
+        }
+        theirs.close();
+        while !pump(&mut ours, &mut Vec::new(), &mut Vec::new()) || !a.conns.is_empty() || !b.conns.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(20));
+            a.poll(Instant::now());
+            b.poll(Instant::now());
+        }
+    }
+
+    // Two shared sockets, with the test passing packets between them and
+    // dropping some on the way. Time is faked so timeouts don't take long.
+    #[test]
+    fn recovers_from_loss() {
+        let raw_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let raw_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for s in [&raw_a, &raw_b].iter() {
+            s.set_nonblocking(true).unwrap();
+        }
+        let mut a = UtpSocket::shared(raw_a.try_clone().unwrap(), UtpConfig::default());
+        let mut b = UtpSocket::shared(raw_b.try_clone().unwrap(), UtpConfig { target_delay: Duration::from_millis(25), ..Default::default() });
+
+        let mut now = Instant::now();
+        let mut ours = a.connect(raw_b.local_addr().unwrap(), now);
+        let sent = data(200_000);
+        let mut send = sent.clone();
+        let mut got = Vec::new();
+        let mut theirs = None;
+        let mut count = 0;
+
+        for _ in 0..20_000 {
+            now += Duration::from_millis(5);
+            let mut buf = [0u8; 4096];
+            for (raw, utp) in [(&raw_a, &mut a), (&raw_b, &mut b)].iter_mut() {
+                while let Ok((n, from)) = raw.recv_from(&mut buf) {
+                    count += 1;
+                    if count % 7 != 0 {
+                        utp.handle_packet(&buf[..n], from, now);
+                    }
+                }
+                utp.poll(now);
+            }
+            if theirs.is_none() {
+                theirs = b.accept();
+            }
+            pump(&mut ours, &mut send, &mut Vec::new());
+            if let Some(ref mut t) = theirs {
+                pump(t, &mut Vec::new(), &mut got);
+            }
+            if got.len() == sent.len() {
+                break;
+            }
+        }
+        assert_eq!(got.len(), sent.len());
+        assert!(got == sent);
+    }
+
+    #[test]
+    fn resets_and_timeouts() {
+        let raw = UdpSocket::bind("127.0.0.1:0").unwrap();
+        raw.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut sock = UtpSocket::bind("127.0.0.1:0", UtpConfig::default()).unwrap();
+        let addr = sock.local_addr().unwrap();
+
+        // Data for a connection it's never heard of gets a reset
+        let mut p = Packet::new(PacketType::Data, 1234);
+        p.seq_nr = 99;
+        p.payload = b"hello".to_vec();
+        raw.send_to(&p.encode(), addr).unwrap();
+        sock.poll(Instant::now());
+        let mut buf = [0u8; 1500];
+        let (n, _) = raw.recv_from(&mut buf).unwrap();
+        let reset = Packet::decode(&buf[..n]).unwrap();
+        assert_eq!(reset.ptype, PacketType::Reset);
+        assert_eq!((reset.conn_id, reset.ack_nr), (1234, 99));
+
+        // A SYN to something that never answers times out eventually
+        let now = Instant::now();
+        let to: SocketAddr = raw.local_addr().unwrap();
+        let mut stream = sock.connect(to, now);
+        let (n, _) = raw.recv_from(&mut buf).unwrap();
+        let syn = Packet::decode(&buf[..n]).unwrap();
+        assert_eq!((syn.ptype, syn.seq_nr), (PacketType::Syn, 1));
+        for i in 1..200 {
+            sock.poll(now + Duration::from_secs(i));
+        }
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(sock.conns.is_empty());
+
+        // And one that gets reset says so
+        let raw = UdpSocket::bind("127.0.0.1:0").unwrap();
+        raw.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut stream = sock.connect(raw.local_addr().unwrap(), now);
+        let (n, _) = raw.recv_from(&mut buf).unwrap();
+        let syn = Packet::decode(&buf[..n]).unwrap();
+        let mut reply = Packet::new(PacketType::State, syn.conn_id);
+        reply.seq_nr = 500;
+        reply.ack_nr = syn.seq_nr;
+        raw.send_to(&reply.encode(), addr).unwrap();
+        let mut reset = Packet::new(PacketType::Reset, syn.conn_id);
+        reset.ack_nr = syn.seq_nr;
+        sock.poll(now);
+        assert!(stream.is_connected());
+        raw.send_to(&reset.encode(), addr).unwrap();
+        sock.poll(now);
+        assert_eq!(stream.write(b"x").unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn limits_incoming() {
+        let raw = UdpSocket::bind("127.0.0.1:0").unwrap();
+        raw.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let from = raw.local_addr().unwrap();
+        let config = UtpConfig { max_backlog: 3, max_connections: 5, ..Default::default() };
+        let mut sock = UtpSocket::bind("127.0.0.1:0", config).unwrap();
+        let now = Instant::now();
+        let mut buf = [0u8; 1500];
+        // What it answered with last
+        let mut syn = |sock: &mut UtpSocket, id: u16| -> Option<PacketType> {
+            sock.handle_packet(&Packet::new(PacketType::Syn, id).encode(), from, now);
+            let mut last = None;
+            while let Ok((n, _)) = raw.recv_from(&mut buf) {
+                last = Some(Packet::decode(&buf[..n]).unwrap().ptype);
+            }
+            last
+        };
+
+        // Nobody's accepting, so the fourth gets turned away
+        for id in 0..3 {
+            assert_eq!(syn(&mut sock, id * 10), Some(PacketType::State));
+        }
+        assert_eq!(syn(&mut sock, 30), Some(PacketType::Reset));
+        assert_eq!(sock.conns.len(), 3);
+
+        // Taking them off the queue makes room, up to the connection limit
+        let accepted: Vec<UtpStream> = (0..3).map(|_| sock.accept().unwrap()).collect();
+        assert_eq!(syn(&mut sock, 40), Some(PacketType::State));
+        assert_eq!(syn(&mut sock, 50), Some(PacketType::State));
+        assert_eq!(syn(&mut sock, 60), Some(PacketType::Reset));
+        assert_eq!(sock.conns.len(), 5);
+        assert_eq!(accepted.len(), 3);
+    }
+}